@@ -0,0 +1,141 @@
+#ifndef RAYTRACER_GPU_H
+#define RAYTRACER_GPU_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum RtMaterialKind {
+  RT_MATERIAL_KIND_LAMBERTIAN = 0,
+  RT_MATERIAL_KIND_METAL = 1,
+  RT_MATERIAL_KIND_DIELECTRIC = 2,
+  RT_MATERIAL_KIND_PBR = 3,
+} RtMaterialKind;
+
+typedef enum RtStatus {
+  RT_STATUS_OK = 0,
+  RT_STATUS_ERROR = 1,
+} RtStatus;
+
+/**
+ * A scene, the camera it's rendered through and how it's traced.
+ */
+typedef struct RtScene RtScene;
+
+/**
+ * A GPU to render on, reused across renders.
+ */
+typedef struct RtTracer RtTracer;
+
+typedef struct RtVec3 {
+  float x;
+  float y;
+  float z;
+} RtVec3;
+
+/**
+ * A material, fields unused by its `kind` are ignored.
+ */
+typedef struct RtMaterial {
+  enum RtMaterialKind kind;
+  struct RtVec3 albedo;
+  /**
+   * Blur of metal reflections, 0 for a mirror.
+   */
+  float fuzz;
+  float metallic;
+  float roughness;
+  /**
+   * Refractive index of dielectrics.
+   */
+  float refract_idx;
+} RtMaterial;
+
+/**
+ * The error of the last failed call on this thread, null if none failed. Valid until the
+ * next failing call.
+ */
+const char *rt_last_error(void);
+
+/**
+ * An empty scene seen by the default camera, 100 samples at 1200x675, traced with the
+ * default bounce depth and a random seed.
+ */
+struct RtScene *rt_scene_new(void);
+
+/**
+ * # Safety
+ *
+ * `scene` must come from `rt_scene_new` and not be used afterwards, or be null.
+ */
+void rt_scene_free(struct RtScene *scene);
+
+/**
+ * # Safety
+ *
+ * `scene` must be a live scene.
+ */
+void rt_scene_add_sphere(struct RtScene *scene,
+                         struct RtVec3 center,
+                         float radius,
+                         struct RtMaterial material);
+
+/**
+ * Places the camera, rendering `width` x `height` images of `samples` samples per pixel.
+ * Neither side may exceed 8192, the largest texture of the tracer's device.
+ *
+ * # Safety
+ *
+ * `scene` must be a live scene.
+ */
+enum RtStatus rt_scene_set_camera(struct RtScene *scene,
+                                  struct RtVec3 lookfrom,
+                                  struct RtVec3 lookat,
+                                  struct RtVec3 vup,
+                                  uint32_t samples,
+                                  uint32_t width,
+                                  uint32_t height);
+
+/**
+ * Traces up to `max_bounces` bounces per path, with the per-pass seeds derived from `seed`.
+ * The same seed renders the same image.
+ *
+ * # Safety
+ *
+ * `scene` must be a live scene.
+ */
+enum RtStatus rt_scene_set_render_options(struct RtScene *scene,
+                                          uint32_t max_bounces,
+                                          uint64_t seed);
+
+/**
+ * A headless tracer on the first suitable GPU, the software fallback adapter when
+ * `fallback_adapter` is set. Null on failure.
+ */
+struct RtTracer *rt_tracer_new(bool fallback_adapter);
+
+/**
+ * # Safety
+ *
+ * `tracer` must come from `rt_tracer_new` and not be used afterwards, or be null.
+ */
+void rt_tracer_free(struct RtTracer *tracer);
+
+/**
+ * Renders `scene` into `pixels`, 8-bit sRGB RGBA rows top to bottom of the camera's size.
+ * Blocks until all samples are traced.
+ *
+ * # Safety
+ *
+ * `tracer` and `scene` must be live, `pixels` must point to `len` writable bytes.
+ */
+enum RtStatus rt_render(const struct RtTracer *tracer,
+                        const struct RtScene *scene,
+                        uint8_t *pixels,
+                        size_t len);
+
+#endif  /* RAYTRACER_GPU_H */
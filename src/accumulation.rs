@@ -0,0 +1,372 @@
+use crate::camera::{Camera, GpuCamera};
+use crate::gpu::Gpu;
+use crate::render::Renderer;
+use anyhow::Result;
+use encase::ShaderType;
+
+/// What happens to the accumulated image when the camera moves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetPolicy {
+    /// Clear the accumulation and start from black.
+    FullReset,
+    /// Warp the previous image into the new view and keep it as a low-weight history.
+    Reproject,
+    /// Keep the previous image as-is, faded to a low weight, while new samples arrive.
+    FreezeAndGhost,
+}
+
+impl ResetPolicy {
+    pub fn next(self) -> Self {
+        match self {
+            ResetPolicy::FullReset => ResetPolicy::Reproject,
+            ResetPolicy::Reproject => ResetPolicy::FreezeAndGhost,
+            ResetPolicy::FreezeAndGhost => ResetPolicy::FullReset,
+        }
+    }
+}
+
+#[derive(ShaderType)]
+struct HistoryUniform {
+    weight: f32,
+}
+
+/// The image, its first hits and moments as they were before the camera moved.
+struct History {
+    color: wgpu::Texture,
+    hit: wgpu::Texture,
+    moments: wgpu::Texture,
+}
+
+impl History {
+    fn new(device: &wgpu::Device, renderer: &Renderer) -> Self {
+        Self {
+            color: create_history_texture(device, renderer),
+            hit: create_history_texture(device, renderer),
+            moments: create_history_texture(device, renderer),
+        }
+    }
+}
+
+pub struct Accumulator {
+    reproject_pipeline: wgpu::ComputePipeline,
+    ghost_pipeline: wgpu::ComputePipeline,
+    history: History,
+    prev_camera_buf: wgpu::Buffer,
+    history_buf: wgpu::Buffer,
+    accumulation_bg: wgpu::BindGroup,
+    accumulation_bgl: wgpu::BindGroupLayout,
+}
+
+impl Accumulator {
+    const REPROJECT_WEIGHT: f32 = 4.0;
+    const GHOST_WEIGHT: f32 = 1.0;
+
+    pub fn new(gpu: &Gpu, gpu_camera: &GpuCamera, renderer: &Renderer) -> Result<Self> {
+        let Gpu { device, .. } = gpu;
+
+        gpu.push_error_scope();
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("accumulation.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("accumulation.wgsl").into()),
+        });
+
+        let history = History::new(device, renderer);
+
+        let prev_camera_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: Camera::min_size().get(),
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let history_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: HistoryUniform::min_size().get(),
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let accumulation_bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::ReadWrite,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::ReadWrite,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let accumulation_bg = create_accumulation_bg(
+            device,
+            &accumulation_bgl,
+            renderer,
+            &history,
+            &prev_camera_buf,
+            &history_buf,
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[gpu_camera.bind_group_layout(), &accumulation_bgl],
+            push_constant_ranges: &[],
+        });
+
+        let reproject_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "reproject",
+        });
+
+        let ghost_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "ghost",
+        });
+        gpu.pop_error_scope("the accumulation pipelines")?;
+
+        Ok(Self {
+            reproject_pipeline,
+            ghost_pipeline,
+            history,
+            prev_camera_buf,
+            history_buf,
+            accumulation_bg,
+            accumulation_bgl,
+        })
+    }
+
+    pub fn on_resize(&mut self, gpu: &Gpu, renderer: &Renderer) {
+        let Gpu { device, .. } = gpu;
+
+        self.history = History::new(device, renderer);
+        self.accumulation_bg = create_accumulation_bg(
+            device,
+            &self.accumulation_bgl,
+            renderer,
+            &self.history,
+            &self.prev_camera_buf,
+            &self.history_buf,
+        );
+    }
+
+    /// Applies `policy` to the accumulated image after the camera moved away from `previous`.
+    #[tracing::instrument(level = "debug", skip_all, fields(?policy), err)]
+    pub fn on_camera_moved(
+        &self,
+        gpu: &Gpu,
+        gpu_camera: &GpuCamera,
+        renderer: &Renderer,
+        previous: &Camera,
+        policy: ResetPolicy,
+    ) -> Result<()> {
+        let Gpu { device, .. } = gpu;
+
+        let (pipeline, weight) = match policy {
+            ResetPolicy::FullReset => {
+                renderer.clear(gpu);
+                return Ok(());
+            }
+            ResetPolicy::Reproject => (&self.reproject_pipeline, Self::REPROJECT_WEIGHT),
+            ResetPolicy::FreezeAndGhost => (&self.ghost_pipeline, Self::GHOST_WEIGHT),
+        };
+
+        gpu.write_uniform(&self.prev_camera_buf, previous)?;
+        gpu.write_uniform(&self.history_buf, &HistoryUniform { weight })?;
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        encoder.copy_texture_to_texture(
+            renderer.scene_texture().as_image_copy(),
+            self.history.color.as_image_copy(),
+            self.history.color.size(),
+        );
+        encoder.copy_texture_to_texture(
+            renderer.scene_hit_texture().as_image_copy(),
+            self.history.hit.as_image_copy(),
+            self.history.hit.size(),
+        );
+        encoder.copy_texture_to_texture(
+            renderer.scene_moments_texture().as_image_copy(),
+            self.history.moments.as_image_copy(),
+            self.history.moments.size(),
+        );
+
+        {
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, gpu_camera.bind_group(), &[]);
+            cpass.set_bind_group(1, &self.accumulation_bg, &[]);
+            cpass.dispatch_workgroups(gpu_camera.camera().width, gpu_camera.camera().height, 1);
+        }
+
+        gpu.submit(Some(encoder.finish()));
+        Ok(())
+    }
+}
+
+fn create_history_texture(device: &wgpu::Device, renderer: &Renderer) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: renderer.scene_texture().size(),
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn create_accumulation_bg(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    renderer: &Renderer,
+    history: &History,
+    prev_camera_buf: &wgpu::Buffer,
+    history_buf: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &renderer
+                        .scene_texture()
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &history
+                        .color
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: prev_camera_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: history_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(
+                    &history
+                        .hit
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(
+                    &renderer
+                        .scene_hit_texture()
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::TextureView(
+                    &renderer
+                        .scene_moments_texture()
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::TextureView(
+                    &history
+                        .moments
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+        ],
+    })
+}
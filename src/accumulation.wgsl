@@ -0,0 +1,129 @@
+struct Camera {
+    num_samples: u32,
+    lookfrom: vec3<f32>,
+    lookat: vec3<f32>,
+    vup: vec3<f32>,
+    top_left_pixel: vec3<f32>,
+    delta_u: vec3<f32>,
+    delta_v: vec3<f32>,
+    width: u32,
+    height: u32,
+    exposure: f32,
+    aperture: f32,
+    focus_distance: f32,
+    bokeh: u32,
+    blades: u32,
+    blade_rotation: f32,
+    bokeh_mask: array<vec4<u32>, 2>,
+    distortion_k1: f32,
+    distortion_k2: f32,
+    projection: u32,
+    fov: f32,
+    view_height: f32,
+    eye_separation: f32,
+    preview_scale: u32,
+};
+
+struct HistoryUniform {
+    weight: f32,
+};
+
+@group(0) @binding(0) var<uniform> cam: Camera;
+@group(1) @binding(0) var raytraced: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(1) var history: texture_2d<f32>;
+@group(1) @binding(2) var<uniform> prev_cam: Camera;
+@group(1) @binding(3) var<uniform> history_uniform: HistoryUniform;
+@group(1) @binding(4) var history_hit: texture_2d<f32>;
+@group(1) @binding(5) var first_hit: texture_storage_2d<rgba32float, write>;
+@group(1) @binding(6) var moments: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(7) var history_moments: texture_2d<f32>;
+
+// Same as in `compute.wgsl`.
+const MISS_DEPTH: f32 = 1000000.0;
+// Refinements of the point seen through a pixel, see `reproject`.
+const REPROJECT_STEPS: i32 = 4;
+// Relative depth difference beyond which a reprojected point counts as occluded.
+const DEPTH_TOLERANCE: f32 = 0.05;
+
+// Factor keeping at most `weight` samples worth of an accumulated pixel with `count` samples,
+// applied to the color (rgb = sum, a = sample count) and its moments alike.
+fn historyScale(count: f32) -> f32 {
+    if count <= history_uniform.weight {
+        return 1.0;
+    }
+    return history_uniform.weight / count;
+}
+
+@compute
+@workgroup_size(1)
+fn ghost(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    var current = textureLoad(raytraced, global_id.xy);
+    var scale = historyScale(current.a);
+    textureStore(raytraced, global_id.xy, current * scale);
+    textureStore(moments, global_id.xy, textureLoad(moments, global_id.xy) * scale);
+}
+
+// Pixel of the previous camera's viewport that `point` is seen through, with z = 0 when it
+// is behind the previous camera or outside its image.
+fn projectPrevious(point: vec3<f32>) -> vec3<f32> {
+    var direction = point - prev_cam.lookfrom;
+    var normal = normalize(cross(prev_cam.delta_u, prev_cam.delta_v));
+    var denom = dot(direction, normal);
+    if abs(denom) < 1e-8 {
+        return vec3<f32>(0.0);
+    }
+
+    var t = dot(prev_cam.top_left_pixel - prev_cam.lookfrom, normal) / denom;
+    var offset = prev_cam.lookfrom + direction * t - prev_cam.top_left_pixel;
+    var x = round(dot(offset, prev_cam.delta_u) / dot(prev_cam.delta_u, prev_cam.delta_u));
+    var y = round(dot(offset, prev_cam.delta_v) / dot(prev_cam.delta_v, prev_cam.delta_v));
+    var inside = t > 0.0 && x >= 0.0 && y >= 0.0 && x < f32(prev_cam.width) && y < f32(prev_cam.height);
+    return vec3<f32>(x, y, f32(inside));
+}
+
+// Finds the point seen through each new pixel in the previous image using its depth: starting
+// on the focal plane, the point moves to the depth the previous camera saw it at, projected
+// back onto the new camera ray. Pixels hidden from the previous camera start from black.
+@compute
+@workgroup_size(1)
+fn reproject(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    var pixel = cam.top_left_pixel + f32(global_id.x) * cam.delta_u + f32(global_id.y) * cam.delta_v;
+    var ray = normalize(pixel - cam.lookfrom);
+    var distance = length(pixel - cam.lookfrom);
+    var result = vec4<f32>(0.0);
+    var result_moments = vec4<f32>(0.0);
+    var hit = vec4<f32>(0.0, 0.0, 0.0, MISS_DEPTH);
+
+    for (var i = 0; i <= REPROJECT_STEPS; i++) {
+        var point = cam.lookfrom + ray * distance;
+        var previous = projectPrevious(point);
+        if previous.z == 0.0 {
+            break;
+        }
+
+        var texel = vec2<u32>(previous.xy);
+        var seen_hit = textureLoad(history_hit, texel, 0);
+        var seen_at = seen_hit.w;
+        var seen_distance = length(point - prev_cam.lookfrom);
+        if i == REPROJECT_STEPS {
+            if abs(seen_distance - seen_at) <= DEPTH_TOLERANCE * seen_at {
+                var color = textureLoad(history, texel, 0);
+                var scale = historyScale(color.a);
+                result = color * scale;
+                result_moments = textureLoad(history_moments, texel, 0) * scale;
+                hit = vec4<f32>(seen_hit.xyz, distance);
+            }
+            break;
+        }
+
+        var seen = prev_cam.lookfrom + (point - prev_cam.lookfrom) / seen_distance * seen_at;
+        distance = dot(seen - cam.lookfrom, ray);
+        if distance <= 0.0 {
+            break;
+        }
+    }
+
+    textureStore(raytraced, global_id.xy, result);
+    textureStore(moments, global_id.xy, result_moments);
+    textureStore(first_hit, global_id.xy, hit);
+}
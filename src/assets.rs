@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+/// Finds files referenced from scene files.
+///
+/// Relative references are looked up, in order, in directories from the
+/// `RAYTRACER_ASSET_PATH` environment variable, next to the referencing file,
+/// in the configured search paths and finally in the working directory.
+#[derive(Debug, Default, Clone)]
+pub struct AssetResolver {
+    overrides: Vec<PathBuf>,
+    search_paths: Vec<PathBuf>,
+    /// Only resolve to files inside `search_paths`, see `confined`.
+    confined: bool,
+}
+
+impl AssetResolver {
+    pub const ENV_VAR: &'static str = "RAYTRACER_ASSET_PATH";
+
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        let overrides = std::env::var_os(Self::ENV_VAR)
+            .map(|paths| std::env::split_paths(&paths).collect())
+            .unwrap_or_default();
+
+        Self {
+            overrides,
+            search_paths,
+            confined: false,
+        }
+    }
+
+    /// A resolver for untrusted scene files, e.g. posted to `--serve`. References are only
+    /// looked up next to the referencing file and in `roots`, and must resolve to a file
+    /// inside one of `roots`, so neither absolute paths nor `..` reach outside them. The
+    /// environment variable is ignored, and without roots nothing resolves.
+    pub fn confined(roots: Vec<PathBuf>) -> Self {
+        Self {
+            overrides: vec![],
+            search_paths: roots,
+            confined: true,
+        }
+    }
+
+    /// Resolves `reference` found in the file `referenced_from`, if any.
+    pub fn resolve(&self, reference: &str, referenced_from: Option<&Path>) -> Result<PathBuf> {
+        let reference = Path::new(reference);
+        if self.confined {
+            return self.resolve_confined(reference, referenced_from);
+        }
+        if reference.is_absolute() {
+            return if reference.exists() {
+                Ok(reference.to_owned())
+            } else {
+                Err(anyhow!("Asset `{}` does not exist", reference.display()))
+            };
+        }
+
+        let base_dir = referenced_from.and_then(Path::parent);
+        let candidates: Vec<PathBuf> = self
+            .overrides
+            .iter()
+            .map(PathBuf::as_path)
+            .chain(base_dir)
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .chain(Some(Path::new(".")))
+            .map(|dir| dir.join(reference))
+            .collect();
+
+        candidates
+            .iter()
+            .find(|candidate| candidate.exists())
+            .cloned()
+            .ok_or_else(|| {
+                let tried: Vec<String> = candidates
+                    .iter()
+                    .map(|c| format!("  {}", c.display()))
+                    .collect();
+                anyhow!(
+                    "Asset `{}` not found, tried:\n{}",
+                    reference.display(),
+                    tried.join("\n")
+                )
+            })
+    }
+
+    fn resolve_confined(
+        &self,
+        reference: &Path,
+        referenced_from: Option<&Path>,
+    ) -> Result<PathBuf> {
+        let roots: Vec<PathBuf> = self
+            .search_paths
+            .iter()
+            .filter_map(|root| root.canonicalize().ok())
+            .collect();
+        let base_dir = referenced_from.and_then(Path::parent);
+        base_dir
+            .into_iter()
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .filter_map(|dir| dir.join(reference).canonicalize().ok())
+            .find(|candidate| roots.iter().any(|root| candidate.starts_with(root)))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Asset `{}` not found in the asset directories",
+                    reference.display()
+                )
+            })
+    }
+}
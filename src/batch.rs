@@ -0,0 +1,215 @@
+//! Batch rendering with `--batch <FILE>`: headless renders run one after another on a single
+//! GPU, set up once for all of them.
+//!
+//! Each line of the manifest is a job, a scene file, optionally the image to write, and options
+//! of that render:
+//!
+//! ```text
+//! # Turntable stills
+//! scenes/front.ron renders/front.exr --size 1920x1080 --seed 1
+//! scenes/side.ron renders/side.png --adaptive 0.02
+//! scenes/top.ron
+//! ```
+//!
+//! The image defaults to the scene file with a `.png` extension. Scene and image paths are
+//! relative to the manifest's directory. Job options override the ones given with `--batch`,
+//! the GPU is picked by the latter. Jobs can't `--resume`, resume an interrupted job on its own
+//! with `--headless --resume`. Paths can't hold spaces, blank lines and `#` comments are
+//! skipped.
+use crate::assets::AssetResolver;
+use crate::cli::Options;
+use crate::console::Console;
+use crate::gpu::Gpu;
+use crate::headless;
+use crate::scene_file::SceneFile;
+use crate::stats::StatsWriter;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug)]
+pub struct Job {
+    pub scene: PathBuf,
+    pub output: PathBuf,
+    /// The batch's options with the job's applied over them.
+    pub options: Options,
+}
+
+/// Renders the jobs of `manifest`, going on with the next one when a job fails. Ctrl+C
+/// writes the partial image of the job in flight and skips the rest.
+pub async fn run(
+    options: &Options,
+    manifest: &Path,
+    stats: Option<Mutex<StatsWriter>>,
+) -> Result<()> {
+    let source = std::fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read {}", manifest.display()))?;
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    let jobs = parse_manifest(&source, base, options)?;
+
+    let gpu = Gpu::headless(options.fallback_adapter, Console::shared()).await?;
+    let interrupted = headless::interrupt_on_ctrl_c();
+    let mut failed = 0;
+    for (i, job) in jobs.iter().enumerate() {
+        eprintln!("Job {} of {}: {}", i + 1, jobs.len(), job.scene.display());
+        if let Err(err) = render(&gpu, job, stats.as_ref(), &interrupted) {
+            eprintln!("Job {} failed: {err:#}", i + 1);
+            failed += 1;
+        }
+        if interrupted.load(Ordering::SeqCst) {
+            eprintln!("Skipping the remaining {} jobs", jobs.len() - i - 1);
+            break;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!("{failed} of {} jobs failed", jobs.len())),
+    }
+}
+
+fn render(
+    gpu: &Gpu,
+    job: &Job,
+    stats: Option<&Mutex<StatsWriter>>,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let resolver = AssetResolver::new(job.options.asset_paths.clone());
+    let scene_file = SceneFile::load(&job.scene, &resolver)?;
+    headless::render(
+        gpu,
+        &job.options,
+        &job.output,
+        scene_file,
+        None,
+        stats,
+        interrupted,
+    )
+}
+
+/// The jobs of a manifest in the directory `base`, their options applied over `defaults`.
+pub fn parse_manifest(source: &str, base: &Path, defaults: &Options) -> Result<Vec<Job>> {
+    let mut jobs = vec![];
+    for (number, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let job = parse_job(line, base, defaults)
+            .with_context(|| format!("Job on line {}", number + 1))?;
+        jobs.push(job);
+    }
+
+    if jobs.is_empty() {
+        return Err(anyhow!("The batch has no jobs"));
+    }
+    Ok(jobs)
+}
+
+fn parse_job(line: &str, base: &Path, defaults: &Options) -> Result<Job> {
+    let mut words = line.split_whitespace().peekable();
+    let scene = base.join(words.next().unwrap());
+    let output = match words.next_if(|word| !word.starts_with('-')) {
+        Some(output) => base.join(output),
+        None => scene.with_extension("png"),
+    };
+    let words: Vec<&str> = words.collect();
+    // The render would start over and overwrite the checkpoint it was asked to resume.
+    if defaults.resume.is_some() || words.contains(&"--resume") {
+        return Err(anyhow!(
+            "Batch jobs can't `--resume`, resume the render on its own with `--headless --resume`"
+        ));
+    }
+
+    let args = ["--headless", "--scene"]
+        .into_iter()
+        .map(str::to_owned)
+        .chain([scene.display().to_string()])
+        .chain(["--output".to_owned(), output.display().to_string()])
+        .chain(words.into_iter().map(str::to_owned));
+    let options = Options {
+        batch: None,
+        ..defaults.clone()
+    }
+    .with_args(args)?;
+
+    Ok(Job {
+        scene,
+        output,
+        options,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_jobs_over_the_batch_options() {
+        let defaults = Options {
+            seed: Some(7),
+            size: (640, 360),
+            ..Options::default()
+        };
+        let manifest = "
+            # Stills
+            a.ron out/a.exr --size 1920x1080
+            b.ron --seed 3  # lower noise
+            c.ron
+        ";
+
+        let jobs = parse_manifest(manifest, Path::new(""), &defaults).unwrap();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[0].output, Path::new("out/a.exr"));
+        assert_eq!(jobs[0].options.size, (1920, 1080));
+        assert_eq!(jobs[0].options.seed, Some(7));
+        assert_eq!(jobs[1].output, Path::new("b.png"));
+        assert_eq!(jobs[1].options.seed, Some(3));
+        assert_eq!(jobs[2].scene, Path::new("c.ron"));
+        assert_eq!(jobs[2].options.size, (640, 360));
+        assert!(jobs.iter().all(|job| job.options.headless));
+    }
+
+    #[test]
+    fn reports_the_line_of_invalid_jobs() {
+        let err = parse_manifest(
+            "a.ron\nb.ron --size big\n",
+            Path::new(""),
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+    }
+
+    #[test]
+    fn resolves_paths_against_the_manifest() {
+        let base = Path::new("batches");
+        let jobs =
+            parse_manifest("a.ron out/a.exr\nscenes/b.ron\n", base, &Options::default()).unwrap();
+        assert_eq!(jobs[0].scene, Path::new("batches/a.ron"));
+        assert_eq!(jobs[0].output, Path::new("batches/out/a.exr"));
+        assert_eq!(
+            jobs[0].options.scene.as_deref(),
+            Some(Path::new("batches/a.ron"))
+        );
+        assert_eq!(jobs[1].output, Path::new("batches/scenes/b.png"));
+    }
+
+    #[test]
+    fn rejects_resumed_jobs() {
+        let err = parse_manifest(
+            "a.ron --resume a.ckpt\n",
+            Path::new(""),
+            &Options::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("--resume"), "{err:#}");
+
+        let defaults = Options {
+            resume: Some("a.ckpt".into()),
+            ..Options::default()
+        };
+        assert!(parse_manifest("a.ron\n", Path::new(""), &defaults).is_err());
+    }
+}
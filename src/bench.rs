@@ -0,0 +1,29 @@
+//! Minimal timing harness for the benchmarks, which are ignored tests so they
+//! only run on request: `cargo test --release -- --ignored --nocapture bench`.
+use std::time::{Duration, Instant};
+
+const WARMUP: usize = 3;
+const BUDGET: Duration = Duration::from_secs(2);
+
+/// Times `f` repeatedly within a fixed budget and prints the fastest and median runs.
+pub fn bench(name: &str, mut f: impl FnMut()) {
+    for _ in 0..WARMUP {
+        f();
+    }
+
+    let start = Instant::now();
+    let mut times = vec![];
+    while times.is_empty() || start.elapsed() < BUDGET {
+        let run = Instant::now();
+        f();
+        times.push(run.elapsed());
+    }
+
+    times.sort();
+    println!(
+        "{name}: median {:?}, fastest {:?} ({} runs)",
+        times[times.len() / 2],
+        times[0],
+        times.len()
+    );
+}
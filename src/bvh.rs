@@ -0,0 +1,258 @@
+//! Bounding volume hierarchies for tracing meshes.
+//!
+//! Every mesh has a bottom level tree over its triangles in mesh space, shared by all of its
+//! instances. A top level tree over the world bounds of the instances finds the instances a
+//! ray may hit, so tracing costs grow with what the ray passes by rather than with the
+//! triangles in the scene. Both are split with the surface area heuristic over binned
+//! centroids, see `hitMeshes` in `shaders/intersections.wgsl` for the traversal.
+use crate::types::*;
+use encase::{ArrayLength, ShaderType};
+use nalgebra as na;
+
+/// Most primitives in a leaf, fewer when splitting is cheaper.
+const MAX_LEAF_SIZE: usize = 4;
+/// Centroid bins along the split axis, the candidate splits lie between them.
+const BINS: usize = 12;
+
+/// Nodes are laid out depth first, the first child of an inner node right after it.
+#[derive(ShaderType, Clone, Copy, Debug, PartialEq)]
+pub struct BvhNode {
+    pub min: Vec3,
+    /// Primitives of a leaf, 0 for inner nodes.
+    pub count: u32,
+    pub max: Vec3,
+    /// First primitive of a leaf or second child of an inner node, relative to the root.
+    pub index: u32,
+}
+
+#[derive(ShaderType)]
+pub struct GpuBvh {
+    pub length: ArrayLength,
+    #[size(runtime)]
+    pub nodes: Vec<BvhNode>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vec3::repeat(f32::INFINITY),
+            max: Vec3::repeat(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn around(points: impl IntoIterator<Item = Vec3>) -> Self {
+        points.into_iter().fold(Self::empty(), |aabb, point| Aabb {
+            min: aabb.min.inf(&point),
+            max: aabb.max.sup(&point),
+        })
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    /// Box around this one moved by the affine `transform`.
+    pub fn transformed(&self, transform: &na::Matrix4<f32>) -> Aabb {
+        Self::around((0..8).map(|corner| {
+            let pick = |bit: usize, axis: usize| {
+                if corner & bit != 0 {
+                    self.max[axis]
+                } else {
+                    self.min[axis]
+                }
+            };
+            let point = na::Point3::new(pick(1, 0), pick(2, 1), pick(4, 2));
+            transform.transform_point(&point).coords
+        }))
+    }
+}
+
+pub struct Bvh {
+    /// The root is the first node, there are none without primitives.
+    pub nodes: Vec<BvhNode>,
+    /// Primitives in the order the leaves reference them.
+    pub order: Vec<u32>,
+}
+
+/// Builds the tree over primitives with the given bounds.
+pub fn build(bounds: &[Aabb]) -> Bvh {
+    let mut order: Vec<u32> = (0..bounds.len() as u32).collect();
+    let mut nodes = vec![];
+    if !bounds.is_empty() {
+        build_node(bounds, &mut order, 0, &mut nodes);
+    }
+    Bvh { nodes, order }
+}
+
+/// Appends the subtree over the primitives `order`, which start at `first` in the final
+/// order, returning the index of its root.
+fn build_node(bounds: &[Aabb], order: &mut [u32], first: usize, nodes: &mut Vec<BvhNode>) -> u32 {
+    let aabb = order
+        .iter()
+        .fold(Aabb::empty(), |aabb, &i| aabb.union(&bounds[i as usize]));
+    let node_idx = nodes.len();
+    nodes.push(BvhNode {
+        min: aabb.min,
+        count: order.len() as u32,
+        max: aabb.max,
+        index: first as u32,
+    });
+
+    let Some(split) = split(bounds, order, &aabb) else {
+        return node_idx as u32;
+    };
+
+    let (left, right) = order.split_at_mut(split);
+    build_node(bounds, left, first, nodes);
+    let right = build_node(bounds, right, first + split, nodes);
+    nodes[node_idx].count = 0;
+    nodes[node_idx].index = right;
+
+    node_idx as u32
+}
+
+/// Partitions `order` where the surface area heuristic is lowest, returning the length of
+/// the first part, or `None` when a leaf is cheaper.
+fn split(bounds: &[Aabb], order: &mut [u32], aabb: &Aabb) -> Option<usize> {
+    let len = order.len();
+    let halve = || (len > MAX_LEAF_SIZE).then_some(len / 2);
+
+    let centroids = Aabb::around(order.iter().map(|&i| bounds[i as usize].centroid()));
+    let axis = (centroids.max - centroids.min).imax();
+    let extent = centroids.max[axis] - centroids.min[axis];
+    if extent <= 0.0 {
+        // Primitives at the same spot can't be told apart, just keep the leaves small.
+        return halve();
+    }
+
+    let bin = |i: u32| {
+        let offset = (bounds[i as usize].centroid()[axis] - centroids.min[axis]) / extent;
+        ((offset * BINS as f32) as usize).min(BINS - 1)
+    };
+    let mut bins = [(Aabb::empty(), 0); BINS];
+    for &i in order.iter() {
+        let (bin_aabb, count) = &mut bins[bin(i)];
+        *bin_aabb = bin_aabb.union(&bounds[i as usize]);
+        *count += 1;
+    }
+
+    // Expected primitive tests of either half, taking a traversal step to cost as much.
+    let cost = |half: &[(Aabb, usize)]| {
+        let (aabb, count) = half.iter().fold((Aabb::empty(), 0), |(aabb, count), bin| {
+            (aabb.union(&bin.0), count + bin.1)
+        });
+        if count == 0 {
+            0.0
+        } else {
+            aabb.surface_area() * count as f32
+        }
+    };
+    let (split_cost, split_bin) = (1..BINS)
+        .map(|split| {
+            let (left, right) = bins.split_at(split);
+            (cost(left) + cost(right), split)
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+    let leaf_cost = aabb.surface_area() * len as f32;
+    if len <= MAX_LEAF_SIZE && leaf_cost <= aabb.surface_area() + split_cost {
+        return None;
+    }
+
+    let mut mid = 0;
+    for i in 0..len {
+        if bin(order[i]) < split_bin {
+            order.swap(i, mid);
+            mid += 1;
+        }
+    }
+
+    if mid == 0 || mid == len {
+        halve()
+    } else {
+        Some(mid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_triangles(count: usize) -> Vec<Aabb> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..count)
+            .map(|_| {
+                let corner = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 100.0;
+                Aabb::around((0..3).map(|_| corner + Vec3::new(rng.gen(), rng.gen(), rng.gen())))
+            })
+            .collect()
+    }
+
+    /// Checks the subtree at `node`, returning its primitives in leaf order.
+    fn leaves(bvh: &Bvh, bounds: &[Aabb], node: u32) -> Vec<u32> {
+        let BvhNode {
+            min,
+            count,
+            max,
+            index,
+        } = bvh.nodes[node as usize];
+        let inside = |aabb: &Aabb| aabb.min >= min && aabb.max <= max;
+
+        let primitives = if count > 0 {
+            assert!(count as usize <= MAX_LEAF_SIZE);
+            bvh.order[index as usize..(index + count) as usize].to_vec()
+        } else {
+            let mut primitives = leaves(bvh, bounds, node + 1);
+            primitives.extend(leaves(bvh, bounds, index));
+            primitives
+        };
+        for &i in &primitives {
+            assert!(inside(&bounds[i as usize]));
+        }
+        primitives
+    }
+
+    #[test]
+    fn leaves_bound_every_primitive_once() {
+        let mut bounds = random_triangles(1000);
+        // Coincident primitives still end up in small leaves.
+        bounds.extend([bounds[0]; 10]);
+
+        let bvh = build(&bounds);
+        let mut primitives = leaves(&bvh, &bounds, 0);
+        assert_eq!(primitives, bvh.order);
+
+        primitives.sort();
+        assert_eq!(primitives, (0..bounds.len() as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_build() {
+        let bounds = random_triangles(100_000);
+        bench("BVH, 100k triangles", || {
+            build(&bounds);
+        });
+    }
+}
@@ -1,9 +1,10 @@
 use crate::gpu::Gpu;
+use crate::image::RgbaImage;
 use crate::types::*;
 use anyhow::Result;
 use encase::ShaderType;
-use winit::window::Window;
 
+#[derive(Debug, Clone, Copy)]
 pub enum CameraChange {
     Forward,
     Backward,
@@ -13,21 +14,179 @@ pub enum CameraChange {
     Down,
 }
 
-#[derive(ShaderType)]
+#[derive(ShaderType, Clone)]
 pub struct Camera {
     pub num_samples: u32,
-    lookfrom: Vec3,
-    lookat: Vec3,
-    vup: Vec3,
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
     top_left_pixel: Vec3,
     delta_u: Vec3,
     delta_v: Vec3,
     pub width: u32,
     pub height: u32,
+    /// Linear scale applied to the averaged color before display, see `Exposure::scale`.
+    exposure: f32,
+    /// Lens radius, 0 for a pinhole camera.
+    aperture: f32,
+    focus_distance: f32,
+    /// 0 for a disk, 1 for a polygon, 2 for a mask.
+    bokeh: u32,
+    blades: u32,
+    blade_rotation: f32,
+    bokeh_mask: [Vec4U; 2],
+    distortion_k1: f32,
+    distortion_k2: f32,
+    /// 0 for perspective, 1 for an equidistant and 2 for an equisolid fisheye, 3 for
+    /// equirectangular and 4 for orthographic.
+    projection: u32,
+    /// Field of view across the fisheye image circle, in radians.
+    fov: f32,
+    /// Height of the orthographic view in world units.
+    view_height: f32,
+    /// Distance between the eyes of side-by-side stereo, 0 for a single view.
+    eye_separation: f32,
+    /// Size of the pixel blocks traced as one while navigating, 1 at full resolution.
+    preview_scale: u32,
+}
+
+/// How pixels map to ray directions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Perspective,
+    /// Fisheye lens with its image circle fitted to the image height, covering `fov`
+    /// radians (up to 360°).
+    Fisheye {
+        mapping: FisheyeMapping,
+        fov: f32,
+    },
+    /// Full 360° panorama, longitude along the width and latitude along the height,
+    /// centered on the view direction and with `vup` at the top. Renders with a 2:1
+    /// aspect ratio are environment maps.
+    Equirectangular,
+    /// Parallel rays along the view direction from a `height` tall view rectangle
+    /// centered on `lookfrom`.
+    Orthographic {
+        height: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    /// Distance from the image center proportional to the angle from the view direction.
+    Equidistant,
+    /// Equal solid angles map to equal image areas.
+    Equisolid,
+}
+
+/// Shape of the lens aperture, visible in out-of-focus highlights.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bokeh {
+    Disk,
+    /// Regular polygon inscribed in the lens circle, rotated by `rotation` radians.
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+    /// 16x16 bitmask over the square around the lens circle, one row per element from
+    /// the top, bit `i` being column `i` from the left.
+    Mask([u16; 16]),
+}
+
+impl Bokeh {
+    pub const MASK_SIZE: u32 = 16;
+
+    /// Thresholds an aperture image at half brightness, resampled to the mask size.
+    pub fn from_image(image: &RgbaImage) -> Self {
+        let mut mask = [0; Self::MASK_SIZE as usize];
+        for row in 0..Self::MASK_SIZE {
+            for col in 0..Self::MASK_SIZE {
+                let x = ((col as f32 + 0.5) / Self::MASK_SIZE as f32 * image.width as f32) as usize;
+                let y =
+                    ((row as f32 + 0.5) / Self::MASK_SIZE as f32 * image.height as f32) as usize;
+                let pixel = &image.pixels[(y * image.width as usize + x) * 4..][..3];
+                let brightness = pixel.iter().map(|&c| c as u32).sum::<u32>() / 3;
+                if brightness > 127 {
+                    mask[row as usize] |= 1 << col;
+                }
+            }
+        }
+        Bokeh::Mask(mask)
+    }
+}
+
+/// Radial distortion coefficients of the Brown-Conrady model as used by OpenCV: an
+/// undistorted point at radius `r` (in units of the focal length) lands at
+/// `r * (1 + k1 * r^2 + k2 * r^4)`. Negative `k1` gives barrel distortion, positive
+/// pincushion.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Distortion {
+    pub k1: f32,
+    pub k2: f32,
+}
+
+/// Depth of field and distortion settings. Changing them restarts the trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lens {
+    /// Lens radius, 0 disables depth of field.
+    pub aperture: f32,
+    /// Distance of the plane in focus, the distance to `lookat` when unset.
+    pub focus_distance: Option<f32>,
+    pub bokeh: Bokeh,
+    pub distortion: Distortion,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture: 0.0,
+            focus_distance: None,
+            bokeh: Bokeh::Disk,
+            distortion: Distortion::default(),
+        }
+    }
+}
+
+/// Photographic exposure settings. They only scale the displayed image, so changing
+/// them doesn't restart the trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exposure {
+    /// Exposure compensation in stops.
+    pub ev: f32,
+    /// Shutter time in seconds.
+    pub shutter: f32,
+    pub iso: f32,
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Self {
+            ev: 0.0,
+            shutter: Self::DEFAULT_SHUTTER,
+            iso: Self::DEFAULT_ISO,
+        }
+    }
+}
+
+impl Exposure {
+    pub const DEFAULT_SHUTTER: f32 = 0.01;
+    pub const DEFAULT_ISO: f32 = 100.0;
+
+    /// Brightness multiplier, 1 for the defaults (1/100 s at ISO 100). Doubling the
+    /// shutter time or ISO, or adding a stop, doubles it.
+    pub fn scale(&self) -> f32 {
+        2.0f32.powf(self.ev)
+            * (self.shutter / Self::DEFAULT_SHUTTER)
+            * (self.iso / Self::DEFAULT_ISO)
+    }
 }
 
 pub struct GpuCamera {
     camera: Camera,
+    exposure: Exposure,
+    lens: Lens,
+    projection: Projection,
+    stereo: Option<f32>,
     camera_buf: wgpu::Buffer,
     camera_bg: wgpu::BindGroup,
     camera_bgl: wgpu::BindGroupLayout,
@@ -41,11 +200,13 @@ impl GpuCamera {
         let mut camera_buf = encase::UniformBuffer::new(vec![]);
         camera_buf.write(&camera)?;
 
+        gpu.push_error_scope();
         let camera_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
+            label: Some("camera"),
             contents: camera_buf.into_inner().as_slice(),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        gpu.pop_error_scope("the camera buffer")?;
 
         let camera_bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
@@ -72,6 +233,10 @@ impl GpuCamera {
 
         Ok(GpuCamera {
             camera,
+            exposure: Exposure::default(),
+            lens: Lens::default(),
+            projection: Projection::Perspective,
+            stereo: None,
             camera_buf,
             camera_bg,
             camera_bgl,
@@ -81,21 +246,107 @@ impl GpuCamera {
     pub fn on_resize(&mut self, gpu: &Gpu, new_size: (u32, u32)) -> Result<()> {
         self.camera.on_resize(new_size);
 
-        let Gpu { queue, .. } = gpu;
-        let mut camera_buf = encase::UniformBuffer::new(vec![]);
-        camera_buf.write(&self.camera)?;
-        queue.write_buffer(&self.camera_buf, 0, camera_buf.into_inner().as_slice());
-        Ok(())
+        gpu.write_uniform(&self.camera_buf, &self.camera)
     }
 
     pub fn on_camera_change(&mut self, gpu: &Gpu, change: CameraChange) -> Result<()> {
         self.camera.on_camera_change(change);
 
-        let Gpu { queue, .. } = gpu;
-        let mut camera_buf = encase::UniformBuffer::new(vec![]);
-        camera_buf.write(&self.camera)?;
-        queue.write_buffer(&self.camera_buf, 0, camera_buf.into_inner().as_slice());
-        Ok(())
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    /// Replaces the camera, e.g. with the one of another scene. The lens, exposure,
+    /// projection and stereo settings have to be set again afterwards.
+    pub fn set_camera(&mut self, gpu: &Gpu, camera: Camera) -> Result<()> {
+        self.camera = Camera {
+            preview_scale: self.camera.preview_scale,
+            ..camera
+        };
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    /// Switches between tracing the low resolution preview and the full image.
+    pub fn set_preview(&mut self, gpu: &Gpu, preview: bool) -> Result<()> {
+        self.camera.preview_scale = if preview { Camera::PREVIEW_SCALE } else { 1 };
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    /// Changes how many passes a full render traces.
+    pub fn set_num_samples(&mut self, gpu: &Gpu, num_samples: u32) -> Result<()> {
+        self.camera.num_samples = num_samples;
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    pub fn set_exposure(&mut self, gpu: &Gpu, exposure: Exposure) -> Result<()> {
+        self.exposure = exposure;
+        self.camera.exposure = exposure.scale();
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    /// Sets the depth of field and distortion, resolving an unset focus distance to the current `lookat`.
+    pub fn set_lens(&mut self, gpu: &Gpu, mut lens: Lens) -> Result<()> {
+        let focus_distance = *lens
+            .focus_distance
+            .get_or_insert((self.camera.lookat - self.camera.lookfrom).norm());
+        self.lens = lens;
+
+        self.camera.aperture = lens.aperture;
+        self.camera.focus_distance = focus_distance;
+        self.camera.distortion_k1 = lens.distortion.k1;
+        self.camera.distortion_k2 = lens.distortion.k2;
+        (
+            self.camera.bokeh,
+            self.camera.blades,
+            self.camera.blade_rotation,
+        ) = match lens.bokeh {
+            Bokeh::Disk => (0, 0, 0.0),
+            Bokeh::Polygon { blades, rotation } => (1, blades, rotation),
+            Bokeh::Mask(mask) => {
+                // Two rows per word.
+                let word = |i: usize| mask[2 * i] as u32 | (mask[2 * i + 1] as u32) << 16;
+                self.camera.bokeh_mask = [
+                    Vec4U::new(word(0), word(1), word(2), word(3)),
+                    Vec4U::new(word(4), word(5), word(6), word(7)),
+                ];
+                (2, 0, 0.0)
+            }
+        };
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    pub fn set_projection(&mut self, gpu: &Gpu, projection: Projection) -> Result<()> {
+        self.projection = projection;
+        self.camera.projection = match projection {
+            Projection::Perspective => 0,
+            Projection::Fisheye { mapping, fov } => {
+                self.camera.fov = fov;
+                match mapping {
+                    FisheyeMapping::Equidistant => 1,
+                    FisheyeMapping::Equisolid => 2,
+                }
+            }
+            Projection::Equirectangular => 3,
+            Projection::Orthographic { height } => {
+                self.camera.view_height = height;
+                4
+            }
+        };
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
+    }
+
+    /// Renders the left eye into the left half of the image and the right eye into the
+    /// right half, `eye_separation` apart. `None` renders a single view.
+    pub fn set_stereo(&mut self, gpu: &Gpu, eye_separation: Option<f32>) -> Result<()> {
+        self.stereo = eye_separation;
+        self.camera.eye_separation = eye_separation.unwrap_or(0.0);
+
+        gpu.write_uniform(&self.camera_buf, &self.camera)
     }
 
     pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
@@ -109,12 +360,33 @@ impl GpuCamera {
     pub fn camera(&self) -> &Camera {
         &self.camera
     }
+
+    pub fn exposure(&self) -> Exposure {
+        self.exposure
+    }
+
+    pub fn lens(&self) -> Lens {
+        self.lens
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    pub fn stereo(&self) -> Option<f32> {
+        self.stereo
+    }
 }
 
 impl Camera {
-    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, num_samples: u32, window: &Window) -> Self {
-        let size = window.inner_size();
-        let (image_width, image_height) = (size.width as f32, size.height as f32);
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        num_samples: u32,
+        (width, height): (u32, u32),
+    ) -> Self {
+        let (image_width, image_height) = (width as f32, height as f32);
 
         let aspect_ratio = image_width / image_height;
 
@@ -145,9 +417,43 @@ impl Camera {
             delta_v,
             width: image_width as u32,
             height: image_height as u32,
+            exposure: 1.0,
+            aperture: 0.0,
+            focus_distance: focal_length,
+            bokeh: 0,
+            blades: 0,
+            blade_rotation: 0.0,
+            bokeh_mask: [Vec4U::zeros(); 2],
+            distortion_k1: 0.0,
+            distortion_k2: 0.0,
+            projection: 0,
+            fov: 0.0,
+            view_height: 0.0,
+            eye_separation: 0.0,
+            preview_scale: 1,
         }
     }
 
+    /// Width and height of the pixel blocks of the navigation preview.
+    pub const PREVIEW_SCALE: u32 = 4;
+
+    /// Brightness multiplier applied before display.
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn is_preview(&self) -> bool {
+        self.preview_scale > 1
+    }
+
+    /// Size of the traced image, smaller than the window while previewing.
+    pub fn traced_size(&self) -> (u32, u32) {
+        (
+            self.width.div_ceil(self.preview_scale),
+            self.height.div_ceil(self.preview_scale),
+        )
+    }
+
     pub fn on_resize(&mut self, (image_width, image_height): (u32, u32)) {
         let Self {
             lookfrom,
@@ -237,3 +543,111 @@ impl Camera {
         self.height = image_height as u32;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::assert_layout;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn camera() -> Camera {
+        Camera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -2.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            100,
+            (200, 100),
+        )
+    }
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).norm() < EPSILON, "{a:?} != {b:?}");
+    }
+
+    /// Position of the center of pixel `(x, y)` on the viewport.
+    fn pixel(camera: &Camera, x: f32, y: f32) -> Vec3 {
+        camera.top_left_pixel + x * camera.delta_u + y * camera.delta_v
+    }
+
+    #[test]
+    fn new_spans_viewport_at_focal_length() {
+        let camera = camera();
+
+        // Viewport is twice the focal length high, u points right and v down.
+        assert_close(camera.delta_u, Vec3::new(8.0 / 200.0, 0.0, 0.0));
+        assert_close(camera.delta_v, Vec3::new(0.0, -4.0 / 100.0, 0.0));
+        assert_close(pixel(&camera, 99.5, 49.5), camera.lookat);
+        assert_close(pixel(&camera, -0.5, -0.5), Vec3::new(-4.0, 2.0, -2.0));
+        assert_eq!((camera.width, camera.height), (200, 100));
+        assert!((camera.focus_distance - 2.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn new_keeps_basis_orthogonal() {
+        let camera = Camera::new(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            1,
+            (1200, 675),
+        );
+        let w = (camera.lookfrom - camera.lookat).normalize();
+
+        assert!(camera.delta_u.dot(&camera.delta_v).abs() < EPSILON);
+        assert!(camera.delta_u.dot(&w).abs() < EPSILON);
+        assert!(camera.delta_v.dot(&w).abs() < EPSILON);
+        // Square pixels.
+        assert!((camera.delta_u.norm() - camera.delta_v.norm()).abs() < EPSILON);
+        assert_close(pixel(&camera, 599.5, 337.0), camera.lookat);
+    }
+
+    #[test]
+    fn on_resize_matches_new() {
+        let mut camera = camera();
+        camera.on_resize((640, 480));
+        let expected = Camera::new(camera.lookfrom, camera.lookat, camera.vup, 100, (640, 480));
+
+        assert_eq!((camera.width, camera.height), (640, 480));
+        assert_close(camera.delta_u, expected.delta_u);
+        assert_close(camera.delta_v, expected.delta_v);
+        assert_close(camera.top_left_pixel, expected.top_left_pixel);
+    }
+
+    #[test]
+    fn on_camera_change_moves_along_basis() {
+        let moves = [
+            (CameraChange::Forward, Vec3::new(0.0, 0.0, -0.1)),
+            (CameraChange::Backward, Vec3::new(0.0, 0.0, 0.1)),
+            (CameraChange::Left, Vec3::new(-0.1, 0.0, 0.0)),
+            (CameraChange::Right, Vec3::new(0.1, 0.0, 0.0)),
+            (CameraChange::Up, Vec3::new(0.0, 0.1, 0.0)),
+            (CameraChange::Down, Vec3::new(0.0, -0.1, 0.0)),
+        ];
+
+        for (change, offset) in moves {
+            let mut camera = camera();
+            camera.on_camera_change(change);
+            let expected = Camera::new(
+                offset,
+                offset + Vec3::new(0.0, 0.0, -1.0),
+                camera.vup,
+                100,
+                (200, 100),
+            );
+
+            assert_close(camera.lookfrom, offset);
+            assert_close(camera.lookat, expected.lookat);
+            assert_close(camera.delta_u, expected.delta_u);
+            assert_close(camera.delta_v, expected.delta_v);
+            assert_close(camera.top_left_pixel, expected.top_left_pixel);
+        }
+    }
+
+    #[test]
+    fn camera_matches_wgsl_layout() {
+        assert_layout::<Camera>(&crate::shader::compute(&[]).unwrap(), "Camera");
+        assert_layout::<Camera>(include_str!("render.wgsl"), "Camera");
+        assert_layout::<Camera>(include_str!("accumulation.wgsl"), "Camera");
+    }
+}
@@ -0,0 +1,279 @@
+//! Checkpoints of long headless renders, resumable with `--resume`.
+//!
+//! A checkpoint starts with a text header in the style of a recording, followed by
+//! the accumulated texels as little-endian `f32` (rgb sum and sample count per pixel)
+//! and then their moments, in the same layout:
+//!
+//! ```text
+//! raytracer-checkpoint 3
+//! seed 1234
+//! passes 520
+//! size 1920 1080
+//! kernel multi-pass
+//! paths-per-pixel 4
+//! pixel-filter tent
+//! adaptive 0.02
+//! scene rt1.…
+//! <texels>
+//! <moments>
+//! ```
+//!
+//! The seed and number of passes traced restore the pass seed sequence, so the
+//! resumed render continues exactly where the checkpointed one stopped. The moments
+//! are restored with the image, otherwise the variance estimate of the resumed
+//! render would only cover the passes traced after resuming. The options shaping the
+//! samples are recorded too, see `TraceSettings`, resuming with others would mix
+//! incompatible samples into the image.
+use crate::cli::Options;
+use crate::image::Accumulation;
+use crate::raytracing::{Kernel, PixelFilter};
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::Path;
+
+const HEADER: &str = "raytracer-checkpoint 3";
+
+/// Options of a render that change the samples it traces, a resumed render must use the same.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceSettings {
+    pub kernel: Kernel,
+    pub paths_per_pixel: u32,
+    pub pixel_filter: PixelFilter,
+    /// Error threshold of adaptive sampling, `None` traces every tile.
+    pub adaptive: Option<f32>,
+}
+
+impl TraceSettings {
+    pub fn from_options(options: &Options) -> Self {
+        Self {
+            kernel: options.kernel,
+            paths_per_pixel: options.paths_per_pixel,
+            pixel_filter: options.pixel_filter,
+            adaptive: options.adaptive,
+        }
+    }
+
+    /// The header lines of the settings, also naming them in errors.
+    fn header(&self) -> String {
+        let adaptive = match self.adaptive {
+            Some(threshold) => threshold.to_string(),
+            None => "off".to_owned(),
+        };
+        format!(
+            "kernel {}\npaths-per-pixel {}\npixel-filter {}\nadaptive {adaptive}\n",
+            self.kernel.name(),
+            self.paths_per_pixel,
+            self.pixel_filter.name()
+        )
+    }
+
+    fn parse(rest: &mut &[u8]) -> Result<Self> {
+        let mut field = |name: &str| -> Result<String> {
+            Ok(next_line(rest, name)?
+                .strip_prefix(name)
+                .and_then(|value| value.strip_prefix(' '))
+                .ok_or_else(|| anyhow!("Expected `{name} <value>`"))?
+                .to_owned())
+        };
+
+        let kernel = field("kernel")?;
+        let kernel =
+            Kernel::from_name(&kernel).ok_or_else(|| anyhow!("Unknown kernel `{kernel}`"))?;
+        let paths_per_pixel = field("paths-per-pixel")?.parse()?;
+        let pixel_filter = field("pixel-filter")?;
+        let pixel_filter = PixelFilter::from_name(&pixel_filter)
+            .ok_or_else(|| anyhow!("Unknown pixel filter `{pixel_filter}`"))?;
+        let adaptive = match field("adaptive")?.as_str() {
+            "off" => None,
+            threshold => Some(threshold.parse()?),
+        };
+
+        Ok(Self {
+            kernel,
+            paths_per_pixel,
+            pixel_filter,
+            adaptive,
+        })
+    }
+
+    /// Fails unless a render with these settings can resume one traced with `checkpointed`.
+    pub fn check_resumes(&self, checkpointed: &TraceSettings) -> Result<()> {
+        if self == checkpointed {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "The checkpoint was traced with other options, resume with the same ones:\n{}",
+            checkpointed.header().trim_end()
+        ))
+    }
+}
+
+pub struct Checkpoint {
+    pub seed: u64,
+    /// Sample passes accumulated so far.
+    pub passes: u32,
+    pub settings: TraceSettings,
+    /// Scene code, see `share`.
+    pub scene: String,
+    pub accumulation: Accumulation,
+    /// Moments of `accumulation`, one texel per pixel, see `Renderer::read_moments`.
+    pub moments: Vec<[f32; 4]>,
+}
+
+impl Checkpoint {
+    /// Writes to a temporary file first, so a crash while saving keeps the previous checkpoint.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let Accumulation {
+            width,
+            height,
+            texels,
+        } = &self.accumulation;
+
+        let mut out = vec![];
+        writeln!(out, "{HEADER}")?;
+        writeln!(out, "seed {}", self.seed)?;
+        writeln!(out, "passes {}", self.passes)?;
+        writeln!(out, "size {width} {height}")?;
+        write!(out, "{}", self.settings.header())?;
+        writeln!(out, "scene {}", self.scene)?;
+        for texel in texels.iter().chain(&self.moments) {
+            for channel in texel {
+                out.extend_from_slice(&channel.to_le_bytes());
+            }
+        }
+
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, out)
+            .with_context(|| format!("Failed to write checkpoint `{}`", temporary.display()))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("Failed to write checkpoint `{}`", path.display()))?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read checkpoint `{}`", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("Invalid checkpoint `{}`", path.display()))
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut rest = bytes;
+
+        if next_line(&mut rest, "header")? != HEADER {
+            return Err(anyhow!(
+                "Not a checkpoint, expected `{HEADER}` on the first line"
+            ));
+        }
+
+        let seed = next_line(&mut rest, "seed")?
+            .strip_prefix("seed ")
+            .ok_or_else(|| anyhow!("Expected `seed <n>`"))?
+            .parse()?;
+
+        let passes = next_line(&mut rest, "passes")?
+            .strip_prefix("passes ")
+            .ok_or_else(|| anyhow!("Expected `passes <n>`"))?
+            .parse()?;
+
+        let size = next_line(&mut rest, "size")?
+            .strip_prefix("size ")
+            .and_then(|size| size.split_once(' '))
+            .ok_or_else(|| anyhow!("Expected `size <width> <height>`"))?;
+        let (width, height): (u32, u32) = (size.0.parse()?, size.1.parse()?);
+
+        let settings = TraceSettings::parse(&mut rest)?;
+
+        let scene = next_line(&mut rest, "scene")?
+            .strip_prefix("scene ")
+            .ok_or_else(|| anyhow!("Expected `scene <code>`"))?
+            .to_owned();
+
+        const TEXEL_SIZE: usize = 16;
+        let pixels = width as usize * height as usize;
+        if rest.len() != 2 * pixels * TEXEL_SIZE {
+            return Err(anyhow!(
+                "Expected {width}x{height} texels and their moments, got {} bytes",
+                rest.len()
+            ));
+        }
+
+        let mut texels: Vec<[f32; 4]> = rest
+            .chunks_exact(TEXEL_SIZE)
+            .map(|texel| {
+                let mut value = [0.0; 4];
+                for (channel, bytes) in value.iter_mut().zip(texel.chunks_exact(4)) {
+                    *channel = f32::from_le_bytes(bytes.try_into().unwrap());
+                }
+                value
+            })
+            .collect();
+        let moments = texels.split_off(pixels);
+
+        Ok(Self {
+            seed,
+            passes,
+            settings,
+            scene,
+            accumulation: Accumulation {
+                width,
+                height,
+                texels,
+            },
+            moments,
+        })
+    }
+}
+
+/// Splits the next `\n` terminated line off `rest`.
+fn next_line<'a>(rest: &mut &'a [u8], what: &str) -> Result<&'a str> {
+    let end = rest
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| anyhow!("Missing {what}"))?;
+    let line = std::str::from_utf8(&rest[..end])?;
+    *rest = &rest[end + 1..];
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_checks_settings() {
+        let settings = TraceSettings {
+            kernel: Kernel::MultiPass,
+            paths_per_pixel: 4,
+            pixel_filter: PixelFilter::Tent,
+            adaptive: Some(0.02),
+        };
+        let checkpoint = Checkpoint {
+            seed: 7,
+            passes: 3,
+            settings,
+            scene: "rt1.abc".to_owned(),
+            accumulation: Accumulation {
+                width: 2,
+                height: 1,
+                texels: vec![[1.0, 2.0, 3.0, 3.0], [0.5; 4]],
+            },
+            moments: vec![[4.0; 4], [0.25; 4]],
+        };
+        let path = std::env::temp_dir().join("raytracer-checkpoint-test.ckpt");
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((loaded.seed, loaded.passes), (7, 3));
+        assert_eq!(loaded.settings, settings);
+        assert_eq!(loaded.accumulation.texels, checkpoint.accumulation.texels);
+        assert_eq!(loaded.moments, checkpoint.moments);
+
+        assert!(settings.check_resumes(&loaded.settings).is_ok());
+        let box_filter = TraceSettings {
+            pixel_filter: PixelFilter::Box,
+            ..settings
+        };
+        assert!(box_filter.check_resumes(&loaded.settings).is_err());
+    }
+}
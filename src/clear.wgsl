@@ -0,0 +1,11 @@
+// Zeroes a float storage texture, for devices without `Features::CLEAR_TEXTURE`.
+@group(0) @binding(0) var cleared: texture_storage_2d<rgba32float, write>;
+
+@compute
+@workgroup_size(8, 8)
+fn clear(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(cleared);
+    if id.x < size.x && id.y < size.y {
+        textureStore(cleared, id.xy, vec4<f32>(0.0));
+    }
+}
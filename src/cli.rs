@@ -0,0 +1,408 @@
+use crate::raytracing::{Kernel, PixelFilter};
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Where to write per-frame JSON stats. `-` means stdout.
+    pub stats: Option<PathBuf>,
+    /// Scene file to render instead of the built-in scene. `-` reads a RON scene from stdin.
+    pub scene: Option<PathBuf>,
+    /// Shared scene code to render instead of the built-in scene.
+    pub scene_code: Option<String>,
+    /// Render the random spheres scene of book 1 generated from this seed.
+    pub random_spheres: Option<u64>,
+    /// Print the scene code of the loaded scene and exit.
+    pub share: bool,
+    /// Write the loaded scene as a scene file and exit.
+    pub save_scene: Option<PathBuf>,
+    /// Write the loaded scene as binary glTF and exit.
+    pub export_gltf: Option<PathBuf>,
+    /// Extra directories searched for assets referenced by scene files.
+    pub asset_paths: Vec<PathBuf>,
+    /// Render without a window.
+    pub headless: bool,
+    /// Image written when rendering finishes or is interrupted.
+    pub output: Option<PathBuf>,
+    /// Image size used in headless mode.
+    pub size: (u32, u32),
+    /// Record input to this file.
+    pub record: Option<PathBuf>,
+    /// Replay input recorded with `record`.
+    pub replay: Option<PathBuf>,
+    /// Periodically save the headless render here, see `checkpoint`.
+    pub checkpoint: Option<PathBuf>,
+    /// Time between checkpoints.
+    pub checkpoint_interval: Duration,
+    /// Continue a headless render from a checkpoint.
+    pub resume: Option<PathBuf>,
+    /// Seed of the sample pass sequence, random when not given.
+    pub seed: Option<u64>,
+    /// Use the software adapter even when a GPU is available.
+    pub fallback_adapter: bool,
+    /// Keep accumulating passes after the sample count is reached.
+    pub progressive: bool,
+    /// Stop a headless render once the mean relative error per pixel drops below this.
+    pub noise_threshold: Option<f32>,
+    /// Trace only the tiles whose mean relative error per pixel is above this.
+    pub adaptive: Option<f32>,
+    /// How full passes follow their paths.
+    pub kernel: Kernel,
+    /// Bounces each dispatch of the multi-pass kernel advances its paths by.
+    pub bounces_per_dispatch: u32,
+    /// Store multi-pass paths with octahedral directions and half precision colors.
+    pub packed_paths: bool,
+    /// Paths each pass of the multi-pass kernel follows from every pixel.
+    pub paths_per_pixel: u32,
+    /// Size of the traced image relative to the window, above 1 to supersample.
+    pub render_scale: f32,
+    /// Fixed size of the traced image in a window of any size.
+    pub resolution: Option<(u32, u32)>,
+    /// Width over height the traced image keeps whatever the window's shape.
+    pub aspect: Option<f32>,
+    /// Filter reconstructing the pixels from their samples.
+    pub pixel_filter: PixelFilter,
+    /// Address to serve renders over HTTP on, see `serve`.
+    pub serve: Option<String>,
+    /// Address to hand out buckets of the image to workers on, see `distributed`.
+    pub coordinator: Option<String>,
+    /// Address of the coordinator to trace buckets for.
+    pub worker: Option<String>,
+    /// Manifest of headless renders to run one after another, see `batch`.
+    pub batch: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            stats: None,
+            scene: None,
+            scene_code: None,
+            random_spheres: None,
+            share: false,
+            save_scene: None,
+            export_gltf: None,
+            asset_paths: vec![],
+            headless: false,
+            output: None,
+            size: (1200, 675),
+            record: None,
+            replay: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(60),
+            resume: None,
+            seed: None,
+            fallback_adapter: false,
+            progressive: false,
+            noise_threshold: None,
+            adaptive: None,
+            kernel: Kernel::Megakernel,
+            bounces_per_dispatch: 1,
+            packed_paths: false,
+            paths_per_pixel: 1,
+            render_scale: 1.0,
+            resolution: None,
+            aspect: None,
+            pixel_filter: PixelFilter::Box,
+            serve: None,
+            coordinator: None,
+            worker: None,
+            batch: None,
+        }
+    }
+}
+
+const USAGE: &str = "Usage: raytracer-gpu [OPTIONS]
+
+Options:
+  --scene <FILE>        Render the given scene file instead of the built-in scene
+  -, --scene -          Read a RON scene file from standard input
+  --scene-code <CODE>   Render a scene shared with --share or the C key
+  --random-spheres <SEED>
+                        Render the final scene of \"Ray Tracing in One Weekend\" generated from SEED
+  --share               Print a compact code for the loaded scene and camera, then exit
+  --save-scene <FILE>   Write the loaded scene and camera to a RON scene file, then exit
+  --export-gltf <FILE>  Write the loaded scene and camera to a binary glTF (.glb) file, then exit
+  --asset-path <DIR>    Additional directory to search for scene assets (repeatable)
+  --headless            Render without a window, requires --output
+  --output <FILE>       Write the final image (.png, .ppm, .pfm or .exr) when rendering ends or is interrupted
+  --size <WxH>          Image size in headless and --coordinator mode [default: 1200x675]
+  --seed <N>            Seed the sample passes, the same scene, size and seed give identical images
+  --record <FILE>       Record camera movement and commands for --replay
+  --replay <FILE>       Replay a recording with the same scene, window size, seeds and timing
+  --checkpoint <FILE>   Periodically save a headless render so it can be resumed
+  --checkpoint-interval <SECONDS>
+                        Time between checkpoints [default: 60]
+  --resume <FILE>       Continue a headless render from a checkpoint, updating it as it goes
+  --noise-threshold <ERROR>
+                        Stop a headless render early once the mean relative error per pixel is below ERROR, e.g. 0.02
+  --adaptive <ERROR>    Only trace the 16x16 tiles whose mean relative error per pixel is above ERROR
+  --multi-pass          Trace full passes with a dispatch per bounce instead of the megakernel (K toggles)
+  --bounces-per-dispatch <K>
+                        Advance the paths of the multi-pass kernel K bounces per dispatch [default: 1]
+  --packed-paths        Keep the paths of the multi-pass kernel with octahedral directions and half precision colors
+  --paths-per-pixel <N> Follow N paths from every pixel in each pass of the multi-pass kernel [default: 1]
+  --render-scale <FACTOR>
+                        Trace FACTOR times the window resolution, filtered to fit it, from 0.25 to 4 [default: 1]
+  --resolution <WxH>    Trace a WxH image whatever the window size, letterboxed to fit it
+  --aspect <W:H>        Keep the traced image at aspect ratio W:H (or a ratio like 2.39), letterboxed in the window
+  --pixel-filter <FILTER>
+                        Reconstruct pixels with a box, tent, gaussian or mitchell filter [default: box]
+  --serve <ADDR>        Run a render service over HTTP on ADDR (e.g. 0.0.0.0:8080) instead of a window
+  --coordinator <ADDR>  Split the image into buckets traced by workers connecting to ADDR, writing it to --output
+  --worker <ADDR>       Trace buckets for the coordinator on ADDR until its image is done
+  --batch <FILE>        Render the jobs listed in FILE one after another, a scene file and its options per line
+  --progressive         Keep refining the image after the sample count is reached (M toggles)
+  --fallback-adapter    Render on the software adapter, used anyway when no GPU adapter is found
+  --stats <FILE>        Append a JSON line with frame statistics per sample pass (`-` for stdout)
+  -h, --help            Print this help";
+
+impl Options {
+    pub fn from_args() -> Result<Self> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self> {
+        Options::default().with_args(args)
+    }
+
+    /// These options with `args` applied over them, e.g. the settings of a `batch` job.
+    pub fn with_args(self, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = self;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--stats" => options.stats = Some(value(&arg, args.next())?.into()),
+                "--scene" => options.scene = Some(value(&arg, args.next())?.into()),
+                "-" => options.scene = Some(arg.into()),
+                "--scene-code" => options.scene_code = Some(value(&arg, args.next())?),
+                "--random-spheres" => {
+                    options.random_spheres = Some(value(&arg, args.next())?.parse()?)
+                }
+                "--share" => options.share = true,
+                "--save-scene" => options.save_scene = Some(value(&arg, args.next())?.into()),
+                "--export-gltf" => options.export_gltf = Some(value(&arg, args.next())?.into()),
+                "--headless" => options.headless = true,
+                "--fallback-adapter" => options.fallback_adapter = true,
+                "--progressive" => options.progressive = true,
+                "--multi-pass" => options.kernel = Kernel::MultiPass,
+                "--packed-paths" => options.packed_paths = true,
+                "--bounces-per-dispatch" => {
+                    options.bounces_per_dispatch = value(&arg, args.next())?.parse()?
+                }
+                "--paths-per-pixel" => {
+                    options.paths_per_pixel = value(&arg, args.next())?.parse()?
+                }
+                "--render-scale" => options.render_scale = value(&arg, args.next())?.parse()?,
+                "--resolution" => {
+                    options.resolution = Some(parse_size(&value(&arg, args.next())?)?)
+                }
+                "--aspect" => options.aspect = Some(parse_aspect(&value(&arg, args.next())?)?),
+                "--pixel-filter" => {
+                    let name = value(&arg, args.next())?;
+                    options.pixel_filter = PixelFilter::from_name(&name).ok_or_else(|| {
+                        anyhow!("Unknown pixel filter `{name}`, expected box, tent, gaussian or mitchell")
+                    })?
+                }
+                "--serve" => options.serve = Some(value(&arg, args.next())?),
+                "--coordinator" => options.coordinator = Some(value(&arg, args.next())?),
+                "--worker" => options.worker = Some(value(&arg, args.next())?),
+                "--batch" => options.batch = Some(value(&arg, args.next())?.into()),
+                "--output" => options.output = Some(value(&arg, args.next())?.into()),
+                "--size" => options.size = parse_size(&value(&arg, args.next())?)?,
+                "--seed" => options.seed = Some(value(&arg, args.next())?.parse()?),
+                "--record" => options.record = Some(value(&arg, args.next())?.into()),
+                "--replay" => options.replay = Some(value(&arg, args.next())?.into()),
+                "--checkpoint" => options.checkpoint = Some(value(&arg, args.next())?.into()),
+                "--checkpoint-interval" => {
+                    options.checkpoint_interval =
+                        Duration::from_secs(value(&arg, args.next())?.parse()?)
+                }
+                "--noise-threshold" => {
+                    options.noise_threshold = Some(value(&arg, args.next())?.parse()?)
+                }
+                "--adaptive" => options.adaptive = Some(value(&arg, args.next())?.parse()?),
+                "--resume" => options.resume = Some(value(&arg, args.next())?.into()),
+                "--asset-path" => options.asset_paths.push(value(&arg, args.next())?.into()),
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => return Err(anyhow!("Unknown argument `{arg}`\n\n{USAGE}")),
+            }
+        }
+
+        let scene_sources = [
+            options.scene.is_some(),
+            options.scene_code.is_some(),
+            options.random_spheres.is_some(),
+        ];
+        if scene_sources.into_iter().filter(|&given| given).count() > 1 {
+            return Err(anyhow!(
+                "`--scene`, `--scene-code` and `--random-spheres` are mutually exclusive"
+            ));
+        }
+
+        let scene_given = scene_sources.contains(&true);
+        if options.replay.is_some() && (scene_given || options.headless) {
+            return Err(anyhow!(
+                "`--replay` uses the recorded scene and needs a window, it can't be combined with `--scene`, `--scene-code`, `--random-spheres` or `--headless`"
+            ));
+        }
+
+        if options.resume.is_some() && (scene_given || options.replay.is_some()) {
+            return Err(anyhow!(
+                "`--resume` uses the checkpointed scene, it can't be combined with `--scene`, `--scene-code`, `--random-spheres` or `--replay`"
+            ));
+        }
+
+        if options.seed.is_some() && (options.replay.is_some() || options.resume.is_some()) {
+            return Err(anyhow!(
+                "`--replay` and `--resume` use their recorded seed, it can't be combined with `--seed`"
+            ));
+        }
+
+        if (options.checkpoint.is_some() || options.resume.is_some()) && !options.headless {
+            return Err(anyhow!(
+                "`--checkpoint` and `--resume` require `--headless`"
+            ));
+        }
+
+        if options.noise_threshold.is_some() && !options.headless && options.batch.is_none() {
+            return Err(anyhow!(
+                "`--noise-threshold` requires `--headless` or `--batch`"
+            ));
+        }
+
+        if options.bounces_per_dispatch == 0 {
+            return Err(anyhow!("`--bounces-per-dispatch` must be at least 1"));
+        }
+
+        if options.paths_per_pixel == 0 {
+            return Err(anyhow!("`--paths-per-pixel` must be at least 1"));
+        }
+
+        if !(0.25..=4.0).contains(&options.render_scale) {
+            return Err(anyhow!("`--render-scale` must be between 0.25 and 4"));
+        }
+
+        if (options.render_scale != 1.0 || options.resolution.is_some()) && options.headless {
+            return Err(anyhow!(
+                "`--render-scale` and `--resolution` size the window's image, use `--size` with `--headless`"
+            ));
+        }
+
+        if options.aspect.is_some() && options.headless {
+            return Err(anyhow!(
+                "`--aspect` requires a window, use `--size` with `--headless`"
+            ));
+        }
+
+        if options.aspect.is_some() && options.resolution.is_some() {
+            return Err(anyhow!(
+                "`--resolution` fixes the aspect ratio, it can't be combined with `--aspect`"
+            ));
+        }
+
+        if options.render_scale != 1.0 && options.resolution.is_some() {
+            return Err(anyhow!(
+                "`--resolution` fixes the traced size, it can't be combined with `--render-scale`"
+            ));
+        }
+
+        let windowed = options.render_scale != 1.0
+            || options.resolution.is_some()
+            || options.aspect.is_some()
+            || options.progressive
+            || options.record.is_some()
+            || options.replay.is_some();
+        if options.serve.is_some()
+            && (options.headless || options.output.is_some() || scene_given || windowed)
+        {
+            return Err(anyhow!(
+                "`--serve` renders the scenes posted to it headless, it can't be combined with `--headless`, `--output`, scene options or window options"
+            ));
+        }
+
+        if options.coordinator.is_some()
+            && (options.headless || options.serve.is_some() || windowed)
+        {
+            return Err(anyhow!(
+                "`--coordinator` renders without a window, it can't be combined with `--headless`, `--serve` or window options"
+            ));
+        }
+
+        if options.coordinator.is_some() && options.output.is_none() {
+            return Err(anyhow!("`--coordinator` requires `--output`"));
+        }
+
+        if options.worker.is_some()
+            && (options.headless
+                || options.output.is_some()
+                || options.serve.is_some()
+                || options.coordinator.is_some()
+                || scene_given
+                || windowed)
+        {
+            return Err(anyhow!(
+                "`--worker` traces the coordinator's scene headless, it can't be combined with `--headless`, `--output`, `--serve`, `--coordinator`, scene options or window options"
+            ));
+        }
+
+        if options.batch.is_some()
+            && (options.headless
+                || options.output.is_some()
+                || options.serve.is_some()
+                || options.coordinator.is_some()
+                || options.worker.is_some()
+                || scene_given
+                || windowed)
+        {
+            return Err(anyhow!(
+                "`--batch` takes the scenes and outputs from its jobs, it can't be combined with `--headless`, `--output`, `--serve`, `--coordinator`, `--worker`, scene options or window options"
+            ));
+        }
+
+        if options.resume.is_some() && options.checkpoint.is_none() {
+            options.checkpoint = options.resume.clone();
+        }
+
+        if options.headless && options.progressive {
+            return Err(anyhow!(
+                "`--progressive` never finishes, it can't be combined with `--headless`"
+            ));
+        }
+
+        if options.headless && options.output.is_none() {
+            return Err(anyhow!("`--headless` requires `--output`"));
+        }
+
+        Ok(options)
+    }
+}
+
+pub fn parse_size(size: &str) -> Result<(u32, u32)> {
+    let (width, height) = size
+        .split_once('x')
+        .ok_or_else(|| anyhow!("Expected size as WIDTHxHEIGHT, got `{size}`"))?;
+
+    Ok((width.parse()?, height.parse()?))
+}
+
+/// Aspect ratio given as `W:H` or a number.
+fn parse_aspect(aspect: &str) -> Result<f32> {
+    let ratio = match aspect.split_once(':') {
+        Some((width, height)) => width.parse::<f32>()? / height.parse::<f32>()?,
+        None => aspect.parse()?,
+    };
+    if !(ratio.is_finite() && ratio > 0.0) {
+        return Err(anyhow!("Expected a positive aspect ratio, got `{aspect}`"));
+    }
+
+    Ok(ratio)
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String> {
+    value.ok_or_else(|| anyhow!("Missing value for `{flag}`"))
+}
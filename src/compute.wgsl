@@ -8,20 +8,118 @@ struct Camera {
     delta_v: vec3<f32>,
     width: u32,
     height: u32,
+    exposure: f32,
+    aperture: f32,
+    focus_distance: f32,
+    bokeh: u32,
+    blades: u32,
+    blade_rotation: f32,
+    bokeh_mask: array<vec4<u32>, 2>,
+    distortion_k1: f32,
+    distortion_k2: f32,
+    projection: u32,
+    fov: f32,
+    view_height: f32,
+    eye_separation: f32,
+    preview_scale: u32,
 };
 
 struct SeedUniform {
     seed: vec3<u32>,
+    // Index of the pass, picking the sub-pixel position of its samples.
+    sample: u32,
 };
 
 struct LimitsUniform {
     num_bounces: u32,
+    // Mesh vertices and indices are split in two bindings, these are the lengths of the first.
+    vertex_chunk: u32,
+    index_chunk: u32,
+    // Bounces each `bounce_paths` dispatch advances its path by.
+    bounces_per_dispatch: u32,
+    // Whether `pathsArr` holds paths with octahedral directions and half precision colors.
+    packed_paths: u32,
+    // Paths each pixel traces per multi-pass trace.
+    paths_per_pixel: u32,
+    // Reconstruction filter the camera rays are distributed by, see `sampleFilter`.
+    pixel_filter: u32,
+};
+
+struct Fog {
+    color: vec3<f32>,
+    density: f32,
+    distance: f32,
+};
+
+// Perez coefficients of luminance (x) and chromaticity (y, z).
+struct Sky {
+    a: vec3<f32>,
+    b: vec3<f32>,
+    c: vec3<f32>,
+    d: vec3<f32>,
+    e: vec3<f32>,
+    zenith: vec3<f32>,
+    sun_direction: vec3<f32>,
+};
+
+struct Light {
+    kind: u32,
+    position: vec3<f32>,
+    radius: f32,
+    intensity: vec3<f32>,
+    // Spot and directional lights only.
+    direction: vec3<f32>,
+    cos_angle: f32,
+    falloff: f32,
+    // Quad lights only, `position` is their corner.
+    edge_u: vec3<f32>,
+    edge_v: vec3<f32>,
+    // Chance of being sampled and the sum of it with the previous lights'.
+    probability: f32,
+    cumulative: f32,
+};
+
+struct Lights {
+    length: u32,
+    lights: array<Light>,
+};
+
+struct LightNode {
+    min: vec3<f32>,
+    power: f32,
+    max: vec3<f32>,
+    // Index into the lights for leaves, NO_LIGHT for inner nodes.
+    light: u32,
+    left: u32,
+    right: u32,
+};
+
+struct LightTree {
+    length: u32,
+    nodes: array<LightNode>,
+};
+
+// Limits on the luminance of a single sample, 0 disables them.
+struct FireflyClamp {
+    max_luminance: f32,
+    // Largest ratio to the mean luminance accumulated around the pixel so far.
+    outlier_ratio: f32,
+};
+
+struct Environment {
+    fog: Fog,
+    sky: Sky,
+    clamp: FireflyClamp,
 };
 
 struct Ray {
     origin: vec3<f32>,
     direction: vec3<f32>,
     finished: u32,
+    // Material id of the participating medium the ray travels through, NO_MEDIUM outside.
+    medium: u32,
+    // Wavelength in nanometres once split by a dispersive dielectric, 0 for white light.
+    wavelength: f32,
 };
 
 struct Spheres {
@@ -39,11 +137,162 @@ struct Sphere {
     radius: f32,
 };
 
+struct Cones {
+    length: u32,
+    cones: array<SceneCone>,
+};
+
+// Rotation by the unit quaternion `quat` (xyz = vector part) around `pivot`.
+struct Rotation {
+    quat: vec4<f32>,
+    pivot: vec3<f32>,
+};
+
+struct SceneCone {
+    mat_id: u32,
+    rotation: Rotation,
+    cone: Cone,
+};
+
+struct Cone {
+    apex: vec3<f32>,
+    angle: f32,
+    axis: vec3<f32>,
+    height: f32,
+};
+
+struct Disks {
+    length: u32,
+    disks: array<SceneDisk>,
+};
+
+struct SceneDisk {
+    mat_id: u32,
+    rotation: Rotation,
+    disk: Disk,
+};
+
+struct Disk {
+    center: vec3<f32>,
+    radius: f32,
+    normal: vec3<f32>,
+};
+
+struct Csg {
+    length: u32,
+    nodes: array<CsgNode>,
+};
+
+struct CsgLeaf {
+    kind: u32,
+    sphere: Sphere,
+    cone: Cone,
+};
+
+struct CsgNode {
+    op: u32,
+    mat_id: u32,
+    rotation: Rotation,
+    left: CsgLeaf,
+    right: CsgLeaf,
+};
+
+struct Sdfs {
+    length: u32,
+    nodes: array<SdfNode>,
+};
+
+struct SdfLeaf {
+    kind: u32,
+    center: vec3<f32>,
+    size: vec3<f32>,
+    radius: f32,
+    scale: f32,
+    iterations: u32,
+};
+
+struct SdfNode {
+    mat_id: u32,
+    rotation: Rotation,
+    blend: f32,
+    bound_center: vec3<f32>,
+    bound_radius: f32,
+    a: SdfLeaf,
+    b: SdfLeaf,
+};
+
+struct MeshInstances {
+    length: u32,
+    instances: array<MeshInstance>,
+};
+
+struct MeshInstance {
+    // World to mesh space.
+    to_local: mat4x3<f32>,
+    mat_id: u32,
+    // Root of the bottom level BVH of the mesh.
+    root: u32,
+    first_index: u32,
+    base_vertex: u32,
+    cull_backfaces: u32,
+    epsilon: f32,
+};
+
+struct BvhNode {
+    min: vec3<f32>,
+    // Primitives of a leaf, 0 for inner nodes.
+    count: u32,
+    max: vec3<f32>,
+    // First primitive of a leaf or second child of an inner node, relative to the root. The
+    // first child follows its parent.
+    index: u32,
+};
+
+struct Bvh {
+    length: u32,
+    nodes: array<BvhNode>,
+};
+
+struct Vertices {
+    length: u32,
+    vertices: array<Vertex>,
+};
+
+struct Vertex {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+};
+
+struct Indices {
+    length: u32,
+    indices: array<u32>,
+};
+
 struct Material {
     mat_type: u32,
     albedo: vec3<f32>,
     fuzz: f32,
     refract_idx: f32,
+    outer_refract_idx: f32,
+    metallic: f32,
+    // Along the tangent, `roughness_bitangent` across it.
+    roughness: f32,
+    roughness_bitangent: f32,
+    // Preferred tangent direction, zero for the default frame.
+    tangent: vec3<f32>,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    clearcoat_ior: f32,
+    // Mean free path inside subsurface materials.
+    scatter_distance: f32,
+    // Thin film on top in nanometres, 0 for none.
+    film_thickness: f32,
+    film_ior: f32,
+    abbe_number: f32,
+    // First word of the parameters of plugin materials in `materialParamsArr`.
+    params: u32,
+    // Layer of `textures` scaling the albedo, NO_TEXTURE for none.
+    texture: u32,
 };
 
 struct Materials {
@@ -51,278 +300,645 @@ struct Materials {
     materials: array<Material>,
 };
 
+struct MaterialParams {
+    length: u32,
+    words: array<u32>,
+};
+
 const pi: f32 = 3.14159265359;
 
+struct TileQueue {
+    workgroups_x: u32,
+    workgroups_y: u32,
+    count: u32,
+    tiles: array<u32>,
+};
+
+// Same as in `tiles.rs`.
+const TILE_SIZE: u32 = 16u;
+
+// Work shared out on the GPU, the header is reset to zero before each pass. The megakernel's
+// persistent threads take pixels from `next`. The multi-pass kernel keeps the paths still alive
+// in `queue`, a ring of path indices: each bounce pops the paths queued before it, from `head`
+// to `end`, and pushes those that survive at `tail`, so the next bounce only launches
+// workgroups for them.
+struct Work {
+    next: atomic<u32>,
+    // Arguments of the indirect dispatch of the running bounce.
+    workgroups_x: u32,
+    workgroups_y: u32,
+    workgroups_z: u32,
+    head: u32,
+    end: u32,
+    tail: atomic<u32>,
+    // A power of two long, so indices wrap around it along with the u32 counters.
+    queue: array<u32>,
+};
+
+const LIVE_WORKGROUP_SIZE: u32 = 64u;
+// Rows of workgroups over the live paths are this wide, under the 65535 limit per dimension.
+const LIVE_WORKGROUPS_X: u32 = 32768u;
+
+// A path between the bounces of a multi-pass trace, kept in `pathsArr` by `storePath`.
+struct Path {
+    ray: Ray,
+    // Throughput and radiance gathered so far.
+    energy: vec3<f32>,
+    radiance: vec3<f32>,
+    // Random state carried over to the pixel's next bounce.
+    rnd: vec3<u32>,
+    // Whether reaching a quad or directional light counts, 0 after sampling them explicitly.
+    sees_lights: u32,
+    // Set once the path ended, `radiance` is then final.
+    done: u32,
+    // Bounces followed so far.
+    bounce: u32,
+    // Width of the ray cone at the ray origin, see `pixelCone`.
+    cone_width: f32,
+};
+
+// Words a path takes in `pathsArr` at full precision and packed, see `storePath`. Same as in
+// `raytracing.rs`.
+const PATH_WORDS: u32 = 21u;
+const PACKED_PATH_WORDS: u32 = 13u;
+
 @group(0) @binding(0) var<uniform> cam: Camera;
-@group(1) @binding(0) var raytraced: texture_storage_2d<rgba32float, read_write>;
+@group(2) @binding(0) var raytraced: texture_storage_2d<rgba32float, read_write>;
+// First hit of the camera ray: normal in xyz (zero for lights and the sky) and distance in w,
+// for reprojection and denoising, see `accumulation.wgsl` and `denoise.wgsl`.
+@group(2) @binding(1) var first_hit: texture_storage_2d<rgba32float, write>;
+// Sum of the squared sample luminance in x, the denoiser estimates the variance from it.
+@group(2) @binding(2) var moments: texture_storage_2d<rgba32float, read_write>;
+// Tiles picked by `tiles.wgsl` for `raytrace_tiles`, packed as y << 16 | x.
+@group(2) @binding(3) var<storage> tileQueue: TileQueue;
+@group(2) @binding(4) var<storage, read_write> work: Work;
+// Paths of the multi-pass trace, `paths_per_pixel` for each pixel in row order of the traced
+// image, see `loadPath`.
+@group(2) @binding(5) var<storage, read_write> pathsArr: array<u32>;
 @group(1) @binding(1) var<storage> spheresArr: Spheres;
 @group(1) @binding(2) var<storage> materialsArr: Materials;
 @group(1) @binding(3) var<uniform> seed_uniform: SeedUniform;
 @group(1) @binding(4) var<uniform> limits_uniform: LimitsUniform;
+@group(1) @binding(5) var<storage> conesArr: Cones;
+@group(1) @binding(6) var<storage> disksArr: Disks;
+@group(1) @binding(7) var<storage> csgArr: Csg;
+@group(1) @binding(8) var<storage> sdfsArr: Sdfs;
+@group(1) @binding(9) var<storage> instancesArr: MeshInstances;
+@group(1) @binding(10) var<storage> verticesArr: Vertices;
+@group(1) @binding(11) var<storage> indicesArr: Indices;
+@group(1) @binding(12) var<uniform> environment: Environment;
+@group(1) @binding(13) var<storage> lightsArr: Lights;
+@group(1) @binding(14) var<storage> lightTree: LightTree;
+@group(1) @binding(15) var<storage> verticesArr2: Vertices;
+@group(1) @binding(16) var<storage> indicesArr2: Indices;
+// The top level BVH over the mesh instances, followed by the bottom level ones of the meshes.
+@group(1) @binding(17) var<storage> bvhArr: Bvh;
+// Parameters of plugin materials, see `material_plugin.rs`.
+@group(1) @binding(18) var<storage> materialParamsArr: MaterialParams;
+// Image textures of the scene, one per layer, see `texture_pool.rs`.
+@group(1) @binding(19) var textures: texture_2d_array<f32>;
+@group(1) @binding(20) var texture_sampler: sampler;
+
+// Depth of rays that hit nothing, far enough to only rotate with the camera when reprojected.
+const MISS_DEPTH: f32 = 1000000.0;
+
+#import rng
+#import intersections
+#import materials
+#import camera
+#import lights
+#import custom_materials
+
+// Samples a pixel needs around it before outliers are rejected.
+const MIN_OUTLIER_SAMPLES: f32 = 4.0;
+
+// Mean luminance accumulated in the 3x3 pixels around (x, y). Neighbours may already hold this
+// pass's sample, which only moves the estimate slightly.
+fn neighbourhoodLuminance(x: u32, y: u32) -> vec2<f32> {
+    let size = vec2<i32>(textureDimensions(raytraced));
+    var sum = 0.0;
+    var samples = 0.0;
+    for (var dy = -1; dy <= 1; dy++) {
+        for (var dx = -1; dx <= 1; dx++) {
+            let p = vec2<i32>(i32(x) + dx, i32(y) + dy);
+            if all(p >= vec2<i32>(0)) && all(p < size) {
+                let c = textureLoad(raytraced, vec2<u32>(p));
+                sum += dot(c.rgb, vec3<f32>(0.2126, 0.7152, 0.0722));
+                samples += c.a;
+            }
+        }
+    }
+    return vec2<f32>(sum / max(samples, 1.0), samples);
+}
 
+// Scales a sample down to the firefly limits, keeping its hue.
+fn clampFirefly(x: u32, y: u32, color: vec3<f32>) -> vec3<f32> {
+    var limit = environment.clamp.max_luminance;
+    if limit <= 0.0 {
+        limit = 3.4e38;
+    }
+    if environment.clamp.outlier_ratio > 0.0 {
+        let around = neighbourhoodLuminance(x, y);
+        if around.y >= 9.0 * MIN_OUTLIER_SAMPLES {
+            // Black surroundings would reject everything, keep some headroom.
+            limit = min(limit, environment.clamp.outlier_ratio * max(around.x, 0.01));
+        }
+    }
 
-const MAT_LAMBERTIAN: u32 = u32(0);
-const MAT_METAL: u32 = u32(1);
-const MAT_DIELECTRIC: u32 = u32(2);
-const MAT_NORMAL_MAP: u32 = u32(3);
-
-struct HitRecord {
-    hit: bool,
-    t: f32,
-    point: vec3<f32>,
-    normal: vec3<f32>,
-    front_face: bool,
-};
+    let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    if luminance > limit {
+        return color * (limit / luminance);
+    }
+    return color;
+}
 
-var<private> rnd : vec3u;
+// Accumulates the sample sum in rgb and the number of samples taken in alpha.
+fn writePixel(x: u32, y: u32, traced: vec3<f32>) {
+    let color = clampFirefly(x, y, traced);
+    var current = textureLoad(raytraced, vec2<u32>(x, y));
+    textureStore(raytraced, vec2<u32>(x, y), vec4<f32>(current.rgb + color, current.a + 1.0));
 
-// Initializes the random number generator.
-fn init_rand(invocation_id: vec3<u32>) {
-    var A = vec3<u32>(u32(1741651 * 1009),
-        u32(140893 * 1609 * 13),
-        u32(6521 * 983 * 7 * 2));
-    rnd = (invocation_id * A) ^ seed_uniform.seed;
+    var luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    var moment = textureLoad(moments, vec2<u32>(x, y));
+    textureStore(moments, vec2<u32>(x, y), moment + vec4<f32>(luminance * luminance, 0.0, 0.0, 0.0));
 }
 
-// Returns a random number between 0 and 1.
-fn rand() -> f32 {
-    var C = vec3<u32>(u32(60493 * 9377),
-        u32(11279 * 2539 * 23),
-        u32(7919 * 631 * 5 * 3));
-
-    rnd = (rnd * C) ^ (rnd.yzx >> vec3(4u));
-    return f32(rnd.x ^ rnd.y) / 4294967295.0; // 4294967295.0 is f32(0xffffffff). See #337
+// Full pass with persistent threads: a fixed number of invocations keep taking the next pixel
+// of the traced image until none are left, so threads whose paths end early pick up more work
+// instead of idling while the rest of their workgroup bounces.
+@compute
+@workgroup_size(64)
+fn raytrace() {
+    let size = textureDimensions(raytraced);
+    let pixels = size.x * size.y;
+    loop {
+        let index = atomicAdd(&work.next, 1u);
+        if index >= pixels {
+            break;
+        }
+        tracePixel(vec3<u32>(index % size.x, index / size.x, 0u));
+    }
 }
 
-fn rand_unit_sphere() -> vec3<f32> {
-    var u = rand();
-    var v = rand();
-    var theta = u * 2.0 * pi;
-    var phi = acos(2.0 * v - 1.0);
-    var r = pow(rand(), 1.0 / 3.0);
-    var sin_theta = sin(theta);
-    var cos_theta = cos(theta);
-    var sin_phi = sin(phi);
-    var cos_phi = cos(phi);
-    var x = r * sin_phi * sin_theta;
-    var y = r * sin_phi * cos_theta;
-    var z = r * cos_phi;
-    return vec3<f32>(x, y, z);
+// Adaptive pass, a TILE_SIZE x TILE_SIZE grid of invocations per queued tile in z.
+@compute
+@workgroup_size(1)
+fn raytrace_tiles(@builtin(global_invocation_id) id: vec3<u32>) {
+    let tile = tileQueue.tiles[id.z];
+    let pixel = vec2<u32>(tile & 0xffffu, tile >> 16u) * TILE_SIZE + id.xy;
+    if any(pixel >= textureDimensions(raytraced)) {
+        return;
+    }
+    tracePixel(vec3<u32>(pixel, 0u));
 }
 
-fn rayAt(ray: Ray, t: f32) -> vec3<f32> {
-    return ray.origin + ray.direction * t;
+// First dispatch of a multi-pass trace, starting `paths_per_pixel` paths of every pixel, one
+// per z.
+@compute
+@workgroup_size(8, 8)
+fn start_paths(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(raytraced);
+    if any(id.xy >= size) {
+        return;
+    }
+
+    let index = (id.y * size.x + id.x) * limits_uniform.paths_per_pixel + id.z;
+    var path = startPath(id.xy, id.z);
+    path.rnd = rnd;
+    storePath(index, path);
+    if !pathEnded(path) {
+        pushPath(index);
+    }
 }
 
-fn inside(x: f32, x_min: f32, x_max: f32) -> bool {
-    return x > x_min && x < x_max;
+fn queueSlot(counter: u32) -> u32 {
+    return counter & (arrayLength(&work.queue) - 1u);
 }
 
-fn reflect(direction: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
-    return direction - 2.0 * dot(direction, normal) * normal;
+// Queues path `index` for the next bounce.
+fn pushPath(index: u32) {
+    work.queue[queueSlot(atomicAdd(&work.tail, 1u))] = index;
 }
 
-fn hitSphere(ray: Ray, sphere: Sphere, t_min: f32, t_max: f32) -> HitRecord {
-    var oc = ray.origin - sphere.center;
-    var a = dot(ray.direction, ray.direction);
-    var b = 2.0 * dot(oc, ray.direction);
-    var c = dot(oc, oc) - sphere.radius * sphere.radius;
+// Runs before each bounce of a multi-pass trace: the paths pushed since the last one become
+// those to pop, with the workgroups to launch for them.
+@compute
+@workgroup_size(1)
+fn prepare_bounce() {
+    work.head = work.end;
+    work.end = atomicLoad(&work.tail);
+    let workgroups = (work.end - work.head + LIVE_WORKGROUP_SIZE - 1u) / LIVE_WORKGROUP_SIZE;
+    work.workgroups_x = min(workgroups, LIVE_WORKGROUPS_X);
+    work.workgroups_y = (workgroups + LIVE_WORKGROUPS_X - 1u) / LIVE_WORKGROUPS_X;
+    work.workgroups_z = 1u;
+}
 
-    var discriminant = b * b - 4.0 * a * c;
+// Advances the queued paths of a multi-pass trace by up to `bounces_per_dispatch` bounces,
+// queueing those still alive for the next bounce. Launched indirectly with the arguments
+// `prepare_bounce` left.
+@compute
+@workgroup_size(64)
+fn bounce_paths(
+    @builtin(workgroup_id) group: vec3<u32>,
+    @builtin(local_invocation_index) local: u32,
+) {
+    let popped = (group.y * LIVE_WORKGROUPS_X + group.x) * LIVE_WORKGROUP_SIZE + local;
+    if popped >= work.end - work.head {
+        return;
+    }
 
-    var record: HitRecord;
-    record.hit = false;
+    let index = work.queue[queueSlot(work.head + popped)];
+    let size = textureDimensions(raytraced);
+    let pixel_index = index / limits_uniform.paths_per_pixel;
+    let pixel = vec2<u32>(pixel_index % size.x, pixel_index / size.x);
+    // The first path of a pixel stands for it in the first hit texture.
+    let primary = index % limits_uniform.paths_per_pixel == 0u;
+
+    var path = loadPath(index);
+    rnd = path.rnd;
+    for (var i = 0u; i < limits_uniform.bounces_per_dispatch && !pathEnded(path); i++) {
+        path = bounce(path, pixel, primary);
+    }
+    path.rnd = rnd;
+    storePath(index, path);
+    if !pathEnded(path) {
+        pushPath(index);
+    }
+}
 
-    if discriminant == 0.0 {
-        var t = -b / (2.0 * a);
+// Last dispatch of a multi-pass trace, accumulating the paths of every pixel. Paths of the same
+// pixel end in any order and place, so they are only added up here.
+@compute
+@workgroup_size(8, 8)
+fn resolve_paths(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(raytraced);
+    if any(id.xy >= size) {
+        return;
+    }
 
-        if inside(t, t_min, t_max) {
-            record.hit = true;
-            record.t = t;
-            record.point = rayAt(ray, t);
-            record.normal = (record.point - sphere.center) / sphere.radius;
+    let first = (id.y * size.x + id.x) * limits_uniform.paths_per_pixel;
+    for (var i = 0u; i < limits_uniform.paths_per_pixel; i++) {
+        writePixel(id.x, id.y, loadPath(first + i).radiance);
+    }
+}
 
-            if dot(ray.direction, record.normal) < 0.0 {
-                record.front_face = true;
-            } else {
-                record.normal = -record.normal;
-                record.front_face = false;
-            }
-        }
-    } else if discriminant >= 0.0 {
-        var t1 = (-b - sqrt(discriminant)) / (2.0 * a);
-        var t2 = (-b + sqrt(discriminant)) / (2.0 * a);
-
-        var t: f32 = t1;
-        if inside(t1, t_min, t_max) {
-            t = t1;
-            record.hit = true;
-        } else if inside(t2, t_min, t_max) {
-            t = t2;
-            record.hit = true;
-        }
+fn storeVec3(word: u32, v: vec3<f32>) {
+    pathsArr[word] = bitcast<u32>(v.x);
+    pathsArr[word + 1u] = bitcast<u32>(v.y);
+    pathsArr[word + 2u] = bitcast<u32>(v.z);
+}
 
-        if record.hit {
-            record.t = t;
-            record.point = rayAt(ray, t);
-            record.normal = (record.point - sphere.center) / sphere.radius;
+fn loadVec3(word: u32) -> vec3<f32> {
+    return bitcast<vec3<f32>>(vec3<u32>(pathsArr[word], pathsArr[word + 1u], pathsArr[word + 2u]));
+}
 
-            if dot(ray.direction, record.normal) < 0.0 {
-                record.front_face = true;
-            } else {
-                record.normal = -record.normal;
-                record.front_face = false;
-            }
-        }
-    }
+// Two halves of a vec3 packed with a fourth value.
+fn storeHalf4(word: u32, v: vec3<f32>, w: f32) {
+    pathsArr[word] = pack2x16float(v.xy);
+    pathsArr[word + 1u] = pack2x16float(vec2<f32>(v.z, w));
+}
 
-    if discriminant >= 0.0 {
-        var t1 = (-b - sqrt(discriminant)) / (2.0 * a);
-        var t2 = (-b + sqrt(discriminant)) / (2.0 * a);
-
-        var t: f32 = t1;
-        if t1 < t_max && t1 > t_min {
-            t = t1;
-            record.hit = true;
-        } else if t2 < t_max && t2 > t_min {
-            t = t2;
-            record.hit = true;
-        }
+fn loadHalf4(word: u32) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(pathsArr[word]), unpack2x16float(pathsArr[word + 1u]));
+}
 
-        if record.hit {
-            record.t = t;
-            record.point = rayAt(ray, t);
-            record.normal = (record.point - sphere.center) / sphere.radius;
+fn signNotZero(v: vec2<f32>) -> vec2<f32> {
+    return select(vec2<f32>(-1.0), vec2<f32>(1.0), v >= vec2<f32>(0.0));
+}
 
-            if dot(ray.direction, record.normal) < 0.0 {
-                record.front_face = true;
-            } else {
-                record.normal = -record.normal;
-                record.front_face = false;
-            }
-        }
+// Direction as two snorm16 coordinates on the octahedron folded onto the unit square, see "A
+// Survey of Efficient Representations for Independent Unit Vectors" (Cigolle et al. 2014).
+fn encodeOctahedral(v: vec3<f32>) -> u32 {
+    var p = v.xy / (abs(v.x) + abs(v.y) + abs(v.z));
+    if v.z < 0.0 {
+        p = (1.0 - abs(p.yx)) * signNotZero(p);
     }
-
-    return record;
+    return pack2x16snorm(p);
 }
 
-fn nearZero(v: vec3<f32>) -> bool {
-    var va = abs(v);
-    var s = 1e-8;
-    return va.x < s && va.y < s && va.z < s;
+// Unit direction of an octahedral word.
+fn decodeOctahedral(word: u32) -> vec3<f32> {
+    let p = unpack2x16snorm(word);
+    var v = vec3<f32>(p, 1.0 - abs(p.x) - abs(p.y));
+    if v.z < 0.0 {
+        v = vec3<f32>((1.0 - abs(v.yx)) * signNotZero(v.xy), v.z);
+    }
+    return normalize(v);
 }
 
-fn initRay(x: f32, y: f32) -> Ray {
-    var origin = cam.lookfrom;
-    var pixel = (cam.top_left_pixel + x * cam.delta_u + y * cam.delta_v);
-    var direction = pixel - origin;
+// Saves a path between dispatches. Packed, the origin stays f32 so hit points don't drift, the
+// direction is octahedral (normalized, which hits and distances don't mind), the colors and the
+// wavelength are halves and the flags share a word with the bounce count.
+fn storePath(index: u32, path: Path) {
+    if limits_uniform.packed_paths != 0u {
+        let word = index * PACKED_PATH_WORDS;
+        storeVec3(word, path.ray.origin);
+        pathsArr[word + 3u] = path.ray.medium;
+        pathsArr[word + 4u] = encodeOctahedral(path.ray.direction);
+        storeHalf4(word + 5u, path.energy, path.ray.wavelength);
+        storeHalf4(word + 7u, path.radiance, path.cone_width);
+        pathsArr[word + 9u] = path.rnd.x;
+        pathsArr[word + 10u] = path.rnd.y;
+        pathsArr[word + 11u] = path.rnd.z;
+        pathsArr[word + 12u] = path.done | (path.sees_lights << 1u) | (path.bounce << 2u);
+        return;
+    }
 
-    var sampleDiff = (-0.5 * rand() * cam.delta_u) + (-0.5 * rand() * cam.delta_v);
+    let word = index * PATH_WORDS;
+    storeVec3(word, path.ray.origin);
+    storeVec3(word + 3u, path.ray.direction);
+    storeVec3(word + 6u, path.energy);
+    storeVec3(word + 9u, path.radiance);
+    pathsArr[word + 12u] = path.rnd.x;
+    pathsArr[word + 13u] = path.rnd.y;
+    pathsArr[word + 14u] = path.rnd.z;
+    pathsArr[word + 15u] = path.ray.medium;
+    pathsArr[word + 16u] = bitcast<u32>(path.ray.wavelength);
+    pathsArr[word + 17u] = path.sees_lights;
+    pathsArr[word + 18u] = path.done;
+    pathsArr[word + 19u] = path.bounce;
+    pathsArr[word + 20u] = bitcast<u32>(path.cone_width);
+}
 
-    var ray: Ray;
-    ray.origin = origin;
-    ray.direction = direction + sampleDiff;
+fn loadPath(index: u32) -> Path {
+    var path: Path;
+    path.ray.finished = 0u;
+    if limits_uniform.packed_paths != 0u {
+        let word = index * PACKED_PATH_WORDS;
+        path.ray.origin = loadVec3(word);
+        path.ray.medium = pathsArr[word + 3u];
+        path.ray.direction = decodeOctahedral(pathsArr[word + 4u]);
+        let energy = loadHalf4(word + 5u);
+        path.energy = energy.xyz;
+        path.ray.wavelength = energy.w;
+        let radiance = loadHalf4(word + 7u);
+        path.radiance = radiance.xyz;
+        path.cone_width = radiance.w;
+        path.rnd = vec3<u32>(pathsArr[word + 9u], pathsArr[word + 10u], pathsArr[word + 11u]);
+        let flags = pathsArr[word + 12u];
+        path.done = flags & 1u;
+        path.sees_lights = (flags >> 1u) & 1u;
+        path.bounce = flags >> 2u;
+        return path;
+    }
 
-    return ray;
+    let word = index * PATH_WORDS;
+    path.ray.origin = loadVec3(word);
+    path.ray.direction = loadVec3(word + 3u);
+    path.energy = loadVec3(word + 6u);
+    path.radiance = loadVec3(word + 9u);
+    path.rnd = vec3<u32>(pathsArr[word + 12u], pathsArr[word + 13u], pathsArr[word + 14u]);
+    path.ray.medium = pathsArr[word + 15u];
+    path.ray.wavelength = bitcast<f32>(pathsArr[word + 16u]);
+    path.sees_lights = pathsArr[word + 17u];
+    path.done = pathsArr[word + 18u];
+    path.bounce = pathsArr[word + 19u];
+    path.cone_width = bitcast<f32>(pathsArr[word + 20u]);
+    return path;
 }
 
-fn writePixel(x: u32, y: u32, color: vec3<f32>) {
-    var current = textureLoad(raytraced, vec2<u32>(x, y)).rgb;
-    textureStore(raytraced, vec2<u32>(x, y), vec4<f32>(current + color, 1.0));
+// Starts path `path_index` of a pixel of the traced image.
+fn startPath(pixel: vec2<u32>, path_index: u32) -> Path {
+    init_rand(vec3<u32>(pixel, path_index));
+    let sample = seed_uniform.sample * limits_uniform.paths_per_pixel + path_index;
+    let filtered = sampleFilter(pixelSample(sample, pixel));
+    var position = vec2<f32>(pixel) + filtered.xy;
+    if cam.preview_scale > 1u {
+        // Each preview texel stands for the block of pixels around its center.
+        position = (position + 0.5) * f32(cam.preview_scale) - 0.5;
+    }
+
+    var path: Path;
+    path.ray = initRay(position.x, position.y);
+    path.energy = vec3<f32>(filtered.z);
+    path.radiance = vec3<f32>(0.0);
+    path.sees_lights = 1u;
+    // Outside the fisheye image circle the path ends black.
+    path.done = path.ray.finished;
+    path.bounce = 0u;
+    path.cone_width = pixelCone().x;
+    return path;
 }
 
-fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
-    var r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-    r0 = r0 * r0;
-    return r0 + (1.0 - r0) * pow((1.0 - cosine), 5.0);
+fn endPath(path: Path, radiance: vec3<f32>) -> Path {
+    var ended = path;
+    ended.radiance = radiance;
+    ended.done = 1u;
+    return ended;
 }
 
-fn refract(uv: vec3<f32>, n: vec3<f32>, etai_over_etat: f32) -> vec3<f32> {
-    var cos_theta = dot(-uv, n);
-    var r_out_parallel = etai_over_etat * (uv + cos_theta * n);
-    var r_out_perp = -sqrt(1.0 - dot(r_out_parallel, r_out_parallel)) * n;
-    return r_out_parallel + r_out_perp;
+// Whether the path's radiance is final, because it ended or ran out of bounces.
+fn pathEnded(path: Path) -> bool {
+    return path.done != 0u || path.bounce > limits_uniform.num_bounces;
 }
 
-@compute
-@workgroup_size(1)
-fn raytrace(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    init_rand(global_id.xyz);
-    var pixel = vec2<f32>(f32(global_id.x), f32(global_id.y));
-    var ray = initRay(pixel.x, pixel.y);
-
-    var energy = vec3<f32>(1.0, 1.0, 1.0);
-    for (var b = u32(0); b <= limits_uniform.num_bounces; b += u32(1)) {
-        var t_max = 100000000000.0;
-        var sphereIdx = u32(100000);
-        var hitRecord: HitRecord;
-        hitRecord.hit = false;
-
-        for (var i = u32(0); i < spheresArr.length; i += u32(1)) {
-            var record = hitSphere(ray, spheresArr.spheres[i].sphere, 0.001, t_max);
-
-            if record.hit {
-                t_max = record.t;
-                sphereIdx = u32(i);
-                hitRecord = record;
-            }
+// Follows a path of `pixel` through its next bounce, storing its first hit if `primary`.
+fn bounce(current: Path, pixel: vec2<u32>, primary: bool) -> Path {
+    var path = current;
+    let b = path.bounce;
+    path.bounce += 1u;
+
+    var sceneHit = hitScene(path.ray, 0.001, 100000000000.0);
+    var hitRecord = sceneHit.record;
+    var matId = sceneHit.mat_id;
+
+    var emitter_t = 100000000000.0;
+    if hitRecord.hit {
+        emitter_t = hitRecord.t;
+    }
+    var emitter = hitEmitters(path.ray, emitter_t);
+
+    if b == 0u && primary {
+        var first = vec4<f32>(0.0, 0.0, 0.0, MISS_DEPTH);
+        if emitter.hit {
+            first.w = emitter.t * length(path.ray.direction);
+        } else if hitRecord.hit {
+            first = vec4<f32>(hitRecord.normal, hitRecord.t * length(path.ray.direction));
         }
+        textureStore(first_hit, pixel, first);
+    }
 
-        if hitRecord.hit {
-            var sphere = spheresArr.spheres[sphereIdx];
-            var material = materialsArr.materials[sphere.mat_id];
+    // Random walk inside a subsurface medium: scatter before reaching the next surface.
+    if path.ray.medium != NO_MEDIUM {
+        var medium = materialsArr.materials[path.ray.medium];
+        var ray_len = length(path.ray.direction);
+        var distance = -log(max(rand(), 1e-7)) * medium.scatter_distance;
+
+        if !hitRecord.hit {
+            // Leaked out through a gap, e.g. at a grazing exit.
+            path.ray.medium = NO_MEDIUM;
+        } else if distance < hitRecord.t * ray_len {
+            path.energy = path.energy * medium.albedo;
+            path.sees_lights = 1u;
+            path.ray.origin = rayAt(path.ray, distance / ray_len);
+            path.ray.direction = rand_unit_vector();
+            return path;
+        }
+    }
 
-            if material.mat_type == MAT_NORMAL_MAP {
-                var color = (hitRecord.normal + 1.0) * 0.5;
-                writePixel(global_id.x, global_id.y, energy * color);
-                return;
-            } else if material.mat_type == MAT_LAMBERTIAN {
-                var direction = (hitRecord.normal + rand_unit_sphere());
+    // Fog between surfaces, rays escaping the scene cross `distance` of it before the sky.
+    if path.ray.medium == NO_MEDIUM && environment.fog.density > 0.0 {
+        var ray_len = length(path.ray.direction);
+        var distance = -log(max(rand(), 1e-7)) / environment.fog.density;
+        var segment = environment.fog.distance;
+        if emitter.hit {
+            segment = emitter.t * ray_len;
+        } else if hitRecord.hit {
+            segment = hitRecord.t * ray_len;
+        }
 
-                if nearZero(direction) {
-                    direction = hitRecord.normal;
-                }
+        if distance < segment {
+            path.energy = path.energy * environment.fog.color;
+            path.sees_lights = 1u;
+            path.ray.origin = rayAt(path.ray, distance / ray_len);
+            path.ray.direction = rand_unit_vector();
+            return path;
+        }
+    }
 
-                energy = energy * material.albedo;
-                ray.origin = hitRecord.point;
-                ray.direction = direction;
-            } else if material.mat_type == MAT_METAL {
-                energy = energy * material.albedo;
-                ray.origin = hitRecord.point;
-                ray.direction = reflect(ray.direction, hitRecord.normal) + material.fuzz * rand_unit_sphere();
-            } else if material.mat_type == MAT_DIELECTRIC {
-                var refraction_ratio = material.refract_idx;
-                if hitRecord.front_face {
-                    refraction_ratio = 1.0 / refraction_ratio;
+    if emitter.hit {
+        if path.sees_lights != 0u {
+            path.radiance += path.energy * emitter.radiance;
+        }
+        return endPath(path, path.radiance);
+    }
+
+    if hitRecord.hit {
+        // The ray cone keeps the camera's spread, ignoring the curvature of the surfaces it
+        // bounces off, and covers more texels on surfaces seen at an angle.
+        path.cone_width += pixelCone().y * hitRecord.t * length(path.ray.direction);
+        var cos_theta = abs(dot(normalize(path.ray.direction), hitRecord.normal));
+        var uv_footprint = path.cone_width * hitRecord.uv_density / max(cos_theta, 0.01);
+
+        var material = materialsArr.materials[matId];
+        material.albedo = materialAlbedo(material, hitRecord.uv, uv_footprint);
+        path.sees_lights = 1u;
+
+        if material.mat_type == MAT_NORMAL_MAP {
+            var color = (hitRecord.normal + 1.0) * 0.5;
+            return endPath(path, path.radiance + path.energy * color);
+        } else if material.mat_type == MAT_UV_MAP {
+            var color = vec3<f32>(hitRecord.uv, 0.0);
+            return endPath(path, path.radiance + path.energy * color);
+        } else if material.mat_type == MAT_LAMBERTIAN {
+            var direction = (hitRecord.normal + rand_unit_sphere());
+
+            if nearZero(direction) {
+                direction = hitRecord.normal;
+            }
+
+            path.energy = path.energy * material.albedo;
+            path.radiance += path.energy * sampleLights(hitRecord.point, hitRecord.normal);
+            path.sees_lights = 0u;
+            path.ray.origin = hitRecord.point;
+            path.ray.direction = direction;
+        } else if material.mat_type == MAT_METAL {
+            if material.film_thickness > 0.0 {
+                var cos_theta = min(dot(-normalize(path.ray.direction), hitRecord.normal), 1.0);
+                path.energy = path.energy * thinFilmOver(cos_theta, material.albedo, material);
+            } else {
+                path.energy = path.energy * material.albedo;
+            }
+            path.ray.origin = hitRecord.point;
+            path.ray.direction = reflect(path.ray.direction, hitRecord.normal) + material.fuzz * rand_unit_sphere();
+        } else if material.mat_type == MAT_DIELECTRIC {
+            var refract_idx = material.refract_idx;
+            if material.abbe_number > 0.0 {
+                // Split white light, following a single wavelength from here on.
+                if path.ray.wavelength == 0.0 {
+                    path.ray.wavelength = mix(MIN_WAVELENGTH, MAX_WAVELENGTH, rand());
+                    path.energy = path.energy * wavelengthWeight(path.ray.wavelength);
                 }
+                refract_idx = cauchyIor(material.refract_idx, material.abbe_number, path.ray.wavelength);
+            }
 
-                var unit_direction = normalize(ray.direction);
-                var cos_theta = min(dot(-unit_direction, hitRecord.normal), 1.0);
-                var sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+            var refraction_ratio = refract_idx / material.outer_refract_idx;
+            if hitRecord.front_face {
+                refraction_ratio = 1.0 / refraction_ratio;
+            }
 
-                var cannot_refract = refraction_ratio * sin_theta > 1.0;
-                var direction: vec3<f32>;
+            var unit_direction = normalize(path.ray.direction);
+            var cos_theta = min(dot(-unit_direction, hitRecord.normal), 1.0);
+            var sin_theta = sqrt(1.0 - cos_theta * cos_theta);
 
-                if cannot_refract || reflectance(cos_theta, refraction_ratio) > rand() {
-                    direction = reflect(unit_direction, hitRecord.normal);
-                } else {
-                    direction = refract(unit_direction, hitRecord.normal, refraction_ratio);
+            var cannot_refract = refraction_ratio * sin_theta > 1.0;
+            var direction: vec3<f32>;
+
+            var film = vec3<f32>(reflectance(cos_theta, refraction_ratio));
+            if material.film_thickness > 0.0 {
+                var incident_ior = material.outer_refract_idx;
+                var base_ior = refract_idx;
+                if !hitRecord.front_face {
+                    incident_ior = refract_idx;
+                    base_ior = material.outer_refract_idx;
                 }
+                var base_f0 = vec3<f32>(reflectance(1.0, base_ior / material.film_ior));
+                film = thinFilm(cos_theta, incident_ior, material.film_thickness, material.film_ior, base_ior, base_f0);
+            }
+            // Reflect or refract by the mean reflectance, weighting by the tint of either.
+            var reflect_chance = dot(film, vec3<f32>(1.0 / 3.0));
+
+            if cannot_refract {
+                direction = reflect(unit_direction, hitRecord.normal);
+            } else if reflect_chance > rand() {
+                direction = reflect(unit_direction, hitRecord.normal);
+                path.energy = path.energy * film / reflect_chance;
+            } else {
+                direction = refract(unit_direction, hitRecord.normal, refraction_ratio);
+                path.energy = path.energy * (1.0 - film) / max(1.0 - reflect_chance, 1e-4);
+            }
 
-                ray.origin = hitRecord.point;
-                ray.direction = direction;
+            path.ray.origin = hitRecord.point;
+            path.ray.direction = direction;
+        } else if material.mat_type == MAT_SUBSURFACE {
+            // Smooth dielectric boundary, refracted rays enter or leave the medium.
+            var refraction_ratio = material.refract_idx / material.outer_refract_idx;
+            if hitRecord.front_face {
+                refraction_ratio = 1.0 / refraction_ratio;
+            }
+
+            var unit_direction = normalize(path.ray.direction);
+            var cos_theta = min(dot(-unit_direction, hitRecord.normal), 1.0);
+            var sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+
+            path.ray.origin = hitRecord.point;
+            if refraction_ratio * sin_theta > 1.0 || reflectance(cos_theta, refraction_ratio) > rand() {
+                path.ray.direction = reflect(unit_direction, hitRecord.normal);
             } else {
-                writePixel(global_id.x, global_id.y, vec3<f32>(1.0, 0.0, 0.0));
-                return;
+                path.ray.direction = refract(unit_direction, hitRecord.normal, refraction_ratio);
+                if hitRecord.front_face {
+                    path.ray.medium = matId;
+                } else {
+                    path.ray.medium = NO_MEDIUM;
+                }
             }
+        } else if material.mat_type == MAT_PBR {
+            var sample = samplePbr(path.ray.direction, hitRecord.normal, material);
+            if !sample.valid {
+                return endPath(path, path.radiance);
+            }
+
+            path.energy = path.energy * sample.weight;
+            path.ray.origin = hitRecord.point;
+            path.ray.direction = sample.direction;
         } else {
-            var unit_d = normalize(ray.direction);
-            var t = 0.5 * (unit_d.y + 1.0);
-            var color = mix(vec3<f32>(1.0, 1.0, 1.0), vec3<f32>(0.5, 0.7, 1.0), t);
-            writePixel(global_id.x, global_id.y, energy * color);
-            return;
+            return shadeCustom(path, hitRecord, material);
+        }
+    } else {
+        var color = skyColor(path.ray.direction);
+        if path.sees_lights != 0u {
+            color += directionalDisks(path.ray.direction);
         }
+        return endPath(path, path.radiance + path.energy * color);
     }
+    return path;
+}
 
-    writePixel(global_id.x, global_id.y, vec3<f32>(0.0, 0.0, 0.0));
+// Traces a whole path of a pixel, used by the megakernel entries.
+fn tracePixel(global_id: vec3<u32>) {
+    var path = startPath(global_id.xy, 0u);
+    while !pathEnded(path) {
+        path = bounce(path, global_id.xy, true);
+    }
+    writePixel(global_id.x, global_id.y, path.radiance);
 }
@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn next(self) -> Self {
+        match self {
+            Severity::Info => Severity::Warning,
+            Severity::Warning => Severity::Error,
+            Severity::Error => Severity::Info,
+        }
+    }
+
+    fn from_level(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Severity::Error,
+            log::Level::Warn => Severity::Warning,
+            _ => Severity::Info,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConsoleEntry {
+    severity: Severity,
+    message: String,
+}
+
+pub type SharedConsole = Arc<Mutex<Console>>;
+
+/// In-app log of GPU validation errors and `log` records, drawn by the overlay.
+#[derive(Debug)]
+pub struct Console {
+    entries: VecDeque<ConsoleEntry>,
+    min_severity: Severity,
+    visible: bool,
+}
+
+impl Console {
+    const MAX_ENTRIES: usize = 256;
+
+    pub fn shared() -> SharedConsole {
+        Arc::new(Mutex::new(Console {
+            entries: VecDeque::new(),
+            min_severity: Severity::Info,
+            visible: false,
+        }))
+    }
+
+    pub fn push(&mut self, severity: Severity, message: impl Into<String>) {
+        if self.entries.len() == Self::MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(ConsoleEntry {
+            severity,
+            message: message.into(),
+        });
+
+        // Errors should never go unnoticed, even if the console is hidden.
+        if severity == Severity::Error {
+            self.visible = true;
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    pub fn cycle_severity(&mut self) {
+        self.min_severity = self.min_severity.next();
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Last `max_lines` lines passing the severity filter, wrapped at `cols` characters.
+    pub fn lines(&self, cols: usize, max_lines: usize) -> Vec<(Severity, String)> {
+        let mut lines = vec![(
+            Severity::Info,
+            format!("console [filter: {:?}+]", self.min_severity),
+        )];
+
+        let mut body = vec![];
+        for entry in self
+            .entries
+            .iter()
+            .filter(|e| e.severity >= self.min_severity)
+        {
+            for line in entry.message.lines() {
+                let chars: Vec<char> = line.chars().collect();
+                for chunk in chars.chunks(cols.max(1)) {
+                    body.push((entry.severity, chunk.iter().collect()));
+                }
+            }
+        }
+
+        let skip = body.len().saturating_sub(max_lines.saturating_sub(1));
+        lines.extend(body.into_iter().skip(skip));
+        lines
+    }
+}
+
+/// Forwards `log` records (wgpu reports validation problems through it) to stderr and the console.
+pub struct ConsoleLogger {
+    console: SharedConsole,
+}
+
+impl ConsoleLogger {
+    pub fn install(console: SharedConsole) -> anyhow::Result<()> {
+        log::set_boxed_logger(Box::new(ConsoleLogger { console }))
+            .map_err(|e| anyhow::anyhow!("Failed to install logger: {e}"))?;
+        log::set_max_level(log::LevelFilter::Warn);
+        Ok(())
+    }
+}
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        eprintln!(
+            "[{}] {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        self.console.lock().unwrap().push(
+            Severity::from_level(record.level()),
+            format!("{}: {}", record.target(), record.args()),
+        );
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,308 @@
+//! SVGF-style denoising of the displayed image, see `denoise.wgsl`.
+use crate::gpu::Gpu;
+use anyhow::Result;
+use encase::ShaderType;
+
+#[derive(ShaderType)]
+struct DenoiseUniform {
+    step: u32,
+    last: u32,
+    guided: u32,
+}
+
+/// How the displayed image is filtered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Off,
+    /// A single edge-aware à-trous pass, cheap enough for previews.
+    Atrous,
+    /// Variance-guided à-trous iterations over the accumulated image and its moments.
+    Svgf,
+}
+
+impl Filter {
+    pub fn name(self) -> &'static str {
+        match self {
+            Filter::Off => "off",
+            Filter::Atrous => "à-trous",
+            Filter::Svgf => "SVGF",
+        }
+    }
+}
+
+/// An image traced by `GpuRaytracer`: the accumulated color, its first hits and moments.
+pub struct Target<'a> {
+    pub color: &'a wgpu::Texture,
+    pub hit: &'a wgpu::Texture,
+    pub moments: &'a wgpu::Texture,
+}
+
+pub struct Denoiser {
+    prepare_pipeline: wgpu::ComputePipeline,
+    atrous_pipeline: wgpu::ComputePipeline,
+    bgl: wgpu::BindGroupLayout,
+    /// Step and flags of each SVGF iteration.
+    iteration_bufs: Vec<wgpu::Buffer>,
+    /// Step and flags of the single unguided pass.
+    atrous_buf: wgpu::Buffer,
+    /// Ping-pong textures of the iterations, the result ends up in `filtered_tex`.
+    scratch_tex: wgpu::Texture,
+    filtered_tex: wgpu::Texture,
+    scene: Passes,
+    preview: Passes,
+}
+
+/// Bind groups of the prepare pass followed by the filter iterations of each filter, for one
+/// target.
+#[derive(Default)]
+struct Passes {
+    size: (u32, u32),
+    svgf: Vec<wgpu::BindGroup>,
+    atrous: Vec<wgpu::BindGroup>,
+}
+
+impl Denoiser {
+    /// SVGF iterations, each doubling the distance between taps.
+    const ITERATIONS: u32 = 5;
+    /// Distance between the taps of the single à-trous pass, covering 9x9 pixels.
+    const ATROUS_STEP: u32 = 2;
+    const WORKGROUP_SIZE: u32 = 8;
+
+    pub fn new(gpu: &Gpu, scene: Target, preview: Target) -> Result<Self> {
+        use wgpu::util::DeviceExt;
+        let Gpu { device, .. } = gpu;
+
+        gpu.push_error_scope();
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("denoise.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("denoise.wgsl").into()),
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                texture_entry(2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&bgl],
+            push_constant_ranges: &[],
+        });
+        let prepare_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "prepare",
+        });
+        let atrous_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "atrous",
+        });
+        gpu.pop_error_scope("the denoising pipelines")?;
+
+        let uniform_buf = |uniform: DenoiseUniform| -> Result<wgpu::Buffer> {
+            let mut buf = encase::UniformBuffer::new(vec![]);
+            buf.write(&uniform)?;
+            Ok(
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents: buf.into_inner().as_slice(),
+                    usage: wgpu::BufferUsages::UNIFORM,
+                }),
+            )
+        };
+        let iteration_bufs = (0..Self::ITERATIONS)
+            .map(|i| {
+                uniform_buf(DenoiseUniform {
+                    step: 1 << i,
+                    last: (i + 1 == Self::ITERATIONS) as u32,
+                    guided: 1,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let atrous_buf = uniform_buf(DenoiseUniform {
+            step: Self::ATROUS_STEP,
+            last: 1,
+            guided: 0,
+        })?;
+
+        let scratch_tex = create_filter_texture(device, scene.color.size());
+        let filtered_tex = create_filter_texture(device, scene.color.size());
+        let mut denoiser = Self {
+            prepare_pipeline,
+            atrous_pipeline,
+            bgl,
+            iteration_bufs,
+            atrous_buf,
+            scratch_tex,
+            filtered_tex,
+            scene: Passes::default(),
+            preview: Passes::default(),
+        };
+        denoiser.scene = denoiser.passes(device, &scene);
+        denoiser.preview = denoiser.passes(device, &preview);
+        Ok(denoiser)
+    }
+
+    pub fn on_resize(&mut self, gpu: &Gpu, scene: Target, preview: Target) {
+        let Gpu { device, .. } = gpu;
+
+        self.scratch_tex = create_filter_texture(device, scene.color.size());
+        self.filtered_tex = create_filter_texture(device, scene.color.size());
+        self.scene = self.passes(device, &scene);
+        self.preview = self.passes(device, &preview);
+    }
+
+    /// The denoised image with a sample count of 1, the size of the scene image. A denoised
+    /// preview covers its top left corner.
+    pub fn output(&self) -> &wgpu::Texture {
+        &self.filtered_tex
+    }
+
+    /// Records filtering the scene image, or the preview, into `output`.
+    pub fn denoise(&self, encoder: &mut wgpu::CommandEncoder, filter: Filter, preview: bool) {
+        let passes = if preview { &self.preview } else { &self.scene };
+        let bind_groups = match filter {
+            Filter::Off => return,
+            Filter::Atrous => &passes.atrous,
+            Filter::Svgf => &passes.svgf,
+        };
+
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        for (i, bind_group) in bind_groups.iter().enumerate() {
+            cpass.set_pipeline(if i == 0 {
+                &self.prepare_pipeline
+            } else {
+                &self.atrous_pipeline
+            });
+            cpass.set_bind_group(0, bind_group, &[]);
+            cpass.dispatch_workgroups(
+                passes.size.0.div_ceil(Self::WORKGROUP_SIZE),
+                passes.size.1.div_ceil(Self::WORKGROUP_SIZE),
+                1,
+            );
+        }
+    }
+
+    /// Prepares into `scratch_tex`, then ping-pongs so the odd number of iterations ends in
+    /// `filtered_tex`. The single à-trous pass goes straight there.
+    fn passes(&self, device: &wgpu::Device, target: &Target) -> Passes {
+        let view =
+            |texture: &wgpu::Texture| texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = |color: &wgpu::Texture, output: &wgpu::Texture, buf: &wgpu::Buffer| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &self.bgl,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&view(color)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&view(target.hit)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&view(target.moments)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&view(output)),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: buf.as_entire_binding(),
+                    },
+                ],
+            })
+        };
+
+        let mut svgf = vec![bind_group(
+            target.color,
+            &self.scratch_tex,
+            &self.iteration_bufs[0],
+        )];
+        for (i, buf) in self.iteration_bufs.iter().enumerate() {
+            svgf.push(if i % 2 == 0 {
+                bind_group(&self.scratch_tex, &self.filtered_tex, buf)
+            } else {
+                bind_group(&self.filtered_tex, &self.scratch_tex, buf)
+            });
+        }
+
+        let atrous = vec![
+            bind_group(target.color, &self.scratch_tex, &self.atrous_buf),
+            bind_group(&self.scratch_tex, &self.filtered_tex, &self.atrous_buf),
+        ];
+
+        Passes {
+            size: (target.hit.width(), target.hit.height()),
+            svgf,
+            atrous,
+        }
+    }
+}
+
+fn create_filter_texture(device: &wgpu::Device, size: wgpu::Extent3d) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::assert_layout;
+
+    #[test]
+    fn uniform_matches_wgsl_layout() {
+        assert_layout::<DenoiseUniform>(include_str!("denoise.wgsl"), "DenoiseUniform");
+    }
+}
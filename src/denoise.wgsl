@@ -0,0 +1,160 @@
+// Variance-guided à-trous filtering of the accumulated image, after "Spatiotemporal
+// Variance-Guided Filtering" (Schied et al. 2017). The temporal part is the accumulation itself,
+// reprojected with its moments when the camera moves. Without the variance guidance a single
+// iteration is the edge-avoiding à-trous filter of Dammertz et al. 2010.
+
+struct DenoiseUniform {
+    // Distance in pixels between the taps of this iteration.
+    step: u32,
+    // Set on the last iteration, which writes a displayable image.
+    last: u32,
+    // Whether the luminance weight follows the estimated variance or the center's luminance.
+    guided: u32,
+};
+
+// The accumulated image (prepare) or the previous iteration (atrous), rgb = sum or mean color.
+@group(0) @binding(0) var color: texture_2d<f32>;
+// Normal and distance of the first hit, zero normal for the sky and emitters.
+@group(0) @binding(1) var first_hit: texture_2d<f32>;
+// x = sum of squared sample luminance.
+@group(0) @binding(2) var moments: texture_2d<f32>;
+// rgb = mean color, a = luminance variance (a = 1 after the last iteration).
+@group(0) @binding(3) var filtered: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(4) var<uniform> denoise: DenoiseUniform;
+
+// Samples below which the variance is estimated from the neighbourhood instead.
+const MIN_TEMPORAL_SAMPLES: f32 = 4.0;
+// Edge-stopping strengths for normals, relative depth and luminance.
+const SIGMA_NORMAL: f32 = 128.0;
+const SIGMA_DEPTH: f32 = 0.1;
+const SIGMA_LUMINANCE: f32 = 4.0;
+// Luminance edge-stopping relative to the center pixel without variance guidance.
+const SIGMA_RELATIVE_LUMINANCE: f32 = 1.0;
+
+fn luminance(c: vec3<f32>) -> f32 {
+    return dot(c, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn inBounds(p: vec2<i32>, size: vec2<i32>) -> bool {
+    return all(p >= vec2<i32>(0)) && all(p < size);
+}
+
+fn meanColor(p: vec2<i32>) -> vec3<f32> {
+    let c = textureLoad(color, p, 0);
+    return c.rgb / max(c.a, 1.0);
+}
+
+// Splits the accumulation into the mean color and the variance of its luminance.
+@compute
+@workgroup_size(8, 8)
+fn prepare(@builtin(global_invocation_id) id: vec3<u32>) {
+    // The hit texture has the size of the traced image, the filter textures may be larger.
+    let size = vec2<i32>(textureDimensions(first_hit));
+    let p = vec2<i32>(id.xy);
+    if !inBounds(p, size) {
+        return;
+    }
+
+    let c = textureLoad(color, p, 0);
+    let count = c.a;
+    let mean = c.rgb / max(count, 1.0);
+    let l = luminance(mean);
+
+    var variance = 0.0;
+    if count >= MIN_TEMPORAL_SAMPLES {
+        let second = textureLoad(moments, p, 0).x / count;
+        variance = max(second - l * l, 0.0) / count;
+    } else {
+        // Too few samples for their own statistics, use those of the 7x7 neighbourhood.
+        var sum = 0.0;
+        var sum2 = 0.0;
+        var n = 0.0;
+        for (var y = -3; y <= 3; y++) {
+            for (var x = -3; x <= 3; x++) {
+                let q = p + vec2<i32>(x, y);
+                if inBounds(q, size) {
+                    let lq = luminance(meanColor(q));
+                    sum += lq;
+                    sum2 += lq * lq;
+                    n += 1.0;
+                }
+            }
+        }
+        variance = max(sum2 / n - (sum / n) * (sum / n), 0.0);
+    }
+
+    textureStore(filtered, p, vec4<f32>(mean, variance));
+}
+
+// Variance of the 3x3 neighbourhood, steadier than the pixel's own for the luminance weight.
+fn blurredVariance(p: vec2<i32>, size: vec2<i32>) -> f32 {
+    var kernel = array<f32, 2>(0.25, 0.125);
+    var sum = 0.0;
+    var weights = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let q = p + vec2<i32>(x, y);
+            if inBounds(q, size) {
+                let w = kernel[abs(x)] * kernel[abs(y)];
+                sum += w * textureLoad(color, q, 0).a;
+                weights += w;
+            }
+        }
+    }
+    return sum / weights;
+}
+
+// One iteration of the edge-aware 5x5 B3-spline wavelet filter.
+@compute
+@workgroup_size(8, 8)
+fn atrous(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = vec2<i32>(textureDimensions(first_hit));
+    let p = vec2<i32>(id.xy);
+    if !inBounds(p, size) {
+        return;
+    }
+
+    var kernel = array<f32, 3>(0.375, 0.25, 0.0625);
+    let center = textureLoad(color, p, 0);
+    let hit = textureLoad(first_hit, p, 0);
+    let l = luminance(center.rgb);
+    var luminance_scale = SIGMA_RELATIVE_LUMINANCE * l + 1e-3;
+    if denoise.guided != 0u {
+        luminance_scale = SIGMA_LUMINANCE * sqrt(blurredVariance(p, size)) + 1e-6;
+    }
+
+    var sum = vec3<f32>(0.0);
+    var variance = 0.0;
+    var weights = 0.0;
+    for (var y = -2; y <= 2; y++) {
+        for (var x = -2; x <= 2; x++) {
+            let q = p + vec2<i32>(x, y) * i32(denoise.step);
+            if !inBounds(q, size) {
+                continue;
+            }
+
+            let tap = textureLoad(color, q, 0);
+            let hit_q = textureLoad(first_hit, q, 0);
+
+            // The sky and emitters have no normal, they only blend with each other.
+            var w_normal = pow(max(dot(hit.xyz, hit_q.xyz), 0.0), SIGMA_NORMAL);
+            if all(hit.xyz == vec3<f32>(0.0)) && all(hit_q.xyz == vec3<f32>(0.0)) {
+                w_normal = 1.0;
+            }
+            let w_depth = exp(-abs(hit.w - hit_q.w) / (SIGMA_DEPTH * max(hit.w, hit_q.w) + 1e-6));
+            let w_luminance = exp(-abs(l - luminance(tap.rgb)) / luminance_scale);
+            let w = kernel[abs(x)] * kernel[abs(y)] * w_normal * w_depth * w_luminance;
+
+            sum += w * tap.rgb;
+            variance += w * w * tap.a;
+            weights += w;
+        }
+    }
+
+    // The center always has a weight of at least 0.375^2.
+    var result = vec4<f32>(sum / weights, variance / (weights * weights));
+    if denoise.last != 0u {
+        result.a = 1.0;
+    }
+    textureStore(filtered, p, result);
+}
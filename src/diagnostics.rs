@@ -0,0 +1,30 @@
+//! Opt-in tracing of GPU work for diagnosing performance and errors from logs.
+//!
+//! Spans cover GPU initialization, scene uploads, compute passes and readbacks. They time
+//! the CPU side: submissions return before the GPU is done, only readbacks wait for it.
+use anyhow::{anyhow, Result};
+use std::io::IsTerminal;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable holding the filter, in `EnvFilter` syntax, e.g. `raytracer_gpu=debug`
+/// to include every compute pass.
+pub const FILTER_ENV: &str = "RAYTRACER_LOG";
+
+/// Prints spans and events matching `RAYTRACER_LOG` to stderr, each span with its duration
+/// when it closes. Does nothing when the variable is not set.
+pub fn init() -> Result<()> {
+    if std::env::var_os(FILTER_ENV).is_none() {
+        return Ok(());
+    }
+
+    let filter = EnvFilter::try_from_env(FILTER_ENV)
+        .map_err(|err| anyhow!("Invalid `{FILTER_ENV}` filter: {err}"))?;
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .try_init()
+        .map_err(|err| anyhow!("Failed to install the tracing subscriber: {err}"))
+}
@@ -0,0 +1,532 @@
+//! Rendering one image on several machines with `--coordinator` and `--worker`. The coordinator
+//! splits the image into `BUCKET_SIZE` buckets, workers running the headless tracer claim them
+//! over TCP and send back their pixels, and the coordinator saves the assembled image.
+//!
+//! The protocol is line based, after connecting a worker is sent the render:
+//!
+//! - `JOB <width> <height> <seed> <kernel> <paths per pixel> <pixel filter> <bounces per
+//!   dispatch> <scene code>`, the code as in `share`. Workers trace with these settings of the
+//!   coordinator rather than their own.
+//!
+//! The worker then repeatedly sends `CLAIM` and is answered `BUCKET <x> <y> <width> <height>`,
+//! which it traces and returns as `RESULT <x> <y> <width> <height>` followed by the bucket's
+//! accumulated texels, four little-endian `f32`s each in row order. `DONE` answers a claim once
+//! all buckets are traced, the coordinator answers every connected worker before it exits.
+//! Buckets of workers that disconnect are handed out again.
+//!
+//! Every worker traces the full pass sequence of the seed, so the image doesn't depend on how
+//! it was split.
+use crate::assets::AssetResolver;
+use crate::cli::Options;
+use crate::console::Console;
+use crate::gpu::Gpu;
+use crate::headless;
+use crate::image::Accumulation;
+use crate::raytracing::{random_seed, Kernel, PixelFilter};
+use crate::scene_file::SceneFile;
+use crate::share;
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+/// Edge length of a bucket in pixels, a multiple of `tiles::TILE_SIZE` so buckets don't share
+/// traced tiles.
+pub const BUCKET_SIZE: u32 = 64;
+
+/// How long the coordinator waits for connected workers to claim again and be answered
+/// `DONE` once the image is done.
+const DONE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hands the buckets of `scene_file` to the workers connecting on `addr` and writes the
+/// assembled image to `output`.
+pub async fn coordinate(
+    options: &Options,
+    addr: &str,
+    output: &Path,
+    scene_file: SceneFile,
+) -> Result<()> {
+    let code = share::encode(&scene_file);
+    let job = Job {
+        size: options.size,
+        seed: options.seed.unwrap_or_else(random_seed),
+        kernel: options.kernel,
+        paths_per_pixel: options.paths_per_pixel,
+        pixel_filter: options.pixel_filter,
+        bounces_per_dispatch: options.bounces_per_dispatch,
+        code: &code,
+    }
+    .line();
+
+    let listener = TcpListener::bind(addr).await?;
+    eprintln!(
+        "Waiting for workers on {}, {} buckets",
+        listener.local_addr()?,
+        buckets(options.size).len()
+    );
+    let image = assemble(listener, job, options.size).await?;
+    image.save(output, scene_file.camera.exposure.scale())?;
+    eprintln!("Wrote {}", output.display());
+
+    Ok(())
+}
+
+/// Claims and traces buckets from the coordinator on `addr` until the image is done.
+pub async fn work(options: &Options, addr: &str) -> Result<()> {
+    let gpu = Gpu::headless(options.fallback_adapter, Console::shared()).await?;
+    let options = options.clone();
+    let addr = addr.to_owned();
+    tokio::task::spawn_blocking(move || trace_buckets(&gpu, &options, &addr)).await?
+}
+
+/// The buckets covering an image of `size`, in row order.
+fn buckets((width, height): (u32, u32)) -> Vec<[u32; 4]> {
+    (0..height)
+        .step_by(BUCKET_SIZE as usize)
+        .flat_map(|y| {
+            (0..width).step_by(BUCKET_SIZE as usize).map(move |x| {
+                [
+                    x,
+                    y,
+                    BUCKET_SIZE.min(width - x),
+                    BUCKET_SIZE.min(height - y),
+                ]
+            })
+        })
+        .collect()
+}
+
+/// Parses the `x y width height` of a `keyword` line.
+fn parse_bucket(line: &str, keyword: &str) -> Result<[u32; 4]> {
+    let fields = line
+        .trim_end()
+        .strip_prefix(keyword)
+        .and_then(|rest| rest.strip_prefix(' '))
+        .ok_or_else(|| anyhow!("Expected `{keyword}`, got `{}`", line.trim_end()))?;
+    let fields = fields
+        .split(' ')
+        .map(str::parse)
+        .collect::<Result<Vec<u32>, _>>()
+        .with_context(|| format!("Invalid bucket `{fields}`"))?;
+
+    fields
+        .try_into()
+        .map_err(|_| anyhow!("Expected `{keyword} <x> <y> <width> <height>`"))
+}
+
+struct Frame {
+    /// Buckets not handed out, or given back by a worker that disconnected.
+    pending: Vec<[u32; 4]>,
+    /// Buckets not traced yet, handed out or not.
+    remaining: usize,
+    total: usize,
+    image: Accumulation,
+}
+
+/// The image traced by the workers connecting to `listener`, each sent `job`.
+async fn assemble(listener: TcpListener, job: String, size: (u32, u32)) -> Result<Accumulation> {
+    let mut pending = buckets(size);
+    pending.reverse();
+    let total = pending.len();
+    let frame = Arc::new(Mutex::new(Frame {
+        pending,
+        remaining: total,
+        total,
+        image: Accumulation {
+            width: size.0,
+            height: size.1,
+            texels: vec![[0.0; 4]; size.0 as usize * size.1 as usize],
+        },
+    }));
+    let changed = Arc::new(Notify::new());
+
+    let job = Arc::new(job);
+    let mut workers = JoinSet::new();
+    loop {
+        let done = changed.notified();
+        if frame.lock().unwrap().remaining == 0 {
+            break;
+        }
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // Such as running out of file descriptors, back off rather than spin.
+                        log::warn!("Failed to accept a worker: {err}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let (frame, changed, job) = (frame.clone(), changed.clone(), job.clone());
+                workers.spawn(async move {
+                    if let Err(err) = serve_worker(stream, &job, &frame, &changed).await {
+                        log::warn!("Worker {peer} dropped out: {err:#}");
+                    }
+                });
+            }
+            _ = done => {}
+        }
+    }
+
+    // Answer `DONE` to the workers still connected before the coordinator exits, rather than
+    // dropping their connections. Give up on workers that stopped claiming.
+    let drained = tokio::time::timeout(DONE_TIMEOUT, async {
+        while workers.join_next().await.is_some() {}
+    });
+    if drained.await.is_err() {
+        log::warn!("{} workers didn't claim again, closing them", workers.len());
+    }
+
+    let texels = std::mem::take(&mut frame.lock().unwrap().image.texels);
+    Ok(Accumulation {
+        width: size.0,
+        height: size.1,
+        texels,
+    })
+}
+
+/// Sends `job` to the worker on `stream` and answers its claims until the image is done,
+/// giving its buckets back when it disconnects.
+async fn serve_worker(
+    stream: TcpStream,
+    job: &str,
+    frame: &Mutex<Frame>,
+    changed: &Notify,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    writer.write_all(job.as_bytes()).await?;
+
+    let mut claimed = vec![];
+    let result = answer_claims(&mut reader, &mut writer, frame, changed, &mut claimed).await;
+    if !claimed.is_empty() {
+        frame.lock().unwrap().pending.extend(claimed);
+        changed.notify_waiters();
+    }
+    result
+}
+
+async fn answer_claims(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl tokio::io::AsyncWrite + Unpin),
+    frame: &Mutex<Frame>,
+    changed: &Notify,
+    claimed: &mut Vec<[u32; 4]>,
+) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return match claimed.len() {
+                0 => Ok(()),
+                n => Err(anyhow!("Disconnected with {n} buckets claimed")),
+            };
+        }
+
+        if line.trim_end() == "CLAIM" {
+            let Some(bucket) = claim(frame, changed).await else {
+                writer.write_all(b"DONE\n").await?;
+                return Ok(());
+            };
+            claimed.push(bucket);
+            let [x, y, width, height] = bucket;
+            writer
+                .write_all(format!("BUCKET {x} {y} {width} {height}\n").as_bytes())
+                .await?;
+        } else {
+            let bucket = parse_bucket(&line, "RESULT")?;
+            let index = claimed
+                .iter()
+                .position(|claim| *claim == bucket)
+                .ok_or_else(|| anyhow!("Bucket {bucket:?} wasn't claimed"))?;
+            let mut bytes = vec![0; bucket[2] as usize * bucket[3] as usize * 16];
+            reader.read_exact(&mut bytes).await?;
+            claimed.swap_remove(index);
+            frame.lock().unwrap().store(bucket, &texels(&bytes));
+            changed.notify_waiters();
+        }
+    }
+}
+
+/// The next bucket to hand out, waiting for one to be given back while others are traced.
+/// `None` once all are traced.
+async fn claim(frame: &Mutex<Frame>, changed: &Notify) -> Option<[u32; 4]> {
+    loop {
+        let notified = changed.notified();
+        {
+            let mut frame = frame.lock().unwrap();
+            if let Some(bucket) = frame.pending.pop() {
+                return Some(bucket);
+            }
+            if frame.remaining == 0 {
+                return None;
+            }
+        }
+        notified.await;
+    }
+}
+
+impl Frame {
+    fn store(&mut self, [x, y, width, _]: [u32; 4], texels: &[[f32; 4]]) {
+        for (row, texels) in texels.chunks_exact(width as usize).enumerate() {
+            let start = (y as usize + row) * self.image.width as usize + x as usize;
+            self.image.texels[start..start + width as usize].copy_from_slice(texels);
+        }
+        self.remaining -= 1;
+        eprintln!(
+            "Traced {} of {} buckets",
+            self.total - self.remaining,
+            self.total
+        );
+    }
+}
+
+fn texels(bytes: &[u8]) -> Vec<[f32; 4]> {
+    bytes
+        .chunks_exact(16)
+        .map(|texel| {
+            let channel =
+                |i: usize| f32::from_le_bytes(texel[i * 4..i * 4 + 4].try_into().unwrap());
+            [channel(0), channel(1), channel(2), channel(3)]
+        })
+        .collect()
+}
+
+fn texel_bytes(texels: &[[f32; 4]]) -> Vec<u8> {
+    texels
+        .iter()
+        .flatten()
+        .flat_map(|c| c.to_le_bytes())
+        .collect()
+}
+
+/// A render as sent to the workers in the `JOB` line.
+#[derive(Debug, PartialEq)]
+struct Job<'a> {
+    size: (u32, u32),
+    seed: u64,
+    kernel: Kernel,
+    paths_per_pixel: u32,
+    pixel_filter: PixelFilter,
+    bounces_per_dispatch: u32,
+    code: &'a str,
+}
+
+impl<'a> Job<'a> {
+    fn line(&self) -> String {
+        let (width, height) = self.size;
+        format!(
+            "JOB {width} {height} {} {} {} {} {} {}\n",
+            self.seed,
+            self.kernel.name(),
+            self.paths_per_pixel,
+            self.pixel_filter.name(),
+            self.bounces_per_dispatch,
+            self.code
+        )
+    }
+
+    fn parse(line: &'a str) -> Result<Self> {
+        let fields: Vec<&str> = line
+            .trim_end()
+            .strip_prefix("JOB ")
+            .ok_or_else(|| anyhow!("Expected `JOB`, got `{}`", line.trim_end()))?
+            .splitn(8, ' ')
+            .collect();
+        let [width, height, seed, kernel, paths_per_pixel, pixel_filter, bounces_per_dispatch, code] =
+            fields[..]
+        else {
+            return Err(anyhow!(
+                "Expected `JOB <width> <height> <seed> <kernel> <paths per pixel> \
+                 <pixel filter> <bounces per dispatch> <scene code>`"
+            ));
+        };
+        Ok(Job {
+            size: (width.parse()?, height.parse()?),
+            seed: seed.parse()?,
+            kernel: Kernel::from_name(kernel)
+                .ok_or_else(|| anyhow!("Unknown kernel `{kernel}`"))?,
+            paths_per_pixel: paths_per_pixel.parse()?,
+            pixel_filter: PixelFilter::from_name(pixel_filter)
+                .ok_or_else(|| anyhow!("Unknown pixel filter `{pixel_filter}`"))?,
+            bounces_per_dispatch: bounces_per_dispatch.parse()?,
+            code,
+        })
+    }
+
+    /// `options` with the tracing settings of the coordinator.
+    fn options(&self, options: &Options) -> Options {
+        Options {
+            kernel: self.kernel,
+            paths_per_pixel: self.paths_per_pixel,
+            pixel_filter: self.pixel_filter,
+            bounces_per_dispatch: self.bounces_per_dispatch,
+            ..options.clone()
+        }
+    }
+}
+
+/// Traces the buckets handed out by the coordinator on `addr`, each with all samples of the
+/// scene.
+fn trace_buckets(gpu: &Gpu, options: &Options, addr: &str) -> Result<()> {
+    let stream = std::net::TcpStream::connect(addr)
+        .with_context(|| format!("Failed to connect to the coordinator on {addr}"))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut read_line = |line: &mut String| -> Result<()> {
+        line.clear();
+        match reader.read_line(line)? {
+            0 => Err(anyhow!("The coordinator closed the connection")),
+            _ => Ok(()),
+        }
+    };
+
+    let mut line = String::new();
+    read_line(&mut line)?;
+    let job = Job::parse(&line)?;
+    let scene_file = share::decode(job.code, &AssetResolver::new(options.asset_paths.clone()))?;
+    let size = job.size;
+    let (gpu_camera, renderer, mut raytracer) =
+        headless::prepare(gpu, &job.options(options), scene_file, size, job.seed)?;
+    let samples = gpu_camera.camera().num_samples;
+    eprintln!(
+        "Tracing buckets of a {}x{} image, {samples} samples",
+        size.0, size.1
+    );
+
+    let mut traced = 0;
+    loop {
+        writer.write_all(b"CLAIM\n")?;
+        read_line(&mut line)?;
+        if line.trim_end() == "DONE" {
+            break;
+        }
+
+        let bucket @ [x, y, width, height] = parse_bucket(&line, "BUCKET")?;
+        raytracer.set_region(Some(bucket));
+        raytracer.restart_seeds();
+        renderer.clear(gpu);
+        raytracer.perform(gpu, &gpu_camera, 0..samples, None, |_| {
+            ControlFlow::Continue(())
+        })?;
+        let accumulation = renderer.read_accumulation_region(gpu, (x, y), (width, height))?;
+
+        writer.write_all(format!("RESULT {x} {y} {width} {height}\n").as_bytes())?;
+        writer.write_all(&texel_bytes(&accumulation.texels))?;
+        traced += 1;
+    }
+    eprintln!("Traced {traced} buckets");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[test]
+    fn buckets_cover_the_image_once() {
+        let size = (150, 70);
+        let mut covered = vec![0; 150 * 70];
+        for [x, y, width, height] in buckets(size) {
+            for py in y..y + height {
+                for px in x..x + width {
+                    covered[(py * size.0 + px) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+        assert_eq!(buckets(size).len(), 6);
+    }
+
+    #[test]
+    fn parses_messages() {
+        assert_eq!(
+            parse_bucket("BUCKET 64 0 64 32\n", "BUCKET").unwrap(),
+            [64, 0, 64, 32]
+        );
+        assert!(parse_bucket("RESULT 64 0 64\n", "RESULT").is_err());
+        assert!(parse_bucket("BUCKET 64 0 64 32\n", "RESULT").is_err());
+        let job = Job {
+            size: (320, 180),
+            seed: 7,
+            kernel: Kernel::MultiPass,
+            paths_per_pixel: 4,
+            pixel_filter: PixelFilter::Mitchell,
+            bounces_per_dispatch: 2,
+            code: "rt1.abc",
+        };
+        let line = job.line();
+        assert_eq!(line, "JOB 320 180 7 multi-pass 4 mitchell 2 rt1.abc\n");
+        assert_eq!(Job::parse(&line).unwrap(), job);
+        assert!(Job::parse("JOB 320 180 7 rt1.abc\n").is_err());
+        assert!(Job::parse("JOB 320 180 7 hyperkernel 4 mitchell 2 rt1.abc\n").is_err());
+    }
+
+    /// A worker answering every bucket with its pixel coordinates, leaving after `limit`
+    /// claims without returning the last one.
+    async fn fake_worker(addr: std::net::SocketAddr, limit: Option<usize>) {
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("JOB 150 70 7 "));
+
+        for claims in 1.. {
+            writer.write_all(b"CLAIM\n").await.unwrap();
+            line.clear();
+            let read = reader.read_line(&mut line).await.unwrap();
+            assert_ne!(
+                read, 0,
+                "The coordinator closed the connection without DONE"
+            );
+            if line == "DONE\n" {
+                return;
+            }
+            if limit == Some(claims) {
+                return;
+            }
+
+            let [x, y, width, height] = parse_bucket(&line, "BUCKET").unwrap();
+            let texels: Vec<[f32; 4]> = (y..y + height)
+                .flat_map(|py| (x..x + width).map(move |px| [px as f32, py as f32, 0.0, 1.0]))
+                .collect();
+            let header = format!("RESULT {x} {y} {width} {height}\n");
+            writer.write_all(header.as_bytes()).await.unwrap();
+            writer.write_all(&texel_bytes(&texels)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn assembles_buckets_of_workers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let image = tokio::spawn(assemble(
+            listener,
+            "JOB 150 70 7 megakernel 1 box 1 rt1.\n".into(),
+            (150, 70),
+        ));
+
+        fake_worker(addr, Some(2)).await;
+        let workers = [
+            tokio::spawn(fake_worker(addr, None)),
+            tokio::spawn(fake_worker(addr, None)),
+        ];
+        let image = image.await.unwrap().unwrap();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+
+        for (i, texel) in image.texels.iter().enumerate() {
+            let (x, y) = (i % 150, i / 150);
+            assert_eq!(*texel, [x as f32, y as f32, 0.0, 1.0]);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Tracing as a viewport of another wgpu application or editor. The application owns the
+//! device, queue and the texture shown, there's no surface or window. It requests its device
+//! with `gpu::device_descriptor` and calls `Tracer::trace_pass` and `Tracer::draw` once a
+//! frame.
+use crate::camera::{Camera, GpuCamera};
+use crate::gpu::Gpu;
+use crate::raytracing::{GpuRaytracer, Progress};
+use crate::render::Renderer;
+use crate::scene_file::SceneFile;
+use anyhow::Result;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+pub struct Tracer {
+    gpu: Gpu,
+    gpu_camera: GpuCamera,
+    renderer: Renderer,
+    raytracer: GpuRaytracer,
+    /// Samples per pixel traced so far.
+    passes: u32,
+}
+
+impl Tracer {
+    /// Traces `scene_file` at `size` on the application's `device`, drawing into textures of
+    /// `format`.
+    pub fn new(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        format: wgpu::TextureFormat,
+        scene_file: SceneFile,
+        size: (u32, u32),
+        seed: u64,
+    ) -> Result<Self> {
+        let SceneFile {
+            scene,
+            camera,
+            max_bounces,
+        } = scene_file;
+
+        let gpu = Gpu::from_device(device, queue)?;
+        let exposure = camera.exposure;
+        let lens = camera.lens;
+        let projection = camera.projection;
+        let stereo = camera.stereo;
+        let camera = Camera::new(
+            camera.lookfrom,
+            camera.lookat,
+            camera.vup,
+            camera.num_samples,
+            size,
+        );
+
+        let mut gpu_camera = GpuCamera::new(&gpu, camera)?;
+        gpu_camera.set_exposure(&gpu, exposure)?;
+        gpu_camera.set_lens(&gpu, lens)?;
+        gpu_camera.set_projection(&gpu, projection)?;
+        gpu_camera.set_stereo(&gpu, stereo)?;
+        let renderer = Renderer::with_format(&gpu, &gpu_camera, format)?;
+        let raytracer = GpuRaytracer::new(&gpu, &gpu_camera, max_bounces, &renderer, scene, seed)?;
+
+        Ok(Tracer {
+            gpu,
+            gpu_camera,
+            renderer,
+            raytracer,
+            passes: 0,
+        })
+    }
+
+    /// Traces one more sample per pixel, returning whether the image still needs more. The
+    /// work is only submitted, the application's next `queue.submit` or `device.poll` waits.
+    pub fn trace_pass(&mut self) -> Result<bool> {
+        let samples = self.gpu_camera.camera().num_samples;
+        if self.passes < samples {
+            self.passes = self.raytracer.perform(
+                &self.gpu,
+                &self.gpu_camera,
+                self.passes..self.passes + 1,
+                None,
+                |_| ControlFlow::Continue(()),
+            )?;
+        }
+        Ok(self.passes < samples)
+    }
+
+    /// Draws the image so far into `target`, a `size` texture of the format given to `new`,
+    /// letterboxed when the sizes differ.
+    pub fn draw(&self, target: &wgpu::TextureView, size: (u32, u32)) -> Result<()> {
+        self.renderer
+            .draw_into(&self.gpu, target, size, &self.gpu_camera)
+    }
+
+    /// Traces at `size` from now on, starting the image over.
+    pub fn resize(&mut self, size: (u32, u32)) -> Result<()> {
+        self.gpu_camera.on_resize(&self.gpu, size)?;
+        self.renderer.on_resize(&self.gpu, &self.gpu_camera)?;
+        self.raytracer.on_resize(&self.gpu, &self.renderer)?;
+        self.renderer.clear(&self.gpu);
+        self.passes = 0;
+        Ok(())
+    }
+
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    /// Reports each traced pass to `progress`, see `GpuRaytracer::set_progress`.
+    pub fn set_progress(&mut self, progress: Option<Box<dyn Fn(Progress) + Send + Sync>>) {
+        self.raytracer.set_progress(progress);
+    }
+}
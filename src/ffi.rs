@@ -0,0 +1,407 @@
+//! C interface for building scenes and rendering them headless, declared in
+//! `include/raytracer_gpu.h`. Regenerate the header with
+//! `cbindgen --config cbindgen.toml --output include/raytracer_gpu.h` after changing it.
+//!
+//! Scenes and tracers are opaque handles freed by their `_free` function. Functions
+//! returning `RtStatus` report failures as `RT_STATUS_ERROR`, `rt_last_error` then describes
+//! the error. Panics are caught at the boundary and reported the same way, they never
+//! unwind into the caller.
+use crate::camera::Camera;
+use crate::console::Console;
+use crate::gpu::Gpu;
+use crate::raytracing::{GpuRaytracer, ImageOptions};
+use crate::scene::{Material, Scene, Sphere};
+use crate::scene_file::CameraSettings;
+use crate::types::*;
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::panic::AssertUnwindSafe;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RtStatus {
+    Ok = 0,
+    Error = 1,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RtVec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl From<RtVec3> for Vec3 {
+    fn from(RtVec3 { x, y, z }: RtVec3) -> Self {
+        Vec3::new(x, y, z)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RtMaterialKind {
+    Lambertian = 0,
+    Metal = 1,
+    Dielectric = 2,
+    Pbr = 3,
+}
+
+/// A material, fields unused by its `kind` are ignored.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RtMaterial {
+    pub kind: RtMaterialKind,
+    pub albedo: RtVec3,
+    /// Blur of metal reflections, 0 for a mirror.
+    pub fuzz: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Refractive index of dielectrics.
+    pub refract_idx: f32,
+}
+
+impl From<RtMaterial> for Material {
+    fn from(material: RtMaterial) -> Self {
+        let albedo = material.albedo.into();
+        match material.kind {
+            RtMaterialKind::Lambertian => Material::new_lambertian(albedo),
+            RtMaterialKind::Metal => Material::new_metal(albedo, material.fuzz),
+            RtMaterialKind::Dielectric => Material::new_dielectric(material.refract_idx, None),
+            RtMaterialKind::Pbr => Material::new_pbr(albedo, material.metallic, material.roughness),
+        }
+    }
+}
+
+/// Largest width or height of a rendered image, the texture size limit of the devices
+/// `rt_tracer_new` creates.
+const MAX_IMAGE_SIZE: u32 = 8192;
+
+/// A scene, the camera it's rendered through and how it's traced.
+pub struct RtScene {
+    scene: Scene,
+    camera: Camera,
+    options: ImageOptions,
+}
+
+/// A GPU to render on, reused across renders.
+pub struct RtTracer {
+    gpu: Gpu,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// `RtStatus` of `result`, keeping its error for `rt_last_error`.
+fn status(result: Result<()>) -> RtStatus {
+    match result {
+        Ok(()) => RtStatus::Ok,
+        Err(err) => {
+            let message = CString::new(format!("{err:#}").replace('\0', " ")).unwrap();
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+            RtStatus::Error
+        }
+    }
+}
+
+/// Runs `body`, turning a panic into an error instead of unwinding across the C ABI.
+fn catch_panic<T>(body: impl FnOnce() -> Result<T>) -> Result<T> {
+    std::panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_owned());
+        Err(anyhow!("Panicked: {message}"))
+    })
+}
+
+/// The object made by `body`, null if it fails, keeping the error for `rt_last_error`.
+fn new_handle<T>(body: impl FnOnce() -> Result<T>) -> *mut T {
+    match catch_panic(body) {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(err) => {
+            status(Err(err));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// The error of the last failed call on this thread, null if none failed. Valid until the
+/// next failing call.
+#[no_mangle]
+pub extern "C" fn rt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// An empty scene seen by the default camera, 100 samples at 1200x675, traced with the
+/// default bounce depth and a random seed.
+#[no_mangle]
+pub extern "C" fn rt_scene_new() -> *mut RtScene {
+    new_handle(|| {
+        let CameraSettings {
+            lookfrom,
+            lookat,
+            vup,
+            num_samples,
+            ..
+        } = CameraSettings::default();
+        Ok(RtScene {
+            scene: Scene::default(),
+            camera: Camera::new(lookfrom, lookat, vup, num_samples, (1200, 675)),
+            options: ImageOptions::default(),
+        })
+    })
+}
+
+/// # Safety
+///
+/// `scene` must come from `rt_scene_new` and not be used afterwards, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_free(scene: *mut RtScene) {
+    if !scene.is_null() {
+        status(catch_panic(|| {
+            drop(Box::from_raw(scene));
+            Ok(())
+        }));
+    }
+}
+
+/// # Safety
+///
+/// `scene` must be a live scene.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_add_sphere(
+    scene: *mut RtScene,
+    center: RtVec3,
+    radius: f32,
+    material: RtMaterial,
+) {
+    status(catch_panic(|| {
+        (*scene)
+            .scene
+            .new_sphere(Sphere::new(center.into(), radius), material.into());
+        Ok(())
+    }));
+}
+
+/// Places the camera, rendering `width` x `height` images of `samples` samples per pixel.
+/// Neither side may exceed 8192, the largest texture of the tracer's device.
+///
+/// # Safety
+///
+/// `scene` must be a live scene.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_camera(
+    scene: *mut RtScene,
+    lookfrom: RtVec3,
+    lookat: RtVec3,
+    vup: RtVec3,
+    samples: u32,
+    width: u32,
+    height: u32,
+) -> RtStatus {
+    status(catch_panic(|| {
+        if width == 0 || height == 0 || samples == 0 {
+            return Err(anyhow!(
+                "Invalid camera, {width}x{height} with {samples} samples"
+            ));
+        }
+        if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+            return Err(anyhow!(
+                "Invalid camera, {width}x{height} exceeds the largest image of {MAX_IMAGE_SIZE}x{MAX_IMAGE_SIZE}"
+            ));
+        }
+        (*scene).camera = Camera::new(
+            lookfrom.into(),
+            lookat.into(),
+            vup.into(),
+            samples,
+            (width, height),
+        );
+        Ok(())
+    }))
+}
+
+/// Traces up to `max_bounces` bounces per path, with the per-pass seeds derived from `seed`.
+/// The same seed renders the same image.
+///
+/// # Safety
+///
+/// `scene` must be a live scene.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_render_options(
+    scene: *mut RtScene,
+    max_bounces: u32,
+    seed: u64,
+) -> RtStatus {
+    status(catch_panic(|| {
+        if max_bounces == 0 {
+            return Err(anyhow!(
+                "Invalid render options, at least one bounce is needed"
+            ));
+        }
+        (*scene).options = ImageOptions {
+            max_bounces: max_bounces as usize,
+            seed,
+            ..(*scene).options
+        };
+        Ok(())
+    }))
+}
+
+/// A headless tracer on the first suitable GPU, the software fallback adapter when
+/// `fallback_adapter` is set. Null on failure.
+#[no_mangle]
+pub extern "C" fn rt_tracer_new(fallback_adapter: bool) -> *mut RtTracer {
+    new_handle(|| {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let gpu = runtime.block_on(Gpu::headless(fallback_adapter, Console::shared()))?;
+        Ok(RtTracer { gpu })
+    })
+}
+
+/// # Safety
+///
+/// `tracer` must come from `rt_tracer_new` and not be used afterwards, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn rt_tracer_free(tracer: *mut RtTracer) {
+    if !tracer.is_null() {
+        status(catch_panic(|| {
+            drop(Box::from_raw(tracer));
+            Ok(())
+        }));
+    }
+}
+
+/// Renders `scene` into `pixels`, 8-bit sRGB RGBA rows top to bottom of the camera's size.
+/// Blocks until all samples are traced.
+///
+/// # Safety
+///
+/// `tracer` and `scene` must be live, `pixels` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rt_render(
+    tracer: *const RtTracer,
+    scene: *const RtScene,
+    pixels: *mut u8,
+    len: usize,
+) -> RtStatus {
+    status(catch_panic(|| {
+        let RtScene {
+            scene,
+            camera,
+            options,
+        } = &*scene;
+        let needed = camera.width as usize * camera.height as usize * 4;
+        if len < needed {
+            return Err(anyhow!(
+                "The pixel buffer holds {len} bytes, a {}x{} image needs {needed}",
+                camera.width,
+                camera.height
+            ));
+        }
+
+        let image = GpuRaytracer::render_to_image(&(*tracer).gpu, scene, camera, options)?;
+        std::slice::from_raw_parts_mut(pixels, needed).copy_from_slice(&image.pixels);
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    /// Names and doc comments of the items this module exports, the docs laid out as
+    /// cbindgen writes them.
+    fn exported_items(source: &str) -> Vec<(&str, String)> {
+        let mut items = vec![];
+        let mut docs = vec![];
+        for line in source.lines().take_while(|line| *line != "#[cfg(test)]") {
+            if let Some(doc) = line.strip_prefix("///") {
+                docs.push(doc.strip_prefix(' ').unwrap_or(doc));
+                continue;
+            }
+            if line.starts_with("#[") {
+                continue;
+            }
+            let declaration = ["fn ", "struct ", "enum "]
+                .into_iter()
+                .find_map(|keyword| Some(line.split_once(keyword)?.1));
+            if let (true, Some(declaration)) = (line.starts_with("pub "), declaration) {
+                let name = declaration
+                    .split(|c: char| !c.is_alphanumeric() && c != '_')
+                    .next()
+                    .unwrap();
+                let comment = docs
+                    .iter()
+                    .map(|doc| format!(" *{}{doc}\n", if doc.is_empty() { "" } else { " " }))
+                    .collect::<String>();
+                items.push((name, comment));
+            }
+            docs.clear();
+        }
+        items
+    }
+
+    #[test]
+    fn header_matches_the_exports() {
+        let header = include_str!("../include/raytracer_gpu.h");
+        let items = exported_items(include_str!("ffi.rs"));
+        assert!(items.iter().any(|(name, _)| *name == "rt_render"));
+        for (name, comment) in items {
+            if comment.is_empty() {
+                assert!(header.contains(name), "`{name}` is missing from the header");
+                continue;
+            }
+            let documented = format!("/**\n{comment} */\n");
+            let declaration = header
+                .split_once(&documented)
+                .and_then(|(_, rest)| rest.lines().next());
+            assert!(
+                declaration.is_some_and(|declaration| declaration.contains(name)),
+                "The header's declaration of `{name}` is missing or documented differently, \
+                 regenerate it with cbindgen"
+            );
+        }
+    }
+
+    #[test]
+    fn image_size_matches_the_device_limit() {
+        let limits = wgpu::Limits::default();
+        assert_eq!(MAX_IMAGE_SIZE, limits.max_texture_dimension_2d);
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        let result: Result<()> = catch_panic(|| panic!("lost device"));
+        assert!(format!("{:#}", result.unwrap_err()).contains("lost device"));
+    }
+
+    #[test]
+    fn errors_are_reported_through_last_error() {
+        let origin = RtVec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        unsafe {
+            let scene = rt_scene_new();
+            let status = rt_scene_set_camera(scene, origin, origin, origin, 16, 0, 480);
+            assert_eq!(status, RtStatus::Error);
+            let error = CStr::from_ptr(rt_last_error()).to_str().unwrap();
+            assert!(error.contains("0x480"), "{error}");
+
+            let status = rt_scene_set_camera(scene, origin, origin, origin, 16, 640, 16384);
+            assert_eq!(status, RtStatus::Error);
+            rt_scene_free(scene);
+        }
+    }
+}
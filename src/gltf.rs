@@ -0,0 +1,812 @@
+//! Export of scenes to binary glTF (`.glb`), to open scenes authored in code or RON in
+//! Blender for tweaking.
+//!
+//! Meshes keep their triangles, spheres, cones and disks are tessellated and CSG and SDF
+//! objects are skipped. Materials are approximated with the metallic-roughness model,
+//! dielectrics with `KHR_materials_transmission` and `KHR_materials_ior`. Point, spot and
+//! directional lights and the sun become `KHR_lights_punctual` lights with their
+//! intensities copied as they are, quad lights become emissive quads. The sky and fog
+//! have no glTF counterpart and are left out.
+use crate::camera::Projection;
+use crate::ron::Value;
+use crate::scene_file::SceneFile;
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use nalgebra as na;
+use std::collections::BTreeSet;
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::fmt;
+use std::path::Path;
+
+/// Segments around tessellated spheres, cones and disks.
+const SEGMENTS: u32 = 48;
+/// Rings from pole to pole of tessellated spheres.
+const RINGS: u32 = 24;
+
+const FLOAT: usize = 5126;
+const UNSIGNED_INT: usize = 5125;
+const ARRAY_BUFFER: usize = 34962;
+const ELEMENT_ARRAY_BUFFER: usize = 34963;
+
+pub fn export(scene_file: &SceneFile, path: &Path) -> Result<()> {
+    let (glb, warnings) = to_glb(scene_file)?;
+    std::fs::write(path, glb)
+        .with_context(|| format!("Failed to write glTF `{}`", path.display()))?;
+
+    for warning in &warnings {
+        eprintln!("Warning: {}: {warning}", path.display());
+    }
+    Ok(())
+}
+
+/// Compact JSON, enough for the glTF document.
+enum Json {
+    Num(f32),
+    Int(usize),
+    Bool(bool),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Num(n) => write!(f, "{n}"),
+            Json::Int(n) => write!(f, "{n}"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Str(s) => {
+                f.write_str("\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Json::Arr(items) => {
+                f.write_str("[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Json::Obj(fields) => {
+                f.write_str("{")?;
+                for (idx, (name, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "\"{name}\":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl From<Vec3> for Json {
+    fn from(v: Vec3) -> Self {
+        Json::Arr(vec![Json::Num(v.x), Json::Num(v.y), Json::Num(v.z)])
+    }
+}
+
+fn rgba(color: Vec3) -> Json {
+    Json::Arr(vec![
+        Json::Num(color.x),
+        Json::Num(color.y),
+        Json::Num(color.z),
+        Json::Num(1.0),
+    ])
+}
+
+/// Triangles of one object, counter-clockwise seen from the front.
+#[derive(Default)]
+struct Triangles {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    indices: Vec<u32>,
+}
+
+impl Triangles {
+    fn vertex(&mut self, position: Vec3, normal: Vec3) -> u32 {
+        self.positions.push(position);
+        self.normals.push(normal);
+        self.positions.len() as u32 - 1
+    }
+
+    /// A flat disk facing `normal`.
+    fn disk(&mut self, center: Vec3, normal: Vec3, radius: f32) {
+        let (u, v) = basis(normal);
+        let middle = self.vertex(center, normal);
+        for segment in 0..SEGMENTS {
+            let phi = 2.0 * PI * segment as f32 / SEGMENTS as f32;
+            self.vertex(center + radius * (phi.cos() * u + phi.sin() * v), normal);
+            let next = (segment + 1) % SEGMENTS;
+            self.indices
+                .extend([middle, middle + 1 + segment, middle + 1 + next]);
+        }
+    }
+
+    fn rotate(&mut self, rotation: Quat, pivot: Vec3) {
+        for position in &mut self.positions {
+            *position = pivot + rotation * (*position - pivot);
+        }
+        for normal in &mut self.normals {
+            *normal = rotation * *normal;
+        }
+    }
+}
+
+/// Two unit vectors perpendicular to `normal` and each other, with `u × v = normal`.
+fn basis(normal: Vec3) -> (Vec3, Vec3) {
+    let normal = normal.normalize();
+    let helper = if normal.x.abs() > 0.9 {
+        Vec3::y()
+    } else {
+        Vec3::x()
+    };
+    let u = helper.cross(&normal).normalize();
+    (u, normal.cross(&u))
+}
+
+fn sphere(object: &Value) -> Result<Triangles> {
+    let center = object.required("center")?.as_vec3()?;
+    let radius = object.required("radius")?.as_f32()?;
+    let mut triangles = Triangles::default();
+
+    for ring in 0..=RINGS {
+        let theta = PI * ring as f32 / RINGS as f32;
+        for segment in 0..=SEGMENTS {
+            let phi = 2.0 * PI * segment as f32 / SEGMENTS as f32;
+            let direction = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                -theta.sin() * phi.sin(),
+            );
+            // Negative radii make hollow spheres. Their vertices are mirrored through the
+            // center, which turns the triangles and these normals inwards.
+            triangles.vertex(center + radius * direction, direction);
+        }
+    }
+    let row = SEGMENTS + 1;
+    for ring in 0..RINGS {
+        for segment in 0..SEGMENTS {
+            let (a, b) = (ring * row + segment, (ring + 1) * row + segment);
+            triangles.indices.extend([a, b, a + 1, a + 1, b, b + 1]);
+        }
+    }
+    Ok(triangles)
+}
+
+fn cone(object: &Value) -> Result<Triangles> {
+    let apex = object.required("apex")?.as_vec3()?;
+    let axis = object.required("axis")?.as_vec3()?.normalize();
+    let angle = object.required("angle")?.as_f32()?;
+    let height = object.required("height")?.as_f32()?;
+    let radius = height * angle.tan();
+    let (u, v) = basis(axis);
+    let side = |phi: f32| phi.cos() * u + phi.sin() * v;
+    let normal = |phi: f32| angle.cos() * side(phi) - angle.sin() * axis;
+    let mut triangles = Triangles::default();
+
+    // An apex vertex per segment, so each keeps the normal of its side.
+    for segment in 0..SEGMENTS {
+        let start = 2.0 * PI * segment as f32 / SEGMENTS as f32;
+        let end = 2.0 * PI * (segment + 1) as f32 / SEGMENTS as f32;
+        let tip = triangles.vertex(apex, normal((start + end) / 2.0));
+        let a = triangles.vertex(apex + height * axis + radius * side(start), normal(start));
+        let b = triangles.vertex(apex + height * axis + radius * side(end), normal(end));
+        triangles.indices.extend([tip, b, a]);
+    }
+    triangles.disk(apex + height * axis, axis, radius);
+    Ok(triangles)
+}
+
+/// Disks are hit from both sides, so they get a back face too.
+fn two_sided_disk(object: &Value) -> Result<Triangles> {
+    let center = object.required("center")?.as_vec3()?;
+    let normal = object.required("normal")?.as_vec3()?.normalize();
+    let radius = object.required("radius")?.as_f32()?;
+
+    let mut triangles = Triangles::default();
+    triangles.disk(center, normal, radius);
+    let mut back = Triangles::default();
+    back.disk(center, -normal, radius);
+
+    let offset = triangles.positions.len() as u32;
+    triangles.positions.extend(back.positions);
+    triangles.normals.extend(back.normals);
+    triangles
+        .indices
+        .extend(back.indices.into_iter().map(|i| offset + i));
+    Ok(triangles)
+}
+
+fn mesh(object: &Value) -> Result<Triangles> {
+    let vec3s = |name| -> Result<Vec<Vec3>> {
+        object
+            .required(name)?
+            .as_list()?
+            .iter()
+            .map(Value::as_vec3)
+            .collect()
+    };
+    let positions = vec3s("positions")?;
+    let indices = object
+        .required("indices")?
+        .as_list()?
+        .iter()
+        .map(Value::as_u32)
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(index) = indices
+        .iter()
+        .find(|&&index| index as usize >= positions.len())
+    {
+        return Err(anyhow!("Mesh index {index} out of range"));
+    }
+
+    if object.field("normals").is_some() {
+        return Ok(Triangles {
+            positions,
+            normals: vec3s("normals")?,
+            indices,
+        });
+    }
+
+    // Without vertex normals, triangles are flat and don't share vertices.
+    let mut triangles = Triangles::default();
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| positions[triangle[i] as usize]);
+        let normal = (b - a)
+            .cross(&(c - a))
+            .try_normalize(0.0)
+            .unwrap_or(Vec3::y());
+        for p in [a, b, c] {
+            let vertex = triangles.vertex(p, normal);
+            triangles.indices.push(vertex);
+        }
+    }
+    Ok(triangles)
+}
+
+fn rotation(object: &Value) -> Result<Option<(Quat, Vec3)>> {
+    match object.field("rotation") {
+        Some(Value::Tuple(Some(name), items)) if name == "Quat" && items.len() == 4 => {
+            let rotation = Quat::from_quaternion(na::Quaternion::new(
+                items[3].as_f32()?,
+                items[0].as_f32()?,
+                items[1].as_f32()?,
+                items[2].as_f32()?,
+            ));
+            Ok(Some((rotation, object.required("pivot")?.as_vec3()?)))
+        }
+        Some(other) => Err(anyhow!("Unexpected rotation {other:?}")),
+        None => Ok(None),
+    }
+}
+
+/// Rotation turning a node's -z axis, along which glTF lights shine, to `direction`.
+fn facing(direction: Vec3) -> Quat {
+    Quat::rotation_between(&-Vec3::z(), &direction)
+        .unwrap_or_else(|| Quat::from_axis_angle(&Vec3::x_axis(), PI))
+}
+
+fn quat(rotation: Quat) -> Json {
+    let q = rotation.into_inner().coords;
+    Json::Arr(vec![
+        Json::Num(q.x),
+        Json::Num(q.y),
+        Json::Num(q.z),
+        Json::Num(q.w),
+    ])
+}
+
+/// The glTF document being assembled, with its binary buffer.
+#[derive(Default)]
+struct Document {
+    bin: Vec<u8>,
+    buffer_views: Vec<Json>,
+    accessors: Vec<Json>,
+    meshes: Vec<Json>,
+    materials: Vec<Json>,
+    nodes: Vec<Json>,
+    lights: Vec<Json>,
+    cameras: Vec<Json>,
+    extensions: BTreeSet<&'static str>,
+}
+
+impl Document {
+    /// Appends 4-byte components to the buffer as a view with one accessor on it.
+    fn accessor(
+        &mut self,
+        bytes: Vec<u8>,
+        target: usize,
+        mut fields: Vec<(&'static str, Json)>,
+    ) -> usize {
+        self.buffer_views.push(Json::Obj(vec![
+            ("buffer", Json::Int(0)),
+            ("byteOffset", Json::Int(self.bin.len())),
+            ("byteLength", Json::Int(bytes.len())),
+            ("target", Json::Int(target)),
+        ]));
+        self.bin.extend(bytes);
+
+        fields.insert(0, ("bufferView", Json::Int(self.buffer_views.len() - 1)));
+        self.accessors.push(Json::Obj(fields));
+        self.accessors.len() - 1
+    }
+
+    fn vec3s(&mut self, values: &[Vec3]) -> usize {
+        let (min, max) = values.iter().fold(
+            (Vec3::repeat(f32::INFINITY), Vec3::repeat(f32::NEG_INFINITY)),
+            |(min, max), v| (min.inf(v), max.sup(v)),
+        );
+        let bytes = values
+            .iter()
+            .flat_map(|v| v.iter().flat_map(|c| c.to_le_bytes()).collect::<Vec<_>>())
+            .collect();
+        self.accessor(
+            bytes,
+            ARRAY_BUFFER,
+            vec![
+                ("componentType", Json::Int(FLOAT)),
+                ("count", Json::Int(values.len())),
+                ("type", Json::Str("VEC3".to_owned())),
+                ("min", min.into()),
+                ("max", max.into()),
+            ],
+        )
+    }
+
+    fn indices(&mut self, indices: &[u32]) -> usize {
+        let bytes = indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+        self.accessor(
+            bytes,
+            ELEMENT_ARRAY_BUFFER,
+            vec![
+                ("componentType", Json::Int(UNSIGNED_INT)),
+                ("count", Json::Int(indices.len())),
+                ("type", Json::Str("SCALAR".to_owned())),
+            ],
+        )
+    }
+
+    fn mesh(&mut self, name: String, triangles: &Triangles, material: usize) {
+        let positions = self.vec3s(&triangles.positions);
+        let normals = self.vec3s(&triangles.normals);
+        let indices = self.indices(&triangles.indices);
+
+        self.meshes.push(Json::Obj(vec![(
+            "primitives",
+            Json::Arr(vec![Json::Obj(vec![
+                (
+                    "attributes",
+                    Json::Obj(vec![
+                        ("POSITION", Json::Int(positions)),
+                        ("NORMAL", Json::Int(normals)),
+                    ]),
+                ),
+                ("indices", Json::Int(indices)),
+                ("material", Json::Int(material)),
+            ])]),
+        )]));
+        self.nodes.push(Json::Obj(vec![
+            ("name", Json::Str(name)),
+            ("mesh", Json::Int(self.meshes.len() - 1)),
+        ]));
+    }
+
+    fn material(&mut self, name: &str, material: &Value) -> Result<()> {
+        let number = |field: &str, default: f32| -> Result<f32> {
+            material.field(field).map_or(Ok(default), Value::as_f32)
+        };
+        let albedo = material
+            .field("albedo")
+            .map_or(Ok(Vec3::new(0.5, 0.5, 0.5)), Value::as_vec3)?;
+        let (base_color, metallic, roughness) = match material.name() {
+            Some("Metal") => (albedo, 1.0, number("fuzz", 0.0)?.clamp(0.0, 1.0)),
+            Some("Dielectric") => (Vec3::new(1.0, 1.0, 1.0), 0.0, 0.0),
+            Some("Pbr") => (albedo, number("metallic", 0.0)?, number("roughness", 0.5)?),
+            _ => (albedo, 0.0, 1.0),
+        };
+
+        let mut extensions = vec![];
+        if material.name() == Some("Dielectric") {
+            extensions.push((
+                "KHR_materials_transmission",
+                Json::Obj(vec![("transmissionFactor", Json::Num(1.0))]),
+            ));
+        }
+        if material.field("refract_idx").is_some() {
+            extensions.push((
+                "KHR_materials_ior",
+                Json::Obj(vec![("ior", Json::Num(number("refract_idx", 1.5)?))]),
+            ));
+        }
+        if material.field("clearcoat").is_some() {
+            extensions.push((
+                "KHR_materials_clearcoat",
+                Json::Obj(vec![
+                    ("clearcoatFactor", Json::Num(number("clearcoat", 0.0)?)),
+                    (
+                        "clearcoatRoughnessFactor",
+                        Json::Num(number("clearcoat_roughness", 0.0)?),
+                    ),
+                ]),
+            ));
+        }
+        self.extensions
+            .extend(extensions.iter().map(|&(extension, _)| extension));
+
+        let mut fields = vec![
+            ("name", Json::Str(name.to_owned())),
+            (
+                "pbrMetallicRoughness",
+                Json::Obj(vec![
+                    ("baseColorFactor", rgba(base_color)),
+                    ("metallicFactor", Json::Num(metallic)),
+                    ("roughnessFactor", Json::Num(roughness)),
+                ]),
+            ),
+            ("doubleSided", Json::Bool(true)),
+        ];
+        if !extensions.is_empty() {
+            fields.push(("extensions", Json::Obj(extensions)));
+        }
+        self.materials.push(Json::Obj(fields));
+        Ok(())
+    }
+
+    /// A quad light as an emissive quad facing `edge_u × edge_v`.
+    fn quad_light(
+        &mut self,
+        name: String,
+        light: &Value,
+        color: Vec3,
+        intensity: f32,
+    ) -> Result<()> {
+        let corner = light.required("corner")?.as_vec3()?;
+        let edge_u = light.required("edge_u")?.as_vec3()?;
+        let edge_v = light.required("edge_v")?.as_vec3()?;
+        let normal = edge_u.cross(&edge_v).normalize();
+
+        self.extensions.insert("KHR_materials_emissive_strength");
+        self.materials.push(Json::Obj(vec![
+            ("name", Json::Str(name.clone())),
+            (
+                "pbrMetallicRoughness",
+                Json::Obj(vec![("baseColorFactor", rgba(Vec3::zeros()))]),
+            ),
+            ("emissiveFactor", color.into()),
+            (
+                "extensions",
+                Json::Obj(vec![(
+                    "KHR_materials_emissive_strength",
+                    Json::Obj(vec![("emissiveStrength", Json::Num(intensity))]),
+                )]),
+            ),
+        ]));
+
+        let mut triangles = Triangles::default();
+        for p in [
+            corner,
+            corner + edge_u,
+            corner + edge_u + edge_v,
+            corner + edge_v,
+        ] {
+            triangles.vertex(p, normal);
+        }
+        triangles.indices = vec![0, 1, 2, 0, 2, 3];
+        self.mesh(name, &triangles, self.materials.len() - 1);
+        Ok(())
+    }
+
+    /// A `KHR_lights_punctual` light shining along `direction`, from `position` unless
+    /// it is directional.
+    fn punctual_light(
+        &mut self,
+        name: String,
+        mut light: Vec<(&'static str, Json)>,
+        position: Option<Vec3>,
+        direction: Option<Vec3>,
+    ) {
+        self.extensions.insert("KHR_lights_punctual");
+        light.insert(0, ("name", Json::Str(name.clone())));
+        self.lights.push(Json::Obj(light));
+
+        let mut node = vec![("name", Json::Str(name))];
+        if let Some(direction) = direction {
+            node.push(("rotation", quat(facing(direction))));
+        }
+        if let Some(position) = position {
+            node.push(("translation", position.into()));
+        }
+        node.push((
+            "extensions",
+            Json::Obj(vec![(
+                "KHR_lights_punctual",
+                Json::Obj(vec![("light", Json::Int(self.lights.len() - 1))]),
+            )]),
+        ));
+        self.nodes.push(Json::Obj(node));
+    }
+
+    /// The JSON document and the binary buffer it refers to.
+    fn finish(self) -> (Json, Vec<u8>) {
+        let mut fields = vec![(
+            "asset",
+            Json::Obj(vec![
+                ("version", Json::Str("2.0".to_owned())),
+                ("generator", Json::Str(env!("CARGO_PKG_NAME").to_owned())),
+            ]),
+        )];
+        if !self.extensions.is_empty() {
+            fields.push((
+                "extensionsUsed",
+                Json::Arr(
+                    self.extensions
+                        .iter()
+                        .map(|&extension| Json::Str(extension.to_owned()))
+                        .collect(),
+                ),
+            ));
+        }
+        if !self.lights.is_empty() {
+            fields.push((
+                "extensions",
+                Json::Obj(vec![(
+                    "KHR_lights_punctual",
+                    Json::Obj(vec![("lights", Json::Arr(self.lights))]),
+                )]),
+            ));
+        }
+        fields.extend([
+            ("scene", Json::Int(0)),
+            (
+                "scenes",
+                Json::Arr(vec![Json::Obj(vec![(
+                    "nodes",
+                    Json::Arr((0..self.nodes.len()).map(Json::Int).collect()),
+                )])]),
+            ),
+            ("nodes", Json::Arr(self.nodes)),
+            ("cameras", Json::Arr(self.cameras)),
+            ("meshes", Json::Arr(self.meshes)),
+            ("materials", Json::Arr(self.materials)),
+            ("accessors", Json::Arr(self.accessors)),
+            ("bufferViews", Json::Arr(self.buffer_views)),
+            (
+                "buffers",
+                Json::Arr(vec![Json::Obj(vec![(
+                    "byteLength",
+                    Json::Int(self.bin.len()),
+                )])]),
+            ),
+        ]);
+        (Json::Obj(fields), self.bin)
+    }
+}
+
+fn to_glb(scene_file: &SceneFile) -> Result<(Vec<u8>, BTreeSet<String>)> {
+    let ron = scene_file.to_ron();
+    let mut document = Document::default();
+    let mut warnings = BTreeSet::new();
+
+    let mut material_names = vec![];
+    for (name, material) in ron.required("materials")?.as_map()? {
+        let name = name.as_str()?;
+        document.material(name, material)?;
+        material_names.push(name);
+    }
+
+    for (idx, object) in ron.required("objects")?.as_list()?.iter().enumerate() {
+        let kind = object.name().unwrap_or_default();
+        let mut triangles = match kind {
+            "Sphere" => sphere(object)?,
+            "Cone" => cone(object)?,
+            "Disk" => two_sided_disk(object)?,
+            "Mesh" => mesh(object)?,
+            _ => {
+                warnings.insert(format!("{kind} objects are not exported"));
+                continue;
+            }
+        };
+        if let Some((rotation, pivot)) = rotation(object)? {
+            triangles.rotate(rotation, pivot);
+        }
+        let material_name = object.required("material")?.as_str()?;
+        let material = material_names
+            .iter()
+            .position(|&name| name == material_name)
+            .ok_or_else(|| anyhow!("Unknown material `{material_name}`"))?;
+        document.mesh(format!("{kind} {idx}"), &triangles, material);
+    }
+
+    let lights = ron.field("lights").map_or(Ok(&[][..]), Value::as_list)?;
+    for (idx, light) in lights.iter().enumerate() {
+        let kind = light.name().unwrap_or_default();
+        let name = format!("{kind} {idx}");
+        let color = light.required("color")?.as_vec3()?;
+        let intensity = light.required("intensity")?.as_f32()?;
+        let punctual = |kind: &str| {
+            vec![
+                ("type", Json::Str(kind.to_owned())),
+                ("color", color.into()),
+                ("intensity", Json::Num(intensity)),
+            ]
+        };
+
+        match kind {
+            "QuadLight" => document.quad_light(name, light, color, intensity)?,
+            "PointLight" => {
+                let position = light.required("position")?.as_vec3()?;
+                document.punctual_light(name, punctual("point"), Some(position), None);
+            }
+            "SpotLight" => {
+                let mut spot = punctual("spot");
+                spot.push((
+                    "spot",
+                    Json::Obj(vec![
+                        ("innerConeAngle", Json::Num(0.0)),
+                        (
+                            "outerConeAngle",
+                            Json::Num(light.required("angle")?.as_f32()?.to_radians()),
+                        ),
+                    ]),
+                ));
+                let position = light.required("position")?.as_vec3()?;
+                let direction = light.required("direction")?.as_vec3()?;
+                document.punctual_light(name, spot, Some(position), Some(direction));
+            }
+            // Directional lights store the direction towards the light.
+            _ => {
+                let direction = light.required("direction")?.as_vec3()?;
+                document.punctual_light(name, punctual("directional"), None, Some(-direction));
+            }
+        }
+    }
+
+    if let Some(sun) = ron.field("sun") {
+        let light = vec![
+            ("type", Json::Str("directional".to_owned())),
+            ("color", sun.required("color")?.as_vec3()?.into()),
+            ("intensity", Json::Num(sun.required("intensity")?.as_f32()?)),
+        ];
+        let direction = sun.required("direction")?.as_vec3()?;
+        document.punctual_light("Sun".to_owned(), light, None, Some(-direction));
+    }
+    let sky_intensity = ron
+        .field("sky")
+        .and_then(|sky| sky.field("intensity"))
+        .map_or(Ok(1.0), Value::as_f32)?;
+    if sky_intensity > 0.0 {
+        warnings.insert("The sky is not exported".to_owned());
+    }
+    if ron.field("fog").is_some() {
+        warnings.insert("Fog is not exported".to_owned());
+    }
+
+    camera(scene_file, &mut document, &mut warnings);
+
+    let (json, mut bin) = document.finish();
+    let mut json = json.to_string().into_bytes();
+    json.resize(json.len().next_multiple_of(4), b' ');
+    bin.resize(bin.len().next_multiple_of(4), 0);
+
+    let mut glb = vec![];
+    glb.extend(b"glTF");
+    glb.extend(2u32.to_le_bytes());
+    glb.extend((12 + 8 + json.len() as u32 + 8 + bin.len() as u32).to_le_bytes());
+    glb.extend((json.len() as u32).to_le_bytes());
+    glb.extend(b"JSON");
+    glb.extend(json);
+    glb.extend((bin.len() as u32).to_le_bytes());
+    glb.extend(b"BIN\0");
+    glb.extend(bin);
+    Ok((glb, warnings))
+}
+
+/// A camera node looking along its -z axis with y up, like the scene's camera.
+fn camera(scene_file: &SceneFile, document: &mut Document, warnings: &mut BTreeSet<String>) {
+    let camera = &scene_file.camera;
+    let (kind, projection) = match camera.projection {
+        Projection::Orthographic { height } => (
+            "orthographic",
+            Json::Obj(vec![
+                ("xmag", Json::Num(height / 2.0)),
+                ("ymag", Json::Num(height / 2.0)),
+                ("znear", Json::Num(0.0)),
+                ("zfar", Json::Num(1e4)),
+            ]),
+        ),
+        projection => {
+            if projection != Projection::Perspective {
+                warnings.insert(
+                    "Fisheye and panorama cameras are exported as perspective ones".to_owned(),
+                );
+            }
+            (
+                "perspective",
+                Json::Obj(vec![
+                    ("yfov", Json::Num(FRAC_PI_2)),
+                    ("znear", Json::Num(1e-3)),
+                ]),
+            )
+        }
+    };
+    document.cameras.push(Json::Obj(vec![
+        ("type", Json::Str(kind.to_owned())),
+        (kind, projection),
+    ]));
+
+    let back = (camera.lookfrom - camera.lookat).normalize();
+    let right = camera.vup.cross(&back).normalize();
+    let up = back.cross(&right);
+    let rotation = Quat::from_matrix(&na::Matrix3::from_columns(&[right, up, back]));
+    document.nodes.push(Json::Obj(vec![
+        ("name", Json::Str("Camera".to_owned())),
+        ("camera", Json::Int(document.cameras.len() - 1)),
+        ("rotation", quat(rotation)),
+        ("translation", camera.lookfrom.into()),
+    ]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets::Preset;
+
+    #[test]
+    fn tessellated_spheres_face_outwards() {
+        for radius in [0.5, -0.5] {
+            let center = Vec3::new(1.0, 2.0, 3.0);
+            let object = Value::named(
+                "Sphere",
+                vec![("center", center.into()), ("radius", radius.into())],
+            );
+            let triangles = sphere(&object).unwrap();
+
+            for triangle in triangles.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| triangles.positions[triangle[i] as usize]);
+                let normal = (b - a).cross(&(c - a));
+                // Triangles at the poles are degenerate.
+                if normal.norm() > 1e-6 {
+                    assert!(normal.dot(&((a + b + c) / 3.0 - center)) * radius > 0.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exports_presets() {
+        for preset in Preset::ALL {
+            let (glb, _) = to_glb(&preset.scene_file()).unwrap();
+            assert_eq!(&glb[..4], b"glTF");
+            assert_eq!(
+                u32::from_le_bytes(glb[8..12].try_into().unwrap()) as usize,
+                glb.len()
+            );
+
+            let json_len = u32::from_le_bytes(glb[12..16].try_into().unwrap()) as usize;
+            assert_eq!(&glb[16..20], b"JSON");
+            let json = std::str::from_utf8(&glb[20..20 + json_len]).unwrap();
+            assert!(
+                json.starts_with("{\"asset\":{\"version\":\"2.0\""),
+                "{json}"
+            );
+            assert_eq!(&glb[24 + json_len..28 + json_len], b"BIN\0");
+        }
+
+        let (glb, warnings) = to_glb(&Preset::CornellBox.scene_file()).unwrap();
+        let json = String::from_utf8_lossy(&glb);
+        assert!(json.contains("KHR_materials_emissive_strength"));
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+}
@@ -1,72 +1,386 @@
 pub struct Gpu {
-    pub instance: wgpu::Instance,
+    /// `None` on the device of another application, see `from_device`.
+    pub instance: Option<wgpu::Instance>,
+    /// The surface of the main window, `None` when rendering headless.
+    pub surface: Option<WindowSurface>,
+    pub adapter: Option<wgpu::Adapter>,
+    /// Shared with the application when embedded.
+    pub device: Arc<wgpu::Device>,
+    pub queue: Arc<wgpu::Queue>,
+    /// Uniforms and other small buffers written between submissions, see `write_buffer`.
+    uploads: Mutex<Uploads>,
+}
+
+/// The surface of a window and how it's configured. Surfaces of other windows come from
+/// `Gpu::create_surface` and must be dropped before their window.
+pub struct WindowSurface {
     pub surface: wgpu::Surface,
-    pub adapter: wgpu::Adapter,
-    pub device: wgpu::Device,
-    pub queue: wgpu::Queue,
-    pub surface_config: wgpu::SurfaceConfiguration,
+    pub config: wgpu::SurfaceConfiguration,
+}
+
+impl WindowSurface {
+    /// Applies `config` again, e.g. after the surface was lost or became outdated.
+    pub fn configure(&self, device: &wgpu::Device) {
+        self.surface.configure(device, &self.config);
+    }
+
+    pub fn on_resize(&mut self, device: &wgpu::Device, (width, height): (u32, u32)) {
+        self.config.width = width;
+        self.config.height = height;
+        self.configure(device);
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.config.width, self.config.height)
+    }
 }
 
-use anyhow::Result;
+/// A staging belt reused for every dynamic upload, and the copies out of it recorded since the
+/// last submission.
+struct Uploads {
+    belt: wgpu::util::StagingBelt,
+    encoder: Option<wgpu::CommandEncoder>,
+}
+
+use crate::console::{Severity, SharedConsole};
+use anyhow::{anyhow, Result};
+use encase::internal::WriteInto;
+use encase::ShaderType;
+use std::future::Future;
+use std::num::NonZeroU64;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 use winit::window::Window;
 
 impl Gpu {
-    pub async fn from_window(window: &Window) -> Result<Self> {
-        get_gpu(window).await
+    /// Bytes of a staging belt chunk, enough for every upload of a frame.
+    const UPLOAD_CHUNK_SIZE: u64 = 64 * 1024;
+
+    /// `fallback_adapter` forces the software adapter, which is also used when no other adapter is found.
+    pub async fn from_window(
+        window: &Window,
+        fallback_adapter: bool,
+        console: SharedConsole,
+    ) -> Result<Self> {
+        let gpu = get_gpu(Some(window), fallback_adapter).await?;
+        gpu.report_errors_to(console);
+        Ok(gpu)
+    }
+
+    pub async fn headless(fallback_adapter: bool, console: SharedConsole) -> Result<Self> {
+        let gpu = get_gpu(None, fallback_adapter).await?;
+        gpu.report_errors_to(console);
+        Ok(gpu)
+    }
+
+    /// Traces on the `device` and `queue` of another application, which requested the device
+    /// with `device_descriptor`. Errors stay with the application's error handler.
+    pub fn from_device(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Result<Self> {
+        let missing = REQUIRED_FEATURES - device.features();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "The device lacks required features {missing:?}, see `gpu::device_descriptor`"
+            ));
+        }
+        let storage_buffers = device.limits().max_storage_buffers_per_shader_stage;
+        if storage_buffers < STORAGE_BUFFERS {
+            return Err(anyhow!(
+                "The device binds {storage_buffers} storage buffers per shader stage, {STORAGE_BUFFERS} are needed"
+            ));
+        }
+
+        Ok(Gpu {
+            instance: None,
+            surface: None,
+            adapter: None,
+            device,
+            queue,
+            uploads: Mutex::new(Uploads {
+                belt: wgpu::util::StagingBelt::new(Gpu::UPLOAD_CHUNK_SIZE),
+                encoder: None,
+            }),
+        })
+    }
+
+    fn report_errors_to(&self, console: SharedConsole) {
+        self.device.on_uncaptured_error(Box::new(move |error| {
+            eprintln!("wgpu error: {error}");
+            console
+                .lock()
+                .unwrap()
+                .push(Severity::Error, format!("wgpu error: {error}"));
+        }));
+    }
+
+    /// Captures validation and out of memory errors of the calls that follow until
+    /// `pop_error_scope`, instead of reporting them to the uncaptured error handler.
+    pub fn push_error_scope(&self) {
+        self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+    }
+
+    /// Fails with `what` as context if any call since `push_error_scope` failed.
+    pub fn pop_error_scope(&self, what: &str) -> Result<()> {
+        let validation = self.wait(self.device.pop_error_scope());
+        let out_of_memory = self.wait(self.device.pop_error_scope());
+
+        match validation.or(out_of_memory) {
+            Some(error) => Err(anyhow!("{error}").context(format!("Failed to create {what}"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Native backends resolve error scopes on the spot, poll the device in case they don't.
+    fn wait<T>(&self, future: impl Future<Output = T>) -> T {
+        let mut future = std::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(value) => return value,
+                Poll::Pending => {
+                    self.device.poll(wgpu::Maintain::Wait);
+                }
+            }
+        }
     }
 
     pub fn on_resize(&mut self, new_size: (u32, u32)) {
-        self.surface_config.width = new_size.0;
-        self.surface_config.height = new_size.1;
-        self.surface.configure(&self.device, &self.surface_config);
+        if let Some(surface) = &mut self.surface {
+            surface.on_resize(&self.device, new_size);
+        }
+    }
+
+    /// A surface for another `window` of the same device, see `WindowSurface`.
+    pub fn create_surface(&self, window: &Window) -> Result<WindowSurface> {
+        let (Some(instance), Some(adapter)) = (&self.instance, &self.adapter) else {
+            return Err(anyhow!(
+                "Cannot create surfaces on another application's device"
+            ));
+        };
+        let surface = unsafe { instance.create_surface(window)? };
+        let size = window.inner_size();
+        let surface = WindowSurface {
+            config: surface_config(&surface, adapter, (size.width, size.height)),
+            surface,
+        };
+        surface.configure(&self.device);
+        Ok(surface)
+    }
+
+    /// Writes `value` to the start of `buffer` with the uniform layout, see `write_buffer`.
+    pub fn write_uniform<T: ShaderType + WriteInto>(
+        &self,
+        buffer: &wgpu::Buffer,
+        value: &T,
+    ) -> Result<()> {
+        self.stage(buffer, 0, value.size(), |bytes| {
+            Ok(encase::UniformBuffer::new(bytes).write(value)?)
+        })
+    }
+
+    /// Writes `value` to the start of `buffer` with the storage layout, see `write_buffer`.
+    pub fn write_storage<T: ShaderType + WriteInto>(
+        &self,
+        buffer: &wgpu::Buffer,
+        value: &T,
+    ) -> Result<()> {
+        self.stage(buffer, 0, value.size(), |bytes| {
+            Ok(encase::StorageBuffer::new(bytes).write(value)?)
+        })
+    }
+
+    /// Copies `data` into `buffer` at `offset` through the shared staging belt, once the commands
+    /// of the next `submit` run. Unlike `queue.write_buffer`, no staging memory is allocated per
+    /// write. `offset` and the length of `data` have to be multiples of 4.
+    pub fn write_buffer(&self, buffer: &wgpu::Buffer, offset: u64, data: &[u8]) {
+        if let Some(size) = NonZeroU64::new(data.len() as u64) {
+            self.stage(buffer, offset, size, |bytes| {
+                bytes.copy_from_slice(data);
+                Ok(())
+            })
+            .unwrap();
+        }
+    }
+
+    fn stage(
+        &self,
+        buffer: &wgpu::Buffer,
+        offset: u64,
+        size: NonZeroU64,
+        write: impl FnOnce(&mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let Uploads { belt, encoder } = &mut *uploads;
+        let encoder = encoder.get_or_insert_with(|| {
+            self.device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("uploads"),
+                })
+        });
+        let mut view = belt.write_buffer(encoder, buffer, offset, size, &self.device);
+        write(&mut view)
+    }
+
+    /// Submits `command_buffers` after the uploads written since the last submission. The belt's
+    /// chunks are reused once `device.poll` sees the GPU is done with them.
+    pub fn submit(&self, command_buffers: impl IntoIterator<Item = wgpu::CommandBuffer>) {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.belt.finish();
+        let staged = uploads.encoder.take().map(|encoder| encoder.finish());
+        self.queue.submit(staged.into_iter().chain(command_buffers));
+        uploads.belt.recall();
     }
 }
 
-async fn get_gpu(window: &Window) -> Result<Gpu> {
+/// Read-write `Rgba32Float` storage textures for accumulating samples, and their moments, in
+/// place across passes. There is no fallback accumulating into buffers: the display, the
+/// accumulation, the denoiser and the tile selection all read these textures.
+const REQUIRED_FEATURES: wgpu::Features = wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES;
+
+/// Features with a fallback when missing, see `Renderer::clear`.
+const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::CLEAR_TEXTURE;
+
+/// Every primitive type has its own storage buffer in the compute shader, more than the default 8,
+/// plus the mesh BVHs, the parameters of plugin materials, the queue of adaptive tiles, the work
+/// counter of persistent threads and the paths of multi-pass traces.
+const STORAGE_BUFFERS: u32 = 18;
+
+#[tracing::instrument(skip(window), err)]
+async fn get_gpu(window: Option<&Window>, fallback_adapter: bool) -> Result<Gpu> {
     let instance = wgpu::Instance::default();
 
-    let surface = unsafe { instance.create_surface(&window)? };
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions {
+    let surface = match window {
+        Some(window) => Some(unsafe { instance.create_surface(&window)? }),
+        None => None,
+    };
+
+    let request_adapter = |force_fallback_adapter| {
+        instance.request_adapter(&wgpu::RequestAdapterOptions {
             power_preference: wgpu::PowerPreference::HighPerformance,
-            compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
+            compatible_surface: surface.as_ref(),
+            force_fallback_adapter,
         })
-        .await
-        .map_or(Err(anyhow::anyhow!("No adapter found")), Ok)?;
+    };
+
+    let mut adapter = request_adapter(fallback_adapter).await;
+    if adapter.is_none() && !fallback_adapter {
+        log::warn!("No GPU adapter found, trying the software fallback adapter");
+        adapter = request_adapter(true).await;
+    }
+    let adapter = adapter.ok_or_else(|| anyhow!("No adapter found"))?;
+
+    let info = adapter.get_info();
+    tracing::info!(adapter = %info.name, backend = ?info.backend, "Selected adapter");
+    let missing = REQUIRED_FEATURES - adapter.features();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Adapter {} ({:?}) lacks required features {missing:?}, needed to accumulate the image in read-write storage textures",
+            info.name,
+            info.backend
+        ));
+    }
+
+    let storage_buffers = adapter.limits().max_storage_buffers_per_shader_stage;
+    if storage_buffers < STORAGE_BUFFERS {
+        return Err(anyhow!(
+            "Adapter {} ({:?}) supports {storage_buffers} storage buffers per shader stage, {STORAGE_BUFFERS} are needed",
+            info.name,
+            info.backend
+        ));
+    }
+
+    let missing = OPTIONAL_FEATURES - adapter.features();
+    if !missing.is_empty() {
+        log::warn!(
+            "Adapter {} lacks {missing:?}, using slower fallbacks",
+            info.name
+        );
+    }
 
     let (device, queue) = adapter
-        .request_device(
-            &wgpu::DeviceDescriptor {
-                label: None,
-                features: adapter.features(),
-                limits: wgpu::Limits::default(),
-            },
-            None,
-        )
+        .request_device(&device_descriptor(&adapter), None)
         .await?;
 
-    let swapchain_capabilities = surface.get_capabilities(&adapter);
-    let swapchain_format = wgpu::TextureFormat::Rgba8UnormSrgb;
+    let surface = surface.zip(window).map(|(surface, window)| {
+        let size = window.inner_size();
+        let surface = WindowSurface {
+            config: surface_config(&surface, &adapter, (size.width, size.height)),
+            surface,
+        };
+        surface.configure(&device);
+        surface
+    });
+
+    Ok(Gpu {
+        instance: Some(instance),
+        surface,
+        adapter: Some(adapter),
+        device: Arc::new(device),
+        queue: Arc::new(queue),
+        uploads: Mutex::new(Uploads {
+            belt: wgpu::util::StagingBelt::new(Gpu::UPLOAD_CHUNK_SIZE),
+            encoder: None,
+        }),
+    })
+}
+
+/// The features and limits the tracer needs from a device of `adapter`, for applications
+/// embedding it with `Gpu::from_device`.
+pub fn device_descriptor(adapter: &wgpu::Adapter) -> wgpu::DeviceDescriptor<'static> {
+    wgpu::DeviceDescriptor {
+        label: None,
+        features: REQUIRED_FEATURES | (OPTIONAL_FEATURES & adapter.features()),
+        // Large scenes bind as much as the adapter allows, see `scene::MESH_CHUNKS`.
+        limits: wgpu::Limits {
+            max_storage_buffers_per_shader_stage: STORAGE_BUFFERS,
+            max_storage_buffer_binding_size: adapter.limits().max_storage_buffer_binding_size,
+            max_buffer_size: adapter.limits().max_buffer_size,
+            ..Default::default()
+        },
+    }
+}
 
-    let surface_config = wgpu::SurfaceConfiguration {
+fn surface_config(
+    surface: &wgpu::Surface,
+    adapter: &wgpu::Adapter,
+    (width, height): (u32, u32),
+) -> wgpu::SurfaceConfiguration {
+    wgpu::SurfaceConfiguration {
         usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        format: swapchain_format,
-        width: window.inner_size().width,
-        height: window.inner_size().height,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        width,
+        height,
         present_mode: wgpu::PresentMode::Fifo,
-        alpha_mode: swapchain_capabilities.alpha_modes[0],
+        alpha_mode: surface.get_capabilities(adapter).alpha_modes[0],
         view_formats: vec![],
-    };
+    }
+}
 
-    surface.configure(&device, &surface_config);
+#[cfg(test)]
+mod tests {
+    use naga::valid::{Capabilities, ValidationFlags, Validator};
 
-    Ok(Gpu {
-        instance,
-        surface,
-        adapter,
-        device,
-        queue,
-        surface_config,
-    })
+    #[test]
+    fn shaders_pass_validation() {
+        let compute = crate::shader::compute(&[]).unwrap();
+        let shaders = [
+            ("compute.wgsl", compute.as_str()),
+            ("render.wgsl", include_str!("render.wgsl")),
+            ("accumulation.wgsl", include_str!("accumulation.wgsl")),
+            ("overlay.wgsl", include_str!("overlay.wgsl")),
+            ("clear.wgsl", include_str!("clear.wgsl")),
+            ("denoise.wgsl", include_str!("denoise.wgsl")),
+            ("tiles.wgsl", include_str!("tiles.wgsl")),
+        ];
+
+        for (name, source) in shaders {
+            let module = naga::front::wgsl::parse_str(source)
+                .unwrap_or_else(|err| panic!("{}", err.emit_to_string_with_path(source, name)));
+            if let Err(err) =
+                Validator::new(ValidationFlags::all(), Capabilities::all()).validate(&module)
+            {
+                panic!("{name} is invalid: {err:?}");
+            }
+        }
+    }
 }
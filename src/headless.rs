@@ -0,0 +1,262 @@
+use crate::camera::{Camera, GpuCamera};
+use crate::checkpoint::{Checkpoint, TraceSettings};
+use crate::cli::Options;
+use crate::console::Console;
+use crate::gpu::Gpu;
+use crate::raytracing::{random_seed, GpuRaytracer};
+use crate::render::Renderer;
+use crate::scene_file::SceneFile;
+use crate::share;
+use crate::stats::StatsWriter;
+use crate::variance;
+use anyhow::Result;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Passes between checks of `--noise-threshold`, each reads the image back.
+const NOISE_CHECK_PASSES: u32 = 16;
+
+/// Renders `scene_file` without a window and writes the image to `output`.
+///
+/// Ctrl+C stops after the pass in flight and still writes the partial image.
+/// With `--checkpoint` the render is also saved periodically and when it ends,
+/// and `resume` continues from such a checkpoint. With `--noise-threshold` it stops
+/// early once the image is clean enough.
+pub async fn run(
+    options: &Options,
+    output: &Path,
+    scene_file: SceneFile,
+    resume: Option<Checkpoint>,
+    stats: Option<Mutex<StatsWriter>>,
+) -> Result<()> {
+    let gpu = Gpu::headless(options.fallback_adapter, Console::shared()).await?;
+    let interrupted = interrupt_on_ctrl_c();
+    render(
+        &gpu,
+        options,
+        output,
+        scene_file,
+        resume,
+        stats.as_ref(),
+        &interrupted,
+    )
+}
+
+/// A flag set once Ctrl+C is pressed, for `render` to stop early.
+pub fn interrupt_on_ctrl_c() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Interrupted, finishing the current pass...");
+                interrupted.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+    interrupted
+}
+
+/// Renders `scene_file` on `gpu` like `run`, stopping after the pass in flight once
+/// `interrupted` is set.
+pub fn render(
+    gpu: &Gpu,
+    options: &Options,
+    output: &Path,
+    scene_file: SceneFile,
+    resume: Option<Checkpoint>,
+    stats: Option<&Mutex<StatsWriter>>,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let scene_code = share::encode(&scene_file);
+    let size = resume.as_ref().map_or(options.size, |c| {
+        (c.accumulation.width, c.accumulation.height)
+    });
+    let seed = match &resume {
+        Some(checkpoint) => checkpoint.seed,
+        None => options.seed.unwrap_or_else(random_seed),
+    };
+    let first_sample = resume.as_ref().map_or(0, |c| c.passes);
+    let settings = TraceSettings::from_options(options);
+    if let Some(checkpoint) = &resume {
+        settings.check_resumes(&checkpoint.settings)?;
+    }
+
+    let (gpu_camera, renderer, raytracer) = prepare(gpu, options, scene_file, size, seed)?;
+
+    if let Some(checkpoint) = &resume {
+        renderer.write_accumulation(gpu, &checkpoint.accumulation, &checkpoint.moments)?;
+        raytracer.skip_passes(checkpoint.passes);
+        eprintln!(
+            "Resuming at {} of {} samples",
+            checkpoint.passes,
+            gpu_camera.camera().num_samples
+        );
+    }
+
+    let save_checkpoint = |passes| -> Result<()> {
+        if let Some(path) = &options.checkpoint {
+            gpu.device.poll(wgpu::Maintain::Wait);
+            Checkpoint {
+                seed,
+                passes,
+                settings,
+                scene: scene_code.clone(),
+                accumulation: renderer.read_accumulation(gpu)?,
+                moments: renderer.read_moments(gpu)?,
+            }
+            .save(path)?;
+        }
+        Ok(())
+    };
+
+    let noise_below = |threshold: f32| -> Result<Option<f32>> {
+        gpu.device.poll(wgpu::Maintain::Wait);
+        let accumulation = renderer.read_accumulation(gpu)?;
+        let moments = renderer.read_moments(gpu)?;
+        let error = variance::mean_relative_error(&accumulation.texels, &moments);
+        Ok(error.filter(|&error| error <= threshold))
+    };
+
+    let mut last_checkpoint = Instant::now();
+    let mut pass_error = None;
+    let samples = tokio::task::block_in_place(|| {
+        let samples = first_sample..gpu_camera.camera().num_samples;
+        raytracer.perform(gpu, &gpu_camera, samples, stats, |passes| {
+            if last_checkpoint.elapsed() >= options.checkpoint_interval {
+                last_checkpoint = Instant::now();
+                if let Err(err) = save_checkpoint(passes) {
+                    pass_error = Some(err);
+                    return ControlFlow::Break(());
+                }
+            }
+
+            if let Some(threshold) = options.noise_threshold {
+                if passes % NOISE_CHECK_PASSES == 0 {
+                    match noise_below(threshold) {
+                        Ok(Some(error)) => {
+                            eprintln!("Mean relative error {error:.4} after {passes} samples");
+                            return ControlFlow::Break(());
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            pass_error = Some(err);
+                            return ControlFlow::Break(());
+                        }
+                    }
+                }
+            }
+
+            if interrupted.load(Ordering::SeqCst) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+    })?;
+
+    if let Some(err) = pass_error {
+        return Err(err);
+    }
+    save_checkpoint(samples)?;
+
+    renderer
+        .read_accumulation(gpu)?
+        .save(output, gpu_camera.camera().exposure())?;
+    eprintln!("Wrote {} ({samples} samples)", output.display());
+
+    Ok(())
+}
+
+/// The camera, renderer and tracer of a headless render of `scene_file` at `size`, set up from
+/// `options`.
+pub fn prepare(
+    gpu: &Gpu,
+    options: &Options,
+    scene_file: SceneFile,
+    size: (u32, u32),
+    seed: u64,
+) -> Result<(GpuCamera, Renderer, GpuRaytracer)> {
+    let SceneFile {
+        scene,
+        camera,
+        max_bounces,
+    } = scene_file;
+
+    let exposure = camera.exposure;
+    let lens = camera.lens;
+    let projection = camera.projection;
+    let stereo = camera.stereo;
+    let camera = Camera::new(
+        camera.lookfrom,
+        camera.lookat,
+        camera.vup,
+        camera.num_samples,
+        size,
+    );
+
+    let mut gpu_camera = GpuCamera::new(gpu, camera)?;
+    gpu_camera.set_exposure(gpu, exposure)?;
+    gpu_camera.set_lens(gpu, lens)?;
+    gpu_camera.set_projection(gpu, projection)?;
+    gpu_camera.set_stereo(gpu, stereo)?;
+    let renderer = Renderer::new(gpu, &gpu_camera)?;
+    let mut raytracer = GpuRaytracer::new(gpu, &gpu_camera, max_bounces, &renderer, scene, seed)?;
+    raytracer.set_adaptive_threshold(gpu, options.adaptive)?;
+    raytracer.set_kernel(gpu, &renderer, options.kernel);
+    raytracer.set_bounces_per_dispatch(gpu, options.bounces_per_dispatch)?;
+    raytracer.set_packed_paths(gpu, &renderer, options.packed_paths)?;
+    raytracer.set_paths_per_pixel(gpu, &renderer, options.paths_per_pixel)?;
+    raytracer.set_pixel_filter(gpu, options.pixel_filter)?;
+
+    Ok((gpu_camera, renderer, raytracer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use crate::raytracing::Kernel;
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, needs a GPU"]
+    async fn bench_render() {
+        let SceneFile {
+            scene,
+            camera,
+            max_bounces,
+        } = crate::presets::three_spheres();
+        let size = (320, 180);
+
+        let gpu = Gpu::headless(false, Console::shared()).await.unwrap();
+        let camera = Camera::new(camera.lookfrom, camera.lookat, camera.vup, 16, size);
+        let gpu_camera = GpuCamera::new(&gpu, camera).unwrap();
+        let renderer = Renderer::new(&gpu, &gpu_camera).unwrap();
+        let mut raytracer =
+            GpuRaytracer::new(&gpu, &gpu_camera, max_bounces, &renderer, scene, 0).unwrap();
+
+        for kernel in [Kernel::Megakernel, Kernel::MultiPass] {
+            raytracer.set_kernel(&gpu, &renderer, kernel);
+            tokio::task::block_in_place(|| {
+                bench(
+                    &format!("default scene, 320x180, 16 samples, {}", kernel.name()),
+                    || {
+                        raytracer
+                            .perform(
+                                &gpu,
+                                &gpu_camera,
+                                0..16,
+                                None,
+                                |_| ControlFlow::Continue(()),
+                            )
+                            .unwrap();
+                        gpu.device.poll(wgpu::Maintain::Wait);
+                    },
+                )
+            });
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::Path;
+
+/// Accumulated samples read back from the GPU (rgb = sum, a = sample count), rows top
+/// to bottom.
+pub struct Accumulation {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<[f32; 4]>,
+}
+
+impl Accumulation {
+    /// Saves as linear floats for PFM and EXR, otherwise as an 8-bit image (see
+    /// `RgbaImage::save`). Colors are scaled by the camera exposure like the on-screen view.
+    pub fn save(&self, path: &Path, exposure: f32) -> Result<()> {
+        let bytes = self
+            .encode(&extension(path), exposure)
+            .ok_or_else(|| anyhow!("Unsupported image format `{}`", path.display()))?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// The image in the format of the lowercase file `extension`, `None` for unknown ones.
+    pub fn encode(&self, extension: &str, exposure: f32) -> Option<Vec<u8>> {
+        match extension {
+            "pfm" => Some(self.to_pfm(exposure)),
+            "exr" => Some(self.to_exr(exposure)),
+            _ => RgbaImage::from_accumulation(self.width, self.height, &self.texels, exposure)
+                .encode(extension),
+        }
+    }
+
+    /// Little-endian Portable Float Map, which stores rows bottom to top.
+    fn to_pfm(&self, exposure: f32) -> Vec<u8> {
+        let mut out = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+        for row in self.texels.chunks_exact(self.width as usize).rev() {
+            for [r, g, b, count] in row {
+                let scale = exposure / count.max(1.0);
+                for channel in [r, g, b] {
+                    out.extend_from_slice(&(channel * scale).to_le_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Uncompressed OpenEXR with 32-bit float B, G and R channels, one scanline per block.
+    fn to_exr(&self, exposure: f32) -> Vec<u8> {
+        let mut out = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            for text in [name, kind] {
+                out.extend_from_slice(text.as_bytes());
+                out.push(0);
+            }
+            out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            out.extend_from_slice(value);
+        };
+
+        // Channels sorted by name, each a 32-bit float sampled at every pixel.
+        let mut channels = vec![];
+        for name in [b'B', b'G', b'R'] {
+            channels.extend_from_slice(&[name, 0]);
+            channels.extend_from_slice(&2i32.to_le_bytes());
+            channels.extend_from_slice(&[0; 4]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        let window: Vec<u8> = [0, 0, self.width as i32 - 1, self.height as i32 - 1]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        attribute("channels", "chlist", &channels);
+        attribute("compression", "compression", &[0]);
+        attribute("dataWindow", "box2i", &window);
+        attribute("displayWindow", "box2i", &window);
+        attribute("lineOrder", "lineOrder", &[0]);
+        attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+        attribute("screenWindowCenter", "v2f", &[0; 8]);
+        attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+        out.push(0);
+
+        let row_size = self.width as usize * 3 * 4;
+        let first_row = out.len() + self.height as usize * 8;
+        for y in 0..self.height as usize {
+            let offset = first_row + y * (8 + row_size);
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+        for (y, row) in self.texels.chunks_exact(self.width as usize).enumerate() {
+            out.extend_from_slice(&(y as i32).to_le_bytes());
+            out.extend_from_slice(&(row_size as i32).to_le_bytes());
+            for channel in [2, 1, 0] {
+                for texel in row {
+                    let value = texel[channel] * exposure / texel[3].max(1.0);
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Lowercase extension of `path`, empty without one.
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default()
+}
+
+/// 8-bit sRGB image, rows top to bottom.
+#[derive(Debug, Clone)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl RgbaImage {
+    /// Converts accumulated samples (rgb = sum, a = sample count) to a displayable image,
+    /// scaling colors by the camera exposure like the on-screen view does.
+    pub fn from_accumulation(
+        width: u32,
+        height: u32,
+        accumulation: &[[f32; 4]],
+        exposure: f32,
+    ) -> Self {
+        let pixels = accumulation
+            .iter()
+            .flat_map(|[r, g, b, count]| {
+                let count = count.max(1.0) / exposure;
+                [
+                    encode_srgb(r / count),
+                    encode_srgb(g / count),
+                    encode_srgb(b / count),
+                    255,
+                ]
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Saves as PNG or binary PPM, depending on the extension.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let bytes = self
+            .encode(&extension(path))
+            .ok_or_else(|| anyhow!("Unsupported image format `{}`", path.display()))?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// The image as PNG or binary PPM by lowercase file `extension`, `None` for others.
+    pub fn encode(&self, extension: &str) -> Option<Vec<u8>> {
+        match extension {
+            "png" => Some(self.to_png()),
+            "ppm" => Some(self.to_ppm()),
+            _ => None,
+        }
+    }
+
+    /// Loads a binary PPM (`P6`) or PGM (`P5`) image with 8-bit samples.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read image `{}`", path.display()))?;
+        Self::from_pnm(&bytes).with_context(|| format!("Invalid image `{}`", path.display()))
+    }
+
+    fn from_pnm(bytes: &[u8]) -> Result<Self> {
+        // Header: magic, width, height and maximum value separated by whitespace and
+        // `#` comments, then a single whitespace byte before the samples.
+        let mut fields = vec![];
+        let mut pos = 0;
+        while fields.len() < 4 {
+            match bytes.get(pos) {
+                Some(b'#') => {
+                    while bytes.get(pos).is_some_and(|&b| b != b'\n') {
+                        pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => pos += 1,
+                Some(_) => {
+                    let start = pos;
+                    while bytes.get(pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        pos += 1;
+                    }
+                    fields.push(std::str::from_utf8(&bytes[start..pos])?);
+                }
+                None => return Err(anyhow!("Truncated header")),
+            }
+        }
+        let data = &bytes[pos + 1..];
+
+        let channels = match fields[0] {
+            "P5" => 1,
+            "P6" => 3,
+            magic => return Err(anyhow!("Unsupported format `{magic}`, expected P5 or P6")),
+        };
+        let width: u32 = fields[1].parse()?;
+        let height: u32 = fields[2].parse()?;
+        let max: u32 = fields[3].parse()?;
+        if max == 0 || max > 255 {
+            return Err(anyhow!("Unsupported maximum value {max}"));
+        }
+
+        let len = (width * height) as usize * channels;
+        if data.len() < len {
+            return Err(anyhow!("Truncated image data"));
+        }
+
+        let pixels = data[..len]
+            .chunks_exact(channels)
+            .flat_map(|pixel| {
+                let scale = |c: u8| (c as u32 * 255 / max) as u8;
+                let rgb = match *pixel {
+                    [gray] => [gray; 3],
+                    [r, g, b] => [r, g, b],
+                    _ => unreachable!(),
+                };
+                [scale(rgb[0]), scale(rgb[1]), scale(rgb[2]), 255]
+            })
+            .collect();
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for pixel in self.pixels.chunks_exact(4) {
+            out.extend_from_slice(&pixel[..3]);
+        }
+        out
+    }
+
+    /// PNG with uncompressed ("stored") deflate blocks - big, but needs no compressor.
+    fn to_png(&self) -> Vec<u8> {
+        let row_len = self.width as usize * 4;
+        let mut raw = Vec::with_capacity((row_len + 1) * self.height as usize);
+        for row in self.pixels.chunks_exact(row_len) {
+            raw.push(0); // No filter.
+            raw.extend_from_slice(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(u16::MAX as usize).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            zlib.push(blocks.peek().is_none() as u8);
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = vec![];
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        header.extend_from_slice(&[8, 6, 0, 0, 0]); // 8-bit RGBA, no interlace.
+
+        let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_chunk(&mut out, b"IHDR", &header);
+        write_chunk(&mut out, b"IDAT", &zlib);
+        write_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+pub fn encode_srgb(linear: f32) -> u8 {
+    let linear = linear.clamp(0.0, 1.0);
+    let srgb = if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round() as u8
+}
+
+pub fn decode_srgb(srgb: u8) -> f32 {
+    let srgb = srgb as f32 / 255.0;
+    if srgb <= 0.04045 {
+        srgb / 12.92
+    } else {
+        ((srgb + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
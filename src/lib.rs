@@ -0,0 +1,52 @@
+//! A path tracer on wgpu compute shaders, grown from "Ray Tracing in One Weekend". The
+//! `raytracer-gpu` binary traces in a window or headless, `embed` traces into the textures of
+//! another wgpu application.
+pub mod accumulation;
+pub mod assets;
+pub mod batch;
+#[cfg(test)]
+pub mod bench;
+pub mod bvh;
+pub mod camera;
+pub mod checkpoint;
+pub mod cli;
+pub mod console;
+pub mod denoise;
+pub mod diagnostics;
+pub mod distributed;
+pub mod embed;
+pub mod ffi;
+pub mod gltf;
+pub mod gpu;
+pub mod headless;
+pub mod image;
+pub mod light_tree;
+pub mod material_plugin;
+pub mod mitsuba;
+pub mod obj;
+pub mod overlay;
+pub mod pbrt;
+pub mod ply;
+pub mod presets;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod ray;
+pub mod raytracing;
+pub mod render;
+pub mod replay;
+pub mod ron;
+pub mod scene;
+pub mod scene_file;
+pub mod serve;
+pub mod shader;
+#[cfg(test)]
+pub mod shader_layout;
+pub mod share;
+pub mod stats;
+pub mod status;
+pub mod texture_pool;
+pub mod tiles;
+pub mod types;
+pub mod usd;
+pub mod variance;
+pub mod xml;
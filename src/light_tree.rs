@@ -0,0 +1,163 @@
+//! Light tree for sampling scenes with many lights.
+//!
+//! Local lights are grouped into a binary tree of bounding boxes. Sampling walks
+//! down from the root, picking a child with probability proportional to its power
+//! over the squared distance to the shading point, so nearby bright lights get
+//! most samples regardless of how many lights there are.
+use crate::scene::Light;
+use crate::types::*;
+use encase::{ArrayLength, ShaderType};
+
+const NO_LIGHT: u32 = u32::MAX;
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+pub struct LightNode {
+    min: Vec3,
+    power: f32,
+    max: Vec3,
+    /// Index into the lights buffer for leaves, `NO_LIGHT` for inner nodes.
+    light: u32,
+    left: u32,
+    right: u32,
+}
+
+#[derive(ShaderType)]
+pub struct GpuLightTree {
+    pub length: ArrayLength,
+    #[size(runtime)]
+    pub nodes: Vec<LightNode>,
+}
+
+struct Bounded {
+    idx: u32,
+    min: Vec3,
+    max: Vec3,
+    power: f32,
+}
+
+impl Bounded {
+    fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+}
+
+/// Builds the tree over lights with bounds, leaving out directional lights. The root is
+/// the first node, an empty tree means there are no local lights.
+pub fn build(lights: &[Light]) -> Vec<LightNode> {
+    let mut items: Vec<Bounded> = lights
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, light)| {
+            light.bounds().map(|(min, max)| Bounded {
+                idx: idx as u32,
+                min,
+                max,
+                power: light.power(),
+            })
+        })
+        .collect();
+
+    let mut nodes = vec![];
+    if !items.is_empty() {
+        build_node(&mut items, &mut nodes);
+    }
+    nodes
+}
+
+fn union(items: &[Bounded]) -> (Vec3, Vec3, f32) {
+    items.iter().fold(
+        (
+            Vec3::repeat(f32::INFINITY),
+            Vec3::repeat(f32::NEG_INFINITY),
+            0.0,
+        ),
+        |(min, max, power), item| (min.inf(&item.min), max.sup(&item.max), power + item.power),
+    )
+}
+
+fn surface_area(min: Vec3, max: Vec3) -> f32 {
+    let size = max - min;
+    2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+}
+
+/// Appends the subtree over `items`, returning the index of its root.
+fn build_node(items: &mut [Bounded], nodes: &mut Vec<LightNode>) -> u32 {
+    let (min, max, power) = union(items);
+    let node_idx = nodes.len() as u32;
+    nodes.push(LightNode {
+        min,
+        power,
+        max,
+        light: NO_LIGHT,
+        left: 0,
+        right: 0,
+    });
+
+    if let [item] = items {
+        nodes[node_idx as usize].light = item.idx;
+        return node_idx;
+    }
+
+    // Split along the longest axis of the centroids where power times surface area,
+    // summed over both halves, is the lowest.
+    let (centroid_min, centroid_max) = items.iter().fold(
+        (Vec3::repeat(f32::INFINITY), Vec3::repeat(f32::NEG_INFINITY)),
+        |(min, max), item| (min.inf(&item.centroid()), max.sup(&item.centroid())),
+    );
+    let axis = (centroid_max - centroid_min).imax();
+    items.sort_by(|a, b| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+
+    let split = (1..items.len())
+        .map(|split| (split, split_cost(items, split)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(items.len() / 2, |(split, _)| split);
+
+    let (left_items, right_items) = items.split_at_mut(split);
+    let left = build_node(left_items, nodes);
+    let right = build_node(right_items, nodes);
+    nodes[node_idx as usize].left = left;
+    nodes[node_idx as usize].right = right;
+
+    node_idx
+}
+
+fn split_cost(items: &[Bounded], split: usize) -> f32 {
+    let (left, right) = items.split_at(split);
+    [left, right]
+        .iter()
+        .map(|half| {
+            let (min, max, power) = union(half);
+            // Degenerate boxes, e.g. a single point light, still count their power.
+            power * surface_area(min, max).max(1e-6)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_build() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let lights: Vec<Light> = (0..10_000)
+            .map(|_| {
+                let position = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 100.0;
+                Light::point(
+                    position,
+                    0.1,
+                    Vec3::new(1.0, 1.0, 1.0),
+                    rng.gen_range(1.0..10.0),
+                )
+            })
+            .collect();
+
+        bench("light tree, 10k point lights", || {
+            build(&lights);
+        });
+    }
+}
@@ -1,52 +1,229 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use raytracing::GpuRaytracer;
+use raytracing::{CancellationToken, GpuRaytracer, Kernel};
 use tokio::task::JoinHandle;
 use winit::keyboard::KeyCode;
 use winit::window::Window;
 use winit::{dpi::PhysicalSize, event_loop::EventLoop};
 
-mod camera;
-mod gpu;
-mod ray;
-mod raytracing;
-mod render;
-mod scene;
-mod types;
+use raytracer_gpu::{
+    accumulation, assets, batch, camera, checkpoint, cli, console, denoise, diagnostics,
+    distributed, gltf, gpu, headless, image, overlay, presets, raytracing, render, replay, scene,
+    scene_file, serve, share, stats, status,
+};
 
-use camera::{Camera, CameraChange, GpuCamera};
-use render::Renderer;
-use scene::{Material, Scene, Sphere};
-use types::*;
+use accumulation::{Accumulator, ResetPolicy};
+use assets::AssetResolver;
+use camera::{Camera, CameraChange, Exposure, GpuCamera};
+use checkpoint::Checkpoint;
+use cli::Options;
+use console::{Console, ConsoleLogger, SharedConsole};
+use denoise::Filter;
+use overlay::Overlay;
+use presets::Preset;
+use render::{Display, Renderer, View};
+use replay::{Command, Recorder, Replay, ReplayHeader};
+use scene::Scene;
+use scene_file::{CameraSettings, SceneFile};
+use stats::StatsWriter;
+use status::RenderStatus;
 
-fn create_window() -> Result<(Window, EventLoop<()>)> {
+const TITLE: &str = "Raytracer";
+
+fn create_window(size: Option<(u32, u32)>) -> Result<(Window, EventLoop<()>)> {
     use winit::window::WindowBuilder;
     let event_loop = EventLoop::new()?;
 
-    let window = WindowBuilder::new()
-        .with_title("Raytracer")
-        .with_inner_size(winit::dpi::LogicalSize::new(1200, 675))
-        .build(&event_loop)?;
+    let builder = WindowBuilder::new().with_title(TITLE);
+    let builder = match size {
+        Some((width, height)) => builder.with_inner_size(PhysicalSize::new(width, height)),
+        None => builder.with_inner_size(winit::dpi::LogicalSize::new(1200, 675)),
+    };
+    let window = builder.build(&event_loop)?;
 
     Ok((window, event_loop))
 }
 
+/// Size of the traced image, following the window or fixed.
+#[derive(Clone, Copy)]
+enum RenderSize {
+    /// The window size times `scale`, above 1 to supersample. With an `aspect` ratio, the
+    /// largest part of the window of that shape, letterboxed by `render::letterbox`.
+    Scaled { scale: f32, aspect: Option<f32> },
+    /// Letterboxed into the window.
+    Fixed(u32, u32),
+}
+
+impl RenderSize {
+    /// Size of the image traced for a window of `size`, within the texture size limit of
+    /// `gpu`.
+    fn for_window(self, gpu: &Gpu, size: PhysicalSize<u32>) -> (u32, u32) {
+        let max_size = gpu.device.limits().max_texture_dimension_2d;
+        match self {
+            RenderSize::Scaled { scale, aspect } => {
+                let (mut width, mut height) = (size.width as f32, size.height as f32);
+                match aspect {
+                    Some(aspect) if width > height * aspect => width = height * aspect,
+                    Some(aspect) => height = width / aspect,
+                    None => {}
+                }
+                let scale = |pixels: f32| ((pixels * scale).round() as u32).clamp(1, max_size);
+                (scale(width), scale(height))
+            }
+            RenderSize::Fixed(width, height) => {
+                (width.clamp(1, max_size), height.clamp(1, max_size))
+            }
+        }
+    }
+}
+
 use gpu::Gpu;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, RwLock};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 struct App {
     renderer: RwLock<Renderer>,
     raytracer: RwLock<GpuRaytracer>,
+    accumulator: RwLock<Accumulator>,
+    reset_policy: RwLock<ResetPolicy>,
     gpu: RwLock<Gpu>,
     gpu_camera: RwLock<GpuCamera>,
     window: Window,
     tracer_tx: Sender<TracerMsg>,
+    console: SharedConsole,
+    stats: Option<Mutex<StatsWriter>>,
+    shutting_down: AtomicBool,
+    /// Cancelled while a camera move or scene switch waits for the tracer, which stops its
+    /// pass loop early.
+    interrupt_tracer: CancellationToken,
+    /// The scene being traced, kept for sharing.
+    scene: RwLock<Scene>,
+    max_bounces: RwLock<usize>,
+    /// Seed of the pass sequence, reused when switching scenes so replays stay deterministic.
+    seed: u64,
+    recorder: Option<Recorder>,
+    /// Opened on the first copy and kept, on X11 the copied image is only available while
+    /// the clipboard is alive.
+    clipboard: Mutex<Option<arboard::Clipboard>>,
+    /// Progress of the latest render, shown in the window title.
+    status: Mutex<Option<RenderStatus>>,
+    /// Passes of the latest render submitted so far.
+    traced_passes: AtomicU32,
+    /// Keep tracing passes into the image after the sample count is reached.
+    progressive: AtomicBool,
+    render_size: RenderSize,
+    /// The second window opened with `O`, if any.
+    detached: Mutex<Option<DetachedWindow>>,
+    /// Position of the cursor over the image in the main window, in texture coordinates.
+    cursor: Mutex<(f32, f32)>,
+}
+
+/// What the detached window shows, `O` steps through them and then closes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DetachedView {
+    /// The image around the cursor, magnified `DetachedWindow::ZOOM` times.
+    Zoom,
+    Normals,
+    Variance,
+}
+
+/// A window next to the main one showing another view of the same image.
+struct DetachedWindow {
+    /// Declared before the window so it's dropped first.
+    surface: gpu::WindowSurface,
+    display: Display,
+    view: DetachedView,
+    window: Window,
+}
+
+impl DetachedWindow {
+    /// Window pixels per image pixel of the zoom view.
+    const ZOOM: f32 = 4.0;
+
+    fn open(
+        target: &winit::event_loop::EventLoopWindowTarget<()>,
+        gpu: &Gpu,
+        renderer: &Renderer,
+    ) -> Result<Self> {
+        let window = winit::window::WindowBuilder::new()
+            .with_inner_size(winit::dpi::LogicalSize::new(480, 270))
+            .build(target)?;
+        Ok(DetachedWindow {
+            surface: gpu.create_surface(&window)?,
+            display: renderer.create_display(gpu, View::Image)?,
+            view: DetachedView::Zoom,
+            window,
+        })
+    }
+
+    fn set_view(&mut self, gpu: &Gpu, view: DetachedView) -> Result<()> {
+        self.view = view;
+        let (title, view) = match view {
+            DetachedView::Zoom => ("zoom", View::Image),
+            DetachedView::Normals => ("normals", View::Normals),
+            DetachedView::Variance => ("variance", View::Variance),
+        };
+        self.window.set_title(&format!("{TITLE} ({title})"));
+        self.display.view = view;
+        if self.view != DetachedView::Zoom {
+            self.display.show_all(gpu)?;
+        }
+        Ok(())
+    }
+
+    /// Draws the window's view, the zoom following the `cursor`.
+    fn render(
+        &mut self,
+        gpu: &Gpu,
+        renderer: &Renderer,
+        gpu_camera: &GpuCamera,
+        cursor: (f32, f32),
+    ) -> Result<()> {
+        if self.view == DetachedView::Zoom {
+            let camera = gpu_camera.camera();
+            self.display.zoom(
+                gpu,
+                cursor,
+                Self::ZOOM,
+                (camera.width, camera.height),
+                self.surface.size(),
+            )?;
+        }
+        renderer.render_display(gpu, &self.surface, gpu_camera, &self.display)
+    }
 }
 
 enum TracerMsg {
     Quit,
     Recompute,
+    /// Continue after `App::traced_passes` without clearing the image.
+    Resume,
+    CameraMoved(Camera),
+    /// No camera move arrived for `App::PREVIEW_IDLE` while previewing.
+    CameraStopped,
+}
+
+impl TracerMsg {
+    /// Merges the messages queued behind `msg` that repeat it, so holding a movement key
+    /// traces one preview for the latest camera instead of one per key repeat. Returns the
+    /// merged message and the first one that could not be merged.
+    fn coalesce(mut msg: TracerMsg, rx: &Receiver<TracerMsg>) -> (TracerMsg, Option<TracerMsg>) {
+        while let Ok(next) = rx.try_recv() {
+            msg = match (msg, next) {
+                // The accumulated image was traced from the camera before the first move.
+                (TracerMsg::CameraMoved(previous), TracerMsg::CameraMoved(_)) => {
+                    TracerMsg::CameraMoved(previous)
+                }
+                (TracerMsg::Recompute, TracerMsg::Recompute) => TracerMsg::Recompute,
+                (TracerMsg::Resume, TracerMsg::Resume) => TracerMsg::Resume,
+                (msg, next) => return (msg, Some(next)),
+            };
+        }
+        (msg, None)
+    }
 }
 
 async fn run(event_loop: EventLoop<()>, app: Arc<App>) -> Result<()> {
@@ -56,6 +233,12 @@ async fn run(event_loop: EventLoop<()>, app: Arc<App>) -> Result<()> {
     let app = app.clone();
 
     event_loop.run(move |event: Event<()>, target| {
+        // A replay can quit from its own thread.
+        if app.shutting_down.load(Ordering::SeqCst) {
+            target.exit();
+            return;
+        }
+
         if let Event::WindowEvent {
             window_id: window_event_id,
             event,
@@ -66,13 +249,30 @@ async fn run(event_loop: EventLoop<()>, app: Arc<App>) -> Result<()> {
             if window_event_id == window.id() {
                 match event {
                     WindowEvent::RedrawRequested => {
-                        app.render().unwrap();
+                        // Lost surfaces are recovered by the renderer, what is left (like running
+                        // out of memory) is fatal. Quit cleanly so `--output` is still written.
+                        if let Err(err) = app.render() {
+                            log::error!("Rendering failed, shutting down: {err:#}");
+                            app.apply(Command::Quit).unwrap();
+                            target.exit();
+                        }
                     }
                     WindowEvent::Resized(new_size) => {
+                        app.record(Command::Resize(new_size.width, new_size.height))
+                            .unwrap();
                         app.on_resize(new_size).unwrap();
                     }
+                    WindowEvent::CursorMoved { position, .. } => {
+                        app.on_cursor_moved(position);
+                    }
+                    WindowEvent::ScaleFactorChanged { .. } => {
+                        // The window keeps its logical size on a monitor with another DPI, so
+                        // its pixels change. Most platforms report them with `Resized` as well,
+                        // this covers the others.
+                        app.on_resize(app.window.inner_size()).unwrap();
+                    }
                     WindowEvent::CloseRequested => {
-                        app.quit().unwrap();
+                        app.apply(Command::Quit).unwrap();
                         target.exit();
                     }
                     WindowEvent::KeyboardInput { event, .. } => {
@@ -80,25 +280,109 @@ async fn run(event_loop: EventLoop<()>, app: Arc<App>) -> Result<()> {
                             if let PhysicalKey::Code(key) = event.physical_key {
                                 match key {
                                     KeyCode::KeyR => {
-                                        app.recompute().unwrap();
+                                        app.apply(Command::Recompute).unwrap();
                                     }
                                     KeyCode::KeyW => {
-                                        app.on_camera_change(CameraChange::Forward).unwrap();
+                                        app.apply(Command::Move(CameraChange::Forward)).unwrap();
                                     }
                                     KeyCode::KeyS => {
-                                        app.on_camera_change(CameraChange::Backward).unwrap();
+                                        app.apply(Command::Move(CameraChange::Backward)).unwrap();
                                     }
                                     KeyCode::KeyA => {
-                                        app.on_camera_change(CameraChange::Left).unwrap();
+                                        app.apply(Command::Move(CameraChange::Left)).unwrap();
                                     }
                                     KeyCode::KeyD => {
-                                        app.on_camera_change(CameraChange::Right).unwrap();
+                                        app.apply(Command::Move(CameraChange::Right)).unwrap();
                                     }
                                     KeyCode::KeyQ => {
-                                        app.on_camera_change(CameraChange::Up).unwrap();
+                                        app.apply(Command::Move(CameraChange::Up)).unwrap();
                                     }
                                     KeyCode::KeyZ => {
-                                        app.on_camera_change(CameraChange::Down).unwrap();
+                                        app.apply(Command::Move(CameraChange::Down)).unwrap();
+                                    }
+                                    KeyCode::Backquote => {
+                                        app.console.lock().unwrap().toggle();
+                                        app.window.request_redraw();
+                                    }
+                                    KeyCode::KeyP => {
+                                        app.apply(Command::CycleResetPolicy).unwrap();
+                                    }
+                                    KeyCode::KeyC => {
+                                        app.share();
+                                    }
+                                    KeyCode::KeyI => {
+                                        if let Err(err) = app.copy_image() {
+                                            log::error!("Copying the image failed: {err:#}");
+                                        }
+                                        app.window.request_redraw();
+                                    }
+                                    KeyCode::BracketLeft | KeyCode::BracketRight => {
+                                        let max_bounces = *app.max_bounces.read().unwrap();
+                                        let max_bounces = match key {
+                                            KeyCode::BracketLeft => max_bounces / 2,
+                                            _ => max_bounces * 2,
+                                        }
+                                        .clamp(1, App::MAX_BOUNCES);
+                                        app.apply(Command::SetMaxBounces(max_bounces)).unwrap();
+                                    }
+                                    KeyCode::Comma | KeyCode::Period => {
+                                        let num_samples =
+                                            app.gpu_camera.read().unwrap().camera().num_samples;
+                                        let num_samples = match key {
+                                            KeyCode::Comma => num_samples / 2,
+                                            _ => num_samples.saturating_mul(2),
+                                        }
+                                        .clamp(1, App::MAX_SAMPLES);
+                                        app.apply(Command::SetSamples(num_samples)).unwrap();
+                                    }
+                                    KeyCode::Minus | KeyCode::Equal => {
+                                        let ev = app.gpu_camera.read().unwrap().exposure().ev;
+                                        let ev = match key {
+                                            KeyCode::Minus => ev - App::EXPOSURE_STEP,
+                                            _ => ev + App::EXPOSURE_STEP,
+                                        };
+                                        app.apply(Command::SetExposure(ev)).unwrap();
+                                    }
+                                    KeyCode::KeyM => {
+                                        app.apply(Command::ToggleProgressive).unwrap();
+                                    }
+                                    KeyCode::KeyN => {
+                                        app.apply(Command::ToggleDenoiser).unwrap();
+                                    }
+                                    KeyCode::KeyF => {
+                                        app.apply(Command::ToggleAtrous).unwrap();
+                                    }
+                                    KeyCode::KeyV => {
+                                        app.apply(Command::ToggleVarianceView).unwrap();
+                                    }
+                                    KeyCode::KeyK => {
+                                        app.apply(Command::ToggleKernel).unwrap();
+                                    }
+                                    KeyCode::KeyO => {
+                                        if let Err(err) = app.cycle_detached(target) {
+                                            log::error!("Opening a window failed: {err:#}");
+                                        }
+                                    }
+                                    KeyCode::F11 => {
+                                        app.toggle_fullscreen();
+                                    }
+                                    KeyCode::KeyL => {
+                                        app.console.lock().unwrap().cycle_severity();
+                                        app.window.request_redraw();
+                                    }
+                                    KeyCode::Digit1
+                                    | KeyCode::Digit2
+                                    | KeyCode::Digit3
+                                    | KeyCode::Digit4
+                                    | KeyCode::Digit5 => {
+                                        let preset = match key {
+                                            KeyCode::Digit1 => Preset::ALL[0],
+                                            KeyCode::Digit2 => Preset::ALL[1],
+                                            KeyCode::Digit3 => Preset::ALL[2],
+                                            KeyCode::Digit4 => Preset::ALL[3],
+                                            _ => Preset::ALL[4],
+                                        };
+                                        app.apply(Command::LoadPreset(preset)).unwrap();
                                     }
                                     _ => {}
                                 }
@@ -107,6 +391,8 @@ async fn run(event_loop: EventLoop<()>, app: Arc<App>) -> Result<()> {
                     }
                     _ => {}
                 }
+            } else {
+                app.on_detached_event(event);
             }
         }
     })?;
@@ -115,34 +401,235 @@ async fn run(event_loop: EventLoop<()>, app: Arc<App>) -> Result<()> {
 }
 
 impl App {
+    /// How long the camera has to stay still before the full image is traced again.
+    const PREVIEW_IDLE: Duration = Duration::from_millis(250);
+    /// Passes traced into the preview per camera move.
+    const PREVIEW_SAMPLES: u32 = 4;
+    /// Upper limit of the bounce keys.
+    const MAX_BOUNCES: usize = 1024;
+    /// Upper limit of the samples per pixel keys.
+    const MAX_SAMPLES: u32 = 1 << 16;
+    /// Stops added or removed by the exposure keys.
+    const EXPOSURE_STEP: f32 = 0.5;
+
+    /// Runs a recordable command, recording it if requested.
+    fn apply(&self, command: Command) -> Result<()> {
+        self.record(command)?;
+
+        match command {
+            Command::Move(change) => self.on_camera_change(change),
+            Command::Recompute => self.recompute(),
+            Command::CycleResetPolicy => {
+                self.cycle_reset_policy();
+                Ok(())
+            }
+            Command::LoadPreset(preset) => self.load_preset(preset),
+            Command::SetMaxBounces(max_bounces) => self.set_max_bounces(max_bounces),
+            Command::SetSamples(num_samples) => self.set_samples(num_samples),
+            Command::SetExposure(ev) => self.set_exposure(ev),
+            Command::ToggleProgressive => self.toggle_progressive(),
+            Command::ToggleDenoiser => {
+                self.toggle_filter(Filter::Svgf);
+                Ok(())
+            }
+            Command::ToggleAtrous => {
+                self.toggle_filter(Filter::Atrous);
+                Ok(())
+            }
+            Command::ToggleVarianceView => {
+                self.toggle_variance_view();
+                Ok(())
+            }
+            Command::ToggleKernel => self.toggle_kernel(),
+            Command::Resize(width, height) => {
+                // The resulting `Resized` event does the actual work.
+                let _ = self
+                    .window
+                    .request_inner_size(PhysicalSize::new(width, height));
+                Ok(())
+            }
+            Command::Quit => {
+                self.quit()?;
+                self.window.request_redraw();
+                Ok(())
+            }
+        }
+    }
+
+    fn record(&self, command: Command) -> Result<()> {
+        match &self.recorder {
+            Some(recorder) => recorder.record(command),
+            None => Ok(()),
+        }
+    }
+
     fn render(&self) -> Result<()> {
-        self.renderer
-            .read()
-            .unwrap()
-            .render(&self.gpu.read().unwrap(), &self.gpu_camera.read().unwrap())?;
+        let renderer = self.renderer.read().unwrap();
+        let gpu = self.gpu.read().unwrap();
+        let gpu_camera = self.gpu_camera.read().unwrap();
+        let overlay = Overlay::snapshot(&self.console.lock().unwrap());
+        renderer.render(&gpu, &gpu_camera, overlay.as_deref())?;
+
+        if let Some(detached) = &mut *self.detached.lock().unwrap() {
+            let cursor = *self.cursor.lock().unwrap();
+            detached.render(&gpu, &renderer, &gpu_camera, cursor)?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens the detached window, shows its next view or closes it after the last one.
+    fn cycle_detached(&self, target: &winit::event_loop::EventLoopWindowTarget<()>) -> Result<()> {
+        // Same lock order as `render`.
+        let renderer = self.renderer.read().unwrap();
+        let gpu = self.gpu.read().unwrap();
+        let mut detached = self.detached.lock().unwrap();
+        let next = match detached.as_ref().map(|detached| detached.view) {
+            None => DetachedView::Zoom,
+            Some(DetachedView::Zoom) => DetachedView::Normals,
+            Some(DetachedView::Normals) => DetachedView::Variance,
+            Some(DetachedView::Variance) => {
+                *detached = None;
+                return Ok(());
+            }
+        };
 
+        if detached.is_none() {
+            *detached = Some(DetachedWindow::open(target, &gpu, &renderer)?);
+        }
+        detached.as_mut().unwrap().set_view(&gpu, next)?;
+        self.window.request_redraw();
         Ok(())
     }
 
-    fn perform(&self) -> Result<()> {
+    fn on_detached_event(&self, event: winit::event::WindowEvent) {
+        use winit::event::WindowEvent;
+
+        let gpu = self.gpu.read().unwrap();
+        let mut detached = self.detached.lock().unwrap();
+        match event {
+            WindowEvent::Resized(size) => {
+                if let Some(detached) = &mut *detached {
+                    detached
+                        .surface
+                        .on_resize(&gpu.device, (size.width, size.height));
+                }
+                self.window.request_redraw();
+            }
+            WindowEvent::RedrawRequested => self.window.request_redraw(),
+            WindowEvent::CloseRequested => *detached = None,
+            _ => {}
+        }
+    }
+
+    /// Remembers where the cursor points at the image, see `DetachedView::Zoom`.
+    fn on_cursor_moved(&self, position: winit::dpi::PhysicalPosition<f64>) {
+        let camera_size = {
+            let gpu_camera = self.gpu_camera.read().unwrap();
+            (gpu_camera.camera().width, gpu_camera.camera().height)
+        };
+        let window = self.window.inner_size();
+        let [x, y, width, height] = render::letterbox(camera_size, (window.width, window.height));
+        *self.cursor.lock().unwrap() = (
+            ((position.x as f32 - x) / width).clamp(0.0, 1.0),
+            ((position.y as f32 - y) / height).clamp(0.0, 1.0),
+        );
+        if self.detached.lock().unwrap().is_some() {
+            self.window.request_redraw();
+        }
+    }
+
+    /// Traces passes from `first_sample` on, 0 starts a new render.
+    fn perform(&self, first_sample: u32) -> Result<()> {
         let raytracer = self.raytracer.read().unwrap();
         let gpu = self.gpu.read().unwrap();
         let gpu_camera = self.gpu_camera.read().unwrap();
-        raytracer.perform(&gpu, &gpu_camera, &self.window)?;
+        let camera = gpu_camera.camera();
+        let preview = camera.is_preview();
+        // Progressive rendering goes on after `num_samples` until the next change.
+        let progressive = !preview && self.progressive.load(Ordering::SeqCst);
+        let (samples, last_sample) = if preview {
+            (
+                Some(Self::PREVIEW_SAMPLES.min(camera.num_samples)),
+                camera.num_samples,
+            )
+        } else if progressive {
+            (None, u32::MAX)
+        } else {
+            (Some(camera.num_samples), camera.num_samples)
+        };
+        let status = match &*self.status.lock().unwrap() {
+            Some(status) if first_sample > 0 => status.with_samples(samples),
+            _ => RenderStatus::new(samples, camera.traced_size()),
+        };
+        *self.status.lock().unwrap() = Some(status.clone());
+
+        let mut passes = 0;
+        let samples = first_sample..last_sample;
+        raytracer.perform(&gpu, &gpu_camera, samples, self.stats.as_ref(), |sample| {
+            gpu.queue.on_submitted_work_done(status.on_pass_done());
+            self.traced_passes.store(sample, Ordering::SeqCst);
+            self.window.request_redraw();
+            passes += 1;
+
+            if self.shutting_down.load(Ordering::SeqCst)
+                || (preview && passes >= Self::PREVIEW_SAMPLES)
+                || (!self.progressive.load(Ordering::SeqCst) && sample >= camera.num_samples)
+            {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
 
         Ok(())
     }
 
+    /// Shows the progress of the latest render in the window title.
+    fn update_title(&self) {
+        // Runs the callbacks of passes the GPU finished since the last submission.
+        self.gpu.read().unwrap().device.poll(wgpu::Maintain::Poll);
+        if let Some(status) = &*self.status.lock().unwrap() {
+            self.window
+                .set_title(&format!("{TITLE} - {}", status.at(Instant::now())));
+        }
+    }
+
+    /// Continues the render interrupted by a change that keeps the accumulated image.
+    fn resume(&self) -> Result<()> {
+        self.tracer_tx.send(TracerMsg::Resume)?;
+        Ok(())
+    }
+
     fn recompute(&self) -> Result<()> {
         self.tracer_tx.send(TracerMsg::Recompute)?;
         Ok(())
     }
 
     fn quit(&self) -> Result<()> {
+        self.shutting_down.store(true, Ordering::SeqCst);
         self.tracer_tx.send(TracerMsg::Quit)?;
         Ok(())
     }
 
+    /// Waits for submitted passes and writes the image accumulated so far.
+    fn flush(&self, output: Option<&std::path::Path>) -> Result<()> {
+        let gpu = self.gpu.read().unwrap();
+        gpu.device.poll(wgpu::Maintain::Wait);
+
+        if let Some(output) = output {
+            let exposure = self.gpu_camera.read().unwrap().camera().exposure();
+            self.renderer
+                .read()
+                .unwrap()
+                .read_accumulation(&gpu)?
+                .save(output, exposure)?;
+            eprintln!("Wrote {}", output.display());
+        }
+
+        Ok(())
+    }
+
     fn clear(&self) {
         self.renderer
             .read()
@@ -150,10 +637,326 @@ impl App {
             .clear(&self.gpu.read().unwrap());
     }
 
+    /// Moves the camera and switches to the low resolution preview until it stops.
     fn on_camera_change(&self, change: CameraChange) -> Result<()> {
+        self.interrupt_tracer.cancel();
         let mut gpu_camera = self.gpu_camera.write().unwrap();
-        gpu_camera.on_camera_change(&self.gpu.read().unwrap(), change)?;
-        self.recompute()?;
+        self.interrupt_tracer.reset();
+
+        let gpu = self.gpu.read().unwrap();
+        let previous = gpu_camera.camera().clone();
+        gpu_camera.on_camera_change(&gpu, change)?;
+        gpu_camera.set_preview(&gpu, true)?;
+        self.tracer_tx.send(TracerMsg::CameraMoved(previous))?;
+        Ok(())
+    }
+
+    fn on_camera_moved(&self, previous: &Camera) -> Result<()> {
+        let policy = *self.reset_policy.read().unwrap();
+        let gpu = self.gpu.read().unwrap();
+        let renderer = self.renderer.read().unwrap();
+        renderer.clear_preview(&gpu);
+        self.accumulator.read().unwrap().on_camera_moved(
+            &gpu,
+            &self.gpu_camera.read().unwrap(),
+            &renderer,
+            previous,
+            policy,
+        )
+    }
+
+    /// Goes back to tracing the full image, which the reset policy already prepared.
+    fn on_camera_stopped(&self) -> Result<()> {
+        self.gpu_camera
+            .write()
+            .unwrap()
+            .set_preview(&self.gpu.read().unwrap(), false)
+    }
+
+    fn cycle_reset_policy(&self) {
+        let mut policy = self.reset_policy.write().unwrap();
+        *policy = policy.next();
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Camera movement policy: {:?}", *policy),
+        );
+    }
+
+    /// Replaces the traced scene and camera with a built-in scene and traces it from scratch.
+    fn load_preset(&self, preset: Preset) -> Result<()> {
+        let SceneFile {
+            scene,
+            camera,
+            max_bounces,
+        } = preset.scene_file();
+
+        {
+            // Same lock order as `perform`.
+            self.interrupt_tracer.cancel();
+            let mut raytracer = self.raytracer.write().unwrap();
+            self.interrupt_tracer.reset();
+            let gpu = self.gpu.read().unwrap();
+            let mut gpu_camera = self.gpu_camera.write().unwrap();
+
+            let size = (gpu_camera.camera().width, gpu_camera.camera().height);
+            gpu_camera.set_camera(
+                &gpu,
+                Camera::new(
+                    camera.lookfrom,
+                    camera.lookat,
+                    camera.vup,
+                    camera.num_samples,
+                    size,
+                ),
+            )?;
+            gpu_camera.set_exposure(&gpu, camera.exposure)?;
+            gpu_camera.set_lens(&gpu, camera.lens)?;
+            gpu_camera.set_projection(&gpu, camera.projection)?;
+            gpu_camera.set_stereo(&gpu, camera.stereo)?;
+
+            let adaptive = raytracer.adaptive_threshold();
+            let kernel = raytracer.kernel();
+            let bounces_per_dispatch = raytracer.bounces_per_dispatch();
+            let packed_paths = raytracer.packed_paths();
+            let paths_per_pixel = raytracer.paths_per_pixel();
+            let pixel_filter = raytracer.pixel_filter();
+            let renderer = self.renderer.read().unwrap();
+            *raytracer = GpuRaytracer::new(
+                &gpu,
+                &gpu_camera,
+                max_bounces,
+                &renderer,
+                scene.clone(),
+                self.seed,
+            )?;
+            raytracer.set_adaptive_threshold(&gpu, adaptive)?;
+            raytracer.set_kernel(&gpu, &renderer, kernel);
+            raytracer.set_bounces_per_dispatch(&gpu, bounces_per_dispatch)?;
+            raytracer.set_packed_paths(&gpu, &renderer, packed_paths)?;
+            raytracer.set_paths_per_pixel(&gpu, &renderer, paths_per_pixel)?;
+            raytracer.set_pixel_filter(&gpu, pixel_filter)?;
+            raytracer.set_cancellation(Some(self.interrupt_tracer.clone()));
+            *self.scene.write().unwrap() = scene;
+            *self.max_bounces.write().unwrap() = max_bounces;
+        }
+
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Loaded preset {}", preset.name()),
+        );
+        self.recompute()
+    }
+
+    /// Changes the bounce limit of the traced scene and traces it from scratch.
+    fn set_max_bounces(&self, max_bounces: usize) -> Result<()> {
+        {
+            // Same lock order as `perform`, passes in flight finish with the old limit.
+            self.interrupt_tracer.cancel();
+            let mut raytracer = self.raytracer.write().unwrap();
+            self.interrupt_tracer.reset();
+            raytracer.set_max_bounces(&self.gpu.read().unwrap(), max_bounces)?;
+            *self.max_bounces.write().unwrap() = max_bounces;
+        }
+
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Max bounces: {max_bounces}"),
+        );
+        self.recompute()
+    }
+
+    /// Changes the samples per pixel of a full render and traces it from scratch.
+    fn set_samples(&self, num_samples: u32) -> Result<()> {
+        {
+            self.interrupt_tracer.cancel();
+            let mut gpu_camera = self.gpu_camera.write().unwrap();
+            self.interrupt_tracer.reset();
+            gpu_camera.set_num_samples(&self.gpu.read().unwrap(), num_samples)?;
+        }
+
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Samples per pixel: {num_samples}"),
+        );
+        self.recompute()
+    }
+
+    /// Sets the exposure compensation in stops. Only the displayed brightness changes, so
+    /// the render continues where it was.
+    fn set_exposure(&self, ev: f32) -> Result<()> {
+        {
+            self.interrupt_tracer.cancel();
+            let mut gpu_camera = self.gpu_camera.write().unwrap();
+            self.interrupt_tracer.reset();
+            let exposure = Exposure {
+                ev,
+                ..gpu_camera.exposure()
+            };
+            gpu_camera.set_exposure(&self.gpu.read().unwrap(), exposure)?;
+        }
+
+        self.console
+            .lock()
+            .unwrap()
+            .push(console::Severity::Info, format!("Exposure: {ev:+} EV"));
+        self.window.request_redraw();
+        self.resume()
+    }
+
+    /// Switches progressive rendering on or off. Switching it on continues a finished render,
+    /// switching it off stops it at the sample count, or right away when already past it.
+    fn toggle_progressive(&self) -> Result<()> {
+        let progressive = !self.progressive.fetch_xor(true, Ordering::SeqCst);
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!(
+                "Progressive rendering {}",
+                if progressive { "on" } else { "off" }
+            ),
+        );
+        self.window.request_redraw();
+
+        if progressive {
+            self.resume()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Switches `filter` of the displayed image on, replacing the other one, or off. The render
+    /// itself is unaffected.
+    fn toggle_filter(&self, filter: Filter) {
+        let filter = {
+            let mut renderer = self.renderer.write().unwrap();
+            let filter = if renderer.filter() == filter {
+                Filter::Off
+            } else {
+                filter
+            };
+            renderer.set_filter(filter);
+            filter
+        };
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Denoiser: {}", filter.name()),
+        );
+        self.window.request_redraw();
+    }
+
+    /// Switches between the image and the estimate of the noise left in each pixel.
+    fn toggle_variance_view(&self) {
+        let show_variance = {
+            let mut renderer = self.renderer.write().unwrap();
+            let show_variance = !renderer.show_variance();
+            renderer.set_show_variance(show_variance);
+            show_variance
+        };
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Variance view {}", if show_variance { "on" } else { "off" }),
+        );
+        self.window.request_redraw();
+    }
+
+    /// Switches full passes between the megakernel and a dispatch per bounce, then traces the
+    /// scene from scratch.
+    fn toggle_kernel(&self) -> Result<()> {
+        let kernel = {
+            self.interrupt_tracer.cancel();
+            let mut raytracer = self.raytracer.write().unwrap();
+            self.interrupt_tracer.reset();
+            let kernel = match raytracer.kernel() {
+                Kernel::Megakernel => Kernel::MultiPass,
+                Kernel::MultiPass => Kernel::Megakernel,
+            };
+            raytracer.set_kernel(
+                &self.gpu.read().unwrap(),
+                &self.renderer.read().unwrap(),
+                kernel,
+            );
+            kernel
+        };
+
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!("Kernel: {}", kernel.name()),
+        );
+        self.recompute()
+    }
+
+    /// Switches between borderless fullscreen on the window's monitor and the window. The
+    /// `Resized` event that follows resizes the surface, restarting the render when the
+    /// traced size changes.
+    fn toggle_fullscreen(&self) {
+        use winit::window::Fullscreen;
+
+        let fullscreen = match self.window.fullscreen() {
+            Some(_) => None,
+            None => Some(Fullscreen::Borderless(self.window.current_monitor())),
+        };
+        self.window.set_fullscreen(fullscreen);
+    }
+
+    /// Prints and logs a scene code reproducing the current view.
+    fn share(&self) {
+        let gpu_camera = self.gpu_camera.read().unwrap();
+        let camera = gpu_camera.camera();
+        let code = share::encode(&SceneFile {
+            scene: self.scene.read().unwrap().clone(),
+            camera: CameraSettings {
+                lookfrom: camera.lookfrom,
+                lookat: camera.lookat,
+                vup: camera.vup,
+                num_samples: camera.num_samples,
+                exposure: gpu_camera.exposure(),
+                lens: gpu_camera.lens(),
+                projection: gpu_camera.projection(),
+                stereo: gpu_camera.stereo(),
+            },
+            max_bounces: *self.max_bounces.read().unwrap(),
+        });
+
+        println!("{code}");
+        self.console
+            .lock()
+            .unwrap()
+            .push(console::Severity::Info, format!("Scene code: {code}"));
+        self.window.request_redraw();
+    }
+
+    /// Places the image accumulated so far on the system clipboard, exposed like on screen.
+    fn copy_image(&self) -> Result<()> {
+        let exposure = self.gpu_camera.read().unwrap().camera().exposure();
+        let accumulation = self
+            .renderer
+            .read()
+            .unwrap()
+            .read_accumulation(&self.gpu.read().unwrap())?;
+        let image = image::RgbaImage::from_accumulation(
+            accumulation.width,
+            accumulation.height,
+            &accumulation.texels,
+            exposure,
+        );
+
+        let mut clipboard = self.clipboard.lock().unwrap();
+        let clipboard = match &mut *clipboard {
+            Some(clipboard) => clipboard,
+            None => clipboard.insert(arboard::Clipboard::new()?),
+        };
+        clipboard.set_image(arboard::ImageData {
+            width: image.width as usize,
+            height: image.height as usize,
+            bytes: image.pixels.into(),
+        })?;
+
+        self.console.lock().unwrap().push(
+            console::Severity::Info,
+            format!(
+                "Copied {}x{} image to the clipboard",
+                image.width, image.height
+            ),
+        );
         Ok(())
     }
 
@@ -161,94 +964,274 @@ impl App {
         let mut changed = false;
         {
             let mut gpu_camera = self.gpu_camera.write().unwrap();
+            let mut gpu = self.gpu.write().unwrap();
+            gpu.on_resize((new_size.width, new_size.height));
 
-            if new_size.width != gpu_camera.camera().width
-                || new_size.height != gpu_camera.camera().height
-            {
+            let size = self.render_size.for_window(&gpu, new_size);
+            if size != (gpu_camera.camera().width, gpu_camera.camera().height) {
                 changed = true;
-                let mut gpu = self.gpu.write().unwrap();
                 let mut renderer = self.renderer.write().unwrap();
                 let mut raytracer = self.raytracer.write().unwrap();
-                gpu.on_resize((new_size.width, new_size.height));
-                gpu_camera.on_resize(&gpu, (new_size.width, new_size.height))?;
+                let mut accumulator = self.accumulator.write().unwrap();
+                gpu_camera.on_resize(&gpu, size)?;
                 renderer.on_resize(&gpu, &gpu_camera)?;
                 raytracer.on_resize(&gpu, &renderer)?;
+                accumulator.on_resize(&gpu, &renderer);
             }
         }
 
         if changed {
             self.recompute()?;
+        } else {
+            // Same image, letterboxed anew into the resized window.
+            self.window.request_redraw();
         }
 
         Ok(())
     }
 }
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let (window, event_loop) = create_window()?;
-    let gpu = gpu::Gpu::from_window(&window).await?;
-    let camera = Camera::new(
-        Vec3::new(0.0, 0.0, 0.0),
-        Vec3::new(0.0, 0.0, -1.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        100,
-        &window,
-    );
+    let options = Options::from_args()?;
+    diagnostics::init()?;
+    let stats = options
+        .stats
+        .as_deref()
+        .map(StatsWriter::open)
+        .transpose()?
+        .map(Mutex::new);
+
+    let resolver = AssetResolver::new(options.asset_paths.clone());
+    let replay = options.replay.as_deref().map(Replay::load).transpose()?;
+    let resume = options
+        .resume
+        .as_deref()
+        .map(Checkpoint::load)
+        .transpose()?;
+    let scene_file = match (&options.scene, &options.scene_code, &replay, &resume) {
+        (Some(path), _, _, _) if path.as_os_str() == "-" => {
+            let source = std::io::read_to_string(std::io::stdin())
+                .context("Failed to read the scene from stdin")?;
+            SceneFile::parse(&source, &resolver)?
+        }
+        (Some(path), _, _, _) => SceneFile::load(path, &resolver)?,
+        (None, Some(code), _, _) => share::decode(code, &resolver)?,
+        (None, None, Some(replay), _) => share::decode(&replay.header.scene, &resolver)?,
+        (None, None, None, Some(checkpoint)) => share::decode(&checkpoint.scene, &resolver)?,
+        (None, None, None, None) => match options.random_spheres {
+            Some(seed) => presets::random_spheres(seed),
+            None => presets::three_spheres(),
+        },
+    };
+
+    if let Some(path) = &options.save_scene {
+        scene_file.save(path)?;
+    }
+    if let Some(path) = &options.export_gltf {
+        gltf::export(&scene_file, path)?;
+    }
+    if options.share {
+        println!("{}", share::encode(&scene_file));
+    }
+    if options.share || options.save_scene.is_some() || options.export_gltf.is_some() {
+        return Ok(());
+    }
+
+    let console = Console::shared();
+    ConsoleLogger::install(console.clone())?;
 
-    let mut scene = Scene::default();
-    let material_left = Material::new_dielectric(1.5);
-    let material_center = Material::new_lambertian(Vec3::new(0.1, 0.2, 0.5));
-    let material_right = Material::new_metal(Vec3::new(0.8, 0.6, 0.2), 0.0);
-    let material_ground = Material::new_lambertian(Vec3::new(0.8, 0.8, 0.0));
+    if let Some(addr) = &options.serve {
+        return serve::run(&options, addr).await;
+    }
+    if let Some(manifest) = &options.batch {
+        return batch::run(&options, manifest, stats).await;
+    }
+    if let Some(addr) = &options.worker {
+        return distributed::work(&options, addr).await;
+    }
+    if let (Some(addr), Some(output)) = (&options.coordinator, &options.output) {
+        return distributed::coordinate(&options, addr, output, scene_file).await;
+    }
+    if let (true, Some(output)) = (options.headless, &options.output) {
+        return headless::run(&options, output, scene_file, resume, stats).await;
+    }
+
+    let (window, event_loop) = create_window(replay.as_ref().map(|r| r.header.size))?;
+    let gpu = gpu::Gpu::from_window(&window, options.fallback_adapter, console.clone()).await?;
 
-    scene.new_sphere(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), 0.5), material_left);
-    scene.new_sphere(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), -0.4), material_left);
-    scene.new_sphere(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5), material_center);
-    scene.new_sphere(Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5), material_right);
+    let seed = match &replay {
+        Some(replay) => replay.header.seed,
+        None => options.seed.unwrap_or_else(raytracing::random_seed),
+    };
+    let recorder = match &options.record {
+        Some(path) => Some(Recorder::create(
+            path,
+            &ReplayHeader {
+                seed,
+                size: (window.inner_size().width, window.inner_size().height),
+                scene: share::encode(&scene_file),
+            },
+        )?),
+        None => None,
+    };
 
-    scene.new_sphere(
-        Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0),
-        material_ground,
+    let SceneFile {
+        scene,
+        camera,
+        max_bounces,
+    } = scene_file;
+    let exposure = camera.exposure;
+    let lens = camera.lens;
+    let projection = camera.projection;
+    let stereo = camera.stereo;
+    let render_size = match options.resolution {
+        Some((width, height)) => RenderSize::Fixed(width, height),
+        None => RenderSize::Scaled {
+            scale: options.render_scale,
+            aspect: options.aspect,
+        },
+    };
+    let camera = Camera::new(
+        camera.lookfrom,
+        camera.lookat,
+        camera.vup,
+        camera.num_samples,
+        render_size.for_window(&gpu, window.inner_size()),
     );
 
-    let gpu_camera: GpuCamera = GpuCamera::new(&gpu, camera)?;
-    let renderer = Renderer::new(&gpu, &gpu_camera);
-    let raytracer: GpuRaytracer = GpuRaytracer::new(&gpu, &gpu_camera, 50, &renderer, scene)?;
+    let mut gpu_camera: GpuCamera = GpuCamera::new(&gpu, camera)?;
+    gpu_camera.set_exposure(&gpu, exposure)?;
+    gpu_camera.set_lens(&gpu, lens)?;
+    gpu_camera.set_projection(&gpu, projection)?;
+    gpu_camera.set_stereo(&gpu, stereo)?;
+    let renderer = Renderer::new(&gpu, &gpu_camera)?;
+    let mut raytracer: GpuRaytracer = GpuRaytracer::new(
+        &gpu,
+        &gpu_camera,
+        max_bounces,
+        &renderer,
+        scene.clone(),
+        seed,
+    )?;
+    let interrupt_tracer = CancellationToken::new();
+    raytracer.set_cancellation(Some(interrupt_tracer.clone()));
+    raytracer.set_adaptive_threshold(&gpu, options.adaptive)?;
+    raytracer.set_kernel(&gpu, &renderer, options.kernel);
+    raytracer.set_bounces_per_dispatch(&gpu, options.bounces_per_dispatch)?;
+    raytracer.set_packed_paths(&gpu, &renderer, options.packed_paths)?;
+    raytracer.set_paths_per_pixel(&gpu, &renderer, options.paths_per_pixel)?;
+    raytracer.set_pixel_filter(&gpu, options.pixel_filter)?;
+    let accumulator = Accumulator::new(&gpu, &gpu_camera, &renderer)?;
 
     let gpu = RwLock::new(gpu);
     let gpu_camera = RwLock::new(gpu_camera);
     let renderer = RwLock::new(renderer);
     let raytracer = RwLock::new(raytracer);
+    let accumulator = RwLock::new(accumulator);
 
     let (tracer_tx, tracer_rx) = channel();
 
     let app = Arc::new(App {
         renderer,
         raytracer,
+        accumulator,
+        reset_policy: RwLock::new(ResetPolicy::FullReset),
         gpu,
         gpu_camera,
         window,
         tracer_tx,
+        console,
+        stats,
+        shutting_down: AtomicBool::new(false),
+        interrupt_tracer,
+        scene: RwLock::new(scene),
+        max_bounces: RwLock::new(max_bounces),
+        seed,
+        recorder,
+        clipboard: Mutex::new(None),
+        status: Mutex::new(None),
+        traced_passes: AtomicU32::new(0),
+        progressive: AtomicBool::new(options.progressive),
+        render_size,
+        detached: Mutex::new(None),
+        cursor: Mutex::new((0.5, 0.5)),
     });
 
     let handle: JoinHandle<()>;
     {
         let app = app.clone();
         handle = tokio::task::spawn_blocking(move || {
-            while let Ok(msg) = tracer_rx.recv() {
+            // First message that could not be merged by `coalesce`.
+            let mut pending = None;
+            loop {
+                let preview = app.gpu_camera.read().unwrap().camera().is_preview();
+                let msg = if let Some(msg) = pending.take() {
+                    msg
+                } else if preview {
+                    match tracer_rx.recv_timeout(App::PREVIEW_IDLE) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => TracerMsg::CameraStopped,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                } else {
+                    match tracer_rx.recv() {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    }
+                };
+
+                if app.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let (msg, next) = TracerMsg::coalesce(msg, &tracer_rx);
+                pending = next;
                 match msg {
                     TracerMsg::Quit => break,
                     TracerMsg::Recompute => {
                         app.clear();
-                        app.perform().unwrap();
+                        app.perform(0).unwrap();
+                    }
+                    TracerMsg::Resume => {
+                        app.perform(app.traced_passes.load(Ordering::SeqCst))
+                            .unwrap();
+                    }
+                    TracerMsg::CameraMoved(previous) => {
+                        app.on_camera_moved(&previous).unwrap();
+                        app.perform(0).unwrap();
+                    }
+                    TracerMsg::CameraStopped => {
+                        app.on_camera_stopped().unwrap();
+                        app.perform(0).unwrap();
                     }
                 }
             }
         });
     }
 
+    {
+        let app = app.clone();
+        std::thread::spawn(move || {
+            while !app.shutting_down.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_secs(1));
+                app.update_title();
+            }
+        });
+    }
+
+    if let Some(replay) = replay {
+        let app = app.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = replay.run(|command| app.apply(command)) {
+                log::error!("Replay failed: {err:#}");
+            }
+        });
+    }
+
     run(event_loop, app.clone()).await?;
     handle.await?;
+    app.flush(options.output.as_deref())?;
 
     Ok(())
 }
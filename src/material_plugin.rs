@@ -0,0 +1,249 @@
+//! Material types defined outside the core shader. A plugin is a WGSL snippet shading the hits
+//! on its materials, with the fields of a parameter struct every such material carries. The
+//! compute shader is composed with the plugins of the scene, `shadeCustom` picking the snippet
+//! by `mat_type`.
+use anyhow::{anyhow, Result};
+
+/// `mat_type` of the first plugin, the built-in materials stay below.
+pub const FIRST_CUSTOM_MATERIAL: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParamType {
+    F32,
+    U32,
+    I32,
+    Vec2,
+    Vec3,
+    Vec4,
+}
+
+impl ParamType {
+    fn parse(ty: &str) -> Option<Self> {
+        Some(match ty {
+            "f32" => ParamType::F32,
+            "u32" => ParamType::U32,
+            "i32" => ParamType::I32,
+            "vec2<f32>" | "vec2f" => ParamType::Vec2,
+            "vec3<f32>" | "vec3f" => ParamType::Vec3,
+            "vec4<f32>" | "vec4f" => ParamType::Vec4,
+            _ => return None,
+        })
+    }
+
+    /// Alignment and size in 4 byte words, as laid out in storage buffers.
+    fn layout(self) -> (u32, u32) {
+        match self {
+            ParamType::F32 | ParamType::U32 | ParamType::I32 => (1, 1),
+            ParamType::Vec2 => (2, 2),
+            ParamType::Vec3 => (4, 3),
+            ParamType::Vec4 => (4, 4),
+        }
+    }
+
+    /// WGSL expression reading a value of this type from the parameter words at `word`.
+    fn load(self, word: &str) -> String {
+        let float = |i: u32| format!("bitcast<f32>(materialParamsArr.words[{word} + {i}u])");
+        let floats = |n: u32| (0..n).map(float).collect::<Vec<_>>().join(", ");
+        match self {
+            ParamType::F32 => float(0),
+            ParamType::U32 => format!("materialParamsArr.words[{word}]"),
+            ParamType::I32 => format!("bitcast<i32>(materialParamsArr.words[{word}])"),
+            ParamType::Vec2 => format!("vec2<f32>({})", floats(2)),
+            ParamType::Vec3 => format!("vec3<f32>({})", floats(3)),
+            ParamType::Vec4 => format!("vec4<f32>({})", floats(4)),
+        }
+    }
+
+    fn wgsl(self) -> &'static str {
+        match self {
+            ParamType::F32 => "f32",
+            ParamType::U32 => "u32",
+            ParamType::I32 => "i32",
+            ParamType::Vec2 => "vec2<f32>",
+            ParamType::Vec3 => "vec3<f32>",
+            ParamType::Vec4 => "vec4<f32>",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Field {
+    name: String,
+    ty: ParamType,
+    /// In words from the start of the parameters.
+    offset: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct MaterialPlugin {
+    name: String,
+    fields: Vec<Field>,
+    /// Words the parameters of a material take, padded like the Rust struct written for them.
+    words: u32,
+    code: String,
+}
+
+impl MaterialPlugin {
+    /// `fields` declares the parameter struct as in WGSL (`color: vec3<f32>, strength: f32`),
+    /// matching the `ShaderType` later passed to `Scene::custom_material`. Scalars and float
+    /// vectors are supported.
+    ///
+    /// `code` is the body of a function of `current: Path`, `hit: HitRecord`,
+    /// `material: Material` and `params`. It updates `var path`, a copy of `current`, and
+    /// returns it for the next bounce or ends it with `endPath(path, radiance)`.
+    pub fn new(name: &str, fields: &str, code: &str) -> Result<Self> {
+        let mut parsed = vec![];
+        let (mut words, mut align) = (0u32, 1);
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (field_name, ty) = field.split_once(':').ok_or_else(|| {
+                anyhow!("Expected `name: type` in material `{name}`, got `{field}`")
+            })?;
+            let ty = ParamType::parse(ty.trim()).ok_or_else(|| {
+                anyhow!(
+                    "Unsupported parameter type `{}` in material `{name}`",
+                    ty.trim()
+                )
+            })?;
+
+            let (field_align, size) = ty.layout();
+            words = words.next_multiple_of(field_align);
+            align = align.max(field_align);
+            parsed.push(Field {
+                name: field_name.trim().to_owned(),
+                ty,
+                offset: words,
+            });
+            words += size;
+        }
+
+        Ok(MaterialPlugin {
+            name: name.to_owned(),
+            fields: parsed,
+            words: words.next_multiple_of(align),
+            code: code.to_owned(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Bytes the parameters of a material take.
+    pub fn params_size(&self) -> u64 {
+        self.words as u64 * 4
+    }
+}
+
+/// The `custom_materials` shader module, dispatching to the plugins in order of `mat_type`.
+pub fn wgsl(plugins: &[MaterialPlugin]) -> String {
+    let mut wgsl = String::new();
+    let mut cases = String::new();
+    for (i, plugin) in plugins.iter().enumerate() {
+        let mut fields: String = plugin
+            .fields
+            .iter()
+            .map(|field| format!("    {}: {},\n", field.name, field.ty.wgsl()))
+            .collect();
+        if fields.is_empty() {
+            // Structs need a member.
+            fields = "    unused: u32,\n".to_owned();
+        }
+        let loads: String = plugin
+            .fields
+            .iter()
+            .map(|field| {
+                let load = field.ty.load(&format!("word + {}u", field.offset));
+                format!("    params.{} = {load};\n", field.name)
+            })
+            .collect();
+
+        wgsl += &format!(
+            "// Material `{name}`.
+struct CustomParams{i} {{
+{fields}}};
+
+fn loadCustomParams{i}(word: u32) -> CustomParams{i} {{
+    var params: CustomParams{i};
+{loads}    return params;
+}}
+
+fn shadeCustom{i}(current: Path, hit: HitRecord, material: Material, params: CustomParams{i}) -> Path {{
+    var path = current;
+{code}
+}}
+
+",
+            name = plugin.name,
+            code = plugin.code,
+        );
+        cases += &format!(
+            "        case {}u: {{
+            return shadeCustom{i}(path, hit, material, loadCustomParams{i}(material.params));
+        }}
+",
+            FIRST_CUSTOM_MATERIAL + i as u32
+        );
+    }
+
+    wgsl + &format!(
+        "// Shades a hit on a plugin material, unknown types turn red like the built-in ones.
+fn shadeCustom(path: Path, hit: HitRecord, material: Material) -> Path {{
+    switch material.mat_type {{
+{cases}        default: {{}}
+    }}
+    return endPath(path, vec3<f32>(1.0, 0.0, 0.0));
+}}
+"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::*;
+    use encase::ShaderType;
+    use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+    #[derive(ShaderType)]
+    struct Params {
+        strength: f32,
+        color: Vec3,
+        offset: nalgebra::Vector2<f32>,
+        count: u32,
+    }
+
+    fn plugin() -> MaterialPlugin {
+        MaterialPlugin::new(
+            "glow",
+            "strength: f32, color: vec3<f32>, offset: vec2<f32>, count: u32,",
+            "    return endPath(path, path.radiance + path.energy * params.color * params.strength);",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn params_match_encase_layout() {
+        let plugin = plugin();
+        assert_eq!(plugin.params_size(), Params::min_size().get());
+        let offsets: Vec<u32> = plugin.fields.iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [0, 4, 8, 10]);
+
+        assert!(MaterialPlugin::new("bad", "m: mat3x3<f32>", "").is_err());
+    }
+
+    #[test]
+    fn plugins_compose_into_valid_shader() {
+        let empty = MaterialPlugin::new(
+            "flat",
+            "",
+            "    path.energy *= material.albedo;\n    return path;",
+        )
+        .unwrap();
+        let source = crate::shader::compute(&[plugin(), empty]).unwrap();
+        let module = naga::front::wgsl::parse_str(&source)
+            .unwrap_or_else(|err| panic!("{}", err.emit_to_string(&source)));
+        Validator::new(ValidationFlags::all(), Capabilities::all())
+            .validate(&module)
+            .unwrap();
+    }
+}
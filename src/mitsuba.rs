@@ -0,0 +1,836 @@
+//! Import of basic Mitsuba 0.6 and 2/3 scene files, for comparing renders with Mitsuba.
+//!
+//! Supported are `perspective`, `thinlens` and `orthographic` sensors with their sampler's
+//! sample count, the integrator's max depth, `sphere`, `rectangle`, `cube`, `disk`, `obj`
+//! and `ply` shapes, the `diffuse`, `conductor`, `plastic`, `dielectric` and `principled`
+//! BSDFs (with their rough and two-sided variants), point, spot, directional, constant
+//! and area emitters, `<default>` parameters, `<ref>` and `<include>`. Everything else is
+//! skipped with a warning, textures leave BSDFs with their constant colors.
+//!
+//! The image size comes from the window or `--size`, not from the film, and the
+//! perspective field of view is fixed at 90°.
+use crate::assets::AssetResolver;
+use crate::camera::{Lens, Projection};
+use crate::scene::{Disk, Light, Material, Rotation, Scene, Sky, Sphere, TriangleMesh};
+use crate::scene_file::{CameraSettings, SceneFile};
+use crate::types::*;
+use crate::xml::{self, Element};
+use crate::{obj, ply};
+use anyhow::{anyhow, Context, Result};
+use nalgebra as na;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+type Mat4 = na::Matrix4<f32>;
+
+/// Mitsuba's independent sampler default.
+const DEFAULT_SAMPLES: u32 = 4;
+/// Mitsuba traces paths until Russian roulette ends them by default.
+const DEFAULT_MAX_DEPTH: usize = 50;
+
+/// Parameter names differ between Mitsuba 0.6 (camel case) and 2/3 (snake case).
+const TO_WORLD: &[&str] = &["to_world", "toWorld"];
+
+pub fn load(path: &Path, resolver: &AssetResolver) -> Result<SceneFile> {
+    let mut importer = Importer::new(resolver);
+    importer.load_file(path)?;
+
+    for warning in &importer.warnings {
+        eprintln!("Warning: {}: {warning}", path.display());
+    }
+    Ok(importer.finish())
+}
+
+struct Importer<'a> {
+    resolver: &'a AssetResolver,
+    scene: Scene,
+    camera: CameraSettings,
+    max_bounces: usize,
+    sky: Sky,
+    /// Values of `<default>` parameters, substituted for `$name`.
+    defaults: HashMap<String, String>,
+    /// BSDFs with an `id`, for `<ref>`.
+    bsdfs: HashMap<String, Material>,
+    warnings: BTreeSet<String>,
+    visiting: Vec<PathBuf>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(resolver: &'a AssetResolver) -> Self {
+        Importer {
+            resolver,
+            scene: Scene::default(),
+            camera: CameraSettings {
+                num_samples: DEFAULT_SAMPLES,
+                ..CameraSettings::default()
+            },
+            max_bounces: DEFAULT_MAX_DEPTH,
+            // Without a constant emitter, rays leaving the scene see black.
+            sky: Sky::new(Sky::default().sun_direction, Sky::MIN_TURBIDITY, 0.0),
+            defaults: HashMap::new(),
+            bsdfs: HashMap::new(),
+            warnings: BTreeSet::new(),
+            visiting: vec![],
+        }
+    }
+
+    fn finish(self) -> SceneFile {
+        let mut scene = self.scene;
+        scene.set_sky(self.sky);
+
+        SceneFile {
+            scene,
+            camera: self.camera,
+            max_bounces: self.max_bounces,
+        }
+    }
+
+    fn warn(&mut self, warning: impl Into<String>) {
+        self.warnings.insert(warning.into());
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        if self.visiting.contains(&canonical) {
+            return Err(anyhow!("Include cycle through `{}`", path.display()));
+        }
+
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scene `{}`", path.display()))?;
+
+        self.visiting.push(canonical);
+        self.load_source(&source, Some(path))
+            .with_context(|| format!("Invalid Mitsuba scene `{}`", path.display()))?;
+        self.visiting.pop();
+        Ok(())
+    }
+
+    fn load_source(&mut self, source: &str, path: Option<&Path>) -> Result<()> {
+        let root = xml::parse(source)?;
+        if root.name != "scene" {
+            return Err(anyhow!("Expected `<scene>` at the top level"));
+        }
+
+        for (idx, element) in root.children.iter().enumerate() {
+            self.load_element(element, path).with_context(|| {
+                let kind = element.attribute("type").unwrap_or_default();
+                format!("Invalid `<{}>` #{idx} {kind}", element.name)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn load_element(&mut self, element: &Element, path: Option<&Path>) -> Result<()> {
+        match element.name.as_str() {
+            "default" => {
+                let name = element.required("name")?.to_owned();
+                let value = element.required("value")?.to_owned();
+                // Values given on the command line win in Mitsuba, there are none here.
+                self.defaults.entry(name).or_insert(value);
+            }
+            "include" => {
+                let filename = self.attribute(element, "filename")?;
+                let resolved = self.resolver.resolve(&filename, path)?;
+                self.load_file(&resolved)?;
+            }
+            "integrator" => {
+                // Wrappers like `aov` hold the actual integrator.
+                let integrator = element
+                    .children
+                    .iter()
+                    .find(|child| child.name == "integrator")
+                    .unwrap_or(element);
+                let depth = self.float(integrator, &["max_depth", "maxDepth"], -1.0)?;
+                if depth >= 0.0 {
+                    self.max_bounces = depth as usize;
+                }
+            }
+            "sensor" => self.sensor(element)?,
+            "bsdf" => {
+                let id = element.required("id")?.to_owned();
+                let material = self.bsdf(element)?;
+                self.bsdfs.insert(id, material);
+            }
+            "shape" => self.shape(element, path)?,
+            "emitter" => self.emitter(element)?,
+            "texture" => self.warn("Textures are not supported, BSDFs keep their constant colors"),
+            "medium" => self.warn("Participating media are not supported"),
+            other => self.warn(format!("Unknown element `<{other}>` skipped")),
+        }
+        Ok(())
+    }
+
+    /// An attribute with `$name` parameters substituted.
+    fn attribute(&self, element: &Element, name: &str) -> Result<String> {
+        let value = element.required(name)?;
+        let mut out = String::new();
+        let mut rest = value;
+        while let Some(dollar) = rest.find('$') {
+            out.push_str(&rest[..dollar]);
+            let len = rest[dollar + 1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len() - dollar - 1);
+            let parameter = &rest[dollar + 1..dollar + 1 + len];
+            out.push_str(
+                self.defaults
+                    .get(parameter)
+                    .ok_or_else(|| anyhow!("Undefined parameter `${parameter}`"))?,
+            );
+            rest = &rest[dollar + 1 + len..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// The child of `element` named by one of `names`.
+    fn property<'e>(&self, element: &'e Element, names: &[&str]) -> Option<&'e Element> {
+        element.children.iter().find(|child| {
+            child
+                .attribute("name")
+                .is_some_and(|name| names.contains(&name))
+        })
+    }
+
+    fn float(&self, element: &Element, names: &[&str], default: f32) -> Result<f32> {
+        match self.property(element, names) {
+            Some(property) => Ok(self.attribute(property, "value")?.trim().parse()?),
+            None => Ok(default),
+        }
+    }
+
+    fn string(&self, element: &Element, names: &[&str]) -> Result<Option<String>> {
+        self.property(element, names)
+            .map(|property| self.attribute(property, "value"))
+            .transpose()
+    }
+
+    fn numbers(&self, element: &Element) -> Result<Vec<f32>> {
+        self.attribute(element, "value")?
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| Ok(word.parse()?))
+            .collect()
+    }
+
+    /// A `point` or `vector` given by `x`, `y`, `z` attributes or a `value` list.
+    fn vec3(&self, element: &Element) -> Result<Vec3> {
+        if element.attribute("value").is_some() {
+            return match self.numbers(element)?.as_slice() {
+                &[x, y, z] => Ok(Vec3::new(x, y, z)),
+                _ => Err(anyhow!("Expected 3 numbers")),
+            };
+        }
+        let coord = |name| -> Result<f32> {
+            match element.attribute(name) {
+                Some(_) => Ok(self.attribute(element, name)?.trim().parse()?),
+                None => Ok(0.0),
+            }
+        };
+        Ok(Vec3::new(coord("x")?, coord("y")?, coord("z")?))
+    }
+
+    /// An `rgb`, `spectrum` or `float` color. Spectra are averaged to grey.
+    fn color(&mut self, element: &Element, names: &[&str]) -> Result<Option<Vec3>> {
+        let Some(property) = self.property(element, names) else {
+            return Ok(None);
+        };
+
+        match property.name.as_str() {
+            "rgb" | "srgb" => match *self.numbers(property)?.as_slice() {
+                [r, g, b] => Ok(Some(Vec3::new(r, g, b))),
+                [v] => Ok(Some(Vec3::new(v, v, v))),
+                _ => Err(anyhow!("Expected 1 or 3 numbers")),
+            },
+            "float" => {
+                let v = self.attribute(property, "value")?.trim().parse()?;
+                Ok(Some(Vec3::new(v, v, v)))
+            }
+            "spectrum" => {
+                let value = self.attribute(property, "value")?;
+                // Either a constant or `wavelength:value` pairs.
+                let values = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|word| !word.is_empty())
+                    .map(|word| word.rsplit(':').next().unwrap_or(word).parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if values.len() > 1 {
+                    self.warn("Spectra are approximated by their average grey");
+                }
+                let average = values.iter().sum::<f32>() / values.len().max(1) as f32;
+                Ok(Some(Vec3::new(average, average, average)))
+            }
+            "texture" | "ref" => {
+                self.warn("Textures are not supported, BSDFs keep their constant colors");
+                Ok(None)
+            }
+            other => Err(anyhow!("Expected a color, got `<{other}>`")),
+        }
+    }
+
+    /// The `to_world` transform of `element`, identity when there is none.
+    fn transform(&self, element: &Element) -> Result<Mat4> {
+        let Some(transform) = self.property(element, TO_WORLD) else {
+            return Ok(Mat4::identity());
+        };
+
+        let mut matrix = Mat4::identity();
+        for op in &transform.children {
+            let op_matrix = match op.name.as_str() {
+                "translate" => Mat4::new_translation(&self.vec3(op)?),
+                "scale" => {
+                    let scale = match op.attribute("value") {
+                        Some(_) => match *self.numbers(op)?.as_slice() {
+                            [s] => Vec3::new(s, s, s),
+                            [x, y, z] => Vec3::new(x, y, z),
+                            _ => return Err(anyhow!("Expected 1 or 3 numbers")),
+                        },
+                        None => {
+                            let coord = |name| -> Result<f32> {
+                                match op.attribute(name) {
+                                    Some(_) => Ok(self.attribute(op, name)?.trim().parse()?),
+                                    None => Ok(1.0),
+                                }
+                            };
+                            Vec3::new(coord("x")?, coord("y")?, coord("z")?)
+                        }
+                    };
+                    Mat4::new_nonuniform_scaling(&scale)
+                }
+                "rotate" => {
+                    let axis = self.vec3(op)?;
+                    let angle: f32 = self.attribute(op, "angle")?.trim().parse()?;
+                    na::Rotation3::from_axis_angle(
+                        &na::Unit::new_normalize(axis),
+                        angle.to_radians(),
+                    )
+                    .to_homogeneous()
+                }
+                "matrix" => {
+                    let values = self.numbers(op)?;
+                    match values.len() {
+                        16 => Mat4::from_row_slice(&values),
+                        9 => na::Matrix3::from_row_slice(&values).to_homogeneous(),
+                        _ => return Err(anyhow!("Expected 9 or 16 numbers")),
+                    }
+                }
+                "lookat" => {
+                    let parse = |name| -> Result<Vec3> {
+                        let values = self
+                            .attribute(op, name)?
+                            .split(|c: char| c == ',' || c.is_whitespace())
+                            .filter(|word| !word.is_empty())
+                            .map(str::parse)
+                            .collect::<Result<Vec<f32>, _>>()?;
+                        match values.as_slice() {
+                            &[x, y, z] => Ok(Vec3::new(x, y, z)),
+                            _ => Err(anyhow!("Expected 3 numbers in `{name}`")),
+                        }
+                    };
+                    let origin = parse("origin")?;
+                    let dir = (parse("target")? - origin).normalize();
+                    let up = if op.attribute("up").is_some() {
+                        parse("up")?
+                    } else {
+                        Vec3::y()
+                    };
+                    let left = up.cross(&dir).normalize();
+                    let up = dir.cross(&left);
+                    let mut look_at = Mat4::identity();
+                    look_at.fixed_view_mut::<3, 1>(0, 0).copy_from(&left);
+                    look_at.fixed_view_mut::<3, 1>(0, 1).copy_from(&up);
+                    look_at.fixed_view_mut::<3, 1>(0, 2).copy_from(&dir);
+                    look_at.fixed_view_mut::<3, 1>(0, 3).copy_from(&origin);
+                    look_at
+                }
+                other => return Err(anyhow!("Unknown transform `<{other}>`")),
+            };
+            matrix = op_matrix * matrix;
+        }
+
+        Ok(matrix)
+    }
+
+    fn sensor(&mut self, element: &Element) -> Result<()> {
+        let to_world = self.transform(element)?;
+        let eye = transform_point(&to_world, Vec3::zeros());
+        self.camera.lookfrom = eye;
+        self.camera.lookat = eye + to_world.transform_vector(&Vec3::z()).normalize();
+        self.camera.vup = to_world.transform_vector(&Vec3::y()).normalize();
+        if to_world.fixed_view::<3, 3>(0, 0).determinant() < 0.0 {
+            self.warn("Mirrored sensor transforms are rendered without the mirroring");
+        }
+
+        match element.required("type")? {
+            kind @ ("perspective" | "thinlens") => {
+                let fov = self.float(element, &["fov"], 90.0)?;
+                if (fov - 90.0).abs() > 0.5 {
+                    self.warn(format!(
+                        "The field of view is fixed at 90°, the sensor's {fov}° are ignored"
+                    ));
+                }
+                if kind == "thinlens" {
+                    let focus_distance =
+                        self.float(element, &["focus_distance", "focusDistance"], 0.0)?;
+                    self.camera.lens = Lens {
+                        aperture: self.float(
+                            element,
+                            &["aperture_radius", "apertureRadius"],
+                            0.0,
+                        )?,
+                        focus_distance: (focus_distance > 0.0).then_some(focus_distance),
+                        ..Lens::default()
+                    };
+                }
+            }
+            "orthographic" => {
+                // The view spans [-1, 1] in camera space.
+                let height = 2.0 * to_world.transform_vector(&Vec3::y()).norm();
+                self.camera.projection = Projection::Orthographic { height };
+            }
+            other => self.warn(format!(
+                "Unknown sensor `{other}`, using a perspective camera"
+            )),
+        }
+
+        if let Some(sampler) = element
+            .children
+            .iter()
+            .find(|child| child.name == "sampler")
+        {
+            self.camera.num_samples = self.float(
+                sampler,
+                &["sample_count", "sampleCount"],
+                DEFAULT_SAMPLES as f32,
+            )? as u32;
+        }
+        Ok(())
+    }
+
+    fn bsdf(&mut self, element: &Element) -> Result<Material> {
+        let grey = Vec3::new(0.5, 0.5, 0.5);
+        let kind = element.required("type")?;
+        let alpha = self.float(
+            element,
+            &["alpha"],
+            if kind.starts_with("rough") { 0.1 } else { 0.0 },
+        )?;
+
+        Ok(match kind {
+            "diffuse" | "roughdiffuse" => {
+                Material::new_lambertian(self.color(element, &["reflectance"])?.unwrap_or(grey))
+            }
+            "conductor" | "roughconductor" => {
+                let albedo =
+                    match self.color(element, &["specular_reflectance", "specularReflectance"])? {
+                        Some(albedo) => albedo,
+                        None => {
+                            let name = self.string(element, &["material"])?;
+                            self.conductor(name.as_deref().unwrap_or("none"))
+                        }
+                    };
+                Material::new_metal(albedo, alpha)
+            }
+            "dielectric" | "roughdielectric" | "thindielectric" => {
+                let int_ior = self.ior(element, &["int_ior", "intIOR"], 1.5046)?;
+                let ext_ior = self.ior(element, &["ext_ior", "extIOR"], 1.000277)?;
+                Material::new_dielectric(int_ior / ext_ior, None)
+            }
+            "plastic" | "roughplastic" => {
+                let albedo = self
+                    .color(element, &["diffuse_reflectance", "diffuseReflectance"])?
+                    .unwrap_or(grey);
+                let int_ior = self.ior(element, &["int_ior", "intIOR"], 1.49)?;
+                Material::new_pbr(albedo, 0.0, 1.0).clearcoat(1.0, alpha, int_ior)
+            }
+            "principled" => Material::new_pbr(
+                self.color(element, &["base_color"])?.unwrap_or(grey),
+                self.float(element, &["metallic"], 0.0)?,
+                self.float(element, &["roughness"], 0.5)?,
+            ),
+            "twosided" | "mask" | "bumpmap" | "normalmap" => {
+                if kind != "twosided" {
+                    self.warn(format!(
+                        "The `{kind}` BSDF is rendered as the BSDF it wraps"
+                    ));
+                }
+                self.material(element)?
+            }
+            other => {
+                self.warn(format!("BSDF `{other}` is rendered as diffuse"));
+                Material::new_lambertian(self.color(element, &["reflectance"])?.unwrap_or(grey))
+            }
+        })
+    }
+
+    /// A numeric index of refraction or one of Mitsuba's named ones.
+    fn ior(&mut self, element: &Element, names: &[&str], default: f32) -> Result<f32> {
+        let Some(value) = self.string(element, names)? else {
+            return Ok(default);
+        };
+        if let Ok(ior) = value.trim().parse() {
+            return Ok(ior);
+        }
+
+        Ok(match value.as_str() {
+            "vacuum" => 1.0,
+            "helium" => 1.000036,
+            "hydrogen" => 1.000132,
+            "air" => 1.000277,
+            "carbon dioxide" => 1.00045,
+            "water" => 1.333,
+            "acetone" => 1.36,
+            "ethanol" => 1.361,
+            "carbon tetrachloride" => 1.461,
+            "glycerol" => 1.4729,
+            "benzene" => 1.501,
+            "silicone oil" => 1.52045,
+            "bromine" => 1.661,
+            "water ice" => 1.31,
+            "fused quartz" => 1.458,
+            "pyrex" => 1.47,
+            "acrylic glass" => 1.49,
+            "polypropylene" => 1.49,
+            "bk7" => 1.5046,
+            "sodium chloride" => 1.544,
+            "amber" => 1.55,
+            "pet" => 1.575,
+            "diamond" => 2.419,
+            other => {
+                self.warn(format!(
+                    "Unknown index of refraction `{other}`, using {default}"
+                ));
+                default
+            }
+        })
+    }
+
+    /// Approximate reflectance of Mitsuba's named conductors.
+    fn conductor(&mut self, name: &str) -> Vec3 {
+        match name {
+            "none" => Vec3::new(1.0, 1.0, 1.0),
+            "Ag" => Vec3::new(0.95, 0.93, 0.88),
+            "Al" => Vec3::new(0.91, 0.92, 0.92),
+            "Au" => Vec3::new(1.0, 0.71, 0.29),
+            "Cr" => Vec3::new(0.55, 0.56, 0.55),
+            "Cu" | "CuZn" => Vec3::new(0.95, 0.64, 0.54),
+            "Fe" => Vec3::new(0.56, 0.57, 0.58),
+            "Ni" => Vec3::new(0.66, 0.61, 0.53),
+            "Pt" => Vec3::new(0.67, 0.64, 0.59),
+            "Ti" => Vec3::new(0.54, 0.5, 0.45),
+            other => {
+                self.warn(format!("Unknown conductor `{other}`, using a grey metal"));
+                Vec3::new(0.9, 0.9, 0.9)
+            }
+        }
+    }
+
+    /// The BSDF of a shape or wrapper, nested or referenced, Mitsuba's diffuse default
+    /// otherwise.
+    fn material(&mut self, element: &Element) -> Result<Material> {
+        for child in &element.children {
+            match child.name.as_str() {
+                "bsdf" => return self.bsdf(child),
+                "ref" if child.attribute("name").is_none_or(|name| name == "bsdf") => {
+                    let id = child.required("id")?;
+                    return self
+                        .bsdfs
+                        .get(id)
+                        .copied()
+                        .ok_or_else(|| anyhow!("Unknown BSDF `{id}`"));
+                }
+                _ => {}
+            }
+        }
+        Ok(Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)))
+    }
+
+    fn shape(&mut self, element: &Element, path: Option<&Path>) -> Result<()> {
+        let to_world = self.transform(element)?;
+        let material = self.material(element)?;
+        let radiance = match element
+            .children
+            .iter()
+            .find(|child| child.name == "emitter")
+        {
+            Some(emitter) if emitter.attribute("type") == Some("area") => Some(
+                self.color(emitter, &["radiance"])?
+                    .unwrap_or(Vec3::new(1.0, 1.0, 1.0)),
+            ),
+            Some(_) => {
+                self.warn("Only area emitters can be attached to shapes");
+                None
+            }
+            None => None,
+        };
+        let scale = to_world.fixed_view::<3, 3>(0, 0).determinant().abs().cbrt();
+
+        match element.required("type")? {
+            "sphere" => {
+                let center = match self.property(element, &["center"]) {
+                    Some(center) => self.vec3(center)?,
+                    None => Vec3::zeros(),
+                };
+                let center = transform_point(&to_world, center);
+                let radius = self.float(element, &["radius"], 1.0)? * scale;
+                match radiance {
+                    Some(radiance) => self
+                        .scene
+                        .new_light(Light::point(center, radius, radiance, 1.0)),
+                    None => self.scene.new_sphere(Sphere::new(center, radius), material),
+                }
+            }
+            "rectangle" => {
+                let [a, b, c, d] = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                    .map(|(x, y)| transform_point(&to_world, Vec3::new(x, y, 0.0)));
+                match radiance {
+                    Some(radiance) => {
+                        let mut normal = transform_normal(&to_world, Vec3::z());
+                        if self.boolean(element, &["flip_normals", "flipNormals"])? {
+                            normal = -normal;
+                        }
+                        let (mut edge_u, mut edge_v) = (b - a, d - a);
+                        if edge_u.cross(&edge_v).dot(&normal) < 0.0 {
+                            std::mem::swap(&mut edge_u, &mut edge_v);
+                        }
+                        self.scene
+                            .new_light(Light::quad(a, edge_u, edge_v, radiance, 1.0));
+                    }
+                    None => self.scene.new_mesh(
+                        TriangleMesh::new(vec![a, b, c, d], vec![0, 1, 2, 0, 2, 3]),
+                        Rotation::identity(),
+                        material,
+                    )?,
+                }
+            }
+            "disk" => {
+                self.warn_unsupported_emitter(radiance);
+                self.scene.new_disk(
+                    Disk::new(
+                        transform_point(&to_world, Vec3::zeros()),
+                        transform_normal(&to_world, Vec3::z()),
+                        scale,
+                    ),
+                    Rotation::identity(),
+                    material,
+                );
+            }
+            "cube" => {
+                self.warn_unsupported_emitter(radiance);
+                let cube = TriangleMesh::cuboid(Vec3::zeros(), Vec3::new(2.0, 2.0, 2.0));
+                self.mesh(cube.positions, None, cube.indices, &to_world, material)?;
+            }
+            kind @ ("obj" | "ply") => {
+                self.warn_unsupported_emitter(radiance);
+                let filename = self
+                    .string(element, &["filename"])?
+                    .ok_or_else(|| anyhow!("Missing `filename`"))?;
+                let resolved = self.resolver.resolve(&filename, path)?;
+                let (positions, normals, indices) = if kind == "obj" {
+                    let mesh = obj::load(&resolved)?;
+                    (mesh.positions, mesh.normals, mesh.indices)
+                } else {
+                    let mesh = ply::load(&resolved)?;
+                    (mesh.positions, mesh.normals, mesh.indices)
+                };
+                let normals = normals.filter(|_| {
+                    !self
+                        .boolean(element, &["face_normals", "faceNormals"])
+                        .unwrap_or(false)
+                });
+                self.mesh(positions, normals, indices, &to_world, material)?;
+            }
+            other => self.warn(format!("Shape `{other}` is not supported")),
+        }
+
+        Ok(())
+    }
+
+    fn boolean(&self, element: &Element, names: &[&str]) -> Result<bool> {
+        match self.string(element, names)? {
+            Some(value) => Ok(value.trim() == "true"),
+            None => Ok(false),
+        }
+    }
+
+    fn warn_unsupported_emitter(&mut self, radiance: Option<Vec3>) {
+        if radiance.is_some() {
+            self.warn("Area emitters are only supported on spheres and rectangles");
+        }
+    }
+
+    fn mesh(
+        &mut self,
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        indices: Vec<u32>,
+        to_world: &Mat4,
+        material: Material,
+    ) -> Result<()> {
+        let positions = positions
+            .into_iter()
+            .map(|p| transform_point(to_world, p))
+            .collect();
+        let mut mesh = TriangleMesh::new(positions, indices);
+        mesh.normals = normals.map(|normals| {
+            normals
+                .into_iter()
+                .map(|n| transform_normal(to_world, n))
+                .collect()
+        });
+        self.scene.new_mesh(mesh, Rotation::identity(), material)
+    }
+
+    fn emitter(&mut self, element: &Element) -> Result<()> {
+        let to_world = self.transform(element)?;
+        let white = Vec3::new(1.0, 1.0, 1.0);
+
+        let light = match element.required("type")? {
+            "point" => {
+                let position = match self.property(element, &["position"]) {
+                    Some(position) => self.vec3(position)?,
+                    None => transform_point(&to_world, Vec3::zeros()),
+                };
+                let intensity = self.color(element, &["intensity"])?.unwrap_or(white);
+                Light::point(position, 0.0, intensity, 1.0)
+            }
+            "spot" => {
+                let intensity = self.color(element, &["intensity"])?.unwrap_or(white);
+                Light::spot(
+                    transform_point(&to_world, Vec3::zeros()),
+                    to_world.transform_vector(&Vec3::z()),
+                    self.float(element, &["cutoff_angle", "cutoffAngle"], 20.0)?
+                        .to_radians(),
+                    0.0,
+                    0.0,
+                    intensity,
+                    1.0,
+                )
+            }
+            "directional" => {
+                let direction = match self.property(element, &["direction"]) {
+                    Some(direction) => self.vec3(direction)?,
+                    None => to_world.transform_vector(&Vec3::z()),
+                };
+                let irradiance = self.color(element, &["irradiance"])?.unwrap_or(white);
+                Light::directional(-direction, 0.0, irradiance, 1.0)
+            }
+            "constant" => {
+                self.warn("Constant emitters are approximated by the daylight sky");
+                let radiance = self.color(element, &["radiance"])?.unwrap_or(white);
+                self.sky = Sky::new(
+                    Sky::default().sun_direction,
+                    Sky::MIN_TURBIDITY,
+                    radiance.max(),
+                );
+                return Ok(());
+            }
+            "sky" | "sunsky" => {
+                self.warn("Sky emitters are approximated by the daylight sky");
+                self.sky = Sky::default();
+                return Ok(());
+            }
+            other => {
+                self.warn(format!("Emitter `{other}` is not supported"));
+                return Ok(());
+            }
+        };
+
+        self.scene.new_light(light);
+        Ok(())
+    }
+}
+
+fn transform_point(matrix: &Mat4, p: Vec3) -> Vec3 {
+    matrix.transform_point(&na::Point3::from(p)).coords
+}
+
+fn transform_normal(matrix: &Mat4, n: Vec3) -> Vec3 {
+    let linear: na::Matrix3<f32> = matrix.fixed_view::<3, 3>(0, 0).into();
+    let inverse_transpose = linear
+        .try_inverse()
+        .map_or(linear, |inverse| inverse.transpose());
+    (inverse_transpose * n).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ron::Value;
+
+    fn import(source: &str) -> SceneFile {
+        let resolver = AssetResolver::new(vec![]);
+        let mut importer = Importer::new(&resolver);
+        importer.load_source(source, None).unwrap();
+        importer.finish()
+    }
+
+    #[test]
+    fn imports_sensor_bsdfs_and_shapes() {
+        let scene_file = import(
+            r#"<scene version="3.0.0">
+                <default name="spp" value="64"/>
+                <integrator type="path"><integer name="max_depth" value="8"/></integrator>
+                <sensor type="perspective">
+                    <float name="fov" value="90"/>
+                    <transform name="to_world">
+                        <lookat origin="0, 1, 4" target="0, 1, 0" up="0, 1, 0"/>
+                    </transform>
+                    <sampler type="independent"><integer name="sample_count" value="$spp"/></sampler>
+                </sensor>
+                <bsdf type="twosided" id="white">
+                    <bsdf type="diffuse"><rgb name="reflectance" value="0.7, 0.7, 0.7"/></bsdf>
+                </bsdf>
+                <shape type="sphere">
+                    <point name="center" x="1" y="0" z="0"/>
+                    <float name="radius" value="0.5"/>
+                    <bsdf type="dielectric"><string name="int_ior" value="diamond"/></bsdf>
+                </shape>
+                <shape type="cube">
+                    <transform name="to_world"><scale value="0.5"/><translate y="0.5"/></transform>
+                    <ref id="white"/>
+                </shape>
+                <shape type="rectangle">
+                    <transform name="to_world">
+                        <rotate x="1" angle="90"/><translate y="2"/>
+                    </transform>
+                    <emitter type="area"><rgb name="radiance" value="10"/></emitter>
+                </shape>
+            </scene>"#,
+        );
+
+        assert_eq!(scene_file.camera.num_samples, 64);
+        assert_eq!(scene_file.max_bounces, 8);
+        assert_eq!(scene_file.camera.lookfrom, Vec3::new(0.0, 1.0, 4.0));
+        assert!((scene_file.camera.lookat - Vec3::new(0.0, 1.0, 3.0)).norm() < 1e-6);
+
+        let ron = scene_file.to_ron();
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(
+            objects[0].field("center").unwrap().as_vec3().unwrap(),
+            Vec3::x()
+        );
+        let materials = ron.field("materials").unwrap().as_map().unwrap();
+        assert!(materials
+            .iter()
+            .any(|(_, material)| material.name() == Some("Dielectric")
+                && material.field("refract_idx").unwrap().as_f32().unwrap() > 2.4));
+
+        // Rotating +z by 90° around x points the rectangle's front down.
+        let lights = ron.field("lights").unwrap().as_list().unwrap();
+        let edge_u = lights[0].field("edge_u").unwrap().as_vec3().unwrap();
+        let edge_v = lights[0].field("edge_v").unwrap().as_vec3().unwrap();
+        assert!(edge_u.cross(&edge_v).y < 0.0);
+        assert_eq!(lights[0].field("intensity"), Some(&Value::Number(10.0)));
+    }
+
+    #[test]
+    fn undefined_parameters_are_errors() {
+        let resolver = AssetResolver::new(vec![]);
+        let mut importer = Importer::new(&resolver);
+        let source =
+            r#"<scene><shape type="sphere"><float name="radius" value="$r"/></shape></scene>"#;
+        let err = importer.load_source(source, None).unwrap_err();
+        assert!(format!("{err:#}").contains("`$r`"), "{err:#}");
+    }
+}
@@ -0,0 +1,121 @@
+//! Reader for the geometry of Wavefront OBJ meshes, as referenced by Mitsuba scenes.
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct ObjMesh {
+    pub positions: Vec<Vec3>,
+    /// Present when every face corner has a normal.
+    pub normals: Option<Vec<Vec3>>,
+    /// Three vertex indices per triangle, larger faces are split into fans.
+    pub indices: Vec<u32>,
+}
+
+pub fn load(path: &Path) -> Result<ObjMesh> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read mesh `{}`", path.display()))?;
+    parse(&source).with_context(|| format!("Invalid OBJ mesh `{}`", path.display()))
+}
+
+fn parse(source: &str) -> Result<ObjMesh> {
+    let mut positions = vec![];
+    let mut normals = vec![];
+    // Corners with the same position and normal share a vertex.
+    let mut vertices: HashMap<(usize, Option<usize>), u32> = HashMap::new();
+    let mut mesh = ObjMesh {
+        positions: vec![],
+        normals: Some(vec![]),
+        indices: vec![],
+    };
+
+    for (idx, line) in source.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let parse_vec3 = |words: std::str::SplitWhitespace| -> Result<Vec3> {
+            let coords = words
+                .take(3)
+                .map(str::parse)
+                .collect::<Result<Vec<f32>, _>>()?;
+            match coords.as_slice() {
+                &[x, y, z] => Ok(Vec3::new(x, y, z)),
+                _ => Err(anyhow!("Expected 3 coordinates")),
+            }
+        };
+
+        let parsed: Result<()> = (|| {
+            match words.next() {
+                Some("v") => positions.push(parse_vec3(words)?),
+                Some("vn") => normals.push(parse_vec3(words)?),
+                Some("f") => {
+                    let mut face = vec![];
+                    for corner in words {
+                        let mut refs = corner.split('/');
+                        let position = resolve(refs.next(), positions.len())?
+                            .ok_or_else(|| anyhow!("Face corner without a position"))?;
+                        let normal = resolve(refs.nth(1), normals.len())?;
+
+                        let next = vertices.len() as u32;
+                        let vertex = *vertices.entry((position, normal)).or_insert_with(|| {
+                            mesh.positions.push(positions[position]);
+                            match (&mut mesh.normals, normal) {
+                                (Some(mesh_normals), Some(normal)) => {
+                                    mesh_normals.push(normals[normal])
+                                }
+                                (normals, _) => *normals = None,
+                            }
+                            next
+                        });
+                        face.push(vertex);
+                    }
+
+                    for i in 1..face.len().saturating_sub(1) {
+                        mesh.indices.extend([face[0], face[i], face[i + 1]]);
+                    }
+                }
+                // Texture coordinates, groups, materials and smoothing are not used.
+                _ => {}
+            }
+            Ok(())
+        })();
+        parsed.with_context(|| format!("On line {}", idx + 1))?;
+    }
+
+    Ok(mesh)
+}
+
+/// Index of an OBJ reference into `len` items, 1-based or negative from the end.
+fn resolve(reference: Option<&str>, len: usize) -> Result<Option<usize>> {
+    let Some(reference) = reference.filter(|r| !r.is_empty()) else {
+        return Ok(None);
+    };
+    let index: i64 = reference.parse()?;
+    let resolved = if index < 0 {
+        len as i64 + index
+    } else {
+        index - 1
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(anyhow!("Reference {index} out of range"));
+    }
+    Ok(Some(resolved as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_corners_and_triangulates() {
+        let mesh = parse(
+            "# quad\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nvt 0 0\nf 1//1 2//1 3//1 -1//-1\n",
+        )
+        .unwrap();
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.normals.unwrap(), vec![Vec3::z(); 4]);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2/2 3/3\n").unwrap();
+        assert!(mesh.normals.is_none());
+        assert!(parse("v 0 0 0\nf 1 2 3\n").is_err());
+    }
+}
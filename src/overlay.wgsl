@@ -0,0 +1,74 @@
+struct OverlayUniform {
+    cols: u32,
+    rows: u32,
+    lines: u32,
+    scale: u32,
+};
+
+struct Font {
+    glyphs: array<u32>,
+};
+
+struct Text {
+    cells: array<u32>,
+};
+
+@group(0) @binding(0) var<uniform> overlay: OverlayUniform;
+@group(0) @binding(1) var<storage> font: Font;
+@group(0) @binding(2) var<storage> text: Text;
+
+const GLYPH_WIDTH: u32 = 5u;
+const GLYPH_HEIGHT: u32 = 7u;
+const CELL_WIDTH: u32 = 6u;
+const CELL_HEIGHT: u32 = 9u;
+
+const SEVERITY_INFO: u32 = 0u;
+const SEVERITY_WARNING: u32 = 1u;
+
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> @builtin(position) vec4<f32> {
+    var VERTEX: array<vec2<f32>, 4> = array<vec2<f32>, 4>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(-1.0, 1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0)
+    );
+
+    return vec4<f32>(VERTEX[in_vertex_index], 0.0, 1.0);
+}
+
+fn severityColor(severity: u32) -> vec3<f32> {
+    if severity == SEVERITY_INFO {
+        return vec3<f32>(0.8, 0.8, 0.8);
+    } else if severity == SEVERITY_WARNING {
+        return vec3<f32>(1.0, 0.85, 0.2);
+    }
+
+    return vec3<f32>(1.0, 0.3, 0.3);
+}
+
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    var pixel = vec2<u32>(position.xy) / overlay.scale;
+    var cell = pixel / vec2<u32>(CELL_WIDTH, CELL_HEIGHT);
+
+    if cell.x >= overlay.cols || cell.y >= overlay.lines {
+        discard;
+    }
+
+    var background = vec4<f32>(0.0, 0.0, 0.0, 0.7);
+    var code = text.cells[cell.y * overlay.cols + cell.x];
+    var ch = code & 0xffu;
+    var local = pixel - cell * vec2<u32>(CELL_WIDTH, CELL_HEIGHT);
+
+    if ch < 32u || ch > 126u || local.x >= GLYPH_WIDTH || local.y >= GLYPH_HEIGHT {
+        return background;
+    }
+
+    var row = font.glyphs[(ch - 32u) * GLYPH_HEIGHT + local.y];
+    if ((row >> (GLYPH_WIDTH - 1u - local.x)) & 1u) == 1u {
+        return vec4<f32>(severityColor(code >> 8u), 1.0);
+    }
+
+    return background;
+}
@@ -0,0 +1,1025 @@
+//! Import of a subset of the PBRT v3 and v4 scene formats, for rendering existing test
+//! scenes.
+//!
+//! Supported are `LookAt` and the other transform directives, attribute blocks, the
+//! perspective, orthographic and spherical cameras, `Sampler` pixel samples, `Integrator`
+//! max depth, the common materials (diffuse/matte, conductor/metal, mirror,
+//! dielectric/glass, coateddiffuse/plastic) also as named materials, sphere, disk,
+//! triangle, bilinear and PLY mesh shapes, point, spot, distant and infinite lights,
+//! diffuse area lights on spheres and quads, object instancing of meshes and
+//! `Include`/`Import`. Everything else is skipped with a warning, textures leave materials
+//! with their constant colors.
+//!
+//! The image size comes from the window or `--size`, not from `Film`, and the
+//! perspective field of view is fixed at 90°. PBRT's camera space is left-handed, so the
+//! world is mirrored about the camera's vertical plane to get the image PBRT renders.
+use crate::assets::AssetResolver;
+use crate::camera::{Lens, Projection};
+use crate::ply;
+use crate::scene::{Disk, Light, Material, MeshId, Rotation, Scene, Sky, Sphere, TriangleMesh};
+use crate::scene_file::{CameraSettings, SceneFile};
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use nalgebra as na;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+type Mat4 = na::Matrix4<f32>;
+
+/// PBRT's defaults, so scenes render with the settings they were made for.
+const DEFAULT_SAMPLES: u32 = 16;
+const DEFAULT_MAX_DEPTH: usize = 5;
+
+pub fn load(path: &Path, resolver: &AssetResolver) -> Result<SceneFile> {
+    let mut importer = Importer::new(resolver);
+    importer.load_file(path)?;
+
+    for warning in &importer.warnings {
+        eprintln!("Warning: {}: {warning}", path.display());
+    }
+    Ok(importer.finish())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            '\n' => {
+                line += 1;
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '[' | ']' => {
+                chars.next();
+                tokens.push((if c == '[' { Token::Open } else { Token::Close }, line));
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                        None => return Err(anyhow!("Unterminated string on line {line}")),
+                    }
+                }
+                tokens.push((Token::Str(text), line));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) =
+                    chars.next_if(|&c| !c.is_whitespace() && !matches!(c, '[' | ']' | '"' | '#'))
+                {
+                    word.push(c);
+                }
+
+                let token = if c.is_ascii_alphabetic() {
+                    Token::Ident(word)
+                } else {
+                    Token::Num(
+                        word.parse()
+                            .map_err(|_| anyhow!("Invalid number `{word}` on line {line}"))?,
+                    )
+                };
+                tokens.push((token, line));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+}
+
+/// A parameter like `"float radius" [ 2 ]`.
+#[derive(Debug)]
+struct Param {
+    ty: String,
+    name: String,
+    values: Vec<Arg>,
+}
+
+#[derive(Debug)]
+struct Directive {
+    name: String,
+    line: usize,
+    /// Values before the parameter list, e.g. the shape type or `LookAt` numbers.
+    args: Vec<Arg>,
+    params: Vec<Param>,
+}
+
+fn parse_directives(tokens: Vec<(Token, usize)>) -> Result<Vec<Directive>> {
+    fn arg(token: Token, line: usize) -> Result<Arg> {
+        match token {
+            Token::Num(n) => Ok(Arg::Num(n)),
+            Token::Str(s) => Ok(Arg::Str(s)),
+            Token::Ident(ident) if ident == "true" || ident == "false" => {
+                Ok(Arg::Bool(ident == "true"))
+            }
+            other => Err(anyhow!("Unexpected {other:?} on line {line}")),
+        }
+    }
+
+    let mut directives: Vec<Directive> = vec![];
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some((token, line)) = tokens.next() {
+        let Token::Ident(name) = token else {
+            return Err(anyhow!(
+                "Expected a directive on line {line}, got {token:?}"
+            ));
+        };
+        let mut directive = Directive {
+            name,
+            line,
+            args: vec![],
+            params: vec![],
+        };
+
+        loop {
+            let is_value = matches!(
+                tokens.peek(),
+                Some((Token::Num(_) | Token::Str(_) | Token::Open, _))
+            ) || matches!(tokens.peek(), Some((Token::Ident(i), _)) if i == "true" || i == "false");
+            if !is_value {
+                break;
+            }
+
+            let (token, line) = tokens.next().unwrap();
+            let values = match token {
+                Token::Open => {
+                    let mut values = vec![];
+                    loop {
+                        match tokens.next() {
+                            Some((Token::Close, _)) => break,
+                            Some((token, line)) => values.push(arg(token, line)?),
+                            None => return Err(anyhow!("Unterminated `[` on line {line}")),
+                        }
+                    }
+                    values
+                }
+                // "type name" declares a parameter, its value follows.
+                Token::Str(declaration) if declaration.split_whitespace().count() == 2 => {
+                    let mut words = declaration.split_whitespace();
+                    let (ty, name) = (words.next().unwrap(), words.next().unwrap());
+                    let values = match tokens.next() {
+                        Some((Token::Open, _)) => {
+                            let mut values = vec![];
+                            loop {
+                                match tokens.next() {
+                                    Some((Token::Close, _)) => break,
+                                    Some((token, line)) => values.push(arg(token, line)?),
+                                    None => return Err(anyhow!("Unterminated `[` on line {line}")),
+                                }
+                            }
+                            values
+                        }
+                        Some((token, line)) => vec![arg(token, line)?],
+                        None => return Err(anyhow!("Missing value of `{name}` on line {line}")),
+                    };
+                    directive.params.push(Param {
+                        ty: ty.to_owned(),
+                        name: name.to_owned(),
+                        values,
+                    });
+                    continue;
+                }
+                token => vec![arg(token, line)?],
+            };
+            directive.args.extend(values);
+        }
+
+        directives.push(directive);
+    }
+
+    Ok(directives)
+}
+
+impl Directive {
+    /// The first string argument, e.g. the type of a shape.
+    fn kind(&self) -> Result<&str> {
+        match self.args.first() {
+            Some(Arg::Str(kind)) => Ok(kind),
+            _ => Err(anyhow!("`{}` needs a type", self.name)),
+        }
+    }
+
+    fn numbers(&self) -> Vec<f32> {
+        numbers(&self.args)
+    }
+
+    fn param(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|param| param.name == name)
+    }
+
+    fn floats(&self, name: &str) -> Option<Vec<f32>> {
+        self.param(name).map(|param| numbers(&param.values))
+    }
+
+    fn float(&self, name: &str, default: f32) -> f32 {
+        self.floats(name)
+            .and_then(|values| values.first().copied())
+            .unwrap_or(default)
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.param(name)?.values.first()? {
+            Arg::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn point(&self, name: &str, default: Vec3) -> Vec3 {
+        match self.floats(name).as_deref() {
+            Some([x, y, z]) => Vec3::new(*x, *y, *z),
+            _ => default,
+        }
+    }
+
+    fn points(&self, name: &str) -> Option<Vec<Vec3>> {
+        let values = self.floats(name)?;
+        Some(
+            values
+                .chunks_exact(3)
+                .map(|p| Vec3::new(p[0], p[1], p[2]))
+                .collect(),
+        )
+    }
+
+    fn indices(&self, name: &str) -> Option<Vec<u32>> {
+        Some(self.floats(name)?.into_iter().map(|i| i as u32).collect())
+    }
+}
+
+fn numbers(args: &[Arg]) -> Vec<f32> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            Arg::Num(n) => Some(*n as f32),
+            _ => None,
+        })
+        .collect()
+}
+
+/// State saved by `AttributeBegin`.
+#[derive(Clone)]
+struct Attributes {
+    /// `None` for `interface` materials, which only bound participating media.
+    material: Option<Material>,
+    /// Radiance of shapes that follow an `AreaLightSource`.
+    area_light: Option<Vec3>,
+    reverse_orientation: bool,
+}
+
+struct Importer<'a> {
+    resolver: &'a AssetResolver,
+    scene: Scene,
+    camera: CameraSettings,
+    max_bounces: usize,
+    sky: Sky,
+    ctm: Mat4,
+    /// Where `WorldBegin` starts, see the module docs.
+    world: Mat4,
+    attributes: Attributes,
+    /// Saved transform and attributes, and whether only the transform is restored.
+    stack: Vec<(Mat4, Attributes, bool)>,
+    named_materials: HashMap<String, Option<Material>>,
+    named_coordinates: HashMap<String, Mat4>,
+    /// The meshes of the object between `ObjectBegin` and `ObjectEnd`, only placed by
+    /// `ObjectInstance`.
+    object: Option<(String, Vec<(MeshId, Material)>)>,
+    objects: HashMap<String, Vec<(MeshId, Material)>>,
+    warnings: BTreeSet<String>,
+    visiting: Vec<PathBuf>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(resolver: &'a AssetResolver) -> Self {
+        Importer {
+            resolver,
+            scene: Scene::default(),
+            camera: CameraSettings {
+                num_samples: DEFAULT_SAMPLES,
+                ..CameraSettings::default()
+            },
+            max_bounces: DEFAULT_MAX_DEPTH,
+            // Without an infinite light, rays leaving the scene see black.
+            sky: Sky::new(Sky::default().sun_direction, Sky::MIN_TURBIDITY, 0.0),
+            ctm: Mat4::identity(),
+            world: Mat4::identity(),
+            attributes: Attributes {
+                material: Some(Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5))),
+                area_light: None,
+                reverse_orientation: false,
+            },
+            stack: vec![],
+            named_materials: HashMap::new(),
+            named_coordinates: HashMap::new(),
+            object: None,
+            objects: HashMap::new(),
+            warnings: BTreeSet::new(),
+            visiting: vec![],
+        }
+    }
+
+    fn finish(self) -> SceneFile {
+        let mut scene = self.scene;
+        scene.set_sky(self.sky);
+
+        SceneFile {
+            scene,
+            camera: self.camera,
+            max_bounces: self.max_bounces,
+        }
+    }
+
+    fn warn(&mut self, warning: impl Into<String>) {
+        self.warnings.insert(warning.into());
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<()> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        if self.visiting.contains(&canonical) {
+            return Err(anyhow!("Include cycle through `{}`", path.display()));
+        }
+
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scene `{}`", path.display()))?;
+
+        self.visiting.push(canonical);
+        self.load_source(&source, Some(path))
+            .with_context(|| format!("Invalid PBRT scene `{}`", path.display()))?;
+        self.visiting.pop();
+        Ok(())
+    }
+
+    fn load_source(&mut self, source: &str, path: Option<&Path>) -> Result<()> {
+        for directive in parse_directives(tokenize(source)?)? {
+            self.apply(&directive, path).with_context(|| {
+                format!("Invalid `{}` on line {}", directive.name, directive.line)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, d: &Directive, path: Option<&Path>) -> Result<()> {
+        match d.name.as_str() {
+            "LookAt" => {
+                let &[ex, ey, ez, lx, ly, lz, ux, uy, uz] = d.numbers().as_slice() else {
+                    return Err(anyhow!("Expected 9 numbers"));
+                };
+                self.ctm *= Mat4::look_at_lh(
+                    &na::Point3::new(ex, ey, ez),
+                    &na::Point3::new(lx, ly, lz),
+                    &Vec3::new(ux, uy, uz),
+                );
+            }
+            "Translate" => self.ctm *= Mat4::new_translation(&vec3(&d.numbers())?),
+            "Scale" => self.ctm *= Mat4::new_nonuniform_scaling(&vec3(&d.numbers())?),
+            "Rotate" => {
+                let &[angle, x, y, z] = d.numbers().as_slice() else {
+                    return Err(anyhow!("Expected an angle and an axis"));
+                };
+                let axis = na::Unit::new_normalize(Vec3::new(x, y, z));
+                self.ctm *=
+                    na::Rotation3::from_axis_angle(&axis, angle.to_radians()).to_homogeneous();
+            }
+            "Transform" | "ConcatTransform" => {
+                let values = d.numbers();
+                if values.len() != 16 {
+                    return Err(anyhow!("Expected 16 numbers"));
+                }
+                // Listed column by column.
+                let matrix = Mat4::from_column_slice(&values);
+                if d.name == "Transform" {
+                    self.ctm = self.world * matrix;
+                } else {
+                    self.ctm *= matrix;
+                }
+            }
+            "Identity" => self.ctm = self.world,
+            "CoordinateSystem" => {
+                self.named_coordinates
+                    .insert(d.kind()?.to_owned(), self.ctm);
+            }
+            "CoordSysTransform" => match self.named_coordinates.get(d.kind()?) {
+                Some(&ctm) => self.ctm = ctm,
+                None => self.warn(format!("Unknown coordinate system `{}`", d.kind()?)),
+            },
+            "ReverseOrientation" => {
+                self.attributes.reverse_orientation = !self.attributes.reverse_orientation;
+            }
+            "Camera" => self.set_camera(d)?,
+            "Sampler" => {
+                self.camera.num_samples = d.float("pixelsamples", DEFAULT_SAMPLES as f32) as u32;
+            }
+            "Integrator" => {
+                self.max_bounces = d.float("maxdepth", DEFAULT_MAX_DEPTH as f32) as usize;
+            }
+            "Film" | "PixelFilter" | "Accelerator" | "ColorSpace" | "Option" => {}
+            "WorldBegin" => {
+                self.ctm = self.world;
+                self.named_coordinates.insert("world".to_owned(), self.ctm);
+            }
+            "WorldEnd" => {}
+            "AttributeBegin" | "TransformBegin" => {
+                self.stack.push((
+                    self.ctm,
+                    self.attributes.clone(),
+                    d.name == "TransformBegin",
+                ));
+            }
+            "AttributeEnd" | "TransformEnd" => {
+                let (ctm, attributes, transform_only) = self
+                    .stack
+                    .pop()
+                    .ok_or_else(|| anyhow!("No matching `AttributeBegin`"))?;
+                self.ctm = ctm;
+                if !transform_only {
+                    self.attributes = attributes;
+                }
+            }
+            "Material" => self.attributes.material = self.material(d.kind()?, d),
+            "MakeNamedMaterial" => {
+                let kind = d.string("type").unwrap_or("diffuse").to_owned();
+                let material = self.material(&kind, d);
+                self.named_materials.insert(d.kind()?.to_owned(), material);
+            }
+            "NamedMaterial" => {
+                let name = d.kind()?;
+                self.attributes.material = *self
+                    .named_materials
+                    .get(name)
+                    .ok_or_else(|| anyhow!("Unknown material `{name}`"))?;
+            }
+            "Texture" => {
+                self.warn("Textures are not supported, materials keep their constant colors")
+            }
+            "LightSource" => self.light(d)?,
+            "AreaLightSource" => {
+                let radiance = self.color(d, "L").unwrap_or(Vec3::new(1.0, 1.0, 1.0));
+                self.attributes.area_light = Some(radiance * d.float("scale", 1.0));
+            }
+            "Shape" => self.shape(d, path)?,
+            "ObjectBegin" => {
+                // Objects keep the transform and attributes they were started with.
+                self.stack.push((self.ctm, self.attributes.clone(), false));
+                self.object = Some((d.kind()?.to_owned(), vec![]));
+            }
+            "ObjectEnd" => {
+                let (name, meshes) = self
+                    .object
+                    .take()
+                    .ok_or_else(|| anyhow!("No matching `ObjectBegin`"))?;
+                self.objects.insert(name, meshes);
+                let (ctm, attributes, _) = self
+                    .stack
+                    .pop()
+                    .ok_or_else(|| anyhow!("No matching `ObjectBegin`"))?;
+                self.ctm = ctm;
+                self.attributes = attributes;
+            }
+            "ObjectInstance" => self.object_instance(d.kind()?)?,
+            "MakeNamedMedium" | "MediumInterface" => {
+                self.warn("Participating media are not supported")
+            }
+            "Include" | "Import" => {
+                let resolved = self.resolver.resolve(d.kind()?, path)?;
+                self.load_file(&resolved)?;
+            }
+            other => self.warn(format!("Unknown directive `{other}` skipped")),
+        }
+
+        Ok(())
+    }
+
+    fn set_camera(&mut self, d: &Directive) -> Result<()> {
+        let camera_to_world = self
+            .ctm
+            .try_inverse()
+            .ok_or_else(|| anyhow!("The camera transform is not invertible"))?;
+        self.named_coordinates
+            .insert("camera".to_owned(), camera_to_world);
+
+        let eye = camera_to_world
+            .transform_point(&na::Point3::origin())
+            .coords;
+        let forward = camera_to_world.transform_vector(&Vec3::z()).normalize();
+        let up = camera_to_world.transform_vector(&Vec3::y()).normalize();
+        let right = camera_to_world.transform_vector(&Vec3::x()).normalize();
+
+        // Our camera has `up × forward` on the left, PBRT on the right unless the camera
+        // transform flips handedness (the common `Scale -1 1 1`).
+        let flipped = camera_to_world.fixed_view::<3, 3>(0, 0).determinant() < 0.0;
+        self.world = if flipped {
+            Mat4::identity()
+        } else {
+            let reflection = na::Matrix3::identity() - 2.0 * right * right.transpose();
+            Mat4::new_translation(&eye) * reflection.to_homogeneous() * Mat4::new_translation(&-eye)
+        };
+
+        self.camera.lookfrom = eye;
+        self.camera.lookat = eye + forward;
+        self.camera.vup = up;
+
+        match d.kind()? {
+            "perspective" | "realistic" => {
+                if d.kind()? == "realistic" {
+                    self.warn("The realistic camera is rendered as a perspective camera");
+                }
+                let fov = d.float("fov", 90.0);
+                if (fov - 90.0).abs() > 0.5 {
+                    self.warn(format!(
+                        "The field of view is fixed at 90°, the camera's {fov}° are ignored"
+                    ));
+                }
+                let aperture = d.float("lensradius", 0.0);
+                if aperture > 0.0 {
+                    self.camera.lens = Lens {
+                        aperture,
+                        focus_distance: d.floats("focaldistance").and_then(|f| f.first().copied()),
+                        ..Lens::default()
+                    };
+                }
+            }
+            "orthographic" => {
+                let height = match d.floats("screenwindow").as_deref() {
+                    Some(&[_, _, bottom, top]) => top - bottom,
+                    _ => 2.0,
+                };
+                self.camera.projection = Projection::Orthographic { height };
+            }
+            "spherical" => {
+                if d.string("mapping").unwrap_or("equalarea") != "equirectangular" {
+                    self.warn("Spherical cameras are rendered with the equirectangular mapping");
+                }
+                self.camera.projection = Projection::Equirectangular;
+            }
+            other => self.warn(format!(
+                "Unknown camera `{other}`, using a perspective camera"
+            )),
+        }
+
+        Ok(())
+    }
+
+    /// An RGB parameter, spectra are approximated by white.
+    fn color(&mut self, d: &Directive, name: &str) -> Option<Vec3> {
+        let param = d.param(name)?;
+        match param.ty.as_str() {
+            "rgb" | "color" => vec3(&numbers(&param.values)).ok(),
+            "texture" => {
+                self.warn("Textures are not supported, materials keep their constant colors");
+                None
+            }
+            _ => {
+                self.warn(format!(
+                    "Spectral `{name}` values are approximated by white"
+                ));
+                Some(Vec3::new(1.0, 1.0, 1.0))
+            }
+        }
+    }
+
+    /// Maps a PBRT material (v4 or v3 names and parameters) onto ours.
+    fn material(&mut self, kind: &str, d: &Directive) -> Option<Material> {
+        let grey = Vec3::new(0.5, 0.5, 0.5);
+        let roughness = d.float("roughness", d.float("uroughness", 0.0));
+        let albedo = |importer: &mut Self, default: Vec3| {
+            importer
+                .color(d, "reflectance")
+                .or_else(|| importer.color(d, "Kd"))
+                .unwrap_or(default)
+        };
+
+        Some(match kind {
+            "diffuse" | "matte" => Material::new_lambertian(albedo(self, grey)),
+            "conductor" | "metal" => {
+                // PBRT's default conductor is copper.
+                let albedo = albedo(self, Vec3::new(0.95, 0.64, 0.54));
+                Material::new_metal(albedo, roughness)
+            }
+            "mirror" => {
+                Material::new_metal(self.color(d, "Kr").unwrap_or(Vec3::new(0.9, 0.9, 0.9)), 0.0)
+            }
+            "dielectric" | "glass" | "thindielectric" => {
+                Material::new_dielectric(d.float("eta", d.float("index", 1.5)), None)
+            }
+            "coateddiffuse" | "plastic" | "substrate" => {
+                Material::new_pbr(albedo(self, grey), 0.0, 1.0).clearcoat(1.0, roughness, 1.5)
+            }
+            "interface" | "" | "none" => return None,
+            other => {
+                self.warn(format!("Material `{other}` is rendered as diffuse"));
+                Material::new_lambertian(albedo(self, grey))
+            }
+        })
+    }
+
+    fn light(&mut self, d: &Directive) -> Result<()> {
+        let radiance = |importer: &mut Self, name: &str| {
+            importer.color(d, name).unwrap_or(Vec3::new(1.0, 1.0, 1.0)) * d.float("scale", 1.0)
+        };
+
+        let light = match d.kind()? {
+            "point" => {
+                let position = self.point(d.point("from", Vec3::zeros()));
+                let (color, intensity) = split(radiance(self, "I"));
+                Light::point(position, 0.0, color, intensity)
+            }
+            "spot" => {
+                let from = self.point(d.point("from", Vec3::zeros()));
+                let to = self.point(d.point("to", Vec3::z()));
+                let (color, intensity) = split(radiance(self, "I"));
+                Light::spot(
+                    from,
+                    to - from,
+                    d.float("coneangle", 30.0).to_radians(),
+                    0.0,
+                    0.0,
+                    color,
+                    intensity,
+                )
+            }
+            "distant" => {
+                let from = self.point(d.point("from", Vec3::zeros()));
+                let to = self.point(d.point("to", Vec3::z()));
+                let (color, intensity) = split(radiance(self, "L"));
+                Light::directional(from - to, 0.0, color, intensity)
+            }
+            "infinite" => {
+                if d.string("filename").is_some() {
+                    self.warn("Environment maps are not supported, using the daylight sky");
+                } else {
+                    self.warn("Infinite lights are approximated by the daylight sky");
+                }
+                let (_, intensity) = split(radiance(self, "L"));
+                self.sky = Sky::new(Sky::default().sun_direction, Sky::MIN_TURBIDITY, intensity);
+                return Ok(());
+            }
+            other => {
+                self.warn(format!("Light `{other}` is not supported"));
+                return Ok(());
+            }
+        };
+
+        self.scene.new_light(light);
+        Ok(())
+    }
+
+    fn shape(&mut self, d: &Directive, path: Option<&Path>) -> Result<()> {
+        // Shapes with an interface material only bound media.
+        let Some(material) = self.attributes.material else {
+            return Ok(());
+        };
+        if self.object.is_some() && matches!(d.kind()?, "sphere" | "disk") {
+            self.warn("Only meshes can be instanced, other shapes in objects are skipped");
+            return Ok(());
+        }
+        let scale = self.ctm.fixed_view::<3, 3>(0, 0).determinant().abs().cbrt();
+
+        match d.kind()? {
+            "sphere" => {
+                let center = self.point(Vec3::zeros());
+                let radius = d.float("radius", 1.0) * scale;
+                match self.attributes.area_light {
+                    Some(radiance) => {
+                        let (color, intensity) = split(radiance);
+                        self.scene
+                            .new_light(Light::point(center, radius, color, intensity));
+                    }
+                    None => self.scene.new_sphere(Sphere::new(center, radius), material),
+                }
+            }
+            "disk" => {
+                if self.attributes.area_light.is_some() {
+                    self.warn("Area lights are only supported on spheres and quads");
+                }
+                let center = self.point(Vec3::new(0.0, 0.0, d.float("height", 0.0)));
+                let normal = self.normal(Vec3::z());
+                let radius = d.float("radius", 1.0) * scale;
+                self.scene.new_disk(
+                    Disk::new(center, normal, radius),
+                    Rotation::identity(),
+                    material,
+                );
+            }
+            "trianglemesh" | "loopsubdiv" => {
+                if d.kind()? == "loopsubdiv" {
+                    self.warn("Subdivision surfaces are rendered as their control mesh");
+                }
+                let positions = d.points("P").ok_or_else(|| anyhow!("Missing `P`"))?;
+                let indices = match d.indices("indices") {
+                    Some(indices) => indices,
+                    None if positions.len() == 3 => vec![0, 1, 2],
+                    None => return Err(anyhow!("Missing `indices`")),
+                };
+                self.mesh(positions, d.points("N"), indices, material)?;
+            }
+            "bilinearmesh" => {
+                let positions = d.points("P").ok_or_else(|| anyhow!("Missing `P`"))?;
+                let quads = d.indices("indices").unwrap_or_else(|| vec![0, 1, 2, 3]);
+                // Vertices of a bilinear patch are p00, p10, p01, p11.
+                let indices = quads
+                    .chunks_exact(4)
+                    .flat_map(|q| [q[0], q[1], q[3], q[0], q[3], q[2]])
+                    .collect();
+                self.mesh(positions, d.points("N"), indices, material)?;
+            }
+            "plymesh" => {
+                let filename = d
+                    .string("filename")
+                    .ok_or_else(|| anyhow!("Missing `filename`"))?;
+                let ply = ply::load(&self.resolver.resolve(filename, path)?)?;
+                self.mesh(ply.positions, ply.normals, ply.indices, material)?;
+            }
+            other => self.warn(format!("Shape `{other}` is not supported")),
+        }
+
+        Ok(())
+    }
+
+    fn mesh(
+        &mut self,
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        indices: Vec<u32>,
+        material: Material,
+    ) -> Result<()> {
+        let positions: Vec<Vec3> = positions.into_iter().map(|p| self.point(p)).collect();
+
+        if let Some(radiance) = self.attributes.area_light {
+            if self.object.is_some() {
+                self.warn("Area lights can't be instanced, area lights in objects are skipped");
+                return Ok(());
+            }
+            return self.quad_light(&positions, &indices, radiance);
+        }
+
+        let mut mesh = TriangleMesh::new(positions, indices);
+        mesh.normals = normals.map(|normals| normals.into_iter().map(|n| self.normal(n)).collect());
+        match &mut self.object {
+            Some((_, meshes)) => {
+                meshes.push((self.scene.add_mesh(mesh)?, material));
+                Ok(())
+            }
+            None => self.scene.new_mesh(mesh, Rotation::identity(), material),
+        }
+    }
+
+    /// Places the meshes of object `name` with the current transform. They were defined with
+    /// the transform at the time, which already includes the mirroring of `world`.
+    fn object_instance(&mut self, name: &str) -> Result<()> {
+        let meshes = self
+            .objects
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown object `{name}`"))?;
+        let world_inverse = self.world.try_inverse().unwrap_or_else(Mat4::identity);
+        let transform = self.ctm * world_inverse;
+        for &(mesh, material) in meshes {
+            self.scene.instance_mesh(mesh, transform, material)?;
+        }
+        Ok(())
+    }
+
+    /// Area lights on two triangles forming a parallelogram become quad lights.
+    fn quad_light(&mut self, positions: &[Vec3], indices: &[u32], radiance: Vec3) -> Result<()> {
+        let corners = if positions.len() == 4 && indices.len() == 6 {
+            parallelogram(positions)
+        } else {
+            None
+        };
+        let Some((corner, mut edge_u, mut edge_v)) = corners else {
+            self.warn("Area lights are only supported on spheres and quads");
+            return Ok(());
+        };
+
+        // Emit on the side PBRT does, the front of the first triangle.
+        let [a, b, c] = [0, 1, 2].map(|i| positions[indices[i] as usize]);
+        let mut normal = (a - c).cross(&(b - c));
+        let swaps_handedness = self.ctm.fixed_view::<3, 3>(0, 0).determinant() < 0.0;
+        if swaps_handedness != self.attributes.reverse_orientation {
+            normal = -normal;
+        }
+        if edge_u.cross(&edge_v).dot(&normal) < 0.0 {
+            std::mem::swap(&mut edge_u, &mut edge_v);
+        }
+
+        let (color, intensity) = split(radiance);
+        self.scene
+            .new_light(Light::quad(corner, edge_u, edge_v, color, intensity));
+        Ok(())
+    }
+
+    fn point(&self, p: Vec3) -> Vec3 {
+        self.ctm.transform_point(&na::Point3::from(p)).coords
+    }
+
+    fn normal(&self, n: Vec3) -> Vec3 {
+        let linear: na::Matrix3<f32> = self.ctm.fixed_view::<3, 3>(0, 0).into();
+        let inverse_transpose = linear
+            .try_inverse()
+            .map_or(linear, |inverse| inverse.transpose());
+        (inverse_transpose * n).normalize()
+    }
+}
+
+fn vec3(values: &[f32]) -> Result<Vec3> {
+    match values {
+        &[x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(anyhow!("Expected 3 numbers")),
+    }
+}
+
+/// Splits radiance into a color with its largest component 1 and an intensity.
+fn split(radiance: Vec3) -> (Vec3, f32) {
+    let intensity = radiance.max();
+    if intensity > 0.0 {
+        (radiance / intensity, intensity)
+    } else {
+        (Vec3::new(1.0, 1.0, 1.0), 0.0)
+    }
+}
+
+/// Corner and edges of the parallelogram the four points span, if they do.
+fn parallelogram(points: &[Vec3]) -> Option<(Vec3, Vec3, Vec3)> {
+    let size = points
+        .iter()
+        .map(|p| (p - points[0]).norm())
+        .fold(0.0, f32::max);
+
+    (0..4).find_map(|corner| {
+        let others: Vec<usize> = (0..4).filter(|&i| i != corner).collect();
+        (0..3).find_map(|opposite| {
+            let [u, v]: [usize; 2] = others
+                .iter()
+                .copied()
+                .filter(|&i| i != others[opposite])
+                .collect::<Vec<_>>()
+                .try_into()
+                .ok()?;
+            let (c, u, v) = (points[corner], points[u], points[v]);
+            let expected = u + v - c;
+            ((expected - points[others[opposite]]).norm() <= 1e-4 * size).then_some((
+                c,
+                u - c,
+                v - c,
+            ))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ron::Value;
+
+    fn import(source: &str) -> SceneFile {
+        let resolver = AssetResolver::new(vec![]);
+        let mut importer = Importer::new(&resolver);
+        importer.load_source(source, None).unwrap();
+        importer.finish()
+    }
+
+    #[test]
+    fn mirrors_left_handed_scenes() {
+        let source = r#"
+            LookAt 0 0 -5  0 0 0  0 1 0
+            Camera "perspective" "float fov" [ 90 ]
+            Sampler "halton" "integer pixelsamples" 64
+            WorldBegin
+            AttributeBegin
+                Translate 1 0 0
+                Material "conductor" "rgb reflectance" [ 0.9 0.8 0.7 ] "float roughness" 0.1
+                Shape "sphere" "float radius" 0.5
+            AttributeEnd
+            Shape "sphere" "float radius" 0.25
+        "#;
+        let scene_file = import(source);
+        assert_eq!(scene_file.camera.num_samples, 64);
+        assert_eq!(scene_file.camera.lookfrom, Vec3::new(0.0, 0.0, -5.0));
+
+        let ron = scene_file.to_ron();
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        assert_eq!(objects.len(), 2);
+        // PBRT shows +x on the right from here, so do we after mirroring.
+        let center = objects[0].field("center").unwrap().as_vec3().unwrap();
+        assert!(
+            (center - Vec3::new(-1.0, 0.0, 0.0)).norm() < 1e-5,
+            "{center}"
+        );
+        assert_eq!(objects[1].field("radius"), Some(&Value::Number(0.25)));
+
+        // Flipping x like many exporters do needs no mirroring.
+        let flipped = import(&format!("Scale -1 1 1\n{source}"));
+        let ron = flipped.to_ron();
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        let center = objects[0].field("center").unwrap().as_vec3().unwrap();
+        assert!(
+            (center - Vec3::new(1.0, 0.0, 0.0)).norm() < 1e-5,
+            "{center}"
+        );
+    }
+
+    #[test]
+    fn instances_objects() {
+        let scene_file = import(
+            r#"
+            Scale -1 1 1
+            LookAt 0 0 -5  0 0 0  0 1 0
+            Camera "perspective"
+            WorldBegin
+            ObjectBegin "triangle"
+                Translate 0 1 0
+                Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0 ]
+                Shape "sphere"
+            ObjectEnd
+            AttributeBegin
+                Translate 2 0 0
+                ObjectInstance "triangle"
+            AttributeEnd
+            ObjectInstance "triangle"
+            "#,
+        );
+
+        let ron = scene_file.to_ron();
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        assert_eq!(objects.len(), 2);
+        let first_vertex = |object: &Value| {
+            object.field("positions").unwrap().as_list().unwrap()[0]
+                .as_vec3()
+                .unwrap()
+        };
+        assert!((first_vertex(&objects[0]) - Vec3::new(2.0, 1.0, 0.0)).norm() < 1e-5);
+        assert!((first_vertex(&objects[1]) - Vec3::new(0.0, 1.0, 0.0)).norm() < 1e-5);
+    }
+
+    #[test]
+    fn quad_area_lights_face_like_pbrt() {
+        let scene_file = import(
+            r#"
+            Scale -1 1 1
+            LookAt 0 1 -3  0 1 0  0 1 0
+            Camera "perspective"
+            WorldBegin
+            AttributeBegin
+                AreaLightSource "diffuse" "rgb L" [ 17 12 4 ]
+                # Facing down in PBRT's convention.
+                Shape "trianglemesh" "point3 P" [ -1 2 -1  1 2 -1  1 2 1  -1 2 1 ]
+                    "integer indices" [ 0 1 2  0 2 3 ]
+            AttributeEnd
+            MakeNamedMaterial "white" "string type" "diffuse" "rgb reflectance" [ .7 .7 .7 ]
+            NamedMaterial "white"
+            Shape "bilinearmesh" "point3 P" [ -1 0 -1  1 0 -1  -1 0 1  1 0 1 ]
+            "#,
+        );
+        assert_eq!(scene_file.max_bounces, DEFAULT_MAX_DEPTH);
+
+        let ron = scene_file.to_ron();
+        let lights = ron.field("lights").unwrap().as_list().unwrap();
+        assert_eq!(lights.len(), 1);
+        let edge_u = lights[0].field("edge_u").unwrap().as_vec3().unwrap();
+        let edge_v = lights[0].field("edge_v").unwrap().as_vec3().unwrap();
+        assert!(edge_u.cross(&edge_v).y < 0.0);
+        assert_eq!(lights[0].field("intensity"), Some(&Value::Number(17.0)));
+
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].name(), Some("Mesh"));
+    }
+
+    #[test]
+    fn unbalanced_attributes_are_errors() {
+        let resolver = AssetResolver::new(vec![]);
+        let mut importer = Importer::new(&resolver);
+        let err = importer
+            .load_source("WorldBegin\nAttributeEnd\n", None)
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+    }
+}
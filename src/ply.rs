@@ -0,0 +1,276 @@
+//! Reader for the vertices and faces of PLY meshes, as referenced by PBRT scenes.
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+
+pub struct PlyMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    /// Three vertex indices per triangle, larger faces are split into fans.
+    pub indices: Vec<u32>,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(anyhow!("Unknown property type `{name}`")),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    /// Name, type of the item count and type of the items.
+    List(String, Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Reads scalars from the body in the file's format.
+enum Body<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { data: &'a [u8], big_endian: bool },
+}
+
+impl Body<'_> {
+    fn read(&mut self, ty: Scalar) -> Result<f64> {
+        match self {
+            Body::Ascii(words) => {
+                let word = words
+                    .next()
+                    .ok_or_else(|| anyhow!("Unexpected end of file"))?;
+                Ok(word.parse()?)
+            }
+            Body::Binary { data, big_endian } => {
+                if data.len() < ty.size() {
+                    return Err(anyhow!("Unexpected end of file"));
+                }
+                let (bytes, rest) = data.split_at(ty.size());
+                *data = rest;
+
+                let mut buf = [0; 8];
+                buf[..bytes.len()].copy_from_slice(bytes);
+                if *big_endian {
+                    buf[..bytes.len()].reverse();
+                }
+                Ok(match ty {
+                    Scalar::I8 => buf[0] as i8 as f64,
+                    Scalar::U8 => buf[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Scalar::U32 => u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Scalar::F32 => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64,
+                    Scalar::F64 => f64::from_le_bytes(buf),
+                })
+            }
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<PlyMesh> {
+    let data =
+        std::fs::read(path).with_context(|| format!("Failed to read mesh `{}`", path.display()))?;
+    parse(&data).with_context(|| format!("Invalid PLY mesh `{}`", path.display()))
+}
+
+fn parse(data: &[u8]) -> Result<PlyMesh> {
+    const END_HEADER: &[u8] = b"end_header";
+    let header_end = data
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+        .ok_or_else(|| anyhow!("Missing `end_header`"))?;
+    let body_start = data[header_end..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(data.len(), |newline| header_end + newline + 1);
+    let header = std::str::from_utf8(&data[..header_end]).context("Header is not UTF-8")?;
+
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        return Err(anyhow!("Not a PLY file"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", name, _version] => format = Some(*name),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse()?,
+                properties: vec![],
+            }),
+            ["property", "list", count, item, name] => elements
+                .last_mut()
+                .ok_or_else(|| anyhow!("Property outside of an element"))?
+                .properties
+                .push(Property::List(
+                    name.to_string(),
+                    Scalar::parse(count)?,
+                    Scalar::parse(item)?,
+                )),
+            ["property", ty, name] => elements
+                .last_mut()
+                .ok_or_else(|| anyhow!("Property outside of an element"))?
+                .properties
+                .push(Property::Scalar(name.to_string(), Scalar::parse(ty)?)),
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(anyhow!("Unexpected header line `{line}`")),
+        }
+    }
+
+    let body = &data[body_start..];
+    let mut body = match format {
+        Some("ascii") => Body::Ascii(
+            std::str::from_utf8(body)
+                .context("ASCII body is not UTF-8")?
+                .split_ascii_whitespace(),
+        ),
+        Some("binary_little_endian") => Body::Binary {
+            data: body,
+            big_endian: false,
+        },
+        Some("binary_big_endian") => Body::Binary {
+            data: body,
+            big_endian: true,
+        },
+        other => return Err(anyhow!("Unsupported format {other:?}")),
+    };
+
+    let mut mesh = PlyMesh {
+        positions: vec![],
+        normals: None,
+        indices: vec![],
+    };
+    let mut normals = vec![];
+
+    for element in &elements {
+        for _ in 0..element.count {
+            let mut position = Vec3::zeros();
+            let mut normal = Vec3::zeros();
+            let mut face = vec![];
+
+            for property in &element.properties {
+                match property {
+                    Property::Scalar(name, ty) => {
+                        let value = body.read(*ty)? as f32;
+                        match name.as_str() {
+                            "x" => position.x = value,
+                            "y" => position.y = value,
+                            "z" => position.z = value,
+                            "nx" => normal.x = value,
+                            "ny" => normal.y = value,
+                            "nz" => normal.z = value,
+                            _ => {}
+                        }
+                    }
+                    Property::List(name, count_ty, item_ty) => {
+                        let count = body.read(*count_ty)? as usize;
+                        let items = (0..count)
+                            .map(|_| body.read(*item_ty).map(|item| item as u32))
+                            .collect::<Result<Vec<_>>>()?;
+                        if name == "vertex_indices" || name == "vertex_index" {
+                            face = items;
+                        }
+                    }
+                }
+            }
+
+            match element.name.as_str() {
+                "vertex" => {
+                    mesh.positions.push(position);
+                    normals.push(normal);
+                }
+                "face" => {
+                    for i in 1..face.len().saturating_sub(1) {
+                        mesh.indices.extend([face[0], face[i], face[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let has_normals = elements.iter().any(|element| {
+        element.name == "vertex"
+            && element
+                .properties
+                .iter()
+                .any(|property| matches!(property, Property::Scalar(name, _) if name == "nx"))
+    });
+    if has_normals {
+        mesh.normals = Some(normals);
+    }
+
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_and_binary_agree() {
+        let header = |format: &str| {
+            format!(
+                "ply\nformat {format} 1.0\ncomment quad\nelement vertex 4\nproperty float x\nproperty float y\nproperty float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n"
+            )
+        };
+
+        let ascii = header("ascii") + "0 0 0\n1 0 0\n1 1 0\n0 1 0\n4 0 1 2 3\n";
+
+        let mut binary = header("binary_little_endian").into_bytes();
+        for [x, y] in [[0.0f32, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]] {
+            for coord in [x, y, 0.0] {
+                binary.extend(coord.to_le_bytes());
+            }
+        }
+        binary.push(4);
+        for index in [0i32, 1, 2, 3] {
+            binary.extend(index.to_le_bytes());
+        }
+
+        for data in [ascii.as_bytes(), &binary] {
+            let mesh = parse(data).unwrap();
+            assert_eq!(mesh.positions.len(), 4);
+            assert_eq!(mesh.positions[2], Vec3::new(1.0, 1.0, 0.0));
+            assert!(mesh.normals.is_none());
+            assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! Built-in scenes, switchable at runtime with the number keys.
+use crate::camera::Lens;
+use crate::material_plugin::MaterialPlugin;
+use crate::scene::{Light, Material, Rotation, Scene, Sky, Sphere, TriangleMesh};
+use crate::scene_file::{CameraSettings, SceneFile};
+use crate::types::*;
+use encase::ShaderType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    ThreeSpheres,
+    RandomSpheres,
+    GlassShowcase,
+    CornellBox,
+    PluginMaterials,
+}
+
+impl Preset {
+    /// In the order of their number keys, starting at 1.
+    pub const ALL: [Preset; 5] = [
+        Preset::ThreeSpheres,
+        Preset::RandomSpheres,
+        Preset::GlassShowcase,
+        Preset::CornellBox,
+        Preset::PluginMaterials,
+    ];
+
+    /// Seed of the random spheres preset, `--random-spheres` picks others.
+    const RANDOM_SPHERES_SEED: u64 = 0;
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::ThreeSpheres => "three-spheres",
+            Preset::RandomSpheres => "random-spheres",
+            Preset::GlassShowcase => "glass-showcase",
+            Preset::CornellBox => "cornell-box",
+            Preset::PluginMaterials => "plugin-materials",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|preset| preset.name() == name)
+    }
+
+    pub fn scene_file(self) -> SceneFile {
+        match self {
+            Preset::ThreeSpheres => three_spheres(),
+            Preset::RandomSpheres => random_spheres(Self::RANDOM_SPHERES_SEED),
+            Preset::GlassShowcase => glass_showcase(),
+            Preset::CornellBox => cornell_box(),
+            Preset::PluginMaterials => plugin_materials(),
+        }
+    }
+}
+
+/// The scene rendered when no other is given.
+pub fn three_spheres() -> SceneFile {
+    let mut scene = Scene::default();
+    let material_left = Material::new_dielectric(1.5, None);
+    let material_center = Material::new_lambertian(Vec3::new(0.1, 0.2, 0.5));
+    let material_right = Material::new_metal(Vec3::new(0.8, 0.6, 0.2), 0.0);
+    let material_ground = Material::new_lambertian(Vec3::new(0.8, 0.8, 0.0));
+
+    scene.new_sphere(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), 0.5), material_left);
+    scene.new_sphere(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), -0.4), material_left);
+    scene.new_sphere(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5), material_center);
+    scene.new_sphere(Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5), material_right);
+
+    scene.new_sphere(
+        Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0),
+        material_ground,
+    );
+
+    SceneFile {
+        scene,
+        camera: CameraSettings::default(),
+        max_bounces: 50,
+    }
+}
+
+/// The cover of "Ray Tracing in One Weekend". The camera has a wider field of view than
+/// the book's, so the framing differs.
+pub fn random_spheres(seed: u64) -> SceneFile {
+    SceneFile {
+        scene: Scene::random_spheres(seed),
+        camera: CameraSettings {
+            lookfrom: Vec3::new(13.0, 2.0, 3.0),
+            lookat: Vec3::new(0.0, 0.0, 0.0),
+            num_samples: 500,
+            lens: Lens {
+                aperture: 0.05,
+                focus_distance: Some(10.0),
+                ..Lens::default()
+            },
+            ..CameraSettings::default()
+        },
+        max_bounces: 50,
+    }
+}
+
+/// A row of glass spheres from water to diamond, a hollow one and one with dispersion,
+/// in front of colored diffuse spheres to refract.
+fn glass_showcase() -> SceneFile {
+    let mut scene = Scene::default();
+
+    for (i, refract_idx) in [1.33, 1.5, 1.8, 2.42].into_iter().enumerate() {
+        let x = -1.8 + 1.2 * i as f32;
+        scene.new_sphere(
+            Sphere::new(Vec3::new(x, 0.0, -1.0), 0.5),
+            Material::new_dielectric(refract_idx, None),
+        );
+        scene.new_sphere(
+            Sphere::new(Vec3::new(x, 0.0, -3.0), 0.5),
+            Material::new_lambertian(Vec3::new(0.2 + 0.2 * i as f32, 0.7 - 0.15 * i as f32, 0.3)),
+        );
+    }
+
+    let hollow = Material::new_dielectric(1.5, None);
+    scene.new_sphere(Sphere::new(Vec3::new(-0.6, 0.8, -2.0), 0.4), hollow);
+    scene.new_sphere(Sphere::new(Vec3::new(-0.6, 0.8, -2.0), -0.35), hollow);
+    scene.new_sphere(
+        Sphere::new(Vec3::new(0.6, 0.8, -2.0), 0.4),
+        Material::new_dielectric(1.6, Some(20.0)),
+    );
+
+    scene.new_sphere(
+        Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0),
+        Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
+    );
+
+    SceneFile {
+        scene,
+        camera: CameraSettings {
+            lookfrom: Vec3::new(0.0, 1.0, 3.0),
+            lookat: Vec3::new(0.0, 0.0, -1.5),
+            num_samples: 200,
+            ..CameraSettings::default()
+        },
+        max_bounces: 50,
+    }
+}
+
+/// The Cornell box, lit only by the area light under its ceiling, for checking global
+/// illumination: the white walls and boxes pick up red and green light bounced off the
+/// side walls. Dimensions follow the original, scaled from 555 to 2 units and mirrored
+/// so that it is seen looking down -z.
+fn cornell_box() -> SceneFile {
+    let mut scene = Scene::default();
+    scene.set_sky(Sky::new(Vec3::new(0.0, 1.0, 0.0), Sky::MIN_TURBIDITY, 0.0));
+
+    let red = Material::new_lambertian(Vec3::new(0.65, 0.05, 0.05));
+    let white = Material::new_lambertian(Vec3::new(0.73, 0.73, 0.73));
+    let green = Material::new_lambertian(Vec3::new(0.12, 0.45, 0.15));
+
+    let walls = [
+        // Floor, ceiling and back wall.
+        (
+            Vec3::new(-1.0, 0.0, -2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            white,
+        ),
+        (
+            Vec3::new(-1.0, 2.0, -2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            white,
+        ),
+        (
+            Vec3::new(-1.0, 0.0, -2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            white,
+        ),
+        (
+            Vec3::new(-1.0, 0.0, -2.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            green,
+        ),
+        (
+            Vec3::new(1.0, 0.0, -2.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            red,
+        ),
+    ];
+    for (corner, edge_u, edge_v, material) in walls {
+        let positions = vec![
+            corner,
+            corner + edge_u,
+            corner + edge_u + edge_v,
+            corner + edge_v,
+        ];
+        scene
+            .new_mesh(
+                TriangleMesh::new(positions, vec![0, 1, 2, 0, 2, 3]),
+                Rotation::identity(),
+                material,
+            )
+            .expect("wall meshes are valid");
+    }
+
+    let boxes = [
+        // Short box in front, tall box at the back.
+        (
+            Vec3::new(0.234, 0.297, -0.532),
+            Vec3::new(0.595, 0.595, 0.595),
+            18.0f32,
+        ),
+        (
+            Vec3::new(-0.252, 0.595, -1.36),
+            Vec3::new(0.595, 1.19, 0.595),
+            -15.0,
+        ),
+    ];
+    for (center, size, angle) in boxes {
+        let rotation = Rotation::new(
+            Quat::from_axis_angle(&Vec3::y_axis(), angle.to_radians()),
+            center,
+        );
+        scene
+            .new_mesh(TriangleMesh::cuboid(center, size), rotation, white)
+            .expect("box meshes are valid");
+    }
+
+    // Edges ordered so that it shines downwards, just below the ceiling.
+    scene.new_light(Light::quad(
+        Vec3::new(-0.235, 1.999, -1.19),
+        Vec3::new(0.47, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 0.38),
+        Vec3::new(1.0, 0.71, 0.24),
+        17.0,
+    ));
+
+    SceneFile {
+        scene,
+        camera: CameraSettings {
+            // The 90° field of view just fits the opening of the box.
+            lookfrom: Vec3::new(0.0, 1.0, 1.0),
+            lookat: Vec3::new(0.0, 1.0, 0.0),
+            num_samples: 500,
+            ..CameraSettings::default()
+        },
+        max_bounces: 50,
+    }
+}
+
+#[derive(ShaderType)]
+struct CheckerParams {
+    even: Vec3,
+    /// Edge length of the cells.
+    scale: f32,
+    odd: Vec3,
+}
+
+#[derive(ShaderType)]
+struct GlowParams {
+    color: Vec3,
+    strength: f32,
+}
+
+/// The three spheres scene with materials defined by plugins: a diffuse checkerboard on the
+/// ground and a glowing sphere in the middle.
+fn plugin_materials() -> SceneFile {
+    let mut scene = Scene::default();
+    let checker = scene.add_material_plugin(
+        MaterialPlugin::new(
+            "checker",
+            "even: vec3<f32>, scale: f32, odd: vec3<f32>",
+            "    var cell = vec3<i32>(floor(hit.point / params.scale));
+    var color = params.odd;
+    if ((cell.x + cell.y + cell.z) & 1) == 0 {
+        color = params.even;
+    }
+    var direction = hit.normal + rand_unit_sphere();
+    if nearZero(direction) {
+        direction = hit.normal;
+    }
+    path.energy = path.energy * color;
+    path.radiance += path.energy * sampleLights(hit.point, hit.normal);
+    path.sees_lights = 0u;
+    path.ray.origin = hit.point;
+    path.ray.direction = direction;
+    return path;",
+        )
+        .expect("checker fields are supported"),
+    );
+    let glow = scene.add_material_plugin(
+        MaterialPlugin::new(
+            "glow",
+            "color: vec3<f32>, strength: f32",
+            "    return endPath(path, path.radiance + path.energy * params.color * params.strength);",
+        )
+        .expect("glow fields are supported"),
+    );
+
+    let ground = CheckerParams {
+        even: Vec3::new(0.8, 0.8, 0.8),
+        scale: 0.5,
+        odd: Vec3::new(0.2, 0.3, 0.1),
+    };
+    let ground = scene
+        .custom_material(checker, &ground)
+        .expect("checker parameters match its fields");
+    let center = GlowParams {
+        color: Vec3::new(1.0, 0.5, 0.2),
+        strength: 4.0,
+    };
+    let center = scene
+        .custom_material(glow, &center)
+        .expect("glow parameters match its fields");
+
+    scene.new_sphere(
+        Sphere::new(Vec3::new(-1.0, 0.0, -1.0), 0.5),
+        Material::new_dielectric(1.5, None),
+    );
+    scene.new_sphere(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5), center);
+    scene.new_sphere(
+        Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5),
+        Material::new_metal(Vec3::new(0.8, 0.6, 0.2), 0.0),
+    );
+    scene.new_sphere(Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0), ground);
+
+    SceneFile {
+        scene,
+        camera: CameraSettings::default(),
+        max_bounces: 50,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        for preset in Preset::ALL {
+            assert_eq!(Preset::from_name(preset.name()), Some(preset));
+        }
+        assert_eq!(Preset::from_name("teapot"), None);
+    }
+
+    #[test]
+    fn presets_fit_gpu_buffers() {
+        for preset in Preset::ALL {
+            let scene_file = preset.scene_file();
+            assert!(scene_file.scene.into_gpu_buffers(u32::MAX as u64).is_ok());
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Python bindings, built with the `python` feature into the `raytracer_gpu` extension module
+//! (`maturin build --release`). They script scene construction and headless rendering for
+//! dataset generation and experiments:
+//!
+//! ```python
+//! import raytracer_gpu as rt
+//!
+//! scene = rt.Scene()
+//! scene.add_sphere((0, -1000, 0), 1000, rt.Material.lambertian((0.5, 0.5, 0.5)))
+//! scene.add_sphere((0, 1, 0), 1, rt.Material.dielectric(1.5))
+//! camera = rt.Camera((13, 2, 3), (0, 0, 0), samples=64, size=(640, 360))
+//! image = rt.Tracer().render(scene, camera)
+//! image.save("spheres.png")
+//! ```
+use crate::assets::AssetResolver;
+use crate::camera::{Camera, Exposure, Lens, Projection};
+use crate::console::Console;
+use crate::gpu::Gpu;
+use crate::image::RgbaImage;
+use crate::raytracing::{random_seed, GpuRaytracer, ImageOptions};
+use crate::scene::{self, Light, Material, Rotation, Sphere, TriangleMesh};
+use crate::scene_file::{CameraSettings, SceneFile};
+use crate::types::*;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::path::PathBuf;
+
+type Tuple3 = (f32, f32, f32);
+
+fn vec3((x, y, z): Tuple3) -> Vec3 {
+    Vec3::new(x, y, z)
+}
+
+#[pyclass(name = "Material", module = "raytracer_gpu")]
+#[derive(Clone)]
+struct PyMaterial(Material);
+
+#[pymethods]
+impl PyMaterial {
+    #[staticmethod]
+    fn lambertian(albedo: Tuple3) -> Self {
+        PyMaterial(Material::new_lambertian(vec3(albedo)))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (albedo, fuzz = 0.0))]
+    fn metal(albedo: Tuple3, fuzz: f32) -> Self {
+        PyMaterial(Material::new_metal(vec3(albedo), fuzz))
+    }
+
+    #[staticmethod]
+    fn pbr(albedo: Tuple3, metallic: f32, roughness: f32) -> Self {
+        PyMaterial(Material::new_pbr(vec3(albedo), metallic, roughness))
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (refract_idx, abbe_number = None))]
+    fn dielectric(refract_idx: f32, abbe_number: Option<f32>) -> Self {
+        PyMaterial(Material::new_dielectric(refract_idx, abbe_number))
+    }
+
+    #[staticmethod]
+    fn subsurface(albedo: Tuple3, scatter_distance: f32, refract_idx: f32) -> Self {
+        PyMaterial(Material::new_subsurface(
+            vec3(albedo),
+            scatter_distance,
+            refract_idx,
+        ))
+    }
+}
+
+#[pyclass(name = "Scene", module = "raytracer_gpu")]
+#[derive(Clone)]
+struct PyScene {
+    scene: scene::Scene,
+    /// Bounces traced per path.
+    #[pyo3(get, set)]
+    max_bounces: usize,
+}
+
+#[pymethods]
+impl PyScene {
+    #[new]
+    #[pyo3(signature = (max_bounces = SceneFile::DEFAULT_MAX_BOUNCES))]
+    fn new(max_bounces: usize) -> Self {
+        PyScene {
+            scene: scene::Scene::default(),
+            max_bounces,
+        }
+    }
+
+    /// The final scene of "Ray Tracing in One Weekend".
+    #[staticmethod]
+    #[pyo3(signature = (seed = 0))]
+    fn random_spheres(seed: u64) -> Self {
+        PyScene {
+            scene: scene::Scene::random_spheres(seed),
+            max_bounces: SceneFile::DEFAULT_MAX_BOUNCES,
+        }
+    }
+
+    fn add_sphere(&mut self, center: Tuple3, radius: f32, material: &PyMaterial) {
+        self.scene
+            .new_sphere(Sphere::new(vec3(center), radius), material.0);
+    }
+
+    /// Adds a triangle mesh, `indices` holding three vertices per triangle.
+    fn add_mesh(
+        &mut self,
+        positions: Vec<Tuple3>,
+        indices: Vec<u32>,
+        material: &PyMaterial,
+    ) -> PyResult<()> {
+        let mesh = TriangleMesh::new(positions.into_iter().map(vec3).collect(), indices);
+        self.scene
+            .new_mesh(mesh, Rotation::identity(), material.0)?;
+        Ok(())
+    }
+
+    #[pyo3(signature = (position, color, intensity, radius = 0.0))]
+    fn add_point_light(&mut self, position: Tuple3, color: Tuple3, intensity: f32, radius: f32) {
+        self.scene
+            .new_light(Light::point(vec3(position), radius, vec3(color), intensity));
+    }
+
+    fn add_quad_light(
+        &mut self,
+        corner: Tuple3,
+        edge_u: Tuple3,
+        edge_v: Tuple3,
+        color: Tuple3,
+        intensity: f32,
+    ) {
+        self.scene.new_light(Light::quad(
+            vec3(corner),
+            vec3(edge_u),
+            vec3(edge_v),
+            vec3(color),
+            intensity,
+        ));
+    }
+}
+
+/// A camera, with the exposure, lens, projection and stereo settings of its scene file when
+/// loaded by `load_scene`.
+#[pyclass(name = "Camera", module = "raytracer_gpu")]
+#[derive(Clone)]
+struct PyCamera {
+    camera: Camera,
+    exposure: Exposure,
+    lens: Lens,
+    projection: Projection,
+    stereo: Option<f32>,
+}
+
+impl PyCamera {
+    fn with_settings(camera: Camera, settings: &CameraSettings) -> Self {
+        PyCamera {
+            camera,
+            exposure: settings.exposure,
+            lens: settings.lens,
+            projection: settings.projection,
+            stereo: settings.stereo,
+        }
+    }
+}
+
+#[pymethods]
+impl PyCamera {
+    #[new]
+    #[pyo3(signature = (lookfrom, lookat, vup = (0.0, 1.0, 0.0), samples = 100, size = (1200, 675)))]
+    fn new(
+        lookfrom: Tuple3,
+        lookat: Tuple3,
+        vup: Tuple3,
+        samples: u32,
+        size: (u32, u32),
+    ) -> PyResult<Self> {
+        if size.0 == 0 || size.1 == 0 || samples == 0 {
+            return Err(PyValueError::new_err(format!(
+                "Invalid camera, {}x{} with {samples} samples",
+                size.0, size.1
+            )));
+        }
+        let camera = Camera::new(vec3(lookfrom), vec3(lookat), vec3(vup), samples, size);
+        Ok(PyCamera::with_settings(camera, &CameraSettings::default()))
+    }
+}
+
+/// An 8-bit sRGB image, rows top to bottom.
+#[pyclass(name = "Image", module = "raytracer_gpu")]
+struct PyImage(RgbaImage);
+
+#[pymethods]
+impl PyImage {
+    #[getter]
+    fn width(&self) -> u32 {
+        self.0.width
+    }
+
+    #[getter]
+    fn height(&self) -> u32 {
+        self.0.height
+    }
+
+    /// RGBA bytes, e.g. for `numpy.frombuffer(...).reshape(height, width, 4)`.
+    #[getter]
+    fn pixels<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.pixels)
+    }
+
+    /// Saves as PNG or binary PPM, depending on the extension.
+    fn save(&self, path: PathBuf) -> PyResult<()> {
+        Ok(self.0.save(&path)?)
+    }
+}
+
+/// A GPU to render on, reused across renders.
+#[pyclass(name = "Tracer", module = "raytracer_gpu")]
+struct PyTracer(Gpu);
+
+#[pymethods]
+impl PyTracer {
+    #[new]
+    #[pyo3(signature = (fallback_adapter = false))]
+    fn new(fallback_adapter: bool) -> PyResult<Self> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let gpu = runtime.block_on(Gpu::headless(fallback_adapter, Console::shared()))?;
+        Ok(PyTracer(gpu))
+    }
+
+    /// Traces all samples of `camera`, detached from the interpreter meanwhile. The same
+    /// `seed` traces the same image, a random one is picked if not given. Raises `ValueError`
+    /// for sizes beyond the GPU's limit or scenes without bounces.
+    #[pyo3(signature = (scene, camera, seed = None))]
+    fn render(
+        &self,
+        py: Python<'_>,
+        scene: &PyScene,
+        camera: &PyCamera,
+        seed: Option<u64>,
+    ) -> PyResult<PyImage> {
+        let options = ImageOptions {
+            max_bounces: scene.max_bounces,
+            seed: seed.unwrap_or_else(random_seed),
+            exposure: camera.exposure,
+            lens: camera.lens,
+            projection: camera.projection,
+            stereo: camera.stereo,
+        };
+        GpuRaytracer::check_image(&self.0, &camera.camera, &options)
+            .map_err(|err| PyValueError::new_err(format!("{err:#}")))?;
+        let image = py.detach(|| {
+            GpuRaytracer::render_to_image(&self.0, &scene.scene, &camera.camera, &options)
+        })?;
+        Ok(PyImage(image))
+    }
+}
+
+/// Loads a scene file and its camera, rendering at `size`. The bounce depth and camera
+/// settings of the file carry over, so the image matches the one rendered by the CLI.
+#[pyfunction]
+#[pyo3(signature = (path, size = (1200, 675)))]
+fn load_scene(path: PathBuf, size: (u32, u32)) -> PyResult<(PyScene, PyCamera)> {
+    let SceneFile {
+        scene,
+        camera: settings,
+        max_bounces,
+    } = SceneFile::load(&path, &AssetResolver::new(vec![]))?;
+    let camera = Camera::new(
+        settings.lookfrom,
+        settings.lookat,
+        settings.vup,
+        settings.num_samples,
+        size,
+    );
+    Ok((
+        PyScene { scene, max_bounces },
+        PyCamera::with_settings(camera, &settings),
+    ))
+}
+
+#[pymodule]
+fn raytracer_gpu(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyMaterial>()?;
+    module.add_class::<PyScene>()?;
+    module.add_class::<PyCamera>()?;
+    module.add_class::<PyImage>()?;
+    module.add_class::<PyTracer>()?;
+    module.add_function(wrap_pyfunction!(load_scene, module)?)?;
+    Ok(())
+}
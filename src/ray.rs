@@ -6,4 +6,17 @@ pub struct Ray {
     origin: Vec3,
     direction: Vec3,
     finished: u32,
+    medium: u32,
+    wavelength: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::assert_layout;
+
+    #[test]
+    fn ray_matches_wgsl_layout() {
+        assert_layout::<Ray>(&crate::shader::compute(&[]).unwrap(), "Ray");
+    }
 }
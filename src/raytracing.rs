@@ -1,33 +1,241 @@
+use crate::camera::{Camera, Exposure, Lens, Projection};
+use crate::denoise::Target;
+use crate::image::RgbaImage;
+use crate::scene_file::SceneFile;
+use crate::stats::{FrameStats, StatsWriter};
+use crate::texture_pool::TexturePool;
+use crate::tiles::TileScheduler;
 use crate::types::*;
 use crate::{camera::GpuCamera, gpu::Gpu, render::Renderer, scene::Scene};
 use encase::ShaderType;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::ops::{ControlFlow, Range};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 pub struct GpuRaytracer {
     pipeline: wgpu::ComputePipeline,
+    /// Traces the tiles picked by `tiles`, see `raytrace_tiles`.
+    tiles_pipeline: wgpu::ComputePipeline,
+    tiles: TileScheduler,
+    /// Part of the image traced, `[x, y, width, height]`, all of it when `None`.
+    region: Option<[u32; 4]>,
+    /// Work shared out by the GPU, see `Work` in `compute.wgsl`: the pixel counter of the
+    /// persistent threads and, with the multi-pass kernel, the queue of live paths.
+    work_buf: wgpu::Buffer,
+    /// Dispatch arguments of the next bounce copied out of `work_buf`, which can't be used for
+    /// an indirect dispatch while it's bound for writing.
+    live_args_buf: wgpu::Buffer,
+    /// Entries of the multi-pass trace, see `start_paths`, `prepare_bounce`, `bounce_paths` and
+    /// `resolve_paths`.
+    start_pipeline: wgpu::ComputePipeline,
+    prepare_pipeline: wgpu::ComputePipeline,
+    bounce_pipeline: wgpu::ComputePipeline,
+    resolve_pipeline: wgpu::ComputePipeline,
+    kernel: Kernel,
+    /// `paths_per_pixel` paths per pixel of the scene image with the multi-pass kernel, a single
+    /// one otherwise.
+    paths_buf: wgpu::Buffer,
+    /// Paths a multi-pass trace follows from each pixel, each one a sample of the pass.
+    paths_per_pixel: u32,
+    /// Whether the paths are stored with octahedral directions and half precision colors.
+    packed_paths: bool,
+    pixel_filter: PixelFilter,
+    max_bounces: u32,
+    /// Bounces of a `bounce_paths` dispatch, fewer dispatches for long paths.
+    bounces_per_dispatch: u32,
     compute_bg: wgpu::BindGroup,
-    spheres_buf: wgpu::Buffer,
-    mats_buf: wgpu::Buffer,
+    /// The accumulated image traced into, and the navigation preview, with their first hits
+    /// and moments.
+    target_bg: wgpu::BindGroup,
+    preview_bg: wgpu::BindGroup,
     seed_buf: wgpu::Buffer,
     limits_buf: wgpu::Buffer,
-    compute_bgl: wgpu::BindGroupLayout,
+    /// Mesh chunk lengths of the scene, rewritten with the limits.
+    vertex_chunk: u32,
+    index_chunk: u32,
+    target_bgl: wgpu::BindGroupLayout,
+    /// Seeds of consecutive passes, reproducible from the seed given to `new`.
+    rng: Mutex<StdRng>,
+    seed: u64,
+    /// Called once each pass of the image is done on the GPU, see `set_progress`.
+    progress: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    /// When the first pass of the render in progress was submitted.
+    started: Mutex<Option<Instant>>,
+    /// Checked before each pass, see `set_cancellation`.
+    cancellation: Option<CancellationToken>,
+}
+
+/// Aborts the renders of the tracers it's given to from another thread, e.g. when the camera
+/// moves. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops `GpuRaytracer::perform` before its next pass, until `reset`.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets renders run again.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// How far a render got, reported by the callback of `GpuRaytracer::set_progress`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    /// Samples per pixel traced so far, of `total`. Progressive renders go on past it.
+    pub samples: u32,
+    pub total: u32,
+    /// `tiles::TILE_SIZE` tiles traced by those passes. Adaptive passes count every tile, the
+    /// ones they skip are picked on the GPU.
+    pub tiles: u64,
+    /// Since the first pass of the render.
+    pub elapsed: Duration,
 }
 
 #[derive(ShaderType, Debug)]
 struct SeedUniform {
     seed: Vec3U,
+    /// Pass index, the sub-pixel offsets of its samples follow from it.
+    sample: u32,
 }
 
 #[derive(ShaderType)]
 struct LimitUniform {
     max_bounces: u32,
+    /// Lengths of the mesh data chunks, see `scene::MESH_CHUNKS`.
+    vertex_chunk: u32,
+    index_chunk: u32,
+    /// A uniform rather than a pipeline-overridable constant, which wgpu 0.18 doesn't support.
+    bounces_per_dispatch: u32,
+    packed_paths: u32,
+    paths_per_pixel: u32,
+    pixel_filter: u32,
+}
+
+/// Words of a path kept per pixel between the dispatches of a multi-pass trace, at full precision
+/// and packed. See `storePath`.
+const PATH_WORDS: u64 = 21;
+const PACKED_PATH_WORDS: u64 = 13;
+
+/// Layout of the header of the WGSL `Work`, followed by the queue of live path indices.
+const WORK_HEADER_SIZE: u64 = 28;
+/// The dispatch arguments of the running bounce, left in the header by `prepare_bounce`.
+const WORK_ARGS_OFFSET: u64 = 4;
+const LIVE_ARGS_SIZE: u64 = 12;
+
+/// How a full pass follows the paths through their bounces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    /// A single dispatch traces every path to its end, see `raytrace`.
+    Megakernel,
+    /// A dispatch per bounce, keeping the paths in a buffer in between, see `bounce_paths`.
+    MultiPass,
+}
+
+impl Kernel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kernel::Megakernel => "megakernel",
+            Kernel::MultiPass => "multi-pass",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Kernel::Megakernel, Kernel::MultiPass]
+            .into_iter()
+            .find(|kernel| kernel.name() == name)
+    }
+}
+
+/// How the samples of a pass make up the pixels, see `sampleFilter` in `shaders/camera.wgsl`.
+/// Wider filters trade sharpness for less aliasing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFilter {
+    /// Averages the samples within each pixel.
+    Box,
+    /// Linear falloff over a radius of one pixel.
+    Tent,
+    /// Gaussian with a standard deviation of half a pixel.
+    Gaussian,
+    /// Mitchell-Netravali (B = C = 1/3) over a radius of two pixels, its negative lobes
+    /// keeping edges sharp.
+    Mitchell,
+}
+
+impl PixelFilter {
+    pub const ALL: [PixelFilter; 4] = [
+        PixelFilter::Box,
+        PixelFilter::Tent,
+        PixelFilter::Gaussian,
+        PixelFilter::Mitchell,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFilter::Box => "box",
+            PixelFilter::Tent => "tent",
+            PixelFilter::Gaussian => "gaussian",
+            PixelFilter::Mitchell => "mitchell",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|filter| filter.name() == name)
+    }
+}
+
+/// Settings of `GpuRaytracer::render_to_image`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageOptions {
+    /// Bounces traced per path, `SceneFile::DEFAULT_MAX_BOUNCES` by default.
+    pub max_bounces: usize,
+    /// Seed of the per-pass seed sequence, the same seed traces the same image. Random by
+    /// default.
+    pub seed: u64,
+    /// Camera settings beyond `Camera`, as in `scene_file::CameraSettings`.
+    pub exposure: Exposure,
+    pub lens: Lens,
+    pub projection: Projection,
+    pub stereo: Option<f32>,
 }
 
-fn generate_seed() -> Vec3U {
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            max_bounces: SceneFile::DEFAULT_MAX_BOUNCES,
+            seed: random_seed(),
+            exposure: Exposure::default(),
+            lens: Lens::default(),
+            projection: Projection::Perspective,
+            stereo: None,
+        }
+    }
+}
+
+/// Picks a random seed for the per-pass seed sequence.
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+fn generate_seed(rng: &mut StdRng) -> Vec3U {
     use rand::Rng;
 
-    let mut rng = rand::thread_rng();
     let x = rng.gen();
     let y = rng.gen();
     let z = rng.gen();
@@ -36,55 +244,357 @@ fn generate_seed() -> Vec3U {
 }
 
 impl GpuRaytracer {
+    /// Workgroups of 64 persistent threads launched for a full pass, enough to keep large GPUs
+    /// busy while the threads share out the pixels.
+    const PERSISTENT_WORKGROUPS: u32 = 512;
+    /// Edge length of the workgroups of `start_paths`.
+    const PATH_WORKGROUP_SIZE: u32 = 8;
+
     pub fn new(
         gpu: &Gpu,
         gpu_camera: &GpuCamera,
         max_bounces: usize,
         renderer: &Renderer,
         scene: Scene,
+        seed: u64,
     ) -> Result<Self> {
         use wgpu::util::DeviceExt;
 
         let Gpu { device, .. } = gpu;
 
+        let compute_source = crate::shader::compute(scene.material_plugins())?;
+        gpu.push_error_scope();
         let compute_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: None,
-            source: wgpu::ShaderSource::Wgsl(include_str!("compute.wgsl").into()),
+            label: Some("compute.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(compute_source.into()),
         });
+        gpu.pop_error_scope("the compute shader")?;
 
-        let (spheres, mats) = scene.into_gpu_buffers()?;
+        let upload = tracing::info_span!("upload_scene").entered();
+        let max_binding_size = device.limits().max_storage_buffer_binding_size as u64;
+        let scene_bufs = scene.into_gpu_buffers(max_binding_size)?;
 
+        let mut limits = encase::UniformBuffer::new(vec![]);
+        limits.write(&LimitUniform {
+            max_bounces: max_bounces as u32,
+            vertex_chunk: scene_bufs.vertex_chunk,
+            index_chunk: scene_bufs.index_chunk,
+            bounces_per_dispatch: 1,
+            packed_paths: 0,
+            paths_per_pixel: 1,
+            pixel_filter: PixelFilter::Box as u32,
+        })?;
+
+        gpu.push_error_scope();
         let spheres_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: spheres.into_inner().as_slice(),
+            label: Some("spheres"),
+            contents: scene_bufs.spheres.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let cones_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("cones"),
+            contents: scene_bufs.cones.as_slice(),
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
 
+        let disks_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("disks"),
+            contents: scene_bufs.disks.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let csg_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("csg"),
+            contents: scene_bufs.csg.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let sdfs_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("sdfs"),
+            contents: scene_bufs.sdfs.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let meshes_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("meshes"),
+            contents: scene_bufs.meshes.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bvh_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("bvh"),
+            contents: scene_bufs.bvh.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let material_params_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("material params"),
+            contents: scene_bufs.material_params.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let texture_pool = TexturePool::new(gpu, &scene_bufs.textures)?;
+
+        let vertices_bufs = scene_bufs.vertices.each_ref().map(|vertices| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("vertices"),
+                contents: vertices.as_slice(),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            })
+        });
+
+        let indices_bufs = scene_bufs.indices.each_ref().map(|indices| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("indices"),
+                contents: indices.as_slice(),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            })
+        });
+
         let mats_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: mats.into_inner().as_slice(),
+            label: Some("materials"),
+            contents: scene_bufs.mats.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let lights_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("lights"),
+            contents: scene_bufs.lights.as_slice(),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let light_tree_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("light tree"),
+            contents: scene_bufs.light_tree.as_slice(),
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
 
+        let environment_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("environment"),
+            contents: scene_bufs.environment.as_slice(),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
         let seed_buf = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
+            label: Some("seed"),
             size: SeedUniform::min_size().get(),
             mapped_at_creation: false,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let mut limits = encase::UniformBuffer::new(vec![]);
-        limits.write(&LimitUniform {
-            max_bounces: max_bounces as u32,
-        })?;
-
         let limits_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
+            label: Some("limits"),
             contents: limits.into_inner().as_slice(),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        gpu.pop_error_scope("the scene buffers")?;
+        drop(upload);
 
+        gpu.push_error_scope();
         let compute_bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 11,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 15,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 16,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 17,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 18,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 19,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 20,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 12,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 13,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 14,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let target_bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
             entries: &[
                 wgpu::BindGroupLayoutEntry {
@@ -100,16 +610,26 @@ impl GpuRaytracer {
                 wgpu::BindGroupLayoutEntry {
                     binding: 1,
                     visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
                     },
                     count: None,
                 },
                 wgpu::BindGroupLayoutEntry {
                     binding: 2,
                     visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::ReadWrite,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
@@ -118,20 +638,20 @@ impl GpuRaytracer {
                     count: None,
                 },
                 wgpu::BindGroupLayoutEntry {
-                    binding: 3,
+                    binding: 4,
                     visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
                     count: None,
                 },
                 wgpu::BindGroupLayoutEntry {
-                    binding: 4,
+                    binding: 5,
                     visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
@@ -139,19 +659,29 @@ impl GpuRaytracer {
                 },
             ],
         });
+        let tiles = TileScheduler::new(gpu, renderer)?;
+        let live_args_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("live paths dispatch"),
+            size: LIVE_ARGS_SIZE,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+        });
+        let kernel = Kernel::Megakernel;
+        let work_buf = create_work_buffer(device, 0);
+        let paths_buf = create_paths_buffer(device, 0, false);
+        let (target_bg, preview_bg) = create_target_bgs(
+            device,
+            &target_bgl,
+            renderer,
+            tiles.queue_buffer(),
+            &work_buf,
+            &paths_buf,
+        );
 
         let compute_bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &compute_bgl,
             entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(
-                        &renderer
-                            .scene_texture()
-                            .create_view(&wgpu::TextureViewDescriptor::default()),
-                    ),
-                },
                 wgpu::BindGroupEntry {
                     binding: 1,
                     resource: spheres_buf.as_entire_binding(),
@@ -168,111 +698,639 @@ impl GpuRaytracer {
                     binding: 4,
                     resource: limits_buf.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: cones_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: disks_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: csg_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: sdfs_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: meshes_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: vertices_bufs[0].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: indices_bufs[0].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 15,
+                    resource: vertices_bufs[1].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 16,
+                    resource: indices_bufs[1].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 17,
+                    resource: bvh_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 18,
+                    resource: material_params_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 19,
+                    resource: wgpu::BindingResource::TextureView(&texture_pool.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 20,
+                    resource: wgpu::BindingResource::Sampler(&texture_pool.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: environment_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: lights_buf.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 14,
+                    resource: light_tree_buf.as_entire_binding(),
+                },
             ],
         });
 
+        let compute_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[gpu_camera.bind_group_layout(), &compute_bgl, &target_bgl],
+                push_constant_ranges: &[],
+            });
         let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
             label: None,
-            layout: Some(
-                &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: None,
-                    bind_group_layouts: &[gpu_camera.bind_group_layout(), &compute_bgl],
-                    push_constant_ranges: &[],
-                }),
-            ),
+            layout: Some(&compute_pipeline_layout),
             module: &compute_shader,
             entry_point: "raytrace",
         });
+        let tiles_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&compute_pipeline_layout),
+            module: &compute_shader,
+            entry_point: "raytrace_tiles",
+        });
+        let start_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&compute_pipeline_layout),
+            module: &compute_shader,
+            entry_point: "start_paths",
+        });
+        let prepare_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&compute_pipeline_layout),
+            module: &compute_shader,
+            entry_point: "prepare_bounce",
+        });
+        let bounce_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&compute_pipeline_layout),
+            module: &compute_shader,
+            entry_point: "bounce_paths",
+        });
+        let resolve_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&compute_pipeline_layout),
+            module: &compute_shader,
+            entry_point: "resolve_paths",
+        });
+        gpu.pop_error_scope("the compute pipelines")?;
 
         Ok(Self {
             pipeline: compute_pipeline,
+            tiles_pipeline,
+            tiles,
+            region: None,
+            work_buf,
+            live_args_buf,
+            start_pipeline,
+            prepare_pipeline,
+            bounce_pipeline,
+            resolve_pipeline,
+            kernel,
+            paths_buf,
+            paths_per_pixel: 1,
+            max_bounces: max_bounces as u32,
+            bounces_per_dispatch: 1,
+            packed_paths: false,
+            pixel_filter: PixelFilter::Box,
             compute_bg,
-            spheres_buf,
+            target_bg,
+            preview_bg,
             seed_buf,
-            mats_buf,
             limits_buf,
-            compute_bgl,
+            vertex_chunk: scene_bufs.vertex_chunk,
+            index_chunk: scene_bufs.index_chunk,
+            target_bgl,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            seed,
+            progress: None,
+            started: Mutex::new(None),
+            cancellation: None,
         })
     }
 
-    fn compute(&self, gpu: &Gpu, gpu_camera: &GpuCamera) -> Result<()> {
-        let Gpu { device, queue, .. } = gpu;
-        let mut seed_uniform = encase::UniformBuffer::new(vec![]);
-
+    /// Traces pass `sample` into the image, only the region set or the noisy tiles with
+    /// adaptive sampling on.
+    fn compute(&self, gpu: &Gpu, gpu_camera: &GpuCamera, sample: u32) -> Result<()> {
+        let Gpu { device, .. } = gpu;
         let seed_uniform_contents = SeedUniform {
-            seed: generate_seed(),
+            seed: generate_seed(&mut self.rng.lock().unwrap()),
+            sample,
         };
+        gpu.write_uniform(&self.seed_buf, &seed_uniform_contents)?;
 
-        seed_uniform.write(&seed_uniform_contents)?;
-        queue.write_buffer(&self.seed_buf, 0, seed_uniform.into_inner().as_slice());
+        let camera = gpu_camera.camera();
+        let adaptive = self.tiles.threshold().is_some()
+            && !camera.is_preview()
+            && sample >= TileScheduler::WARMUP_PASSES;
 
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let target_bg = if camera.is_preview() {
+            &self.preview_bg
+        } else {
+            &self.target_bg
+        };
 
-        {
-            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: None,
-                timestamp_writes: None,
-            });
+        if let Some(region) = self.region.filter(|_| !camera.is_preview()) {
+            self.tiles.queue_region(gpu, region);
+            let mut cpass = self.begin_trace_pass(&mut encoder, gpu_camera, target_bg);
+            cpass.set_pipeline(&self.tiles_pipeline);
+            cpass.dispatch_workgroups_indirect(self.tiles.queue_buffer(), 0);
+        } else if adaptive {
+            self.tiles.select(gpu, &mut encoder);
+            let mut cpass = self.begin_trace_pass(&mut encoder, gpu_camera, target_bg);
+            cpass.set_pipeline(&self.tiles_pipeline);
+            cpass.dispatch_workgroups_indirect(self.tiles.queue_buffer(), 0);
+        } else if self.kernel == Kernel::MultiPass {
+            gpu.write_buffer(&self.work_buf, 0, &[0; WORK_HEADER_SIZE as usize]);
+            let (width, height) = camera.traced_size();
+            {
+                let mut cpass = self.begin_trace_pass(&mut encoder, gpu_camera, target_bg);
+                cpass.set_pipeline(&self.start_pipeline);
+                cpass.dispatch_workgroups(
+                    width.div_ceil(Self::PATH_WORKGROUP_SIZE),
+                    height.div_ceil(Self::PATH_WORKGROUP_SIZE),
+                    self.paths_per_pixel,
+                );
+                cpass.set_pipeline(&self.prepare_pipeline);
+                cpass.dispatch_workgroups(1, 1, 1);
+            }
+
+            for _ in 0..(self.max_bounces + 1).div_ceil(self.bounces_per_dispatch) {
+                encoder.copy_buffer_to_buffer(
+                    &self.work_buf,
+                    WORK_ARGS_OFFSET,
+                    &self.live_args_buf,
+                    0,
+                    LIVE_ARGS_SIZE,
+                );
+                let mut cpass = self.begin_trace_pass(&mut encoder, gpu_camera, target_bg);
+                cpass.set_pipeline(&self.bounce_pipeline);
+                cpass.dispatch_workgroups_indirect(&self.live_args_buf, 0);
+                cpass.set_pipeline(&self.prepare_pipeline);
+                cpass.dispatch_workgroups(1, 1, 1);
+            }
+
+            let mut cpass = self.begin_trace_pass(&mut encoder, gpu_camera, target_bg);
+            cpass.set_pipeline(&self.resolve_pipeline);
+            cpass.dispatch_workgroups(
+                width.div_ceil(Self::PATH_WORKGROUP_SIZE),
+                height.div_ceil(Self::PATH_WORKGROUP_SIZE),
+                1,
+            );
+        } else {
+            gpu.write_buffer(&self.work_buf, 0, &[0; WORK_HEADER_SIZE as usize]);
+            let mut cpass = self.begin_trace_pass(&mut encoder, gpu_camera, target_bg);
             cpass.set_pipeline(&self.pipeline);
-            cpass.set_bind_group(0, gpu_camera.bind_group(), &[]);
-            cpass.set_bind_group(1, &self.compute_bg, &[]);
-            cpass.dispatch_workgroups(gpu_camera.camera().width, gpu_camera.camera().height, 1);
+            cpass.dispatch_workgroups(Self::PERSISTENT_WORKGROUPS, 1, 1);
         }
 
-        queue.submit(Some(encoder.finish()));
+        gpu.submit(Some(encoder.finish()));
         Ok(())
     }
 
+    fn begin_trace_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        gpu_camera: &'a GpuCamera,
+        target_bg: &'a wgpu::BindGroup,
+    ) -> wgpu::ComputePass<'a> {
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        cpass.set_bind_group(0, gpu_camera.bind_group(), &[]);
+        cpass.set_bind_group(1, &self.compute_bg, &[]);
+        cpass.set_bind_group(2, target_bg, &[]);
+        cpass
+    }
+
     pub fn on_resize(&mut self, gpu: &Gpu, renderer: &Renderer) -> Result<()> {
+        self.tiles.on_resize(gpu, renderer);
+        self.rebind_targets(gpu, renderer);
+        Ok(())
+    }
+
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    /// Switches how full passes follow their paths, see `Kernel`.
+    pub fn set_kernel(&mut self, gpu: &Gpu, renderer: &Renderer, kernel: Kernel) {
+        self.kernel = kernel;
+        self.rebind_targets(gpu, renderer);
+    }
+
+    /// Recreates the work and paths buffers for the kernel and image size, and the bind groups
+    /// using them.
+    fn rebind_targets(&mut self, gpu: &Gpu, renderer: &Renderer) {
         let Gpu { device, .. } = gpu;
+        let paths = match self.kernel {
+            Kernel::Megakernel => 0,
+            Kernel::MultiPass => {
+                let size = renderer.scene_texture().size();
+                size.width as u64 * size.height as u64 * self.paths_per_pixel as u64
+            }
+        };
+        self.work_buf = create_work_buffer(device, paths);
+        self.paths_buf = create_paths_buffer(device, paths, self.packed_paths);
+        (self.target_bg, self.preview_bg) = create_target_bgs(
+            device,
+            &self.target_bgl,
+            renderer,
+            self.tiles.queue_buffer(),
+            &self.work_buf,
+            &self.paths_buf,
+        );
+    }
 
-        let compute_bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
+    /// Changes how many times rays bounce in passes submitted from now on.
+    pub fn set_max_bounces(&mut self, gpu: &Gpu, max_bounces: usize) -> Result<()> {
+        self.max_bounces = max_bounces as u32;
+        self.write_limits(gpu)
+    }
+
+    pub fn bounces_per_dispatch(&self) -> u32 {
+        self.bounces_per_dispatch
+    }
+
+    /// Changes how many bounces each dispatch of the multi-pass kernel advances its paths by.
+    pub fn set_bounces_per_dispatch(&mut self, gpu: &Gpu, bounces_per_dispatch: u32) -> Result<()> {
+        self.bounces_per_dispatch = bounces_per_dispatch.max(1);
+        self.write_limits(gpu)
+    }
+
+    pub fn packed_paths(&self) -> bool {
+        self.packed_paths
+    }
+
+    /// Stores the paths of the multi-pass kernel with octahedral directions and half precision
+    /// colors, 13 words per pixel instead of 21.
+    pub fn set_packed_paths(&mut self, gpu: &Gpu, renderer: &Renderer, packed: bool) -> Result<()> {
+        self.packed_paths = packed;
+        self.rebind_targets(gpu, renderer);
+        self.write_limits(gpu)
+    }
+
+    pub fn paths_per_pixel(&self) -> u32 {
+        self.paths_per_pixel
+    }
+
+    /// Changes how many paths each pass of the multi-pass kernel follows from every pixel.
+    pub fn set_paths_per_pixel(
+        &mut self,
+        gpu: &Gpu,
+        renderer: &Renderer,
+        paths_per_pixel: u32,
+    ) -> Result<()> {
+        self.paths_per_pixel = paths_per_pixel.max(1);
+        self.rebind_targets(gpu, renderer);
+        self.write_limits(gpu)
+    }
+
+    pub fn pixel_filter(&self) -> PixelFilter {
+        self.pixel_filter
+    }
+
+    /// Changes the filter the samples of later passes are reconstructed with.
+    pub fn set_pixel_filter(&mut self, gpu: &Gpu, filter: PixelFilter) -> Result<()> {
+        self.pixel_filter = filter;
+        self.write_limits(gpu)
+    }
+
+    fn write_limits(&self, gpu: &Gpu) -> Result<()> {
+        gpu.write_uniform(
+            &self.limits_buf,
+            &LimitUniform {
+                max_bounces: self.max_bounces,
+                vertex_chunk: self.vertex_chunk,
+                index_chunk: self.index_chunk,
+                bounces_per_dispatch: self.bounces_per_dispatch,
+                packed_paths: self.packed_paths as u32,
+                paths_per_pixel: self.paths_per_pixel,
+                pixel_filter: self.pixel_filter as u32,
+            },
+        )
+    }
+
+    pub fn adaptive_threshold(&self) -> Option<f32> {
+        self.tiles.threshold()
+    }
+
+    /// Turns adaptive sampling on with a mean relative error per tile to reach, or off. See
+    /// `tiles`.
+    pub fn set_adaptive_threshold(&mut self, gpu: &Gpu, threshold: Option<f32>) -> Result<()> {
+        self.tiles.set_threshold(gpu, threshold)
+    }
+
+    pub fn region(&self) -> Option<[u32; 4]> {
+        self.region
+    }
+
+    /// Traces only the `[x, y, width, height]` region of the image from now on, or all of it
+    /// with `None`. Pixels of the tiles around the region's edges may be traced too.
+    pub fn set_region(&mut self, region: Option<[u32; 4]>) {
+        self.region = region;
+    }
+
+    /// Calls `progress` once each pass of the image is done on the GPU, e.g. for a progress bar
+    /// of an application embedding the tracer, or stops reporting with `None`. Passes of the
+    /// navigation preview aren't reported. It's called from whichever thread polls the device,
+    /// send the progress over a channel to watch it from another thread.
+    pub fn set_progress(&mut self, progress: Option<Box<dyn Fn(Progress) + Send + Sync>>) {
+        self.progress = progress.map(Arc::from);
+    }
+
+    /// Makes `perform` stop before its next pass once `cancellation` is cancelled, or never
+    /// with `None`. A pass is one submission, so the GPU only finishes the one in flight.
+    pub fn set_cancellation(&mut self, cancellation: Option<CancellationToken>) {
+        self.cancellation = cancellation;
+    }
+
+    /// Starts the pass seed sequence over from the seed given to `new`, to trace the same
+    /// passes again.
+    pub fn restart_seeds(&self) {
+        *self.rng.lock().unwrap() = StdRng::seed_from_u64(self.seed);
+    }
+
+    /// Advances the pass seed sequence as if `passes` passes were traced, to resume a render.
+    pub fn skip_passes(&self, passes: u32) {
+        let mut rng = self.rng.lock().unwrap();
+        for _ in 0..passes {
+            generate_seed(&mut rng);
+        }
+    }
+
+    /// Traces the passes in `samples`, usually `first_sample..num_samples`, calling `after_sample`
+    /// once each pass is submitted. Stops early when it returns `ControlFlow::Break` or the
+    /// render is cancelled, see `set_cancellation`. Returns the number of passes accumulated.
+    pub fn perform(
+        &self,
+        gpu: &Gpu,
+        gpu_camera: &GpuCamera,
+        samples: Range<u32>,
+        stats: Option<&Mutex<StatsWriter>>,
+        mut after_sample: impl FnMut(u32) -> ControlFlow<()>,
+    ) -> Result<u32> {
+        let camera = gpu_camera.camera();
+        let (width, height) = camera.traced_size();
+        let paths_per_pixel = match self.kernel {
+            Kernel::Megakernel => 1,
+            Kernel::MultiPass => self.paths_per_pixel as u64,
+        };
+
+        for sample in samples.clone() {
+            if self.is_cancelled() {
+                return Ok(sample);
+            }
+            let _pass = tracing::debug_span!("compute_pass", sample, width, height).entered();
+            let frame_start = Instant::now();
+            self.compute(gpu, gpu_camera, sample)?;
+            if !camera.is_preview() {
+                self.report_progress(gpu, camera, sample + 1);
+            }
+
+            if let Some(stats) = stats {
+                // Waiting for the queue serializes passes, so only pay for it when asked.
+                let gpu_start = Instant::now();
+                gpu.device.poll(wgpu::Maintain::Wait);
+
+                stats.lock().unwrap().write(&FrameStats {
+                    spp: sample + 1,
+                    rays: width as u64 * height as u64 * paths_per_pixel,
+                    frame_time: frame_start.elapsed(),
+                    gpu_time: gpu_start.elapsed(),
+                })?;
+            }
+
+            if after_sample(sample + 1).is_break() {
+                return Ok(sample + 1);
+            }
+        }
+
+        Ok(samples.end.max(samples.start))
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Reports the pass just submitted to the progress callback once the GPU is done with it.
+    fn report_progress(&self, gpu: &Gpu, camera: &Camera, samples: u32) {
+        let Some(progress) = self.progress.clone() else {
+            return;
+        };
+        let started = {
+            let mut started = self.started.lock().unwrap();
+            if samples == 1 || started.is_none() {
+                *started = Some(Instant::now());
+            }
+            started.unwrap()
+        };
+
+        let total = camera.num_samples;
+        let tiles = samples as u64 * self.tiles.count(self.region) as u64;
+        gpu.queue.on_submitted_work_done(move || {
+            progress(Progress {
+                samples,
+                total,
+                tiles,
+                elapsed: started.elapsed(),
+            })
+        });
+    }
+
+    /// Fails unless `render_to_image` can trace `camera` with `options` on `gpu`: the image
+    /// needs a size within the device's texture limit, samples and bounces.
+    pub fn check_image(gpu: &Gpu, camera: &Camera, options: &ImageOptions) -> Result<()> {
+        let max_size = gpu.device.limits().max_texture_dimension_2d;
+        let (width, height) = (camera.width, camera.height);
+        if width == 0 || height == 0 || width > max_size || height > max_size {
+            return Err(anyhow!(
+                "Invalid image size {width}x{height}, each side must be 1 to {max_size}"
+            ));
+        }
+        if camera.num_samples == 0 {
+            return Err(anyhow!("Invalid camera, it needs at least one sample"));
+        }
+        if options.max_bounces == 0 {
+            return Err(anyhow!("Invalid options, at least one bounce is needed"));
+        }
+        Ok(())
+    }
+
+    /// Traces all samples of `scene` through `camera` and reads the image back, for library
+    /// users after pixels rather than a window. Sets up a renderer and tracer of its own, so
+    /// reuse them with `perform` for more than one-off images. Blocks until the GPU is done.
+    pub fn render_to_image(
+        gpu: &Gpu,
+        scene: &Scene,
+        camera: &Camera,
+        options: &ImageOptions,
+    ) -> Result<RgbaImage> {
+        Self::check_image(gpu, camera, options)?;
+        let mut gpu_camera = GpuCamera::new(gpu, camera.clone())?;
+        gpu_camera.set_exposure(gpu, options.exposure)?;
+        gpu_camera.set_lens(gpu, options.lens)?;
+        gpu_camera.set_projection(gpu, options.projection)?;
+        gpu_camera.set_stereo(gpu, options.stereo)?;
+        let renderer = Renderer::new(gpu, &gpu_camera)?;
+        let raytracer = GpuRaytracer::new(
+            gpu,
+            &gpu_camera,
+            options.max_bounces,
+            &renderer,
+            scene.clone(),
+            options.seed,
+        )?;
+        raytracer.perform(gpu, &gpu_camera, 0..camera.num_samples, None, |_| {
+            ControlFlow::Continue(())
+        })?;
+
+        let accumulation = renderer.read_accumulation(gpu)?;
+        Ok(RgbaImage::from_accumulation(
+            accumulation.width,
+            accumulation.height,
+            &accumulation.texels,
+            gpu_camera.camera().exposure(),
+        ))
+    }
+}
+
+/// Work buffer with a queue long enough for `paths` live paths, those being popped by a bounce
+/// and those it pushes.
+fn create_work_buffer(device: &wgpu::Device, paths: u64) -> wgpu::Buffer {
+    let queue = (2 * paths).max(1).next_power_of_two();
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("work"),
+        size: WORK_HEADER_SIZE + queue * 4,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+fn create_paths_buffer(device: &wgpu::Device, paths: u64, packed: bool) -> wgpu::Buffer {
+    let words = if packed {
+        PACKED_PATH_WORDS
+    } else {
+        PATH_WORDS
+    };
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("paths"),
+        size: paths.max(1) * words * 4,
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::STORAGE,
+    })
+}
+
+/// Bind groups tracing into the scene image and into the preview.
+fn create_target_bgs(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    renderer: &Renderer,
+    tile_queue: &wgpu::Buffer,
+    work: &wgpu::Buffer,
+    paths: &wgpu::Buffer,
+) -> (wgpu::BindGroup, wgpu::BindGroup) {
+    let bind_group = |target: Target| {
+        let view =
+            |texture: &wgpu::Texture| texture.create_view(&wgpu::TextureViewDescriptor::default());
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
-            layout: &self.compute_bgl,
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(
-                        &renderer
-                            .scene_texture()
-                            .create_view(&wgpu::TextureViewDescriptor::default()),
-                    ),
+                    resource: wgpu::BindingResource::TextureView(&view(target.color)),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: self.spheres_buf.as_entire_binding(),
+                    resource: wgpu::BindingResource::TextureView(&view(target.hit)),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: self.mats_buf.as_entire_binding(),
+                    resource: wgpu::BindingResource::TextureView(&view(target.moments)),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: self.seed_buf.as_entire_binding(),
+                    resource: tile_queue.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 4,
-                    resource: self.limits_buf.as_entire_binding(),
+                    resource: work.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: paths.as_entire_binding(),
                 },
             ],
-        });
+        })
+    };
 
-        self.compute_bg = compute_bg;
-        Ok(())
-    }
+    (
+        bind_group(renderer.scene_target()),
+        bind_group(renderer.preview_target()),
+    )
+}
 
-    pub fn perform(
-        &self,
-        gpu: &Gpu,
-        gpu_camera: &GpuCamera,
-        window: &winit::window::Window,
-    ) -> Result<()> {
-        for _ in 0..gpu_camera.camera().num_samples {
-            self.compute(gpu, gpu_camera)?;
-            window.request_redraw();
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::{assert_layout, wgsl_offset, wgsl_u32};
 
-        Ok(())
+    #[test]
+    fn uniforms_match_wgsl_layout() {
+        let shader = &crate::shader::compute(&[]).unwrap();
+        assert_layout::<SeedUniform>(shader, "SeedUniform");
+        assert_layout::<LimitUniform>(shader, "LimitsUniform");
+        assert_eq!(
+            wgsl_offset(shader, "Work", "workgroups_x"),
+            WORK_ARGS_OFFSET
+        );
+        assert_eq!(wgsl_offset(shader, "Work", "queue"), WORK_HEADER_SIZE);
+        assert_eq!(wgsl_u32(shader, "PATH_WORDS") as u64, PATH_WORDS);
+        assert_eq!(
+            wgsl_u32(shader, "PACKED_PATH_WORDS") as u64,
+            PACKED_PATH_WORDS
+        );
     }
 }
@@ -1,45 +1,138 @@
-use crate::camera::GpuCamera;
-use crate::gpu::Gpu;
-use anyhow::Result;
+use crate::camera::{Camera, GpuCamera};
+use crate::console::Severity;
+use crate::denoise::{Denoiser, Filter, Target};
+use crate::gpu::{Gpu, WindowSurface};
+use crate::image::Accumulation;
+use crate::overlay::Overlay;
+use crate::types::Vec4;
+use anyhow::{anyhow, Result};
+use encase::ShaderType;
 
 pub struct Renderer {
     scene_tex: wgpu::Texture,
-    sampler: wgpu::Sampler,
+    /// Low resolution image traced while the camera moves, see `Camera::PREVIEW_SCALE`.
+    preview_tex: wgpu::Texture,
+    /// Normal and distance of the first hit per pixel of the scene and preview images,
+    /// written by the tracer for reprojection and denoising.
+    scene_hit_tex: wgpu::Texture,
+    preview_hit_tex: wgpu::Texture,
+    /// Sums of squared sample luminance accumulated alongside the images.
+    scene_moments_tex: wgpu::Texture,
+    preview_moments_tex: wgpu::Texture,
     pipeline: wgpu::RenderPipeline,
+    /// Draws the noise estimate instead of the image, see `fs_variance`.
+    variance_pipeline: wgpu::RenderPipeline,
+    /// Draws the normals of the first hits, see `fs_normals`.
+    normals_pipeline: wgpu::RenderPipeline,
+    /// The whole image, as the main window shows it.
+    display: Display,
+    view_bgl: wgpu::BindGroupLayout,
     render_bg: wgpu::BindGroup,
+    /// Shows the denoiser's output in place of both images.
+    denoised_bg: wgpu::BindGroup,
     render_bgl: wgpu::BindGroupLayout,
+    denoiser: Denoiser,
+    filter: Filter,
+    overlay: Overlay,
+    /// Set when the device can't clear textures itself.
+    clear_pipeline: Option<ClearPipeline>,
+}
+
+/// What a window shows of the traced image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum View {
+    /// The image, denoised when a filter is on.
+    Image,
+    /// The noise estimate of every pixel, see `fs_variance`.
+    Variance,
+    /// Normals of the first hits, see `fs_normals`.
+    Normals,
+}
+
+#[derive(ShaderType)]
+struct ViewUniform {
+    /// Offset in xy and size in zw of the shown part of the image, in texture coordinates.
+    region: Vec4,
+}
+
+/// A view of the image for a window, all of it letterboxed or a zoomed part filling the
+/// window. See `Renderer::create_display`.
+pub struct Display {
+    pub view: View,
+    zoomed: bool,
+    /// The renderer's own display, which denoises the image for the others.
+    primary: bool,
+    view_buf: wgpu::Buffer,
+    view_bg: wgpu::BindGroup,
+}
+
+impl Display {
+    /// Shows the whole image.
+    pub fn show_all(&mut self, gpu: &Gpu) -> Result<()> {
+        self.zoomed = false;
+        gpu.write_uniform(
+            &self.view_buf,
+            &ViewUniform {
+                region: Vec4::new(0.0, 0.0, 1.0, 1.0),
+            },
+        )
+    }
+
+    /// Fills a `window` sized surface with the part of an `image` sized image around `center`
+    /// (in texture coordinates), each image pixel `magnification` window pixels wide.
+    pub fn zoom(
+        &mut self,
+        gpu: &Gpu,
+        center: (f32, f32),
+        magnification: f32,
+        image: (u32, u32),
+        window: (u32, u32),
+    ) -> Result<()> {
+        self.zoomed = true;
+        let width = window.0 as f32 / (magnification * image.0 as f32);
+        let height = window.1 as f32 / (magnification * image.1 as f32);
+        gpu.write_uniform(
+            &self.view_buf,
+            &ViewUniform {
+                region: Vec4::new(
+                    center.0 - width / 2.0,
+                    center.1 - height / 2.0,
+                    width,
+                    height,
+                ),
+            },
+        )
+    }
+}
+
+struct ClearPipeline {
+    pipeline: wgpu::ComputePipeline,
+    bgl: wgpu::BindGroupLayout,
 }
 
 impl Renderer {
-    pub fn new(gpu: &Gpu, gpu_camera: &GpuCamera) -> Self {
-        let Gpu { device, .. } = gpu;
+    pub fn new(gpu: &Gpu, gpu_camera: &GpuCamera) -> Result<Self> {
+        Self::with_format(gpu, gpu_camera, wgpu::TextureFormat::Rgba8UnormSrgb)
+    }
 
-        let swap_format = wgpu::TextureFormat::Rgba8UnormSrgb;
+    /// A renderer drawing into textures of `swap_format`, see `draw`.
+    pub fn with_format(
+        gpu: &Gpu,
+        gpu_camera: &GpuCamera,
+        swap_format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let Gpu { device, .. } = gpu;
 
+        gpu.push_error_scope();
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: None,
+            label: Some("render.wgsl"),
             source: wgpu::ShaderSource::Wgsl(include_str!("render.wgsl").into()),
         });
 
-        let scene_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label: None,
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            lod_min_clamp: 0.0,
-            lod_max_clamp: 100.0,
-            compare: None,
-            anisotropy_clamp: 1,
-            border_color: None,
-        });
-
         let camera = gpu_camera.camera();
 
         let scene_tex = device.create_texture(&wgpu::TextureDescriptor {
-            label: None,
+            label: Some("scene"),
             size: wgpu::Extent3d {
                 width: camera.width,
                 height: camera.height,
@@ -56,6 +149,12 @@ impl Renderer {
             view_formats: &[],
         });
 
+        let preview_tex = create_preview_texture(device, camera);
+        let scene_hit_tex = create_aov_texture(device, scene_tex.size());
+        let preview_hit_tex = create_aov_texture(device, preview_tex.size());
+        let scene_moments_tex = create_aov_texture(device, scene_tex.size());
+        let preview_moments_tex = create_aov_texture(device, preview_tex.size());
+
         let render_bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
             entries: &[
@@ -72,76 +171,177 @@ impl Renderer {
                 wgpu::BindGroupLayoutEntry {
                     binding: 1,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
                     count: None,
                 },
             ],
         });
 
-        let render_bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &render_bgl,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(
-                        &scene_tex.create_view(&wgpu::TextureViewDescriptor::default()),
-                    ),
+        let view_bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("view"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&scene_sampler),
-                },
-            ],
+                count: None,
+            }],
         });
 
+        let render_bg = create_render_bg(
+            device,
+            &render_bgl,
+            [&scene_tex, &preview_tex],
+            [&scene_moments_tex, &preview_moments_tex],
+            [&scene_hit_tex, &preview_hit_tex],
+        );
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
-            bind_group_layouts: &[gpu_camera.bind_group_layout(), &render_bgl],
+            bind_group_layouts: &[gpu_camera.bind_group_layout(), &render_bgl, &view_bgl],
             push_constant_ranges: &[],
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[],
+        let create_pipeline = |fragment_entry_point| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: None,
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: fragment_entry_point,
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: swap_format,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleStrip,
+                    ..Default::default()
+                },
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let pipeline = create_pipeline("fs_main");
+        let variance_pipeline = create_pipeline("fs_variance");
+        let normals_pipeline = create_pipeline("fs_normals");
+        gpu.pop_error_scope("the render pipelines")?;
+
+        let denoiser = Denoiser::new(
+            gpu,
+            Target {
+                color: &scene_tex,
+                hit: &scene_hit_tex,
+                moments: &scene_moments_tex,
             },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: swap_format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleStrip,
-                ..Default::default()
+            Target {
+                color: &preview_tex,
+                hit: &preview_hit_tex,
+                moments: &preview_moments_tex,
             },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState::default(),
-            multiview: None,
-        });
+        )?;
+        let denoised_bg = create_render_bg(
+            device,
+            &render_bgl,
+            [denoiser.output(), denoiser.output()],
+            [&scene_moments_tex, &preview_moments_tex],
+            [&scene_hit_tex, &preview_hit_tex],
+        );
+
+        let overlay = Overlay::new(gpu, swap_format)?;
 
-        Self {
+        let clear_pipeline = if device.features().contains(wgpu::Features::CLEAR_TEXTURE) {
+            None
+        } else {
+            Some(ClearPipeline::new(gpu)?)
+        };
+
+        let mut display = create_display(gpu, &view_bgl, View::Image)?;
+        display.primary = true;
+
+        Ok(Self {
             scene_tex,
+            preview_tex,
+            scene_hit_tex,
+            preview_hit_tex,
+            scene_moments_tex,
+            preview_moments_tex,
             pipeline,
+            variance_pipeline,
+            normals_pipeline,
+            display,
+            view_bgl,
             render_bg,
+            denoised_bg,
             render_bgl,
-            sampler: scene_sampler,
-        }
+            denoiser,
+            filter: Filter::Off,
+            overlay,
+            clear_pipeline,
+        })
     }
 
     pub fn on_resize(&mut self, gpu: &Gpu, gpu_camera: &GpuCamera) -> Result<()> {
         let Gpu { device, .. } = gpu;
         let camera = gpu_camera.camera();
 
+        gpu.push_error_scope();
         let new_scene_tex = device.create_texture(&wgpu::TextureDescriptor {
-            label: None,
+            label: Some("scene"),
             size: wgpu::Extent3d {
                 width: camera.width,
                 height: camera.height,
@@ -176,48 +376,157 @@ impl Renderer {
         // queue.submit(Some(encoder.finish()));
 
         self.scene_tex = new_scene_tex;
-        self.render_bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &self.render_bgl,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(
-                        &self
-                            .scene_tex
-                            .create_view(&wgpu::TextureViewDescriptor::default()),
-                    ),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&self.sampler),
-                },
-            ],
-        });
+        self.preview_tex = create_preview_texture(device, camera);
+        self.scene_hit_tex = create_aov_texture(device, self.scene_tex.size());
+        self.preview_hit_tex = create_aov_texture(device, self.preview_tex.size());
+        self.scene_moments_tex = create_aov_texture(device, self.scene_tex.size());
+        self.preview_moments_tex = create_aov_texture(device, self.preview_tex.size());
+        self.render_bg = create_render_bg(
+            device,
+            &self.render_bgl,
+            [&self.scene_tex, &self.preview_tex],
+            [&self.scene_moments_tex, &self.preview_moments_tex],
+            [&self.scene_hit_tex, &self.preview_hit_tex],
+        );
+        self.denoiser.on_resize(
+            gpu,
+            Target {
+                color: &self.scene_tex,
+                hit: &self.scene_hit_tex,
+                moments: &self.scene_moments_tex,
+            },
+            Target {
+                color: &self.preview_tex,
+                hit: &self.preview_hit_tex,
+                moments: &self.preview_moments_tex,
+            },
+        );
+        self.denoised_bg = create_render_bg(
+            device,
+            &self.render_bgl,
+            [self.denoiser.output(), self.denoiser.output()],
+            [&self.scene_moments_tex, &self.preview_moments_tex],
+            [&self.scene_hit_tex, &self.preview_hit_tex],
+        );
+        gpu.pop_error_scope("the resized scene textures")?;
 
         Ok(())
     }
 
-    pub fn render(&self, gpu: &Gpu, gpu_camera: &GpuCamera) -> Result<()> {
-        let Gpu {
-            device,
-            queue,
-            surface,
-            ..
-        } = gpu;
+    /// Presents the image to the main window, with the `overlay` lines taken by
+    /// `Overlay::snapshot` drawn on top.
+    pub fn render(
+        &self,
+        gpu: &Gpu,
+        gpu_camera: &GpuCamera,
+        overlay: Option<&[(Severity, String)]>,
+    ) -> Result<()> {
+        let surface = gpu
+            .surface
+            .as_ref()
+            .ok_or_else(|| anyhow!("Cannot present without a surface"))?;
+        self.present(gpu, surface, gpu_camera, &self.display, overlay)
+    }
+
+    /// A view for another window, showing the whole image until zoomed. Shown with
+    /// `render_display` after the main window's `render` has denoised the image.
+    pub fn create_display(&self, gpu: &Gpu, view: View) -> Result<Display> {
+        create_display(gpu, &self.view_bgl, view)
+    }
+
+    pub fn render_display(
+        &self,
+        gpu: &Gpu,
+        surface: &WindowSurface,
+        gpu_camera: &GpuCamera,
+        display: &Display,
+    ) -> Result<()> {
+        self.present(gpu, surface, gpu_camera, display, None)
+    }
 
-        let frame = surface.get_current_texture()?;
+    /// Draws `display` into the next frame of `surface` and presents it.
+    fn present(
+        &self,
+        gpu: &Gpu,
+        surface: &WindowSurface,
+        gpu_camera: &GpuCamera,
+        display: &Display,
+        overlay: Option<&[(Severity, String)]>,
+    ) -> Result<()> {
+        let Gpu { device, .. } = gpu;
+
+        let frame = match surface.surface.get_current_texture() {
+            Ok(frame) => frame,
+            // Alt-tabbing, resizing or a driver reset, the surface works again once reconfigured.
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                surface.configure(device);
+                match surface.surface.get_current_texture() {
+                    Ok(frame) => frame,
+                    Err(wgpu::SurfaceError::OutOfMemory) => {
+                        return Err(wgpu::SurfaceError::OutOfMemory.into())
+                    }
+                    // Still unusable, e.g. while minimized. Skip the frame.
+                    Err(_) => return Ok(()),
+                }
+            }
+            Err(wgpu::SurfaceError::Timeout) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
         let frame_tex_view: wgpu::TextureView = frame
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
 
+        self.draw(
+            gpu,
+            &frame_tex_view,
+            surface.size(),
+            gpu_camera,
+            display,
+            overlay,
+        )?;
+        frame.present();
+        Ok(())
+    }
+
+    /// Draws the whole image into `target`, a `size` texture of the format the renderer was
+    /// created with, for applications embedding the tracer.
+    pub fn draw_into(
+        &self,
+        gpu: &Gpu,
+        target: &wgpu::TextureView,
+        size: (u32, u32),
+        gpu_camera: &GpuCamera,
+    ) -> Result<()> {
+        self.draw(gpu, target, size, gpu_camera, &self.display, None)
+    }
+
+    /// Draws the view of `display` into `target`, letterboxed unless zoomed. The primary
+    /// display denoises the image first, given `overlay` lines they are drawn on top.
+    fn draw(
+        &self,
+        gpu: &Gpu,
+        target: &wgpu::TextureView,
+        window: (u32, u32),
+        gpu_camera: &GpuCamera,
+        display: &Display,
+        overlay: Option<&[(Severity, String)]>,
+    ) -> Result<()> {
+        let Gpu { device, .. } = gpu;
+
+        if let Some(lines) = overlay {
+            self.overlay.prepare(gpu, lines)?;
+        }
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        if display.primary && display.view == View::Image {
+            self.denoiser
+                .denoise(&mut encoder, self.filter, gpu_camera.camera().is_preview());
+        }
         {
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &frame_tex_view,
+                    view: target,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
@@ -229,26 +538,476 @@ impl Renderer {
                 occlusion_query_set: None,
             });
 
-            rpass.set_pipeline(&self.pipeline);
+            let camera = gpu_camera.camera();
+            if !display.zoomed {
+                let [x, y, width, height] = letterbox((camera.width, camera.height), window);
+                rpass.set_viewport(x, y, width, height, 0.0, 1.0);
+            }
             rpass.set_bind_group(0, gpu_camera.bind_group(), &[]);
-            rpass.set_bind_group(1, &self.render_bg, &[]);
+            rpass.set_bind_group(2, &display.view_bg, &[]);
+            match display.view {
+                View::Image => {
+                    rpass.set_pipeline(&self.pipeline);
+                    rpass.set_bind_group(
+                        1,
+                        match self.filter {
+                            Filter::Off => &self.render_bg,
+                            Filter::Atrous | Filter::Svgf => &self.denoised_bg,
+                        },
+                        &[],
+                    );
+                }
+                View::Variance => {
+                    rpass.set_pipeline(&self.variance_pipeline);
+                    rpass.set_bind_group(1, &self.render_bg, &[]);
+                }
+                View::Normals => {
+                    rpass.set_pipeline(&self.normals_pipeline);
+                    rpass.set_bind_group(1, &self.render_bg, &[]);
+                }
+            }
             rpass.draw(0..4, 0..1);
+
+            if overlay.is_some() {
+                let (width, height) = (window.0 as f32, window.1 as f32);
+                rpass.set_viewport(0.0, 0.0, width, height, 0.0, 1.0);
+                self.overlay.draw(&mut rpass);
+            }
         }
 
-        queue.submit(Some(encoder.finish()));
-        frame.present();
+        gpu.submit(Some(encoder.finish()));
         Ok(())
     }
 
+    /// How the displayed image is denoised, saved images never are.
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Whether the per-pixel noise estimate is shown instead of the image.
+    pub fn show_variance(&self) -> bool {
+        self.display.view == View::Variance
+    }
+
+    pub fn set_show_variance(&mut self, show_variance: bool) {
+        self.display.view = if show_variance {
+            View::Variance
+        } else {
+            View::Image
+        };
+    }
+
     pub fn clear(&self, gpu: &Gpu) {
-        let Gpu { device, queue, .. } = gpu;
+        self.clear_texture(gpu, &self.scene_tex);
+        self.clear_texture(gpu, &self.scene_moments_tex);
+    }
+
+    pub fn clear_preview(&self, gpu: &Gpu) {
+        self.clear_texture(gpu, &self.preview_tex);
+        self.clear_texture(gpu, &self.preview_moments_tex);
+    }
+
+    fn clear_texture(&self, gpu: &Gpu, texture: &wgpu::Texture) {
+        let Gpu { device, .. } = gpu;
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        encoder.clear_texture(&self.scene_tex, &wgpu::ImageSubresourceRange::default());
-        queue.submit(Some(encoder.finish()));
+        match &self.clear_pipeline {
+            Some(clear_pipeline) => clear_pipeline.clear(device, &mut encoder, texture),
+            None => encoder.clear_texture(texture, &wgpu::ImageSubresourceRange::default()),
+        }
+        gpu.submit(Some(encoder.finish()));
     }
 
     pub fn scene_texture(&self) -> &wgpu::Texture {
         &self.scene_tex
     }
+
+    /// The scene image with its first hits and moments, as traced by `GpuRaytracer`.
+    pub fn scene_target(&self) -> Target<'_> {
+        Target {
+            color: &self.scene_tex,
+            hit: &self.scene_hit_tex,
+            moments: &self.scene_moments_tex,
+        }
+    }
+
+    pub fn preview_target(&self) -> Target<'_> {
+        Target {
+            color: &self.preview_tex,
+            hit: &self.preview_hit_tex,
+            moments: &self.preview_moments_tex,
+        }
+    }
+
+    pub fn scene_hit_texture(&self) -> &wgpu::Texture {
+        &self.scene_hit_tex
+    }
+
+    pub fn scene_moments_texture(&self) -> &wgpu::Texture {
+        &self.scene_moments_tex
+    }
+
+    /// Replaces the accumulated image and its `moments`, e.g. with ones restored from a
+    /// checkpoint.
+    #[tracing::instrument(skip_all, err)]
+    pub fn write_accumulation(
+        &self,
+        gpu: &Gpu,
+        accumulation: &Accumulation,
+        moments: &[[f32; 4]],
+    ) -> Result<()> {
+        let wgpu::Extent3d { width, height, .. } = self.scene_tex.size();
+        if (accumulation.width, accumulation.height) != (width, height) {
+            return Err(anyhow!(
+                "Expected a {width}x{height} image, got {}x{}",
+                accumulation.width,
+                accumulation.height
+            ));
+        }
+        if moments.len() != accumulation.texels.len() {
+            return Err(anyhow!(
+                "Expected {} moments, got {}",
+                accumulation.texels.len(),
+                moments.len()
+            ));
+        }
+
+        write_texture(gpu, &self.scene_tex, &accumulation.texels);
+        write_texture(gpu, &self.scene_moments_tex, moments);
+        Ok(())
+    }
+
+    /// Copies the accumulated image back to the CPU. Blocks until the GPU is done.
+    #[tracing::instrument(skip_all, err)]
+    pub fn read_accumulation(&self, gpu: &Gpu) -> Result<Accumulation> {
+        let wgpu::Extent3d { width, height, .. } = self.scene_tex.size();
+        self.read_accumulation_region(gpu, (0, 0), (width, height))
+    }
+
+    /// Copies the `size` part of the accumulated image at `origin` back to the CPU. Blocks
+    /// until the GPU is done.
+    #[tracing::instrument(skip_all, err)]
+    pub fn read_accumulation_region(
+        &self,
+        gpu: &Gpu,
+        origin: (u32, u32),
+        (width, height): (u32, u32),
+    ) -> Result<Accumulation> {
+        Ok(Accumulation {
+            width,
+            height,
+            texels: read_texture(gpu, &self.scene_tex, origin, (width, height))?,
+        })
+    }
+
+    /// Copies the moments of the accumulated image back to the CPU, one texel per pixel of
+    /// `read_accumulation`. Blocks until the GPU is done.
+    #[tracing::instrument(skip_all, err)]
+    pub fn read_moments(&self, gpu: &Gpu) -> Result<Vec<[f32; 4]>> {
+        let wgpu::Extent3d { width, height, .. } = self.scene_moments_tex.size();
+        read_texture(gpu, &self.scene_moments_tex, (0, 0), (width, height))
+    }
+}
+
+/// Replaces all `texels` of an `Rgba32Float` texture.
+fn write_texture(gpu: &Gpu, texture: &wgpu::Texture, texels: &[[f32; 4]]) {
+    let wgpu::Extent3d { width, height, .. } = texture.size();
+    let bytes: Vec<u8> = texels
+        .iter()
+        .flatten()
+        .flat_map(|channel| channel.to_le_bytes())
+        .collect();
+    gpu.queue.write_texture(
+        texture.as_image_copy(),
+        &bytes,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(width * 16),
+            rows_per_image: Some(height),
+        },
+        texture.size(),
+    );
+}
+
+/// Reads the `width` x `height` texels at `origin` of an `Rgba32Float` texture back to the
+/// CPU.
+fn read_texture(
+    gpu: &Gpu,
+    texture: &wgpu::Texture,
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+) -> Result<Vec<[f32; 4]>> {
+    let Gpu { device, .. } = gpu;
+
+    const TEXEL_SIZE: u32 = 16;
+    let unpadded_row = width * TEXEL_SIZE;
+    let padded_row = unpadded_row.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+        * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+
+    let readback_buf = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: (padded_row * height) as u64,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder =
+        device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            origin: wgpu::Origin3d { x, y, z: 0 },
+            ..texture.as_image_copy()
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &readback_buf,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row),
+                rows_per_image: Some(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    gpu.submit(Some(encoder.finish()));
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let slice = readback_buf.slice(..);
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = tx.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    rx.recv()??;
+
+    let mut texels = Vec::with_capacity((width * height) as usize);
+    {
+        let data = slice.get_mapped_range();
+        for row in data.chunks_exact(padded_row as usize) {
+            for texel in row[..unpadded_row as usize].chunks_exact(TEXEL_SIZE as usize) {
+                let mut value = [0.0; 4];
+                for (channel, bytes) in value.iter_mut().zip(texel.chunks_exact(4)) {
+                    *channel = f32::from_le_bytes(bytes.try_into().unwrap());
+                }
+                texels.push(value);
+            }
+        }
+    }
+    readback_buf.unmap();
+
+    Ok(texels)
+}
+
+impl ClearPipeline {
+    const WORKGROUP_SIZE: u32 = 8;
+
+    fn new(gpu: &Gpu) -> Result<Self> {
+        let Gpu { device, .. } = gpu;
+
+        gpu.push_error_scope();
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("clear.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("clear.wgsl").into()),
+        });
+
+        let bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            }],
+        });
+
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(
+                &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[&bgl],
+                    push_constant_ranges: &[],
+                }),
+            ),
+            module: &shader,
+            entry_point: "clear",
+        });
+        gpu.pop_error_scope("the clear pipeline")?;
+
+        Ok(Self { pipeline, bgl })
+    }
+
+    fn clear(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bgl,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            }],
+        });
+
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        cpass.set_pipeline(&self.pipeline);
+        cpass.set_bind_group(0, &bind_group, &[]);
+        cpass.dispatch_workgroups(
+            texture.width().div_ceil(Self::WORKGROUP_SIZE),
+            texture.height().div_ceil(Self::WORKGROUP_SIZE),
+            1,
+        );
+    }
+}
+
+/// Viewport showing an `image` sized image in a `window` sized surface: scaled to fit with
+/// its aspect ratio kept and centered, the rest of the window stays black.
+pub fn letterbox(image: (u32, u32), window: (u32, u32)) -> [f32; 4] {
+    let (image_width, image_height) = (image.0 as f32, image.1 as f32);
+    let (window_width, window_height) = (window.0 as f32, window.1 as f32);
+    let scale = (window_width / image_width).min(window_height / image_height);
+    let (width, height) = (image_width * scale, image_height * scale);
+    [
+        (window_width - width) / 2.0,
+        (window_height - height) / 2.0,
+        width,
+        height,
+    ]
+}
+
+fn create_display(gpu: &Gpu, layout: &wgpu::BindGroupLayout, view: View) -> Result<Display> {
+    let Gpu { device, .. } = gpu;
+    let view_buf = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("view"),
+        size: ViewUniform::min_size().get(),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let view_bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("view"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: view_buf.as_entire_binding(),
+        }],
+    });
+
+    let mut display = Display {
+        view,
+        zoomed: false,
+        primary: false,
+        view_buf,
+        view_bg,
+    };
+    display.show_all(gpu)?;
+    Ok(display)
+}
+
+/// Binds the scene and preview images, their moments and first hits.
+fn create_render_bg(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    [scene, preview]: [&wgpu::Texture; 2],
+    [scene_moments, preview_moments]: [&wgpu::Texture; 2],
+    [scene_hits, preview_hits]: [&wgpu::Texture; 2],
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &scene.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &preview.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(
+                    &scene_moments.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(
+                    &preview_moments.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(
+                    &scene_hits.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(
+                    &preview_hits.create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+        ],
+    })
+}
+
+fn create_preview_texture(device: &wgpu::Device, camera: &Camera) -> wgpu::Texture {
+    let width = camera.width.div_ceil(Camera::PREVIEW_SCALE);
+    let height = camera.height.div_ceil(Camera::PREVIEW_SCALE);
+
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+/// Per-pixel data traced alongside an image, see `Renderer::scene_hit_texture`.
+fn create_aov_texture(device: &wgpu::Device, size: wgpu::Extent3d) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: None,
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_SRC
+            | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
 }
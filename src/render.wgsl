@@ -8,11 +8,47 @@ struct Camera {
     delta_v: vec3<f32>,
     width: u32,
     height: u32,
+    exposure: f32,
+    aperture: f32,
+    focus_distance: f32,
+    bokeh: u32,
+    blades: u32,
+    blade_rotation: f32,
+    bokeh_mask: array<vec4<u32>, 2>,
+    distortion_k1: f32,
+    distortion_k2: f32,
+    projection: u32,
+    fov: f32,
+    view_height: f32,
+    eye_separation: f32,
+    preview_scale: u32,
 };
 
 @group(0) @binding(0) var<uniform> cam: Camera;
+// Traced at the window size times the render scale. Float32 textures can't be sampled with
+// filtering everywhere, `filtered` loads the texels itself.
 @group(1) @binding(0) var scene: texture_2d<f32>;
-@group(1) @binding(1) var sceneSampler: sampler;
+@group(1) @binding(1) var preview: texture_2d<f32>;
+// Sums of the squared sample luminance of the scene and preview images.
+@group(1) @binding(2) var scene_moments: texture_2d<f32>;
+@group(1) @binding(3) var preview_moments: texture_2d<f32>;
+// Normal and distance of the first hits of the scene and preview images.
+@group(1) @binding(4) var scene_hits: texture_2d<f32>;
+@group(1) @binding(5) var preview_hits: texture_2d<f32>;
+
+// Part of the image a window shows, offset in xy and size in zw, in texture coordinates.
+struct View {
+    region: vec4<f32>,
+};
+@group(2) @binding(0) var<uniform> view: View;
+
+// Same as in `variance.rs`.
+const MIN_SAMPLES: f32 = 4.0;
+const LUMINANCE_EPSILON: f32 = 0.01;
+// Relative error shown at full red in the variance view.
+const MAX_SHOWN_ERROR: f32 = 0.2;
+// Texels averaged along each axis at most when downscaling, enough for a render scale of 4.
+const MAX_FOOTPRINT: i32 = 4;
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
@@ -38,13 +74,106 @@ fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> VertexOutput {
     );
 
     out.clip_position = vec4<f32>(VERTEX[in_vertex_index], 0.0, 1.0);
-    out.tex_coords = vec2<f32>(TEX[in_vertex_index]);
+    out.tex_coords = view.region.xy + TEX[in_vertex_index] * view.region.zw;
 
     return out;
 }
 
+// Mean color of a texel, which holds the sum of its samples and their count.
+fn texelColor(image: texture_2d<f32>, texel: vec2<i32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(image));
+    let color = textureLoad(image, clamp(texel, vec2<i32>(0), size - 1), 0);
+    return color.rgb / max(color.a, 1.0);
+}
+
+// Color of `image` at `uv` for a window pixel covering `footprint` texels. Bilinear when
+// upscaling, a box over the covered texels when downscaling a supersampled image.
+fn filtered(image: texture_2d<f32>, uv: vec2<f32>, footprint: vec2<f32>) -> vec3<f32> {
+    let position = uv * vec2<f32>(textureDimensions(image)) - 0.5;
+    if max(footprint.x, footprint.y) <= 1.0 {
+        let texel = vec2<i32>(floor(position));
+        let t = position - floor(position);
+        let top = mix(texelColor(image, texel), texelColor(image, texel + vec2<i32>(1, 0)), t.x);
+        let bottom = mix(
+            texelColor(image, texel + vec2<i32>(0, 1)),
+            texelColor(image, texel + vec2<i32>(1, 1)),
+            t.x
+        );
+        return mix(top, bottom, t.y);
+    }
+
+    let first = vec2<i32>(round(position - 0.5 * (footprint - 1.0)));
+    let count = clamp(vec2<i32>(round(footprint)), vec2<i32>(1), vec2<i32>(MAX_FOOTPRINT));
+    var sum = vec3<f32>(0.0);
+    for (var y = 0; y < count.y; y++) {
+        for (var x = 0; x < count.x; x++) {
+            sum += texelColor(image, first + vec2<i32>(x, y));
+        }
+    }
+    return sum / f32(count.x * count.y);
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    var color = textureSample(scene, sceneSampler, in.tex_coords);
-    return vec4<f32>(color.xyz / f32(cam.num_samples), 1.0);
+    // Texels of the scene image per window pixel, below 1 when rendering below native
+    // resolution.
+    let footprint = abs(vec2<f32>(dpdx(in.tex_coords.x), dpdy(in.tex_coords.y)))
+        * vec2<f32>(textureDimensions(scene));
+    var color = filtered(scene, in.tex_coords, footprint);
+    if cam.preview_scale > 1u {
+        // Nearest-neighbour upscale of the low resolution image traced while navigating.
+        let pixel = vec2<u32>(in.tex_coords * vec2<f32>(f32(cam.width), f32(cam.height)));
+        color = texelColor(preview, vec2<i32>(pixel / cam.preview_scale));
+    }
+    return vec4<f32>(cam.exposure * color, 1.0);
+}
+
+// Relative standard error of the pixel's mean luminance, see `variance::relative_error`.
+// Negative below `MIN_SAMPLES`.
+fn relativeError(color: vec4<f32>, moments: vec4<f32>) -> f32 {
+    let samples = color.a;
+    if samples < MIN_SAMPLES {
+        return -1.0;
+    }
+    let mean = dot(color.rgb, vec3<f32>(0.2126, 0.7152, 0.0722)) / samples;
+    let variance = max(moments.x / samples - mean * mean, 0.0) * samples / (samples - 1.0);
+    return sqrt(variance / samples) / (mean + LUMINANCE_EPSILON);
+}
+
+// Debug view of the noise left in each pixel: blue when converged through green to red at
+// `MAX_SHOWN_ERROR`, gray while a pixel has too few samples for an estimate.
+@fragment
+fn fs_variance(in: VertexOutput) -> @location(0) vec4<f32> {
+    var p = vec2<u32>(in.tex_coords * vec2<f32>(f32(cam.width), f32(cam.height)));
+    var color = textureLoad(scene, p, 0);
+    var moments = textureLoad(scene_moments, p, 0);
+    if cam.preview_scale > 1u {
+        p /= cam.preview_scale;
+        color = textureLoad(preview, p, 0);
+        moments = textureLoad(preview_moments, p, 0);
+    }
+
+    let error = relativeError(color, moments);
+    if error < 0.0 {
+        return vec4<f32>(0.2, 0.2, 0.2, 1.0);
+    }
+    let t = clamp(error / MAX_SHOWN_ERROR, 0.0, 1.0);
+    let cold = mix(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), clamp(2.0 * t, 0.0, 1.0));
+    return vec4<f32>(mix(cold, vec3<f32>(1.0, 0.0, 0.0), clamp(2.0 * t - 1.0, 0.0, 1.0)), 1.0);
+}
+
+// Debug view of the first hits' normals mapped to colors, black where the camera ray hit
+// nothing or a light.
+@fragment
+fn fs_normals(in: VertexOutput) -> @location(0) vec4<f32> {
+    var p = vec2<u32>(in.tex_coords * vec2<f32>(f32(cam.width), f32(cam.height)));
+    var hit = textureLoad(scene_hits, p, 0);
+    if cam.preview_scale > 1u {
+        hit = textureLoad(preview_hits, p / cam.preview_scale, 0);
+    }
+
+    if all(hit.xyz == vec3<f32>(0.0)) {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+    return vec4<f32>(0.5 * hit.xyz + 0.5, 1.0);
 }
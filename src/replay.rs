@@ -0,0 +1,228 @@
+//! Recording and replaying of user input.
+//!
+//! A recording stores the scene, window size and seed of the pass sequence,
+//! followed by one timestamped command per line:
+//!
+//! ```text
+//! raytracer-replay 1
+//! seed 1234
+//! size 1200 675
+//! scene rt1.…
+//! 0 recompute
+//! 1532 move forward
+//! 8000 quit
+//! ```
+//!
+//! Replaying the file re-issues the commands at the same offsets from start,
+//! with the same seeds, so traces and bugs reproduce on other machines.
+use crate::camera::CameraChange;
+use crate::presets::Preset;
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "raytracer-replay 1";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    Move(CameraChange),
+    Recompute,
+    CycleResetPolicy,
+    LoadPreset(Preset),
+    SetMaxBounces(usize),
+    SetSamples(u32),
+    SetExposure(f32),
+    ToggleProgressive,
+    ToggleDenoiser,
+    ToggleAtrous,
+    ToggleVarianceView,
+    ToggleKernel,
+    Resize(u32, u32),
+    Quit,
+}
+
+impl Command {
+    fn parse(text: &str) -> Result<Self> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["move", direction] => Command::Move(match *direction {
+                "forward" => CameraChange::Forward,
+                "backward" => CameraChange::Backward,
+                "left" => CameraChange::Left,
+                "right" => CameraChange::Right,
+                "up" => CameraChange::Up,
+                "down" => CameraChange::Down,
+                _ => return Err(anyhow!("Unknown direction `{direction}`")),
+            }),
+            ["recompute"] => Command::Recompute,
+            ["cycle-reset-policy"] => Command::CycleResetPolicy,
+            ["preset", name] => Command::LoadPreset(
+                Preset::from_name(name).ok_or_else(|| anyhow!("Unknown preset `{name}`"))?,
+            ),
+            ["max-bounces", max_bounces] => Command::SetMaxBounces(max_bounces.parse()?),
+            ["samples", num_samples] => Command::SetSamples(num_samples.parse()?),
+            ["exposure", ev] => Command::SetExposure(ev.parse()?),
+            ["toggle-progressive"] => Command::ToggleProgressive,
+            ["toggle-denoiser"] => Command::ToggleDenoiser,
+            ["toggle-atrous"] => Command::ToggleAtrous,
+            ["toggle-variance-view"] => Command::ToggleVarianceView,
+            ["toggle-kernel"] => Command::ToggleKernel,
+            ["resize", width, height] => Command::Resize(width.parse()?, height.parse()?),
+            ["quit"] => Command::Quit,
+            _ => return Err(anyhow!("Unknown command `{text}`")),
+        };
+
+        Ok(command)
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Move(change) => {
+                let direction = match change {
+                    CameraChange::Forward => "forward",
+                    CameraChange::Backward => "backward",
+                    CameraChange::Left => "left",
+                    CameraChange::Right => "right",
+                    CameraChange::Up => "up",
+                    CameraChange::Down => "down",
+                };
+                write!(f, "move {direction}")
+            }
+            Command::Recompute => write!(f, "recompute"),
+            Command::CycleResetPolicy => write!(f, "cycle-reset-policy"),
+            Command::LoadPreset(preset) => write!(f, "preset {}", preset.name()),
+            Command::SetMaxBounces(max_bounces) => write!(f, "max-bounces {max_bounces}"),
+            Command::SetSamples(num_samples) => write!(f, "samples {num_samples}"),
+            Command::SetExposure(ev) => write!(f, "exposure {ev}"),
+            Command::ToggleProgressive => write!(f, "toggle-progressive"),
+            Command::ToggleDenoiser => write!(f, "toggle-denoiser"),
+            Command::ToggleAtrous => write!(f, "toggle-atrous"),
+            Command::ToggleVarianceView => write!(f, "toggle-variance-view"),
+            Command::ToggleKernel => write!(f, "toggle-kernel"),
+            Command::Resize(width, height) => write!(f, "resize {width} {height}"),
+            Command::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+/// Setup a recording starts from.
+pub struct ReplayHeader {
+    pub seed: u64,
+    pub size: (u32, u32),
+    /// Scene code, see `share`.
+    pub scene: String,
+}
+
+pub struct Recorder {
+    start: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, header: &ReplayHeader) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create recording `{}`", path.display()))?;
+        let mut out = BufWriter::new(file);
+
+        writeln!(out, "{HEADER}")?;
+        writeln!(out, "seed {}", header.seed)?;
+        writeln!(out, "size {} {}", header.size.0, header.size.1)?;
+        writeln!(out, "scene {}", header.scene)?;
+
+        Ok(Self {
+            start: Instant::now(),
+            out: Mutex::new(out),
+        })
+    }
+
+    pub fn record(&self, command: Command) -> Result<()> {
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{} {command}", self.start.elapsed().as_millis())?;
+        // Flushed right away so a crash still leaves a usable recording.
+        out.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Replay {
+    pub header: ReplayHeader,
+    commands: Vec<(Duration, Command)>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read recording `{}`", path.display()))?;
+        Self::parse(&source).with_context(|| format!("Invalid recording `{}`", path.display()))
+    }
+
+    fn parse(source: &str) -> Result<Self> {
+        let mut lines = source.lines().enumerate();
+        let mut next_line = |what: &str| {
+            lines
+                .next()
+                .map(|(_, line)| line)
+                .ok_or_else(|| anyhow!("Missing {what}"))
+        };
+
+        if next_line("header")? != HEADER {
+            return Err(anyhow!(
+                "Not a recording, expected `{HEADER}` on the first line"
+            ));
+        }
+
+        let seed = next_line("seed")?
+            .strip_prefix("seed ")
+            .ok_or_else(|| anyhow!("Expected `seed <n>`"))?
+            .parse()?;
+
+        let size = next_line("size")?
+            .strip_prefix("size ")
+            .and_then(|size| size.split_once(' '))
+            .ok_or_else(|| anyhow!("Expected `size <width> <height>`"))?;
+        let size = (size.0.parse()?, size.1.parse()?);
+
+        let scene = next_line("scene")?
+            .strip_prefix("scene ")
+            .ok_or_else(|| anyhow!("Expected `scene <code>`"))?
+            .to_owned();
+
+        let mut commands = vec![];
+        for (idx, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (millis, command) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("Expected `<millis> <command>` on line {}", idx + 1))?;
+            let command =
+                Command::parse(command).with_context(|| format!("On line {}", idx + 1))?;
+            commands.push((Duration::from_millis(millis.parse()?), command));
+        }
+
+        Ok(Self {
+            header: ReplayHeader { seed, size, scene },
+            commands,
+        })
+    }
+
+    /// Calls `apply` with each command at its recorded offset from now.
+    pub fn run(&self, mut apply: impl FnMut(Command) -> Result<()>) -> Result<()> {
+        let start = Instant::now();
+
+        for (at, command) in &self.commands {
+            if let Some(wait) = at.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+            apply(*command)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,521 @@
+//! Minimal parser for the subset of RON used by scene files.
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+    String(String),
+    /// Bare identifier, e.g. a unit enum variant.
+    Ident(String),
+    List(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    /// `Name(a, b)` or `(a, b)`.
+    Tuple(Option<String>, Vec<Value>),
+    /// `Name(field: a)` or `(field: a)`.
+    Struct(Option<String>, Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Value::Tuple(name, _) | Value::Struct(name, _) => name.as_deref(),
+            Value::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(_, fields) => fields.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn required(&self, name: &str) -> Result<&Value> {
+        self.field(name).ok_or_else(|| {
+            anyhow!(
+                "Missing field `{name}` in `{}`",
+                self.name().unwrap_or("struct")
+            )
+        })
+    }
+
+    pub fn as_f32(&self) -> Result<f32> {
+        match self {
+            Value::Number(n) => Ok(*n as f32),
+            _ => Err(anyhow!("Expected a number, got {self:?}")),
+        }
+    }
+
+    pub fn as_u32(&self) -> Result<u32> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as u32),
+            _ => Err(anyhow!("Expected a non-negative integer, got {self:?}")),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(anyhow!("Expected a boolean, got {self:?}")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(anyhow!("Expected a string, got {self:?}")),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&[Value]> {
+        match self {
+            Value::List(items) => Ok(items),
+            _ => Err(anyhow!("Expected a list, got {self:?}")),
+        }
+    }
+
+    pub fn as_map(&self) -> Result<&[(Value, Value)]> {
+        match self {
+            Value::Map(entries) => Ok(entries),
+            _ => Err(anyhow!("Expected a map, got {self:?}")),
+        }
+    }
+
+    pub fn as_vec3(&self) -> Result<crate::types::Vec3> {
+        match self {
+            Value::Tuple(None, items) if items.len() == 3 => Ok(crate::types::Vec3::new(
+                items[0].as_f32()?,
+                items[1].as_f32()?,
+                items[2].as_f32()?,
+            )),
+            _ => Err(anyhow!("Expected a (x, y, z) tuple, got {self:?}")),
+        }
+    }
+}
+
+impl From<f32> for Value {
+    fn from(n: f32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_owned())
+    }
+}
+
+impl From<crate::types::Vec3> for Value {
+    fn from(v: crate::types::Vec3) -> Self {
+        Value::Tuple(None, vec![v.x.into(), v.y.into(), v.z.into()])
+    }
+}
+
+impl Value {
+    /// `Name(field: value, ...)`.
+    pub fn named(name: &str, fields: Vec<(&str, Value)>) -> Self {
+        Value::Struct(
+            Some(name.to_owned()),
+            fields
+                .into_iter()
+                .map(|(field, value)| (field.to_owned(), value))
+                .collect(),
+        )
+    }
+}
+
+impl Value {
+    /// Longest compact value kept on one line by `pretty`.
+    const PRETTY_WIDTH: usize = 80;
+
+    /// Human-readable RON: lists, maps and structs too long for one line get one item per
+    /// line, indented by four spaces.
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize) {
+        let compact = format!("{self:#}");
+        if compact.len() + indent <= Self::PRETTY_WIDTH {
+            out.push_str(&compact);
+            return;
+        }
+
+        let pad = " ".repeat(indent + 4);
+        let (open, close, items): (String, &str, Vec<(Option<String>, &Value)>) = match self {
+            Value::List(items) => (
+                "[".to_owned(),
+                "]",
+                items.iter().map(|v| (None, v)).collect(),
+            ),
+            Value::Map(entries) => (
+                "{".to_owned(),
+                "}",
+                entries
+                    .iter()
+                    .map(|(k, v)| (Some(format!("{k:#}")), v))
+                    .collect(),
+            ),
+            Value::Tuple(name, items) => (
+                format!("{}(", name.as_deref().unwrap_or("")),
+                ")",
+                items.iter().map(|v| (None, v)).collect(),
+            ),
+            Value::Struct(name, fields) => (
+                format!("{}(", name.as_deref().unwrap_or("")),
+                ")",
+                fields.iter().map(|(k, v)| (Some(k.clone()), v)).collect(),
+            ),
+            _ => {
+                out.push_str(&compact);
+                return;
+            }
+        };
+
+        out.push_str(&open);
+        out.push('\n');
+        for (key, value) in items {
+            out.push_str(&pad);
+            if let Some(key) = key {
+                out.push_str(&key);
+                out.push_str(": ");
+            }
+            value.write_pretty(out, indent + 4);
+            out.push_str(",\n");
+        }
+        out.push_str(&" ".repeat(indent));
+        out.push_str(close);
+    }
+}
+
+/// Writes compact RON that `parse` reads back, `{:#}` puts spaces after commas and colons.
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn join<T>(
+            f: &mut std::fmt::Formatter<'_>,
+            items: &[T],
+            mut write: impl FnMut(&mut std::fmt::Formatter<'_>, &T) -> std::fmt::Result,
+        ) -> std::fmt::Result {
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, "{}", if f.alternate() { ", " } else { "," })?;
+                }
+                write(f, item)?;
+            }
+            Ok(())
+        }
+
+        fn item(f: &mut std::fmt::Formatter<'_>, value: &Value) -> std::fmt::Result {
+            if f.alternate() {
+                write!(f, "{value:#}")
+            } else {
+                write!(f, "{value}")
+            }
+        }
+        let colon = if f.alternate() { ": " } else { ":" };
+
+        match self {
+            // Scene values are f32, printing them as such keeps the text short.
+            Value::Number(n) => write!(f, "{}", *n as f32),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' | '\\' => write!(f, "\\{c}")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Ident(ident) => write!(f, "{ident}"),
+            Value::List(items) => {
+                write!(f, "[")?;
+                join(f, items, item)?;
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                join(f, entries, |f, (key, value)| {
+                    item(f, key)?;
+                    write!(f, "{colon}")?;
+                    item(f, value)
+                })?;
+                write!(f, "}}")
+            }
+            Value::Tuple(name, items) => {
+                write!(f, "{}(", name.as_deref().unwrap_or(""))?;
+                join(f, items, item)?;
+                write!(f, ")")
+            }
+            Value::Struct(name, fields) => {
+                write!(f, "{}(", name.as_deref().unwrap_or(""))?;
+                join(f, fields, |f, (field, value)| {
+                    write!(f, "{field}{colon}")?;
+                    item(f, value)
+                })?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+    };
+
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("Unexpected trailing characters"));
+    }
+
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> anyhow::Error {
+        let consumed = &self.chars[..self.pos.min(self.chars.len())];
+        let line = consumed.iter().filter(|c| **c == '\n').count() + 1;
+        let column = consumed.iter().rev().take_while(|c| **c != '\n').count() + 1;
+        anyhow!("{message} at line {line}, column {column}")
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => self.pos += 1,
+                Some('/') if self.chars.get(self.pos + 1) == Some(&'/') => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                Some('/') if self.chars.get(self.pos + 1) == Some(&'*') => {
+                    self.pos += 2;
+                    while self.pos < self.chars.len()
+                        && !(self.chars[self.pos] == '*'
+                            && self.chars.get(self.pos + 1) == Some(&'/'))
+                    {
+                        self.pos += 1;
+                    }
+                    self.pos += 2;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected `{expected}`")))
+        }
+    }
+
+    /// Consumes `close` or a `,` separator. Returns `true` when the sequence ended.
+    fn end_or_comma(&mut self, close: char) -> Result<bool> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                self.skip_whitespace();
+                if self.peek() == Some(close) {
+                    self.pos += 1;
+                    return Ok(true);
+                }
+                Ok(false)
+            }
+            Some(c) if c == close => {
+                self.pos += 1;
+                Ok(true)
+            }
+            _ => Err(self.error(&format!("Expected `,` or `{close}`"))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => self.list(),
+            Some('{') => self.map(),
+            Some('(') => self.parens(None),
+            Some(c) if c == '-' || c == '+' || c == '.' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let ident = self.ident();
+                self.skip_whitespace();
+                match ident.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ if self.peek() == Some('(') => self.parens(Some(ident)),
+                    _ => Ok(Value::Ident(ident)),
+                }
+            }
+            _ => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn number(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.'))
+        {
+            self.pos += 1;
+        }
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse()
+            .map(Value::Number)
+            .map_err(|_| self.error(&format!("Invalid number `{text}`")))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+
+        loop {
+            match self.peek() {
+                None => return Err(self.error("Unterminated string")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    let escaped = match self.peek() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c @ ('"' | '\\')) => c,
+                        _ => return Err(self.error("Invalid escape sequence")),
+                    };
+                    out.push(escaped);
+                    self.pos += 1;
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn list(&mut self) -> Result<Value> {
+        self.expect('[')?;
+        let mut items = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::List(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            if self.end_or_comma(']')? {
+                return Ok(Value::List(items));
+            }
+        }
+    }
+
+    fn map(&mut self) -> Result<Value> {
+        self.expect('{')?;
+        let mut entries = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Map(entries));
+        }
+
+        loop {
+            let key = self.value()?;
+            self.expect(':')?;
+            entries.push((key, self.value()?));
+            if self.end_or_comma('}')? {
+                return Ok(Value::Map(entries));
+            }
+        }
+    }
+
+    fn parens(&mut self, name: Option<String>) -> Result<Value> {
+        self.expect('(')?;
+        self.skip_whitespace();
+
+        if self.peek() == Some(')') {
+            self.pos += 1;
+            return Ok(Value::Struct(name, vec![]));
+        }
+
+        // `ident:` means named fields, anything else is a tuple.
+        let start = self.pos;
+        let is_struct = {
+            let ident = self.ident();
+            self.skip_whitespace();
+            !ident.is_empty() && self.peek() == Some(':')
+        };
+        self.pos = start;
+
+        if is_struct {
+            let mut fields = vec![];
+            loop {
+                self.skip_whitespace();
+                let field = self.ident();
+                if field.is_empty() {
+                    return Err(self.error("Expected a field name"));
+                }
+                self.expect(':')?;
+                fields.push((field, self.value()?));
+                if self.end_or_comma(')')? {
+                    return Ok(Value::Struct(name, fields));
+                }
+            }
+        }
+
+        let mut items = vec![];
+        loop {
+            items.push(self.value()?);
+            if self.end_or_comma(')')? {
+                return Ok(Value::Tuple(name, items));
+            }
+        }
+    }
+}
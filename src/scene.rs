@@ -1,6 +1,14 @@
+use crate::bvh::{self, Aabb, BvhNode};
+use crate::image::RgbaImage;
+use crate::light_tree;
+use crate::material_plugin::{MaterialPlugin, FIRST_CUSTOM_MATERIAL};
+use crate::ron::Value;
 use crate::types::*;
-use anyhow::Result;
-use encase::{ArrayLength, ShaderType};
+use anyhow::{anyhow, Result};
+use encase::{internal::WriteInto, ArrayLength, ShaderType};
+use nalgebra as na;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 #[derive(ShaderType, Clone, Copy, Debug)]
 pub struct Sphere {
@@ -8,12 +16,199 @@ pub struct Sphere {
     radius: f32,
 }
 
+/// Rotation of an object around `pivot`.
+#[derive(ShaderType, Clone, Copy, Debug)]
+pub struct Rotation {
+    /// Unit quaternion as (x, y, z, w).
+    quat: Vec4,
+    pivot: Vec3,
+}
+
 #[derive(ShaderType, Clone, Copy, Debug)]
 struct SceneSphere {
     mat_id: u32,
     sphere: Sphere,
 }
 
+/// Capped cone. `angle` is the half-angle at the apex in radians, the base cap
+/// lies `height` units from the apex along `axis`.
+#[derive(ShaderType, Clone, Copy, Debug)]
+pub struct Cone {
+    apex: Vec3,
+    angle: f32,
+    axis: Vec3,
+    height: f32,
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct SceneCone {
+    mat_id: u32,
+    rotation: Rotation,
+    cone: Cone,
+}
+
+/// Flat disk, hit from both sides. `normal` faces the front side.
+#[derive(ShaderType, Clone, Copy, Debug)]
+pub struct Disk {
+    center: Vec3,
+    radius: f32,
+    normal: Vec3,
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct SceneDisk {
+    mat_id: u32,
+    rotation: Rotation,
+    disk: Disk,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    /// Left operand with the right one carved out.
+    Difference,
+}
+
+/// Convex shape usable as a CSG operand.
+#[derive(Clone, Copy, Debug)]
+pub enum CsgShape {
+    Sphere(Sphere),
+    Cone(Cone),
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct CsgLeaf {
+    kind: u32,
+    sphere: Sphere,
+    cone: Cone,
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct CsgNode {
+    op: u32,
+    mat_id: u32,
+    rotation: Rotation,
+    left: CsgLeaf,
+    right: CsgLeaf,
+}
+
+/// Shape described by a signed distance function, intersected by sphere tracing.
+#[derive(Clone, Copy, Debug)]
+pub enum SdfShape {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    RoundedBox {
+        center: Vec3,
+        half_extents: Vec3,
+        radius: f32,
+    },
+    /// Mandelbox (box fold + sphere fold) fractal fitted into a sphere of `radius`.
+    Mandelbox {
+        center: Vec3,
+        radius: f32,
+        scale: f32,
+        iterations: u32,
+    },
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct SdfLeaf {
+    kind: u32,
+    center: Vec3,
+    size: Vec3,
+    radius: f32,
+    scale: f32,
+    iterations: u32,
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct SdfNode {
+    mat_id: u32,
+    rotation: Rotation,
+    /// Smooth-union radius between `a` and `b`, ignored if `b` is empty.
+    blend: f32,
+    bound_center: Vec3,
+    bound_radius: f32,
+    a: SdfLeaf,
+    b: SdfLeaf,
+}
+
+/// Indexed triangle mesh. `normals`, if given, are interpolated per vertex.
+#[derive(Clone, Debug)]
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    /// Three vertex indices per triangle, counter-clockwise when seen from the front.
+    pub indices: Vec<u32>,
+    /// Ignore hits on triangles facing away from the ray.
+    pub cull_backfaces: bool,
+    /// Determinant and distance threshold of the intersection test.
+    pub epsilon: f32,
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct Vertex {
+    position: Vec3,
+    /// Zero when the mesh has no vertex normals.
+    normal: Vec3,
+}
+
+/// Mesh data shared by its instances, with the triangles in the order of its BVH.
+#[derive(Clone, Debug)]
+struct SceneMesh {
+    first_index: u32,
+    num_triangles: u32,
+    base_vertex: u32,
+    cull_backfaces: u32,
+    epsilon: f32,
+    /// Bottom level BVH over the triangles, in mesh space.
+    nodes: Vec<BvhNode>,
+}
+
+/// Handle of mesh data added with `Scene::add_mesh`, to be placed with `Scene::instance_mesh`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeshId(u32);
+
+/// Handle of an image added with `Scene::add_texture`, see `Material::textured`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureId(u32);
+
+#[derive(Clone, Debug)]
+pub struct Texture {
+    /// Path of the image as referenced by the scene file.
+    pub name: String,
+    pub image: RgbaImage,
+}
+
+/// Handle of a material plugin added with `Scene::add_material_plugin`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaterialKind(u32);
+
+#[derive(Clone, Copy, Debug)]
+struct SceneInstance {
+    mesh: u32,
+    mat_id: u32,
+    rotation: Rotation,
+    /// Applied before `rotation`, the identity unless placed by `Scene::instance_mesh`.
+    transform: na::Matrix4<f32>,
+}
+
+#[derive(ShaderType, Clone, Copy, Debug)]
+struct MeshInstance {
+    /// World to mesh space, an affine transform without its last row.
+    to_local: na::Matrix3x4<f32>,
+    mat_id: u32,
+    /// Root of the mesh's bottom level BVH.
+    root: u32,
+    first_index: u32,
+    base_vertex: u32,
+    cull_backfaces: u32,
+    epsilon: f32,
+}
+
 #[derive(ShaderType)]
 struct GpuMats {
     length: ArrayLength,
@@ -21,6 +216,13 @@ struct GpuMats {
     mats: Vec<Material>,
 }
 
+#[derive(ShaderType)]
+struct GpuMaterialParams {
+    length: ArrayLength,
+    #[size(runtime)]
+    words: Vec<u32>,
+}
+
 #[derive(ShaderType)]
 struct GpuSpheres {
     length: ArrayLength,
@@ -28,12 +230,112 @@ struct GpuSpheres {
     spheres: Vec<SceneSphere>,
 }
 
-#[derive(ShaderType, Default, PartialEq, PartialOrd, Clone, Copy, Debug)]
+#[derive(ShaderType)]
+struct GpuCones {
+    length: ArrayLength,
+    #[size(runtime)]
+    cones: Vec<SceneCone>,
+}
+
+#[derive(ShaderType)]
+struct GpuDisks {
+    length: ArrayLength,
+    #[size(runtime)]
+    disks: Vec<SceneDisk>,
+}
+
+#[derive(ShaderType)]
+struct GpuCsg {
+    length: ArrayLength,
+    #[size(runtime)]
+    nodes: Vec<CsgNode>,
+}
+
+#[derive(ShaderType)]
+struct GpuSdfs {
+    length: ArrayLength,
+    #[size(runtime)]
+    nodes: Vec<SdfNode>,
+}
+
+#[derive(ShaderType)]
+struct GpuMeshInstances {
+    length: ArrayLength,
+    #[size(runtime)]
+    instances: Vec<MeshInstance>,
+}
+
+#[derive(ShaderType)]
+struct GpuVertices {
+    length: ArrayLength,
+    #[size(runtime)]
+    vertices: Vec<Vertex>,
+}
+
+#[derive(ShaderType)]
+struct GpuIndices {
+    length: ArrayLength,
+    #[size(runtime)]
+    indices: Vec<u32>,
+}
+
+#[derive(ShaderType, PartialEq, PartialOrd, Clone, Copy, Debug)]
 pub struct Material {
     mat_type: u32,
     albedo: Vec3,
     fuzz: f32,
     refract_idx: f32,
+    /// Refractive index of the medium the object is embedded in.
+    outer_refract_idx: f32,
+    metallic: f32,
+    /// Along the tangent.
+    roughness: f32,
+    /// Across the tangent, equal to `roughness` unless anisotropic.
+    roughness_bitangent: f32,
+    /// Preferred tangent direction, zero for the default frame.
+    tangent: Vec3,
+    /// Strength of the clear specular layer on top, 0 for none.
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    clearcoat_ior: f32,
+    /// Mean free path inside subsurface materials.
+    scatter_distance: f32,
+    /// Thickness in nanometres of the thin film on top, 0 for none.
+    film_thickness: f32,
+    film_ior: f32,
+    /// Dispersion of dielectrics, 0 for none.
+    abbe_number: f32,
+    /// First word of the parameters of plugin materials, see `Scene::custom_material`.
+    params: u32,
+    /// Layer of the texture pool scaling the albedo, `NO_TEXTURE` for none.
+    texture: u32,
+}
+
+const NO_TEXTURE: u32 = u32::MAX;
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            mat_type: 0,
+            albedo: Vec3::zeros(),
+            fuzz: 0.0,
+            refract_idx: 0.0,
+            outer_refract_idx: 1.0,
+            metallic: 0.0,
+            roughness: 0.0,
+            roughness_bitangent: 0.0,
+            tangent: Vec3::zeros(),
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.0,
+            clearcoat_ior: 1.5,
+            scatter_distance: 0.0,
+            film_thickness: 0.0,
+            film_ior: 1.33,
+            abbe_number: 0.0,
+            params: 0,
+            texture: NO_TEXTURE,
+        }
+    }
 }
 
 impl Material {
@@ -46,7 +348,6 @@ impl Material {
     }
 
     // This is for Debug only.
-    #[allow(unused)]
     pub fn new_normal_map() -> Self {
         Material {
             mat_type: 3,
@@ -54,6 +355,14 @@ impl Material {
         }
     }
 
+    // Shows the surface (u, v) coordinates as red/green, for debugging.
+    pub fn new_uv_map() -> Self {
+        Material {
+            mat_type: 4,
+            ..Default::default()
+        }
+    }
+
     pub fn new_metal(albedo: Vec3, fuzz: f32) -> Self {
         Material {
             mat_type: 1,
@@ -63,13 +372,266 @@ impl Material {
         }
     }
 
-    pub fn new_dielectric(refract_idx: f32) -> Self {
+    /// Metallic-roughness material as used by glTF.
+    pub fn new_pbr(albedo: Vec3, metallic: f32, roughness: f32) -> Self {
+        let roughness = roughness.clamp(0.0, 1.0);
+        Material {
+            mat_type: 5,
+            albedo,
+            metallic: metallic.clamp(0.0, 1.0),
+            roughness,
+            roughness_bitangent: roughness,
+            ..Default::default()
+        }
+    }
+
+    /// Makes a PBR material anisotropic (e.g. brushed metal), `roughness` applying along
+    /// `tangent` and `roughness_bitangent` across it. Without a tangent, it circles the Y axis.
+    pub fn anisotropic(self, roughness_bitangent: f32, tangent: Option<Vec3>) -> Self {
+        Material {
+            roughness_bitangent: roughness_bitangent.clamp(0.0, 1.0),
+            tangent: tangent.map_or(Vec3::zeros(), |t| t.normalize()),
+            ..self
+        }
+    }
+
+    /// Adds a clearcoat layer with its own roughness and index of refraction on top of a PBR
+    /// material, e.g. car paint or varnish.
+    pub fn clearcoat(self, strength: f32, roughness: f32, ior: f32) -> Self {
+        Material {
+            clearcoat: strength.clamp(0.0, 1.0),
+            clearcoat_roughness: roughness.clamp(0.0, 1.0),
+            clearcoat_ior: ior,
+            ..self
+        }
+    }
+
+    /// Translucent material (wax, skin, marble) simulated by a random walk inside the
+    /// object. Light travels `scatter_distance` on average between scattering events, each
+    /// tinting it by `albedo`.
+    pub fn new_subsurface(albedo: Vec3, scatter_distance: f32, refract_idx: f32) -> Self {
+        Material {
+            mat_type: 6,
+            albedo,
+            scatter_distance,
+            refract_idx,
+            ..Default::default()
+        }
+    }
+
+    /// `refract_idx` is the index at 587.6nm. Glass with an `abbe_number` disperses light,
+    /// the lower the number the stronger (around 60 for crown glass, 30 for flint glass).
+    pub fn new_dielectric(refract_idx: f32, abbe_number: Option<f32>) -> Self {
         Material {
             mat_type: 2,
             refract_idx,
+            abbe_number: abbe_number.unwrap_or(0.0).max(0.0),
             ..Default::default()
         }
     }
+
+    /// Thin transparent film (soap, oil) on a metal, dielectric or PBR surface. Light
+    /// reflected off both sides of the film interferes, tinting reflections by viewing angle.
+    pub fn thin_film(self, thickness_nm: f32, ior: f32) -> Self {
+        Material {
+            film_thickness: thickness_nm.max(0.0),
+            film_ior: ior,
+            ..self
+        }
+    }
+
+    /// Scales the albedo by an image texture at the (u, v) coordinates of hits, which spheres
+    /// provide.
+    pub fn textured(self, texture: TextureId) -> Self {
+        Material {
+            texture: texture.0,
+            ..self
+        }
+    }
+
+    /// Places the object inside a dielectric with index `outer_refract_idx`,
+    /// e.g. an air bubble (`new_dielectric(1.0, None)`) inside glass.
+    pub fn inside(self, outer_refract_idx: f32) -> Self {
+        Material {
+            outer_refract_idx,
+            ..self
+        }
+    }
+
+    pub fn refract_idx(&self) -> Option<f32> {
+        (self.mat_type == 2).then_some(self.refract_idx)
+    }
+
+    /// Scene file representation, see `SceneFile::load`. Textures are referenced by the names
+    /// they were added with.
+    pub fn to_ron(self, textures: &[Texture]) -> Value {
+        let mut fields = match self.mat_type {
+            0 => vec![("albedo", self.albedo.into())],
+            1 => vec![("albedo", self.albedo.into()), ("fuzz", self.fuzz.into())],
+            2 => {
+                let mut fields = vec![("refract_idx", self.refract_idx.into())];
+                if self.abbe_number > 0.0 {
+                    fields.push(("abbe_number", self.abbe_number.into()));
+                }
+                fields
+            }
+            5 => {
+                let mut fields = vec![
+                    ("albedo", self.albedo.into()),
+                    ("metallic", self.metallic.into()),
+                    ("roughness", self.roughness.into()),
+                ];
+                if self.roughness_bitangent != self.roughness {
+                    fields.push(("roughness_bitangent", self.roughness_bitangent.into()));
+                }
+                if self.tangent != Vec3::zeros() {
+                    fields.push(("tangent", self.tangent.into()));
+                }
+                if self.clearcoat > 0.0 {
+                    fields.push(("clearcoat", self.clearcoat.into()));
+                    fields.push(("clearcoat_roughness", self.clearcoat_roughness.into()));
+                    fields.push(("clearcoat_ior", self.clearcoat_ior.into()));
+                }
+                fields
+            }
+            6 => vec![
+                ("albedo", self.albedo.into()),
+                ("scatter_distance", self.scatter_distance.into()),
+                ("refract_idx", self.refract_idx.into()),
+            ],
+            // Plugins only exist in code, scene files get a grey stand-in.
+            t if t >= FIRST_CUSTOM_MATERIAL => vec![("albedo", Vec3::repeat(0.5).into())],
+            _ => vec![],
+        };
+        if self.outer_refract_idx != 1.0 {
+            fields.push(("outer_refract_idx", self.outer_refract_idx.into()));
+        }
+        if self.film_thickness > 0.0 {
+            fields.push(("film_thickness", self.film_thickness.into()));
+            fields.push(("film_ior", self.film_ior.into()));
+        }
+        if let Some(texture) = textures.get(self.texture as usize) {
+            fields.push(("texture", Value::String(texture.name.clone())));
+        }
+
+        let name = match self.mat_type {
+            0 => "Lambertian",
+            1 => "Metal",
+            2 => "Dielectric",
+            4 => "UvMap",
+            5 => "Pbr",
+            6 => "Subsurface",
+            t if t >= FIRST_CUSTOM_MATERIAL => "Lambertian",
+            _ => "NormalMap",
+        };
+
+        if fields.is_empty() {
+            Value::Ident(name.to_owned())
+        } else {
+            Value::named(name, fields)
+        }
+    }
+}
+
+impl Rotation {
+    pub fn new(rotation: Quat, pivot: Vec3) -> Self {
+        Rotation {
+            quat: rotation.into_inner().coords,
+            pivot,
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new(Quat::identity(), Vec3::zeros())
+    }
+}
+
+impl Rotation {
+    fn to_matrix(self) -> na::Matrix4<f32> {
+        let quat = Quat::new_normalize(na::Quaternion::from(self.quat));
+        na::Matrix4::new_translation(&self.pivot)
+            * quat.to_homogeneous()
+            * na::Matrix4::new_translation(&-self.pivot)
+    }
+
+    fn is_identity(&self) -> bool {
+        self.quat == Quat::identity().into_inner().coords
+    }
+
+    /// Appends `rotation` and `pivot` fields unless this is the identity.
+    fn push_fields(&self, fields: &mut Vec<(&str, Value)>) {
+        if !self.is_identity() {
+            let q = self.quat;
+            fields.push((
+                "rotation",
+                Value::Tuple(
+                    Some("Quat".to_owned()),
+                    vec![q.x.into(), q.y.into(), q.z.into(), q.w.into()],
+                ),
+            ));
+            fields.push(("pivot", self.pivot.into()));
+        }
+    }
+}
+
+impl Sphere {
+    fn ron_fields(self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("center", self.center.into()),
+            ("radius", self.radius.into()),
+        ]
+    }
+}
+
+impl Cone {
+    fn ron_fields(self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("apex", self.apex.into()),
+            ("axis", self.axis.into()),
+            ("angle", self.angle.into()),
+            ("height", self.height.into()),
+        ]
+    }
+}
+
+impl CsgLeaf {
+    fn to_ron(self) -> Value {
+        match self.kind {
+            0 => Value::named("Sphere", self.sphere.ron_fields()),
+            _ => Value::named("Cone", self.cone.ron_fields()),
+        }
+    }
+}
+
+impl SdfLeaf {
+    fn to_ron(self) -> Value {
+        match self.kind {
+            1 => Value::named(
+                "Sphere",
+                vec![
+                    ("center", self.center.into()),
+                    ("radius", self.radius.into()),
+                ],
+            ),
+            2 => Value::named(
+                "RoundedBox",
+                vec![
+                    ("center", self.center.into()),
+                    ("half_extents", self.size.into()),
+                    ("radius", self.radius.into()),
+                ],
+            ),
+            _ => Value::named(
+                "Mandelbox",
+                vec![
+                    ("center", self.center.into()),
+                    ("radius", self.radius.into()),
+                    ("scale", self.scale.into()),
+                    ("iterations", self.iterations.into()),
+                ],
+            ),
+        }
+    }
 }
 
 impl Sphere {
@@ -78,44 +640,1515 @@ impl Sphere {
     }
 }
 
-#[derive(Default, Debug)]
+impl Cone {
+    pub fn new(apex: Vec3, axis: Vec3, angle: f32, height: f32) -> Self {
+        Cone {
+            apex,
+            angle,
+            axis: axis.normalize(),
+            height,
+        }
+    }
+}
+
+impl Disk {
+    pub fn new(center: Vec3, normal: Vec3, radius: f32) -> Self {
+        Disk {
+            center,
+            radius,
+            normal: normal.normalize(),
+        }
+    }
+}
+
+impl TriangleMesh {
+    pub const DEFAULT_EPSILON: f32 = 1e-6;
+
+    pub fn new(positions: Vec<Vec3>, indices: Vec<u32>) -> Self {
+        TriangleMesh {
+            positions,
+            normals: None,
+            indices,
+            cull_backfaces: false,
+            epsilon: Self::DEFAULT_EPSILON,
+        }
+    }
+
+    /// Axis-aligned box around `center`, its triangles counter-clockwise seen from outside.
+    pub fn cuboid(center: Vec3, size: Vec3) -> Self {
+        let half = size / 2.0;
+        let positions = (0..8)
+            .map(|i| {
+                let sign = |bit: u32| if i & bit != 0 { 1.0 } else { -1.0 };
+                center + Vec3::new(sign(1) * half.x, sign(2) * half.y, sign(4) * half.z)
+            })
+            .collect();
+        let indices = vec![
+            0, 4, 6, 0, 6, 2, // -x
+            1, 3, 7, 1, 7, 5, // +x
+            0, 1, 5, 0, 5, 4, // -y
+            2, 6, 7, 2, 7, 3, // +y
+            0, 2, 3, 0, 3, 1, // -z
+            4, 5, 7, 4, 7, 6, // +z
+        ];
+
+        Self::new(positions, indices)
+    }
+}
+
+impl From<CsgShape> for CsgLeaf {
+    fn from(shape: CsgShape) -> Self {
+        let empty_sphere = Sphere::new(Vec3::zeros(), 0.0);
+        let empty_cone = Cone {
+            apex: Vec3::zeros(),
+            angle: 0.0,
+            axis: Vec3::y(),
+            height: 0.0,
+        };
+
+        match shape {
+            CsgShape::Sphere(sphere) => CsgLeaf {
+                kind: 0,
+                sphere,
+                cone: empty_cone,
+            },
+            CsgShape::Cone(cone) => CsgLeaf {
+                kind: 1,
+                sphere: empty_sphere,
+                cone,
+            },
+        }
+    }
+}
+
+const SDF_NONE: u32 = 0;
+
+impl SdfShape {
+    fn leaf(&self) -> SdfLeaf {
+        match *self {
+            SdfShape::Sphere { center, radius } => SdfLeaf {
+                kind: 1,
+                center,
+                size: Vec3::zeros(),
+                radius,
+                scale: 0.0,
+                iterations: 0,
+            },
+            SdfShape::RoundedBox {
+                center,
+                half_extents,
+                radius,
+            } => SdfLeaf {
+                kind: 2,
+                center,
+                size: half_extents,
+                radius,
+                scale: 0.0,
+                iterations: 0,
+            },
+            SdfShape::Mandelbox {
+                center,
+                radius,
+                scale,
+                iterations,
+            } => SdfLeaf {
+                kind: 3,
+                center,
+                size: Vec3::zeros(),
+                radius,
+                scale,
+                iterations,
+            },
+        }
+    }
+
+    /// Bounding sphere as (center, radius).
+    fn bounds(&self) -> (Vec3, f32) {
+        match *self {
+            SdfShape::Sphere { center, radius } | SdfShape::Mandelbox { center, radius, .. } => {
+                (center, radius)
+            }
+            SdfShape::RoundedBox {
+                center,
+                half_extents,
+                radius,
+            } => (center, half_extents.norm() + radius),
+        }
+    }
+}
+
+/// Suppression of fireflies, the bright single pixels left by rarely sampled paths such as small
+/// lights seen through glass. Samples brighter than `max_luminance`, or `outlier_ratio` times the
+/// mean luminance around their pixel, are scaled down to it. Both darken the image where they
+/// apply, 0 disables them.
+#[derive(ShaderType, Default, Clone, Copy, Debug, PartialEq)]
+pub struct FireflyClamp {
+    pub max_luminance: f32,
+    pub outlier_ratio: f32,
+}
+
+impl FireflyClamp {
+    pub fn is_enabled(&self) -> bool {
+        self.max_luminance > 0.0 || self.outlier_ratio > 0.0
+    }
+
+    fn to_ron(self) -> Value {
+        Value::Struct(
+            None,
+            vec![
+                ("max_luminance".to_owned(), self.max_luminance.into()),
+                ("outlier_ratio".to_owned(), self.outlier_ratio.into()),
+            ],
+        )
+    }
+}
+
+/// Homogeneous fog filling the scene. Rays scatter in a random direction
+/// `density` times per unit of distance on average, tinted by `color`.
+#[derive(ShaderType, Default, Clone, Copy, Debug)]
+pub struct Fog {
+    pub color: Vec3,
+    /// 0 for no fog.
+    pub density: f32,
+    /// Depth of fog rays escaping the scene pass before reaching the sky.
+    pub distance: f32,
+}
+
+impl Fog {
+    pub const DEFAULT_DISTANCE: f32 = 100.0;
+
+    pub fn new(color: Vec3, density: f32) -> Self {
+        Fog {
+            color,
+            density: density.max(0.0),
+            distance: Self::DEFAULT_DISTANCE,
+        }
+    }
+
+    fn to_ron(self) -> Value {
+        Value::Struct(
+            None,
+            vec![
+                ("color".to_owned(), self.color.into()),
+                ("density".to_owned(), self.density.into()),
+                ("distance".to_owned(), self.distance.into()),
+            ],
+        )
+    }
+}
+
+/// Preetham et al. daylight sky ("A Practical Analytic Model for Daylight"), lighting
+/// rays that leave the scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sky {
+    /// Towards the sun.
+    pub sun_direction: Vec3,
+    /// Haziness, from 2 (clear) to 10 (hazy).
+    pub turbidity: f32,
+    /// Brightness of the sky at the zenith.
+    pub intensity: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky {
+            sun_direction: Vec3::new(0.2, 0.8, 0.4).normalize(),
+            turbidity: 3.0,
+            intensity: 0.5,
+        }
+    }
+}
+
+/// Perez distribution coefficients and zenith values of the luminance (x) and
+/// chromaticity (y, z) of the sky.
+#[derive(ShaderType)]
+struct GpuSky {
+    a: Vec3,
+    b: Vec3,
+    c: Vec3,
+    d: Vec3,
+    e: Vec3,
+    /// Divided by the distribution at the zenith.
+    zenith: Vec3,
+    sun_direction: Vec3,
+}
+
+impl Sky {
+    pub const MIN_TURBIDITY: f32 = 2.0;
+    pub const MAX_TURBIDITY: f32 = 10.0;
+
+    pub fn new(sun_direction: Vec3, turbidity: f32, intensity: f32) -> Self {
+        Sky {
+            sun_direction: sun_direction.normalize(),
+            turbidity: turbidity.clamp(Self::MIN_TURBIDITY, Self::MAX_TURBIDITY),
+            intensity,
+        }
+    }
+
+    fn to_gpu(self) -> GpuSky {
+        let t = self.turbidity;
+        // The model is fitted for the sun above the horizon.
+        let theta_sun = self.sun_direction.y.clamp(0.01, 1.0).acos();
+
+        let a = Vec3::new(
+            0.1787 * t - 1.4630,
+            -0.0193 * t - 0.2592,
+            -0.0167 * t - 0.2608,
+        );
+        let b = Vec3::new(
+            -0.3554 * t + 0.4275,
+            -0.0665 * t + 0.0008,
+            -0.0950 * t + 0.0092,
+        );
+        let c = Vec3::new(
+            -0.0227 * t + 5.3251,
+            -0.0004 * t + 0.2125,
+            -0.0079 * t + 0.2102,
+        );
+        let d = Vec3::new(
+            0.1206 * t - 2.5771,
+            -0.0641 * t - 0.8989,
+            -0.0441 * t - 1.6537,
+        );
+        let e = Vec3::new(
+            -0.0670 * t + 0.3703,
+            -0.0033 * t + 0.0452,
+            -0.0109 * t + 0.0529,
+        );
+
+        let chromaticity = |m: [[f32; 4]; 3]| {
+            let thetas = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+            [t * t, t, 1.0]
+                .iter()
+                .zip(m)
+                .map(|(tp, row)| tp * row.iter().zip(thetas).map(|(m, th)| m * th).sum::<f32>())
+                .sum::<f32>()
+        };
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        // Perez distribution looking straight up, where the angle to the sun is theta_sun.
+        let perez_zenith = Vec3::from_fn(|i, _| {
+            (1.0 + a[i] * b[i].exp())
+                * (1.0 + c[i] * (d[i] * theta_sun).exp() + e[i] * theta_sun.cos().powi(2))
+        });
+        // Luminance is normalized to `intensity` instead of the model's kcd/m².
+        let zenith = Vec3::new(self.intensity, x, y).component_div(&perez_zenith);
+
+        GpuSky {
+            a,
+            b,
+            c,
+            d,
+            e,
+            zenith,
+            sun_direction: self.sun_direction,
+        }
+    }
+
+    fn to_ron(self) -> Value {
+        Value::Struct(
+            None,
+            vec![
+                ("sun_direction".to_owned(), self.sun_direction.into()),
+                ("turbidity".to_owned(), self.turbidity.into()),
+                ("intensity".to_owned(), self.intensity.into()),
+            ],
+        )
+    }
+}
+
+/// Distant light casting soft shadows, a directional `Light` on the GPU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sun {
+    /// Towards the sun.
+    pub direction: Vec3,
+    /// Radius of the sun disk in radians.
+    pub angular_radius: f32,
+    pub color: Vec3,
+    /// Irradiance on a surface facing the sun, 0 for no sun.
+    pub intensity: f32,
+}
+
+impl Default for Sun {
+    fn default() -> Self {
+        Sun {
+            direction: Sky::default().sun_direction,
+            angular_radius: Self::DEFAULT_ANGULAR_RADIUS,
+            color: Vec3::new(1.0, 1.0, 1.0),
+            intensity: 0.0,
+        }
+    }
+}
+
+impl Sun {
+    /// As seen from Earth.
+    pub const DEFAULT_ANGULAR_RADIUS: f32 = 0.0047;
+
+    pub fn new(direction: Vec3, angular_radius: f32, color: Vec3, intensity: f32) -> Self {
+        Sun {
+            direction: direction.normalize(),
+            angular_radius: angular_radius.clamp(0.0, std::f32::consts::FRAC_PI_2),
+            color,
+            intensity: intensity.max(0.0),
+        }
+    }
+
+    fn to_light(self) -> Light {
+        Light::directional(
+            self.direction,
+            self.angular_radius,
+            self.color,
+            self.intensity,
+        )
+    }
+
+    fn to_ron(self) -> Value {
+        Value::Struct(
+            None,
+            vec![
+                ("direction".to_owned(), self.direction.into()),
+                (
+                    "angular_radius".to_owned(),
+                    self.angular_radius.to_degrees().into(),
+                ),
+                ("color".to_owned(), self.color.into()),
+                ("intensity".to_owned(), self.intensity.into()),
+            ],
+        )
+    }
+}
+
+/// How a light is picked for sampling at each bounce.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LightSampling {
+    /// All lights equally likely.
+    Uniform,
+    /// Proportional to the estimated emitted power, so dim lights don't take samples
+    /// from bright ones.
+    #[default]
+    Power,
+    /// Like `Power`, but local lights are picked by walking a light tree towards the
+    /// ones likely to contribute most at the shading point. For scenes with many lights.
+    Tree,
+}
+
+/// Light sampled explicitly from diffuse surfaces, one picked at random per bounce.
+/// Only quad and directional lights are visible themselves.
+#[derive(ShaderType, Clone, Copy, Debug)]
+pub struct Light {
+    /// 0 for point lights, 1 for spot lights, 2 for quad lights, 3 for directional lights.
+    kind: u32,
+    /// Corner of quad lights.
+    position: Vec3,
+    /// Soft shadows for lights with a radius, hard for 0.
+    radius: f32,
+    /// Radiant intensity in each color channel, radiance for quad lights and
+    /// irradiance for directional lights.
+    intensity: Vec3,
+    /// Spot light axis, towards directional lights.
+    direction: Vec3,
+    /// Cosine of the spot cone half-angle or the directional light's angular radius.
+    cos_angle: f32,
+    /// Exponent of the cosine to the axis scaling the intensity of spot lights.
+    falloff: f32,
+    /// Sides of quad lights.
+    edge_u: Vec3,
+    edge_v: Vec3,
+    /// Chance of being picked, set when uploaded.
+    probability: f32,
+    /// Sum of the probabilities of this and all previous lights.
+    cumulative: f32,
+}
+
+impl Light {
+    /// Directional lights count as lighting a disk of this radius when estimating
+    /// their power.
+    const DIRECTIONAL_EXTENT: f32 = 10.0;
+
+    /// Light shining equally in all directions from `position`, or from a ball of `radius`.
+    pub fn point(position: Vec3, radius: f32, color: Vec3, intensity: f32) -> Self {
+        Light {
+            kind: 0,
+            position,
+            radius: radius.max(0.0),
+            intensity: color * intensity.max(0.0),
+            direction: Vec3::zeros(),
+            cos_angle: -1.0,
+            falloff: 0.0,
+            edge_u: Vec3::zeros(),
+            edge_v: Vec3::zeros(),
+            probability: 0.0,
+            cumulative: 0.0,
+        }
+    }
+
+    /// Point light limited to a cone of half-`angle` (radians) around `direction`, dimming
+    /// towards the edge by the `falloff` power of the cosine to the axis.
+    pub fn spot(
+        position: Vec3,
+        direction: Vec3,
+        angle: f32,
+        falloff: f32,
+        radius: f32,
+        color: Vec3,
+        intensity: f32,
+    ) -> Self {
+        Light {
+            kind: 1,
+            direction: direction.normalize(),
+            cos_angle: angle.clamp(0.0, std::f32::consts::PI).cos(),
+            falloff: falloff.max(0.0),
+            ..Self::point(position, radius, color, intensity)
+        }
+    }
+
+    /// Rectangle spanned by `edge_u` and `edge_v` from `corner`, emitting `intensity` radiance
+    /// towards `edge_u × edge_v`.
+    pub fn quad(corner: Vec3, edge_u: Vec3, edge_v: Vec3, color: Vec3, intensity: f32) -> Self {
+        Light {
+            kind: 2,
+            edge_u,
+            edge_v,
+            ..Self::point(corner, 0.0, color, intensity)
+        }
+    }
+
+    /// Light from a disk of `angular_radius` (radians) in the sky towards `direction`, with
+    /// `intensity` irradiance on a surface facing it.
+    pub fn directional(direction: Vec3, angular_radius: f32, color: Vec3, intensity: f32) -> Self {
+        Light {
+            kind: 3,
+            direction: direction.normalize(),
+            cos_angle: angular_radius.clamp(0.0, std::f32::consts::FRAC_PI_2).cos(),
+            ..Self::point(Vec3::zeros(), 0.0, color, intensity)
+        }
+    }
+
+    /// Axis-aligned box around the emitting surface, `None` for directional lights.
+    pub(crate) fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        match self.kind {
+            3 => None,
+            2 => {
+                let corners = [
+                    self.position,
+                    self.position + self.edge_u,
+                    self.position + self.edge_v,
+                    self.position + self.edge_u + self.edge_v,
+                ];
+                Some(
+                    corners
+                        .iter()
+                        .fold((self.position, self.position), |(min, max), corner| {
+                            (min.inf(corner), max.sup(corner))
+                        }),
+                )
+            }
+            _ => Some((
+                self.position - Vec3::repeat(self.radius),
+                self.position + Vec3::repeat(self.radius),
+            )),
+        }
+    }
+
+    /// Estimated total emitted power, weighting the channels by luminance.
+    pub(crate) fn power(&self) -> f32 {
+        use std::f32::consts::PI;
+
+        let luminance = self.intensity.dot(&Vec3::new(0.2126, 0.7152, 0.0722));
+        let spread = match self.kind {
+            1 if self.falloff > 0.0 => {
+                let cos = self.cos_angle.max(0.0);
+                2.0 * PI * (1.0 - cos.powf(self.falloff + 1.0)) / (self.falloff + 1.0)
+            }
+            1 => 2.0 * PI * (1.0 - self.cos_angle),
+            2 => PI * self.edge_u.cross(&self.edge_v).norm(),
+            3 => PI * Self::DIRECTIONAL_EXTENT * Self::DIRECTIONAL_EXTENT,
+            _ => 4.0 * PI,
+        };
+
+        luminance * spread
+    }
+
+    fn to_ron(self) -> Value {
+        let intensity = self.intensity.max();
+        let color = if intensity > 0.0 {
+            self.intensity / intensity
+        } else {
+            Vec3::new(1.0, 1.0, 1.0)
+        };
+
+        let (name, mut fields) = match self.kind {
+            3 => (
+                "DirectionalLight",
+                vec![
+                    ("direction", self.direction.into()),
+                    ("angular_radius", self.cos_angle.acos().to_degrees().into()),
+                ],
+            ),
+            2 => (
+                "QuadLight",
+                vec![
+                    ("corner", self.position.into()),
+                    ("edge_u", self.edge_u.into()),
+                    ("edge_v", self.edge_v.into()),
+                ],
+            ),
+            1 => (
+                "SpotLight",
+                vec![
+                    ("position", self.position.into()),
+                    ("direction", self.direction.into()),
+                    ("angle", self.cos_angle.acos().to_degrees().into()),
+                    ("falloff", self.falloff.into()),
+                    ("radius", self.radius.into()),
+                ],
+            ),
+            _ => (
+                "PointLight",
+                vec![
+                    ("position", self.position.into()),
+                    ("radius", self.radius.into()),
+                ],
+            ),
+        };
+        fields.push(("color", color.into()));
+        fields.push(("intensity", intensity.into()));
+
+        Value::named(name, fields)
+    }
+}
+
+#[derive(ShaderType)]
+struct GpuLights {
+    length: ArrayLength,
+    #[size(runtime)]
+    lights: Vec<Light>,
+}
+
+/// Sets the chance of picking each light. With a light tree, local lights only
+/// matter in total, the tree decides between them. Expects directional lights first.
+fn set_light_probabilities(lights: &mut [Light], sampling: LightSampling) {
+    let weights: Vec<f32> = match sampling {
+        LightSampling::Uniform => vec![1.0; lights.len()],
+        LightSampling::Power | LightSampling::Tree => lights.iter().map(Light::power).collect(),
+    };
+    let total: f32 = weights.iter().sum();
+    let count = lights.len() as f32;
+
+    let mut cumulative = 0.0;
+    for (light, weight) in lights.iter_mut().zip(weights) {
+        light.probability = if total > 0.0 {
+            weight / total
+        } else {
+            1.0 / count
+        };
+        cumulative += light.probability;
+        light.cumulative = cumulative;
+    }
+
+    // Guards against rounding leaving the last light unreachable.
+    if let Some(last) = lights.last_mut() {
+        last.cumulative = 1.0;
+    }
+
+    // Local lights follow the directional ones. They are picked as a group, which
+    // each of them describes.
+    if sampling == LightSampling::Tree {
+        let local = lights.iter().position(|light| light.bounds().is_some());
+        if let Some(first) = local {
+            let group = lights[first..].iter().map(|light| light.probability).sum();
+            for light in &mut lights[first..] {
+                light.probability = group;
+                light.cumulative = 1.0;
+            }
+        }
+    }
+}
+
+/// Scene-wide settings, bound as a uniform.
+#[derive(ShaderType)]
+struct GpuEnvironment {
+    fog: Fog,
+    sky: GpuSky,
+    clamp: FireflyClamp,
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct Scene {
     spheres: Vec<SceneSphere>,
+    cones: Vec<SceneCone>,
+    disks: Vec<SceneDisk>,
+    csg: Vec<CsgNode>,
+    sdfs: Vec<SdfNode>,
+    meshes: Vec<SceneMesh>,
+    instances: Vec<SceneInstance>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u32>,
     mats: Vec<Material>,
+    material_plugins: Vec<MaterialPlugin>,
+    /// Parameters of the plugin materials, as 4 byte words.
+    material_params: Vec<u32>,
+    textures: Vec<Texture>,
+    lights: Vec<Light>,
+    light_sampling: LightSampling,
+    fog: Fog,
+    sky: Sky,
+    sun: Sun,
+    clamp: FireflyClamp,
+}
+
+/// Contents of the storage buffers bound to the compute shader.
+/// Mesh vertices and indices are each split across this many bindings, so large meshes
+/// can exceed `max_storage_buffer_binding_size`.
+pub const MESH_CHUNKS: usize = 2;
+
+pub struct SceneBuffers {
+    pub spheres: Vec<u8>,
+    pub cones: Vec<u8>,
+    pub disks: Vec<u8>,
+    pub csg: Vec<u8>,
+    pub sdfs: Vec<u8>,
+    /// Mesh instances and the BVH nodes over them and their triangles.
+    pub meshes: Vec<u8>,
+    pub bvh: Vec<u8>,
+    pub vertices: [Vec<u8>; MESH_CHUNKS],
+    pub indices: [Vec<u8>; MESH_CHUNKS],
+    /// Vertices in every chunk but the last.
+    pub vertex_chunk: u32,
+    /// Indices in every chunk but the last.
+    pub index_chunk: u32,
+    pub mats: Vec<u8>,
+    pub material_params: Vec<u8>,
+    /// Layers of the texture pool, see `TexturePool`.
+    pub textures: Vec<RgbaImage>,
+    pub lights: Vec<u8>,
+    pub light_tree: Vec<u8>,
+    /// Uniform buffer contents.
+    pub environment: Vec<u8>,
+}
+
+/// Serializes a runtime-sized array struct, padding it to the size of a single
+/// element so empty arrays still satisfy the shader's minimum binding size.
+fn storage_bytes<T>(value: &T) -> Result<Vec<u8>>
+where
+    T: ShaderType + encase::internal::WriteInto,
+{
+    let mut buf = encase::StorageBuffer::new(vec![]);
+    buf.write(value)?;
+
+    let mut bytes = buf.into_inner();
+    let min_size = T::min_size().get() as usize;
+    if bytes.len() < min_size {
+        bytes.resize(min_size, 0);
+    }
+
+    Ok(bytes)
 }
 
-type StorageBuf = encase::StorageBuffer<Vec<u8>>;
+/// Splits `items` into `MESH_CHUNKS` runtime-sized arrays which fit into a binding of
+/// `max_binding_size` bytes each. Returns the chunks and their length.
+fn mesh_chunks<T, G>(
+    what: &str,
+    items: Vec<T>,
+    max_binding_size: u64,
+    to_gpu: impl Fn(Vec<T>) -> G,
+) -> Result<([Vec<u8>; MESH_CHUNKS], u32)>
+where
+    T: ShaderType,
+    G: ShaderType + encase::internal::WriteInto,
+{
+    // The array follows the length, which is padded to the element alignment (at most 16).
+    let chunk = ((max_binding_size - 16) / T::min_size().get()).min(u32::MAX as u64) as usize;
+    if items.len() > chunk * MESH_CHUNKS {
+        return Err(anyhow!(
+            "Scene has {} mesh {what}, the device can bind at most {}",
+            items.len(),
+            chunk * MESH_CHUNKS
+        ));
+    }
+
+    let mut items = items.into_iter();
+    let mut chunks: [Vec<u8>; MESH_CHUNKS] = Default::default();
+    for bytes in &mut chunks {
+        *bytes = storage_bytes(&to_gpu(items.by_ref().take(chunk).collect()))?;
+    }
+
+    Ok((chunks, chunk as u32))
+}
 
 impl Scene {
-    pub fn new_sphere(&mut self, sphere: Sphere, material: Material) {
-        let mut mat_id: u32 = u32::MAX;
+    /// The final scene of "Ray Tracing in One Weekend": a 22×22 grid of small random
+    /// lambertian, metal and glass spheres around three big ones. The same seed gives the same scene.
+    pub fn random_spheres(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let random_color = |rng: &mut StdRng, min: f32, max: f32| {
+            Vec3::new(
+                rng.gen_range(min..max),
+                rng.gen_range(min..max),
+                rng.gen_range(min..max),
+            )
+        };
+
+        let mut scene = Scene::default();
+        scene.new_sphere(
+            Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0),
+            Material::new_lambertian(Vec3::new(0.5, 0.5, 0.5)),
+        );
+
+        for a in -11..11 {
+            for b in -11..11 {
+                let choose_mat: f32 = rng.gen();
+                let center = Vec3::new(
+                    a as f32 + 0.9 * rng.gen::<f32>(),
+                    0.2,
+                    b as f32 + 0.9 * rng.gen::<f32>(),
+                );
+                if (center - Vec3::new(4.0, 0.2, 0.0)).norm() <= 0.9 {
+                    continue;
+                }
+
+                let material = if choose_mat < 0.8 {
+                    let albedo = random_color(&mut rng, 0.0, 1.0)
+                        .component_mul(&random_color(&mut rng, 0.0, 1.0));
+                    Material::new_lambertian(albedo)
+                } else if choose_mat < 0.95 {
+                    let albedo = random_color(&mut rng, 0.5, 1.0);
+                    Material::new_metal(albedo, rng.gen_range(0.0..0.5))
+                } else {
+                    Material::new_dielectric(1.5, None)
+                };
+                scene.new_sphere(Sphere::new(center, 0.2), material);
+            }
+        }
+
+        scene.new_sphere(
+            Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0),
+            Material::new_dielectric(1.5, None),
+        );
+        scene.new_sphere(
+            Sphere::new(Vec3::new(-4.0, 1.0, 0.0), 1.0),
+            Material::new_lambertian(Vec3::new(0.4, 0.2, 0.1)),
+        );
+        scene.new_sphere(
+            Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0),
+            Material::new_metal(Vec3::new(0.7, 0.6, 0.5), 0.0),
+        );
+
+        scene
+    }
+
+    fn material_id(&mut self, material: Material) -> u32 {
         if let Some(found_id) = self.mats.iter().position(|m| *m == material) {
-            mat_id = found_id as u32;
+            return found_id as u32;
         }
 
-        if mat_id == u32::MAX {
-            mat_id = self.mats.len() as u32;
-            self.mats.push(material);
+        self.mats.push(material);
+        (self.mats.len() - 1) as u32
+    }
+
+    /// Registers a material type shaded by the plugin's WGSL, see `custom_material`.
+    pub fn add_material_plugin(&mut self, plugin: MaterialPlugin) -> MaterialKind {
+        self.material_plugins.push(plugin);
+        MaterialKind(self.material_plugins.len() as u32 - 1)
+    }
+
+    pub fn material_plugins(&self) -> &[MaterialPlugin] {
+        &self.material_plugins
+    }
+
+    /// Material of a plugin type with its parameters, laid out like the plugin's fields.
+    pub fn custom_material<P>(&mut self, kind: MaterialKind, params: &P) -> Result<Material>
+    where
+        P: ShaderType + WriteInto,
+    {
+        let plugin = &self.material_plugins[kind.0 as usize];
+        let mut bytes = encase::StorageBuffer::new(vec![]);
+        bytes.write(params)?;
+        let bytes = bytes.into_inner();
+        if bytes.len() as u64 != plugin.params_size() {
+            return Err(anyhow!(
+                "Parameters of material `{}` take {} bytes, its fields {}",
+                plugin.name(),
+                bytes.len(),
+                plugin.params_size()
+            ));
         }
 
+        let first_word = self.material_params.len() as u32;
+        self.material_params.extend(
+            bytes
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap())),
+        );
+        Ok(Material {
+            mat_type: FIRST_CUSTOM_MATERIAL + kind.0,
+            params: first_word,
+            ..Default::default()
+        })
+    }
+
+    pub fn add_texture(&mut self, name: String, image: RgbaImage) -> TextureId {
+        self.textures.push(Texture { name, image });
+        TextureId(self.textures.len() as u32 - 1)
+    }
+
+    pub fn new_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    pub fn set_light_sampling(&mut self, light_sampling: LightSampling) {
+        self.light_sampling = light_sampling;
+    }
+
+    pub fn set_firefly_clamp(&mut self, clamp: FireflyClamp) {
+        self.clamp = clamp;
+    }
+
+    pub fn set_fog(&mut self, fog: Fog) {
+        self.fog = fog;
+    }
+
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.sky = sky;
+    }
+
+    pub fn set_sun(&mut self, sun: Sun) {
+        self.sun = sun;
+    }
+
+    pub fn new_sphere(&mut self, sphere: Sphere, material: Material) {
+        let mat_id = self.material_id(material);
         self.spheres.push(SceneSphere { mat_id, sphere });
     }
 
-    pub fn into_gpu_buffers(self) -> Result<(StorageBuf, StorageBuf)> {
-        let Scene { spheres, mats } = self;
+    pub fn new_cone(&mut self, cone: Cone, rotation: Rotation, material: Material) {
+        let mat_id = self.material_id(material);
+        self.cones.push(SceneCone {
+            mat_id,
+            rotation,
+            cone,
+        });
+    }
 
-        let mut spheres_buf = encase::StorageBuffer::new(vec![]);
-        spheres_buf.write(&GpuSpheres {
-            length: ArrayLength,
+    pub fn new_disk(&mut self, disk: Disk, rotation: Rotation, material: Material) {
+        let mat_id = self.material_id(material);
+        self.disks.push(SceneDisk {
+            mat_id,
+            rotation,
+            disk,
+        });
+    }
+
+    pub fn new_csg(
+        &mut self,
+        op: CsgOp,
+        left: CsgShape,
+        right: CsgShape,
+        rotation: Rotation,
+        material: Material,
+    ) {
+        let mat_id = self.material_id(material);
+        self.csg.push(CsgNode {
+            op: op as u32,
+            mat_id,
+            rotation,
+            left: left.into(),
+            right: right.into(),
+        });
+    }
+
+    pub fn new_sdf(&mut self, shape: SdfShape, rotation: Rotation, material: Material) {
+        let mat_id = self.material_id(material);
+        let (bound_center, bound_radius) = shape.bounds();
+        let mut empty = shape.leaf();
+        empty.kind = SDF_NONE;
+
+        self.sdfs.push(SdfNode {
+            mat_id,
+            rotation,
+            blend: 0.0,
+            bound_center,
+            bound_radius,
+            a: shape.leaf(),
+            b: empty,
+        });
+    }
+
+    /// Smoothly blends two SDF shapes into one object.
+    pub fn new_sdf_blend(
+        &mut self,
+        a: SdfShape,
+        b: SdfShape,
+        blend: f32,
+        rotation: Rotation,
+        material: Material,
+    ) {
+        let mat_id = self.material_id(material);
+        let ((center_a, radius_a), (center_b, radius_b)) = (a.bounds(), b.bounds());
+
+        // Sphere enclosing both bounds, grown by the blend radius.
+        let distance = (center_b - center_a).norm();
+        let (bound_center, bound_radius) = if distance + radius_b <= radius_a {
+            (center_a, radius_a)
+        } else if distance + radius_a <= radius_b {
+            (center_b, radius_b)
+        } else {
+            let radius = (distance + radius_a + radius_b) / 2.0;
+            let center = center_a + (center_b - center_a) * ((radius - radius_a) / distance);
+            (center, radius)
+        };
+
+        self.sdfs.push(SdfNode {
+            mat_id,
+            rotation,
+            blend,
+            bound_center,
+            bound_radius: bound_radius + blend,
+            a: a.leaf(),
+            b: b.leaf(),
+        });
+    }
+
+    pub fn new_mesh(
+        &mut self,
+        mesh: TriangleMesh,
+        rotation: Rotation,
+        material: Material,
+    ) -> Result<()> {
+        let mesh = self.add_mesh(mesh)?;
+        let mat_id = self.material_id(material);
+        self.instances.push(SceneInstance {
+            mesh: mesh.0,
+            mat_id,
+            rotation,
+            transform: na::Matrix4::identity(),
+        });
+        Ok(())
+    }
+
+    /// Adds mesh data without placing it, see `instance_mesh`. The BVH over its triangles is
+    /// built once and shared by the instances.
+    pub fn add_mesh(&mut self, mesh: TriangleMesh) -> Result<MeshId> {
+        let TriangleMesh {
+            positions,
+            normals,
+            indices,
+            cull_backfaces,
+            epsilon,
+        } = mesh;
+
+        if indices.len() % 3 != 0 {
+            return Err(anyhow!(
+                "Mesh index count {} is not a multiple of 3",
+                indices.len()
+            ));
+        }
+        if let Some(out_of_range) = indices.iter().find(|&&i| i as usize >= positions.len()) {
+            return Err(anyhow!(
+                "Mesh index {out_of_range} out of range for {} vertices",
+                positions.len()
+            ));
+        }
+        if let Some(normals) = &normals {
+            if normals.len() != positions.len() {
+                return Err(anyhow!(
+                    "Mesh has {} normals for {} vertices",
+                    normals.len(),
+                    positions.len()
+                ));
+            }
+        }
+
+        let triangles: Vec<Aabb> = indices
+            .chunks_exact(3)
+            .map(|triangle| Aabb::around(triangle.iter().map(|&i| positions[i as usize])))
+            .collect();
+        let bvh = bvh::build(&triangles);
+
+        let id = MeshId(self.meshes.len() as u32);
+        self.meshes.push(SceneMesh {
+            first_index: self.indices.len() as u32,
+            num_triangles: triangles.len() as u32,
+            base_vertex: self.vertices.len() as u32,
+            cull_backfaces: cull_backfaces as u32,
+            epsilon,
+            nodes: bvh.nodes,
+        });
+
+        self.indices.extend(bvh.order.iter().flat_map(|&triangle| {
+            let first = triangle as usize * 3;
+            indices[first..first + 3].iter().copied()
+        }));
+        self.vertices
+            .extend(positions.iter().enumerate().map(|(idx, &position)| {
+                Vertex {
+                    position,
+                    normal: normals
+                        .as_ref()
+                        .map_or(Vec3::zeros(), |normals| normals[idx].normalize()),
+                }
+            }));
+
+        Ok(id)
+    }
+
+    /// Places mesh data with the affine mesh to world `transform`.
+    pub fn instance_mesh(
+        &mut self,
+        mesh: MeshId,
+        transform: na::Matrix4<f32>,
+        material: Material,
+    ) -> Result<()> {
+        if transform.try_inverse().is_none() {
+            return Err(anyhow!("Mesh instance transform is not invertible"));
+        }
+        let mat_id = self.material_id(material);
+        self.instances.push(SceneInstance {
+            mesh: mesh.0,
+            mat_id,
+            rotation: Rotation::identity(),
+            transform,
+        });
+        Ok(())
+    }
+
+    /// Scene file representation, see `SceneFile::load`. Materials are named `m<id>`.
+    pub fn to_ron(&self) -> Value {
+        let material = |mat_id: u32| Value::String(format!("m{mat_id}"));
+        let mut objects = vec![];
+
+        for SceneSphere { mat_id, sphere } in &self.spheres {
+            let mut fields = sphere.ron_fields();
+            fields.push(("material", material(*mat_id)));
+            objects.push(Value::named("Sphere", fields));
+        }
+
+        for SceneCone {
+            mat_id,
+            rotation,
+            cone,
+        } in &self.cones
+        {
+            let mut fields = cone.ron_fields();
+            rotation.push_fields(&mut fields);
+            fields.push(("material", material(*mat_id)));
+            objects.push(Value::named("Cone", fields));
+        }
+
+        for SceneDisk {
+            mat_id,
+            rotation,
+            disk,
+        } in &self.disks
+        {
+            let mut fields = vec![
+                ("center", disk.center.into()),
+                ("normal", disk.normal.into()),
+                ("radius", disk.radius.into()),
+            ];
+            rotation.push_fields(&mut fields);
+            fields.push(("material", material(*mat_id)));
+            objects.push(Value::named("Disk", fields));
+        }
+
+        for node in &self.csg {
+            let op = match node.op {
+                0 => "Union",
+                1 => "Intersection",
+                _ => "Difference",
+            };
+            let mut fields = vec![
+                ("op", Value::Ident(op.to_owned())),
+                ("left", node.left.to_ron()),
+                ("right", node.right.to_ron()),
+            ];
+            node.rotation.push_fields(&mut fields);
+            fields.push(("material", material(node.mat_id)));
+            objects.push(Value::named("Csg", fields));
+        }
+
+        for node in &self.sdfs {
+            let mut fields = vec![("shape", node.a.to_ron())];
+            if node.b.kind != SDF_NONE {
+                fields.push(("blend_with", node.b.to_ron()));
+                fields.push(("blend", node.blend.into()));
+            }
+            node.rotation.push_fields(&mut fields);
+            fields.push(("material", material(node.mat_id)));
+            objects.push(Value::named("Sdf", fields));
+        }
+
+        // Scene files have no instancing, every instance is written out with its own copy of
+        // the mesh data.
+        for instance in &self.instances {
+            let idx = instance.mesh as usize;
+            let mesh = &self.meshes[idx];
+            let vertex_end = self
+                .meshes
+                .get(idx + 1)
+                .map_or(self.vertices.len(), |next| next.base_vertex as usize);
+            let vertices = &self.vertices[mesh.base_vertex as usize..vertex_end];
+            let first = mesh.first_index as usize;
+            let mut indices = self.indices[first..first + mesh.num_triangles as usize * 3].to_vec();
+
+            let transform = instance.transform;
+            let linear: na::Matrix3<f32> = transform.fixed_view::<3, 3>(0, 0).into();
+            let normal_transform = linear
+                .try_inverse()
+                .map_or(linear, |inverse| inverse.transpose());
+            if linear.determinant() < 0.0 {
+                // Mirroring flips the winding, keep the same side in front.
+                for triangle in indices.chunks_exact_mut(3) {
+                    triangle.swap(1, 2);
+                }
+            }
+
+            let positions = vertices.iter().map(|v| {
+                transform
+                    .transform_point(&na::Point3::from(v.position))
+                    .coords
+            });
+            let mut fields = vec![
+                (
+                    "positions",
+                    Value::List(positions.map(Value::from).collect()),
+                ),
+                (
+                    "indices",
+                    Value::List(indices.iter().map(|&i| i.into()).collect()),
+                ),
+            ];
+            if vertices.iter().any(|v| v.normal != Vec3::zeros()) {
+                let normals = vertices
+                    .iter()
+                    .map(|v| (normal_transform * v.normal).normalize().into());
+                fields.push(("normals", Value::List(normals.collect())));
+            }
+            fields.push(("cull_backfaces", (mesh.cull_backfaces != 0).into()));
+            fields.push(("epsilon", mesh.epsilon.into()));
+            instance.rotation.push_fields(&mut fields);
+            fields.push(("material", material(instance.mat_id)));
+            objects.push(Value::named("Mesh", fields));
+        }
+
+        let materials = self
+            .mats
+            .iter()
+            .enumerate()
+            .map(|(mat_id, mat)| (material(mat_id as u32), mat.to_ron(&self.textures)))
+            .collect();
+
+        let mut fields = vec![
+            ("materials", Value::Map(materials)),
+            ("objects", Value::List(objects)),
+        ];
+        if !self.lights.is_empty() {
+            let lights = self.lights.iter().map(|light| light.to_ron()).collect();
+            fields.push(("lights", Value::List(lights)));
+        }
+        if self.light_sampling != LightSampling::default() {
+            fields.insert(
+                0,
+                (
+                    "light_sampling",
+                    Value::Ident(format!("{:?}", self.light_sampling)),
+                ),
+            );
+        }
+        if self.clamp.is_enabled() {
+            fields.insert(0, ("firefly_clamp", self.clamp.to_ron()));
+        }
+        if self.sun.intensity > 0.0 {
+            fields.insert(0, ("sun", self.sun.to_ron()));
+        }
+        if self.sky != Sky::default() {
+            fields.insert(0, ("sky", self.sky.to_ron()));
+        }
+        if self.fog.density > 0.0 {
+            fields.insert(0, ("fog", self.fog.to_ron()));
+        }
+
+        Value::named("Scene", fields)
+    }
+
+    /// Fails when a buffer other than the chunked mesh data exceeds `max_binding_size`.
+    pub fn into_gpu_buffers(self, max_binding_size: u64) -> Result<SceneBuffers> {
+        let Scene {
             spheres,
+            cones,
+            disks,
+            csg,
+            sdfs,
+            meshes,
+            mut instances,
+            vertices,
+            indices,
+            mats,
+            material_plugins: _,
+            material_params,
+            textures,
+            mut lights,
+            light_sampling,
+            fog,
+            sky,
+            sun,
+            clamp,
+        } = self;
+
+        if sun.intensity > 0.0 {
+            lights.push(sun.to_light());
+        }
+        // Directional lights first, the light tree covers the rest.
+        lights.sort_by_key(|light| light.bounds().is_some());
+        set_light_probabilities(&mut lights, light_sampling);
+
+        let light_tree = match light_sampling {
+            LightSampling::Tree => light_tree::build(&lights),
+            _ => vec![],
+        };
+
+        let mut environment = encase::UniformBuffer::new(vec![]);
+        environment.write(&GpuEnvironment {
+            fog,
+            sky: sky.to_gpu(),
+            clamp,
         })?;
 
-        let mut mats_buf = encase::StorageBuffer::new(vec![]);
-        mats_buf.write(&GpuMats {
+        // The top level BVH goes first, the bottom level ones of the meshes follow. Meshes
+        // without triangles have no root to instance.
+        instances.retain(|instance| !meshes[instance.mesh as usize].nodes.is_empty());
+        let to_world: Vec<na::Matrix4<f32>> = instances
+            .iter()
+            .map(|instance| instance.rotation.to_matrix() * instance.transform)
+            .collect();
+        let instance_bounds: Vec<Aabb> = instances
+            .iter()
+            .zip(&to_world)
+            .map(|(instance, to_world)| {
+                let root = meshes[instance.mesh as usize].nodes[0];
+                Aabb {
+                    min: root.min,
+                    max: root.max,
+                }
+                .transformed(to_world)
+            })
+            .collect();
+        let tlas = bvh::build(&instance_bounds);
+        let mut nodes = tlas.nodes;
+        let mut roots = vec![];
+        for mesh in &meshes {
+            roots.push(nodes.len() as u32);
+            nodes.extend(&mesh.nodes);
+        }
+        let instances: Vec<MeshInstance> = tlas
+            .order
+            .iter()
+            .map(|&i| {
+                let instance = &instances[i as usize];
+                let mesh = &meshes[instance.mesh as usize];
+                let to_local = to_world[i as usize]
+                    .try_inverse()
+                    .unwrap_or_else(na::Matrix4::identity);
+                MeshInstance {
+                    to_local: to_local.fixed_view::<3, 4>(0, 0).into(),
+                    mat_id: instance.mat_id,
+                    root: roots[instance.mesh as usize],
+                    first_index: mesh.first_index,
+                    base_vertex: mesh.base_vertex,
+                    cull_backfaces: mesh.cull_backfaces,
+                    epsilon: mesh.epsilon,
+                }
+            })
+            .collect();
+
+        let (vertices, vertex_chunk) =
+            mesh_chunks("vertices", vertices, max_binding_size, |vertices| {
+                GpuVertices {
+                    length: ArrayLength,
+                    vertices,
+                }
+            })?;
+        let (indices, index_chunk) =
+            mesh_chunks("indices", indices, max_binding_size, |indices| GpuIndices {
+                length: ArrayLength,
+                indices,
+            })?;
+
+        let buffers = SceneBuffers {
+            spheres: storage_bytes(&GpuSpheres {
+                length: ArrayLength,
+                spheres,
+            })?,
+            cones: storage_bytes(&GpuCones {
+                length: ArrayLength,
+                cones,
+            })?,
+            disks: storage_bytes(&GpuDisks {
+                length: ArrayLength,
+                disks,
+            })?,
+            csg: storage_bytes(&GpuCsg {
+                length: ArrayLength,
+                nodes: csg,
+            })?,
+            sdfs: storage_bytes(&GpuSdfs {
+                length: ArrayLength,
+                nodes: sdfs,
+            })?,
+            meshes: storage_bytes(&GpuMeshInstances {
+                length: ArrayLength,
+                instances,
+            })?,
+            bvh: storage_bytes(&bvh::GpuBvh {
+                length: ArrayLength,
+                nodes,
+            })?,
+            vertices,
+            indices,
+            vertex_chunk,
+            index_chunk,
+            mats: storage_bytes(&GpuMats {
+                length: ArrayLength,
+                mats,
+            })?,
+            material_params: storage_bytes(&GpuMaterialParams {
+                length: ArrayLength,
+                words: material_params,
+            })?,
+            textures: textures.into_iter().map(|texture| texture.image).collect(),
+            lights: storage_bytes(&GpuLights {
+                length: ArrayLength,
+                lights,
+            })?,
+            light_tree: storage_bytes(&light_tree::GpuLightTree {
+                length: ArrayLength,
+                nodes: light_tree,
+            })?,
+            environment: environment.into_inner(),
+        };
+
+        for (what, bytes) in [
+            ("spheres", &buffers.spheres),
+            ("cones", &buffers.cones),
+            ("disks", &buffers.disks),
+            ("CSG nodes", &buffers.csg),
+            ("SDF nodes", &buffers.sdfs),
+            ("mesh instances", &buffers.meshes),
+            ("BVH nodes", &buffers.bvh),
+            ("materials", &buffers.mats),
+            ("material parameters", &buffers.material_params),
+            ("lights", &buffers.lights),
+            ("light tree", &buffers.light_tree),
+        ] {
+            if bytes.len() as u64 > max_binding_size {
+                return Err(anyhow!(
+                    "Scene {what} take {} bytes, the device can bind at most {max_binding_size}",
+                    bytes.len()
+                ));
+            }
+        }
+
+        Ok(buffers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::bench;
+    use crate::shader_layout::assert_layout;
+
+    fn shader() -> String {
+        crate::shader::compute(&[]).unwrap()
+    }
+
+    #[test]
+    fn sphere_matches_wgsl_layout() {
+        assert_layout::<Sphere>(&shader(), "Sphere");
+        assert_layout::<SceneSphere>(&shader(), "SceneSphere");
+        assert_layout::<GpuSpheres>(&shader(), "Spheres");
+    }
+
+    #[test]
+    fn material_matches_wgsl_layout() {
+        assert_layout::<Material>(&shader(), "Material");
+        assert_layout::<GpuMats>(&shader(), "Materials");
+        assert_layout::<GpuMaterialParams>(&shader(), "MaterialParams");
+    }
+
+    #[test]
+    fn meshes_match_wgsl_layout() {
+        assert_layout::<MeshInstance>(&shader(), "MeshInstance");
+        assert_layout::<GpuMeshInstances>(&shader(), "MeshInstances");
+        assert_layout::<BvhNode>(&shader(), "BvhNode");
+        assert_layout::<bvh::GpuBvh>(&shader(), "Bvh");
+    }
+
+    #[test]
+    fn environment_matches_wgsl_layout() {
+        assert_layout::<GpuEnvironment>(&shader(), "Environment");
+    }
+
+    #[test]
+    fn mesh_data_is_split_into_chunks() {
+        let vertices = vec![
+            Vertex {
+                position: Vec3::zeros(),
+                normal: Vec3::zeros(),
+            };
+            10
+        ];
+        let to_gpu = |vertices| GpuVertices {
             length: ArrayLength,
-            mats,
-        })?;
+            vertices,
+        };
+
+        // Room for 5 vertices after the length.
+        let (chunks, chunk) =
+            mesh_chunks("vertices", vertices.clone(), 16 + 5 * 32, to_gpu).unwrap();
+        assert_eq!(chunk, 5);
+        assert_eq!(chunks[0].len(), 16 + 5 * 32);
+        assert_eq!(chunks[1].len(), 16 + 5 * 32);
+
+        assert!(mesh_chunks("vertices", vertices, 16 + 4 * 32, to_gpu).is_err());
+    }
+
+    /// A book 1 style field of small random spheres lit by point lights.
+    fn random_scene(spheres: usize, lights: usize) -> Scene {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut scene = Scene::default();
+        for _ in 0..spheres {
+            let center = Vec3::new(rng.gen_range(-50.0..50.0), 0.2, rng.gen_range(-50.0..50.0));
+            let albedo = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+            let material = match rng.gen_range(0..3) {
+                0 => Material::new_lambertian(albedo),
+                1 => Material::new_metal(albedo, rng.gen_range(0.0..0.5)),
+                _ => Material::new_dielectric(1.5, None),
+            };
+            scene.new_sphere(Sphere::new(center, 0.2), material);
+        }
+        for _ in 0..lights {
+            let position = Vec3::new(rng.gen_range(-50.0..50.0), 5.0, rng.gen_range(-50.0..50.0));
+            scene.new_light(Light::point(position, 0.1, Vec3::new(1.0, 1.0, 1.0), 10.0));
+        }
+        scene
+    }
 
-        Ok((spheres_buf, mats_buf))
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_gpu_buffers() {
+        let scene = random_scene(10_000, 1_000);
+        bench("scene buffers, 10k spheres, 1k lights", || {
+            scene.clone().into_gpu_buffers(u32::MAX as u64).unwrap();
+        });
     }
 }
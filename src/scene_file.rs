@@ -0,0 +1,982 @@
+use crate::assets::AssetResolver;
+use crate::camera::{Bokeh, Distortion, Exposure, FisheyeMapping, Lens, Projection};
+use crate::image::RgbaImage;
+use crate::ron::{self, Value};
+use crate::scene::{
+    Cone, CsgOp, CsgShape, Disk, FireflyClamp, Fog, Light, LightSampling, Material, Rotation,
+    Scene, SdfShape, Sky, Sphere, Sun, TextureId, TriangleMesh,
+};
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use nalgebra as na;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub struct CameraSettings {
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub num_samples: u32,
+    pub exposure: Exposure,
+    pub lens: Lens,
+    pub projection: Projection,
+    /// Eye separation of side-by-side stereo.
+    pub stereo: Option<f32>,
+}
+
+impl CameraSettings {
+    /// Average human interocular distance, assuming the scene is in meters.
+    pub const DEFAULT_EYE_SEPARATION: f32 = 0.065;
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            lookfrom: Vec3::new(0.0, 0.0, 0.0),
+            lookat: Vec3::new(0.0, 0.0, -1.0),
+            vup: Vec3::new(0.0, 1.0, 0.0),
+            num_samples: 100,
+            exposure: Exposure::default(),
+            lens: Lens::default(),
+            projection: Projection::Perspective,
+            stereo: None,
+        }
+    }
+}
+
+/// Everything a scene file describes.
+pub struct SceneFile {
+    pub scene: Scene,
+    pub camera: CameraSettings,
+    pub max_bounces: usize,
+}
+
+impl SceneFile {
+    pub const DEFAULT_MAX_BOUNCES: usize = 50;
+
+    /// Loads a scene file in RON format:
+    ///
+    /// ```ron
+    /// Scene(
+    ///     camera: (lookfrom: (0, 0, 0), lookat: (0, 0, -1), vup: (0, 1, 0), samples: 100),
+    ///     max_bounces: 50,
+    ///     fog: (color: (0.8, 0.8, 0.9), density: 0.02, distance: 100),
+    ///     sky: (sun_direction: (0.2, 0.8, 0.4), turbidity: 3, intensity: 0.5),
+    ///     sun: (direction: (0.2, 0.8, 0.4), angular_radius: 0.27, color: (1, 1, 1), intensity: 3),
+    ///     include: ["materials.ron"],
+    ///     materials: { "ground": Lambertian(albedo: (0.8, 0.8, 0.0)) },
+    ///     objects: [
+    ///         Sphere(center: (0, -100.5, -1), radius: 100, material: "ground"),
+    ///         Sphere(center: (0, 0, -1), radius: 0.5, material: Metal(albedo: (0.8, 0.6, 0.2), fuzz: 0)),
+    ///         Sphere(name: "ball", center: (1, 0, -1), radius: 0.5, material: Dielectric(refract_idx: 1.5)),
+    ///         Sphere(center: (1, 0, -1), radius: 0.4, material: Dielectric(refract_idx: 1.0), inside: "ball"),
+    ///     ],
+    ///     lights: [
+    ///         PointLight(position: (0, 2, 0), radius: 0.1, color: (1, 1, 1), intensity: 2),
+    ///         SpotLight(position: (0, 2, 0), direction: (0, -1, 0), angle: 30, falloff: 2, intensity: 2),
+    ///         QuadLight(corner: (-0.5, 2, -1.5), edge_u: (1, 0, 0), edge_v: (0, 0, 1), intensity: 4),
+    ///         DirectionalLight(direction: (1, 1, 0), angular_radius: 0.27, intensity: 1),
+    ///     ],
+    ///     light_sampling: Power,
+    ///     firefly_clamp: (max_luminance: 20, outlier_ratio: 8),
+    /// )
+    /// ```
+    ///
+    /// Objects other than spheres take an optional `rotation` (`Quat(x, y, z, w)`,
+    /// `Euler(x, y, z)` or `AxisAngle(axis, angle)`, angles in degrees) around `pivot`.
+    /// One light is sampled per bounce, picked by `light_sampling` (`Uniform`, `Power` or `Tree`).
+    /// `firefly_clamp` limits the luminance of single samples, see `FireflyClamp`.
+    /// The sun's and directional lights' `angular_radius` and spot light `angle` are in degrees, its direction defaults to the sky's sun.
+    /// Quad lights emit towards `edge_u × edge_v` only.
+    /// The camera also takes `ev` (stops), `shutter` (seconds) and `iso`, scaling the
+    /// displayed brightness relative to 1/100 s at ISO 100. A non-zero `aperture` (lens
+    /// radius) enables depth of field, focused at `focus_distance` (`lookat` by default),
+    /// with a `bokeh` shape of `Disk`, `Polygon(blades, rotation)` (degrees),
+    /// `Image("aperture.pgm")` (binary PGM/PPM, bright where the lens is open) or
+    /// `Mask([...])`, the 16 rows of 16-bit masks an image is converted to.
+    /// `distortion: (k1, k2)` adds radial lens distortion with OpenCV's coefficients.
+    /// `projection` is `Perspective` (default), `Fisheye(mapping, fov)`, with an
+    /// `Equidistant` or `Equisolid` mapping and the field of view in degrees, a 360°
+    /// `Equirectangular` panorama or `Orthographic(height)` with the view height in
+    /// world units. `stereo: (eye_separation)` renders side-by-side left and right eye
+    /// views, 0.065 apart by default.
+    /// An object `inside` a named dielectric refracts relative to that object's index, which
+    /// makes hollow glass and bubbles work. Included files may define materials and objects;
+    /// their camera, fog, sky, sun, light sampling and firefly clamp settings are ignored.
+    ///
+    /// Files with a `.pbrt` extension are imported as PBRT scenes, see `pbrt`, `.xml`
+    /// files as Mitsuba scenes, see `mitsuba`, and `.usda`, `.usdz` and `.usd` files as
+    /// USD stages, see `usd`.
+    pub fn load(path: &Path, resolver: &AssetResolver) -> Result<Self> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("pbrt") => return crate::pbrt::load(path, resolver),
+            Some("xml") => return crate::mitsuba::load(path, resolver),
+            Some("usda" | "usdz" | "usd") => return crate::usd::load(path),
+            _ => {}
+        }
+
+        let mut loader = Loader::new(resolver);
+        let root = loader.load_file(path)?;
+        loader.finish(&root, Some(path))
+    }
+
+    /// Loads a scene from RON source, includes are resolved without a referencing file.
+    pub fn parse(source: &str, resolver: &AssetResolver) -> Result<Self> {
+        let mut loader = Loader::new(resolver);
+        let root = ron::parse(source).context("Failed to parse scene")?;
+        loader.load_value(&root, None).context("Invalid scene")?;
+        loader.finish(&root, None)
+    }
+
+    /// Writes the scene and camera to a scene file that `load` reads back, e.g. to keep a
+    /// generated scene for editing.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, format!("{}\n", self.to_ron().pretty()))
+            .with_context(|| format!("Failed to write scene `{}`", path.display()))
+    }
+
+    /// Self-contained scene file representation, the inverse of `parse`.
+    pub fn to_ron(&self) -> Value {
+        let Value::Struct(name, mut fields) = self.scene.to_ron() else {
+            unreachable!("Scene::to_ron returns a struct")
+        };
+
+        let mut camera = vec![
+            ("lookfrom".to_owned(), self.camera.lookfrom.into()),
+            ("lookat".to_owned(), self.camera.lookat.into()),
+            ("vup".to_owned(), self.camera.vup.into()),
+            ("samples".to_owned(), self.camera.num_samples.into()),
+        ];
+        let exposure = self.camera.exposure;
+        let defaults = Exposure::default();
+        if exposure.ev != defaults.ev {
+            camera.push(("ev".to_owned(), exposure.ev.into()));
+        }
+        if exposure.shutter != defaults.shutter {
+            camera.push(("shutter".to_owned(), exposure.shutter.into()));
+        }
+        if exposure.iso != defaults.iso {
+            camera.push(("iso".to_owned(), exposure.iso.into()));
+        }
+        let lens = self.camera.lens;
+        if lens.aperture > 0.0 {
+            camera.push(("aperture".to_owned(), lens.aperture.into()));
+            if let Some(focus_distance) = lens.focus_distance {
+                camera.push(("focus_distance".to_owned(), focus_distance.into()));
+            }
+            let bokeh = match lens.bokeh {
+                Bokeh::Disk => Value::Ident("Disk".to_owned()),
+                Bokeh::Polygon { blades, rotation } => Value::named(
+                    "Polygon",
+                    vec![
+                        ("blades", blades.into()),
+                        ("rotation", rotation.to_degrees().into()),
+                    ],
+                ),
+                Bokeh::Mask(mask) => Value::Tuple(
+                    Some("Mask".to_owned()),
+                    vec![Value::List(
+                        mask.iter().map(|&bits| (bits as u32).into()).collect(),
+                    )],
+                ),
+            };
+            camera.push(("bokeh".to_owned(), bokeh));
+        }
+        match self.camera.projection {
+            Projection::Perspective => {}
+            Projection::Fisheye { mapping, fov } => {
+                let mapping = match mapping {
+                    FisheyeMapping::Equidistant => "Equidistant",
+                    FisheyeMapping::Equisolid => "Equisolid",
+                };
+                camera.push((
+                    "projection".to_owned(),
+                    Value::named(
+                        "Fisheye",
+                        vec![
+                            ("mapping", Value::Ident(mapping.to_owned())),
+                            ("fov", fov.to_degrees().into()),
+                        ],
+                    ),
+                ));
+            }
+            Projection::Equirectangular => camera.push((
+                "projection".to_owned(),
+                Value::Ident("Equirectangular".to_owned()),
+            )),
+            Projection::Orthographic { height } => camera.push((
+                "projection".to_owned(),
+                Value::named("Orthographic", vec![("height", height.into())]),
+            )),
+        }
+        if let Some(eye_separation) = self.camera.stereo {
+            camera.push((
+                "stereo".to_owned(),
+                Value::Struct(
+                    None,
+                    vec![("eye_separation".to_owned(), eye_separation.into())],
+                ),
+            ));
+        }
+        if lens.distortion != Distortion::default() {
+            camera.push((
+                "distortion".to_owned(),
+                Value::Struct(
+                    None,
+                    vec![
+                        ("k1".to_owned(), lens.distortion.k1.into()),
+                        ("k2".to_owned(), lens.distortion.k2.into()),
+                    ],
+                ),
+            ));
+        }
+        let camera = Value::Struct(None, camera);
+        fields.insert(0, ("camera".to_owned(), camera));
+        fields.insert(
+            1,
+            ("max_bounces".to_owned(), (self.max_bounces as u32).into()),
+        );
+
+        Value::Struct(name, fields)
+    }
+}
+
+struct Loader<'a> {
+    resolver: &'a AssetResolver,
+    scene: Scene,
+    materials: HashMap<String, Material>,
+    /// Materials of objects with a `name`, for objects placed `inside` them.
+    named_objects: HashMap<String, Material>,
+    /// Images loaded so far, by resolved path.
+    textures: HashMap<PathBuf, TextureId>,
+    visiting: Vec<PathBuf>,
+}
+
+impl<'a> Loader<'a> {
+    fn new(resolver: &'a AssetResolver) -> Self {
+        Loader {
+            resolver,
+            scene: Scene::default(),
+            materials: HashMap::new(),
+            named_objects: HashMap::new(),
+            textures: HashMap::new(),
+            visiting: vec![],
+        }
+    }
+
+    fn finish(self, root: &Value, path: Option<&Path>) -> Result<SceneFile> {
+        let camera = match root.field("camera") {
+            Some(camera) => parse_camera(camera, self.resolver, path).context("Invalid camera")?,
+            None => CameraSettings::default(),
+        };
+
+        let max_bounces = match root.field("max_bounces") {
+            Some(value) => value.as_u32()? as usize,
+            None => SceneFile::DEFAULT_MAX_BOUNCES,
+        };
+
+        let mut scene = self.scene;
+        if let Some(fog) = root.field("fog") {
+            scene.set_fog(parse_fog(fog).context("Invalid fog")?);
+        }
+        let sky = match root.field("sky") {
+            Some(sky) => parse_sky(sky).context("Invalid sky")?,
+            None => Sky::default(),
+        };
+        scene.set_sky(sky);
+        if let Some(sun) = root.field("sun") {
+            scene.set_sun(parse_sun(sun, &sky).context("Invalid sun")?);
+        }
+        if let Some(clamp) = root.field("firefly_clamp") {
+            scene.set_firefly_clamp(parse_firefly_clamp(clamp).context("Invalid firefly clamp")?);
+        }
+        if let Some(sampling) = root.field("light_sampling") {
+            scene.set_light_sampling(match sampling.name() {
+                Some("Uniform") => LightSampling::Uniform,
+                Some("Power") => LightSampling::Power,
+                Some("Tree") => LightSampling::Tree,
+                other => return Err(anyhow!("Unknown light sampling {other:?}")),
+            });
+        }
+
+        Ok(SceneFile {
+            scene,
+            camera,
+            max_bounces,
+        })
+    }
+
+    /// Adds the contents of `path` (and its includes) to the scene, returning the parsed root.
+    fn load_file(&mut self, path: &Path) -> Result<Value> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        if self.visiting.contains(&canonical) {
+            return Err(anyhow!("Include cycle through `{}`", path.display()));
+        }
+
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scene `{}`", path.display()))?;
+        let root = ron::parse(&source)
+            .with_context(|| format!("Failed to parse scene `{}`", path.display()))?;
+
+        self.visiting.push(canonical);
+        self.load_value(&root, Some(path))
+            .with_context(|| format!("Invalid scene `{}`", path.display()))?;
+        self.visiting.pop();
+
+        Ok(root)
+    }
+
+    fn load_value(&mut self, root: &Value, path: Option<&Path>) -> Result<()> {
+        if root.name() != Some("Scene") {
+            return Err(anyhow!("Expected `Scene(...)` at the top level"));
+        }
+
+        if let Some(includes) = root.field("include") {
+            for include in includes.as_list()? {
+                let resolved = self.resolver.resolve(include.as_str()?, path)?;
+                self.load_file(&resolved)?;
+            }
+        }
+
+        if let Some(materials) = root.field("materials") {
+            for (name, material) in materials.as_map()? {
+                let material = self.parse_material(material, path)?;
+                self.materials.insert(name.as_str()?.to_owned(), material);
+            }
+        }
+
+        if let Some(objects) = root.field("objects") {
+            for (idx, object) in objects.as_list()?.iter().enumerate() {
+                self.load_object(object, path)
+                    .with_context(|| format!("Invalid object #{idx}"))?;
+            }
+        }
+
+        if let Some(lights) = root.field("lights") {
+            for (idx, light) in lights.as_list()?.iter().enumerate() {
+                let light = parse_light(light).with_context(|| format!("Invalid light #{idx}"))?;
+                self.scene.new_light(light);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn material(&mut self, object: &Value, path: Option<&Path>) -> Result<Material> {
+        match object.required("material")? {
+            Value::String(name) => self
+                .materials
+                .get(name)
+                .copied()
+                .ok_or_else(|| anyhow!("Unknown material `{name}`")),
+            material => self.parse_material(material, path),
+        }
+    }
+
+    /// Parses a material, loading its `texture` image into the scene once per file.
+    fn parse_material(&mut self, material: &Value, path: Option<&Path>) -> Result<Material> {
+        let parsed = parse_material(material)?;
+        let Some(texture) = material.field("texture") else {
+            return Ok(parsed);
+        };
+
+        let resolved = self.resolver.resolve(texture.as_str()?, path)?;
+        let texture = match self.textures.get(&resolved) {
+            Some(&texture) => texture,
+            None => {
+                let image = RgbaImage::load(&resolved)?;
+                let texture = self
+                    .scene
+                    .add_texture(resolved.display().to_string(), image);
+                self.textures.insert(resolved, texture);
+                texture
+            }
+        };
+        Ok(parsed.textured(texture))
+    }
+
+    fn load_object(&mut self, object: &Value, path: Option<&Path>) -> Result<()> {
+        let mut material = self.material(object, path)?;
+
+        if let Some(outer) = object.field("inside") {
+            let outer = outer.as_str()?;
+            let outer_material = self
+                .named_objects
+                .get(outer)
+                .ok_or_else(|| anyhow!("Unknown object `{outer}`, it must be defined earlier"))?;
+            let outer_idx = outer_material
+                .refract_idx()
+                .ok_or_else(|| anyhow!("Object `{outer}` is not a dielectric"))?;
+            material = material.inside(outer_idx);
+        }
+
+        if let Some(name) = object.field("name") {
+            self.named_objects
+                .insert(name.as_str()?.to_owned(), material);
+        }
+
+        let rotation = match object.field("rotation") {
+            Some(rotation) if object.name() != Some("Sphere") => Rotation::new(
+                parse_quat(rotation).context("Invalid rotation")?,
+                object
+                    .field("pivot")
+                    .map_or_else(|| default_pivot(object), Value::as_vec3)?,
+            ),
+            Some(_) => return Err(anyhow!("Spheres cannot be rotated")),
+            None => Rotation::identity(),
+        };
+
+        match object.name() {
+            Some("Sphere") => self.scene.new_sphere(parse_sphere(object)?, material),
+            Some("Cone") => self.scene.new_cone(parse_cone(object)?, rotation, material),
+            Some("Disk") => self.scene.new_disk(
+                Disk::new(
+                    object.required("center")?.as_vec3()?,
+                    object.required("normal")?.as_vec3()?,
+                    object.required("radius")?.as_f32()?,
+                ),
+                rotation,
+                material,
+            ),
+            Some("Csg") => {
+                let op = match object.required("op")?.name() {
+                    Some("Union") => CsgOp::Union,
+                    Some("Intersection") => CsgOp::Intersection,
+                    Some("Difference") => CsgOp::Difference,
+                    other => return Err(anyhow!("Unknown CSG operation {other:?}")),
+                };
+
+                self.scene.new_csg(
+                    op,
+                    parse_csg_shape(object.required("left")?)?,
+                    parse_csg_shape(object.required("right")?)?,
+                    rotation,
+                    material,
+                );
+            }
+            Some("Sdf") => {
+                let shape = parse_sdf_shape(object.required("shape")?)?;
+                match object.field("blend_with") {
+                    Some(other) => self.scene.new_sdf_blend(
+                        shape,
+                        parse_sdf_shape(other)?,
+                        object.field("blend").map_or(Ok(0.0), Value::as_f32)?,
+                        rotation,
+                        material,
+                    ),
+                    None => self.scene.new_sdf(shape, rotation, material),
+                }
+            }
+            Some("Mesh") => self
+                .scene
+                .new_mesh(parse_mesh(object)?, rotation, material)?,
+            other => return Err(anyhow!("Unknown object type {other:?}")),
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_camera(
+    camera: &Value,
+    resolver: &AssetResolver,
+    path: Option<&Path>,
+) -> Result<CameraSettings> {
+    let defaults = CameraSettings::default();
+
+    Ok(CameraSettings {
+        lookfrom: camera
+            .field("lookfrom")
+            .map_or(Ok(defaults.lookfrom), Value::as_vec3)?,
+        lookat: camera
+            .field("lookat")
+            .map_or(Ok(defaults.lookat), Value::as_vec3)?,
+        vup: camera
+            .field("vup")
+            .map_or(Ok(defaults.vup), Value::as_vec3)?,
+        num_samples: camera
+            .field("samples")
+            .map_or(Ok(defaults.num_samples), Value::as_u32)?,
+        exposure: Exposure {
+            ev: camera
+                .field("ev")
+                .map_or(Ok(defaults.exposure.ev), Value::as_f32)?,
+            shutter: camera
+                .field("shutter")
+                .map_or(Ok(defaults.exposure.shutter), Value::as_f32)?,
+            iso: camera
+                .field("iso")
+                .map_or(Ok(defaults.exposure.iso), Value::as_f32)?,
+        },
+        lens: Lens {
+            aperture: camera
+                .field("aperture")
+                .map_or(Ok(defaults.lens.aperture), Value::as_f32)?,
+            focus_distance: camera
+                .field("focus_distance")
+                .map(Value::as_f32)
+                .transpose()?,
+            bokeh: match camera.field("bokeh") {
+                Some(bokeh) => parse_bokeh(bokeh, resolver, path).context("Invalid bokeh")?,
+                None => defaults.lens.bokeh,
+            },
+            distortion: match camera.field("distortion") {
+                Some(distortion) => Distortion {
+                    k1: distortion.field("k1").map_or(Ok(0.0), Value::as_f32)?,
+                    k2: distortion.field("k2").map_or(Ok(0.0), Value::as_f32)?,
+                },
+                None => defaults.lens.distortion,
+            },
+        },
+        projection: match camera.field("projection") {
+            Some(projection) => parse_projection(projection).context("Invalid projection")?,
+            None => defaults.projection,
+        },
+        stereo: camera
+            .field("stereo")
+            .map(|stereo| {
+                stereo
+                    .field("eye_separation")
+                    .map_or(Ok(CameraSettings::DEFAULT_EYE_SEPARATION), Value::as_f32)
+            })
+            .transpose()?,
+    })
+}
+
+fn parse_projection(projection: &Value) -> Result<Projection> {
+    match projection.name() {
+        Some("Perspective") => Ok(Projection::Perspective),
+        Some("Fisheye") => {
+            let fov = projection.field("fov").map_or(Ok(180.0), Value::as_f32)?;
+            if !(0.0..=360.0).contains(&fov) || fov == 0.0 {
+                return Err(anyhow!(
+                    "Fisheye field of view must be in (0, 360] degrees, got {fov}"
+                ));
+            }
+            Ok(Projection::Fisheye {
+                mapping: match projection.field("mapping").and_then(Value::name) {
+                    None | Some("Equidistant") => FisheyeMapping::Equidistant,
+                    Some("Equisolid") => FisheyeMapping::Equisolid,
+                    Some(other) => return Err(anyhow!("Unknown fisheye mapping `{other}`")),
+                },
+                fov: fov.to_radians(),
+            })
+        }
+        Some("Equirectangular") => Ok(Projection::Equirectangular),
+        Some("Orthographic") => {
+            let height = projection.required("height")?.as_f32()?;
+            if height <= 0.0 {
+                return Err(anyhow!(
+                    "Orthographic view height must be positive, got {height}"
+                ));
+            }
+            Ok(Projection::Orthographic { height })
+        }
+        other => Err(anyhow!("Unknown projection {other:?}")),
+    }
+}
+
+fn parse_bokeh(bokeh: &Value, resolver: &AssetResolver, path: Option<&Path>) -> Result<Bokeh> {
+    match (bokeh.name(), bokeh) {
+        (Some("Disk"), _) => Ok(Bokeh::Disk),
+        (Some("Polygon"), _) => {
+            let blades = bokeh.required("blades")?.as_u32()?;
+            if blades < 3 {
+                return Err(anyhow!("A polygon needs at least 3 blades, got {blades}"));
+            }
+            Ok(Bokeh::Polygon {
+                blades,
+                rotation: bokeh
+                    .field("rotation")
+                    .map_or(Ok(0.0), Value::as_f32)?
+                    .to_radians(),
+            })
+        }
+        (Some("Image"), Value::Tuple(_, items)) if items.len() == 1 => {
+            let image = resolver.resolve(items[0].as_str()?, path)?;
+            Ok(Bokeh::from_image(&RgbaImage::load(&image)?))
+        }
+        (Some("Mask"), Value::Tuple(_, items)) if items.len() == 1 => {
+            let rows = items[0].as_list()?;
+            let mut mask = [0; Bokeh::MASK_SIZE as usize];
+            if rows.len() != mask.len() {
+                return Err(anyhow!(
+                    "Expected {} mask rows, got {}",
+                    mask.len(),
+                    rows.len()
+                ));
+            }
+            for (bits, row) in mask.iter_mut().zip(rows) {
+                *bits = row
+                    .as_u32()?
+                    .try_into()
+                    .map_err(|_| anyhow!("Mask row {row:?} doesn't fit in 16 bits"))?;
+            }
+            Ok(Bokeh::Mask(mask))
+        }
+        (other, _) => Err(anyhow!("Unknown bokeh {other:?}")),
+    }
+}
+
+fn parse_fog(fog: &Value) -> Result<Fog> {
+    let mut parsed = Fog::new(
+        fog.field("color")
+            .map_or(Ok(Vec3::new(1.0, 1.0, 1.0)), Value::as_vec3)?,
+        fog.required("density")?.as_f32()?,
+    );
+    if let Some(distance) = fog.field("distance") {
+        parsed.distance = distance.as_f32()?;
+    }
+
+    Ok(parsed)
+}
+
+fn parse_firefly_clamp(clamp: &Value) -> Result<FireflyClamp> {
+    Ok(FireflyClamp {
+        max_luminance: clamp
+            .field("max_luminance")
+            .map_or(Ok(0.0), Value::as_f32)?,
+        outlier_ratio: clamp
+            .field("outlier_ratio")
+            .map_or(Ok(0.0), Value::as_f32)?,
+    })
+}
+
+fn parse_sky(sky: &Value) -> Result<Sky> {
+    let defaults = Sky::default();
+
+    Ok(Sky::new(
+        sky.field("sun_direction")
+            .map_or(Ok(defaults.sun_direction), Value::as_vec3)?,
+        sky.field("turbidity")
+            .map_or(Ok(defaults.turbidity), Value::as_f32)?,
+        sky.field("intensity")
+            .map_or(Ok(defaults.intensity), Value::as_f32)?,
+    ))
+}
+
+fn parse_sun(sun: &Value, sky: &Sky) -> Result<Sun> {
+    Ok(Sun::new(
+        sun.field("direction")
+            .map_or(Ok(sky.sun_direction), Value::as_vec3)?,
+        sun.field("angular_radius")
+            .map_or(Ok(Sun::DEFAULT_ANGULAR_RADIUS), |radius| {
+                radius.as_f32().map(f32::to_radians)
+            })?,
+        sun.field("color")
+            .map_or(Ok(Vec3::new(1.0, 1.0, 1.0)), Value::as_vec3)?,
+        sun.required("intensity")?.as_f32()?,
+    ))
+}
+
+fn parse_light(light: &Value) -> Result<Light> {
+    match light.name() {
+        Some("PointLight") => Ok(Light::point(
+            light.required("position")?.as_vec3()?,
+            light.field("radius").map_or(Ok(0.0), Value::as_f32)?,
+            light
+                .field("color")
+                .map_or(Ok(Vec3::new(1.0, 1.0, 1.0)), Value::as_vec3)?,
+            light.required("intensity")?.as_f32()?,
+        )),
+        Some("SpotLight") => Ok(Light::spot(
+            light.required("position")?.as_vec3()?,
+            light.required("direction")?.as_vec3()?,
+            light.required("angle")?.as_f32()?.to_radians(),
+            light.field("falloff").map_or(Ok(0.0), Value::as_f32)?,
+            light.field("radius").map_or(Ok(0.0), Value::as_f32)?,
+            light
+                .field("color")
+                .map_or(Ok(Vec3::new(1.0, 1.0, 1.0)), Value::as_vec3)?,
+            light.required("intensity")?.as_f32()?,
+        )),
+        Some("QuadLight") => Ok(Light::quad(
+            light.required("corner")?.as_vec3()?,
+            light.required("edge_u")?.as_vec3()?,
+            light.required("edge_v")?.as_vec3()?,
+            light
+                .field("color")
+                .map_or(Ok(Vec3::new(1.0, 1.0, 1.0)), Value::as_vec3)?,
+            light.required("intensity")?.as_f32()?,
+        )),
+        Some("DirectionalLight") => Ok(Light::directional(
+            light.required("direction")?.as_vec3()?,
+            light
+                .field("angular_radius")
+                .map_or(Ok(Sun::DEFAULT_ANGULAR_RADIUS), |radius| {
+                    radius.as_f32().map(f32::to_radians)
+                })?,
+            light
+                .field("color")
+                .map_or(Ok(Vec3::new(1.0, 1.0, 1.0)), Value::as_vec3)?,
+            light.required("intensity")?.as_f32()?,
+        )),
+        other => Err(anyhow!("Unknown light type {other:?}")),
+    }
+}
+
+fn parse_material(material: &Value) -> Result<Material> {
+    let parsed = match material.name() {
+        Some("Lambertian") => Ok(Material::new_lambertian(
+            material.required("albedo")?.as_vec3()?,
+        )),
+        Some("Metal") => Ok(Material::new_metal(
+            material.required("albedo")?.as_vec3()?,
+            material.field("fuzz").map_or(Ok(0.0), Value::as_f32)?,
+        )),
+        Some("Dielectric") => Ok(Material::new_dielectric(
+            material.required("refract_idx")?.as_f32()?,
+            material
+                .field("abbe_number")
+                .map(Value::as_f32)
+                .transpose()?,
+        )),
+        Some("NormalMap") => Ok(Material::new_normal_map()),
+        Some("UvMap") => Ok(Material::new_uv_map()),
+        Some("Subsurface") => Ok(Material::new_subsurface(
+            material.required("albedo")?.as_vec3()?,
+            material.required("scatter_distance")?.as_f32()?,
+            material
+                .field("refract_idx")
+                .map_or(Ok(1.3), Value::as_f32)?,
+        )),
+        Some("Pbr") => {
+            let roughness = material.field("roughness").map_or(Ok(0.5), Value::as_f32)?;
+            let pbr = Material::new_pbr(
+                material.required("albedo")?.as_vec3()?,
+                material.field("metallic").map_or(Ok(0.0), Value::as_f32)?,
+                roughness,
+            );
+
+            let pbr = match (
+                material.field("roughness_bitangent"),
+                material.field("tangent"),
+            ) {
+                (None, None) => pbr,
+                (bitangent, tangent) => pbr.anisotropic(
+                    bitangent.map_or(Ok(roughness), Value::as_f32)?,
+                    tangent.map(Value::as_vec3).transpose()?,
+                ),
+            };
+
+            match material.field("clearcoat") {
+                Some(strength) => Ok(pbr.clearcoat(
+                    strength.as_f32()?,
+                    material
+                        .field("clearcoat_roughness")
+                        .map_or(Ok(0.0), Value::as_f32)?,
+                    material
+                        .field("clearcoat_ior")
+                        .map_or(Ok(1.5), Value::as_f32)?,
+                )),
+                None => Ok(pbr),
+            }
+        }
+        other => Err(anyhow!("Unknown material type {other:?}")),
+    }?;
+
+    let parsed = match material.field("film_thickness") {
+        Some(thickness) => parsed.thin_film(
+            thickness.as_f32()?,
+            material.field("film_ior").map_or(Ok(1.33), Value::as_f32)?,
+        ),
+        None => parsed,
+    };
+
+    match material.field("outer_refract_idx") {
+        Some(outer) => Ok(parsed.inside(outer.as_f32()?)),
+        None => Ok(parsed),
+    }
+}
+
+fn parse_sphere(sphere: &Value) -> Result<Sphere> {
+    Ok(Sphere::new(
+        sphere.required("center")?.as_vec3()?,
+        sphere.required("radius")?.as_f32()?,
+    ))
+}
+
+fn parse_cone(cone: &Value) -> Result<Cone> {
+    Ok(Cone::new(
+        cone.required("apex")?.as_vec3()?,
+        cone.required("axis")?.as_vec3()?,
+        cone.required("angle")?.as_f32()?,
+        cone.required("height")?.as_f32()?,
+    ))
+}
+
+fn parse_csg_shape(shape: &Value) -> Result<CsgShape> {
+    match shape.name() {
+        Some("Sphere") => Ok(CsgShape::Sphere(parse_sphere(shape)?)),
+        Some("Cone") => Ok(CsgShape::Cone(parse_cone(shape)?)),
+        other => Err(anyhow!("Unsupported CSG operand {other:?}")),
+    }
+}
+
+fn parse_sdf_shape(shape: &Value) -> Result<SdfShape> {
+    match shape.name() {
+        Some("Sphere") => Ok(SdfShape::Sphere {
+            center: shape.required("center")?.as_vec3()?,
+            radius: shape.required("radius")?.as_f32()?,
+        }),
+        Some("RoundedBox") => Ok(SdfShape::RoundedBox {
+            center: shape.required("center")?.as_vec3()?,
+            half_extents: shape.required("half_extents")?.as_vec3()?,
+            radius: shape.field("radius").map_or(Ok(0.0), Value::as_f32)?,
+        }),
+        Some("Mandelbox") => Ok(SdfShape::Mandelbox {
+            center: shape.required("center")?.as_vec3()?,
+            radius: shape.required("radius")?.as_f32()?,
+            scale: shape.field("scale").map_or(Ok(2.0), Value::as_f32)?,
+            iterations: shape.field("iterations").map_or(Ok(12), Value::as_u32)?,
+        }),
+        other => Err(anyhow!("Unknown SDF shape {other:?}")),
+    }
+}
+
+fn parse_mesh(mesh: &Value) -> Result<TriangleMesh> {
+    let positions = mesh
+        .required("positions")?
+        .as_list()?
+        .iter()
+        .map(Value::as_vec3)
+        .collect::<Result<_>>()?;
+    let indices = mesh
+        .required("indices")?
+        .as_list()?
+        .iter()
+        .map(Value::as_u32)
+        .collect::<Result<_>>()?;
+
+    let mut parsed = TriangleMesh::new(positions, indices);
+    if let Some(normals) = mesh.field("normals") {
+        parsed.normals = Some(
+            normals
+                .as_list()?
+                .iter()
+                .map(Value::as_vec3)
+                .collect::<Result<_>>()?,
+        );
+    }
+    if let Some(cull) = mesh.field("cull_backfaces") {
+        parsed.cull_backfaces = cull.as_bool()?;
+    }
+    if let Some(epsilon) = mesh.field("epsilon") {
+        parsed.epsilon = epsilon.as_f32()?;
+    }
+
+    Ok(parsed)
+}
+
+/// `Quat(x, y, z, w)`, `Euler(x, y, z)` in degrees applied in X, Y, Z order,
+/// or `AxisAngle(axis: (x, y, z), angle: degrees)`.
+fn parse_quat(rotation: &Value) -> Result<Quat> {
+    match (rotation.name(), rotation) {
+        (Some("Quat"), Value::Tuple(_, items)) if items.len() == 4 => {
+            Ok(Quat::from_quaternion(na::Quaternion::new(
+                items[3].as_f32()?,
+                items[0].as_f32()?,
+                items[1].as_f32()?,
+                items[2].as_f32()?,
+            )))
+        }
+        (Some("Euler"), Value::Tuple(_, items)) if items.len() == 3 => Ok(Quat::from_euler_angles(
+            items[0].as_f32()?.to_radians(),
+            items[1].as_f32()?.to_radians(),
+            items[2].as_f32()?.to_radians(),
+        )),
+        (Some("AxisAngle"), _) => Ok(Quat::from_axis_angle(
+            &na::Unit::new_normalize(rotation.required("axis")?.as_vec3()?),
+            rotation.required("angle")?.as_f32()?.to_radians(),
+        )),
+        _ => Err(anyhow!(
+            "Expected Quat(x, y, z, w), Euler(x, y, z) or AxisAngle(axis, angle)"
+        )),
+    }
+}
+
+/// Objects rotate around their center (apex for cones, first operand for CSG)
+/// unless a `pivot` is given.
+fn default_pivot(object: &Value) -> Result<Vec3> {
+    match object.name() {
+        Some("Cone") => object.required("apex")?.as_vec3(),
+        Some("Csg") => default_pivot(object.required("left")?),
+        Some("Sdf") => object.required("shape")?.required("center")?.as_vec3(),
+        Some("Mesh") => {
+            let positions = object.required("positions")?.as_list()?;
+            let sum = positions.iter().try_fold(Vec3::zeros(), |sum, p| {
+                Ok::<_, anyhow::Error>(sum + p.as_vec3()?)
+            })?;
+            Ok(sum / positions.len().max(1) as f32)
+        }
+        _ => object.required("center")?.as_vec3(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets::Preset;
+
+    #[test]
+    fn save_round_trips() {
+        let path = std::env::temp_dir().join(format!("raytracer-save-{}.ron", std::process::id()));
+        let resolver = AssetResolver::new(vec![]);
+
+        for preset in Preset::ALL {
+            let scene_file = preset.scene_file();
+            if !scene_file.scene.material_plugins().is_empty() {
+                // Plugins live in code, their materials are saved as stand-ins.
+                continue;
+            }
+            scene_file.save(&path).unwrap();
+            let loaded = SceneFile::load(&path, &resolver).unwrap();
+            assert_eq!(loaded.to_ron(), scene_file.to_ron(), "{}", preset.name());
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn textures_load_once() {
+        let dir = std::env::temp_dir().join(format!("raytracer-textures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("checker.ppm"),
+            b"P6 2 1 255\n\xff\x00\x00\x00\x00\xff",
+        )
+        .unwrap();
+        let resolver = AssetResolver::new(vec![dir.clone()]);
+        let source = r#"Scene(
+            materials: {
+                "red": Lambertian(albedo: (1, 0, 0), texture: "checker.ppm"),
+                "white": Lambertian(albedo: (1, 1, 1), texture: "checker.ppm"),
+            },
+            objects: [
+                Sphere(center: (0, 0, 0), radius: 1, material: "red"),
+                Sphere(center: (0, 2, 0), radius: 1, material: "white"),
+            ],
+        )"#;
+        let scene_file = SceneFile::parse(source, &resolver).unwrap();
+
+        let saved = scene_file.to_ron().pretty();
+        assert_eq!(saved.matches("checker.ppm").count(), 2, "{saved}");
+        let buffers = scene_file.scene.into_gpu_buffers(u32::MAX as u64).unwrap();
+        assert_eq!(buffers.textures.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn firefly_clamp_round_trips() {
+        let resolver = AssetResolver::new(vec![]);
+        let source = "Scene(firefly_clamp: (outlier_ratio: 8), objects: [])";
+        let scene_file = SceneFile::parse(source, &resolver).unwrap();
+
+        let saved = scene_file.to_ron().pretty();
+        assert!(saved.contains("outlier_ratio: 8"), "{saved}");
+        let loaded = SceneFile::parse(&saved, &resolver).unwrap();
+        assert_eq!(loaded.to_ron(), scene_file.to_ron());
+    }
+}
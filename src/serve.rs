@@ -0,0 +1,603 @@
+//! Render service for `--serve`, a small HTTP API over headless rendering for running the
+//! tracer on a GPU box:
+//!
+//! - `POST /renders` queues the RON scene file in the body, `?size=WxH` and `?samples=N`
+//!   override the default size and the scene's sample count. Answers `201 Created` with the
+//!   id of the render, `{"id":0}`.
+//! - `GET /renders/<id>` reports its progress, e.g.
+//!   `{"id":0,"state":"rendering","samples":12,"total":100,"width":1200,"height":675}`.
+//!   Failed renders carry an `error`.
+//! - `GET /renders/<id>/image.<ext>` fetches the finished image as `png`, `ppm`, or as
+//!   linear floats `exr` or `pfm`.
+//!
+//! Renders run one at a time in the order they were posted, on a single GPU. At most
+//! `MAX_QUEUED` wait their turn, further posts answer `503 Service Unavailable`. Only the
+//! last `MAX_FINISHED` finished renders are kept, older ids answer `404 Not Found`. Scenes
+//! above `MAX_SAMPLES` samples or `MAX_BOUNCES` bounces are refused.
+//!
+//! Posted scenes can only include files and load textures from the `--asset-path`
+//! directories.
+use crate::assets::AssetResolver;
+use crate::cli::{parse_size, Options};
+use crate::console::Console;
+use crate::gpu::Gpu;
+use crate::headless;
+use crate::image::Accumulation;
+use crate::raytracing::random_seed;
+use crate::scene_file::SceneFile;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Largest scene accepted in a request body.
+const MAX_BODY: usize = 64 << 20;
+/// Most header lines read per request.
+const MAX_HEADERS: usize = 100;
+/// Longest request or header line.
+const MAX_LINE: usize = 8 << 10;
+/// Finished renders kept for fetching their images.
+const MAX_FINISHED: usize = 32;
+/// Renders waiting for the GPU.
+const MAX_QUEUED: usize = 64;
+/// Most samples per pixel of a render, as in the window.
+const MAX_SAMPLES: u32 = 1 << 16;
+/// Most bounces per path of a render, as in the window.
+const MAX_BOUNCES: usize = 1024;
+
+/// Serves renders on `addr` until the process is stopped.
+pub async fn run(options: &Options, addr: &str) -> Result<()> {
+    let gpu = Gpu::headless(options.fallback_adapter, Console::shared()).await?;
+    let (queue, renders) = mpsc::channel(MAX_QUEUED);
+    let service = Arc::new(Service {
+        jobs: Mutex::new(Jobs::default()),
+        queue,
+        resolver: AssetResolver::confined(options.asset_paths.clone()),
+        size: options.size,
+        max_size: gpu.device.limits().max_texture_dimension_2d,
+    });
+    {
+        let service = service.clone();
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || render_jobs(&gpu, &options, &service, renders));
+    }
+
+    let listener = TcpListener::bind(addr).await?;
+    eprintln!("Serving renders on http://{}", listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = service.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let response = match read_request(&mut BufReader::new(reader)).await {
+                Ok(request) => service.respond(&request),
+                Err(err) => Response::text(400, &format!("{err:#}")),
+            };
+            if let Err(err) = response.write(&mut writer).await {
+                log::warn!("Failed to answer a request: {err:#}");
+            }
+        });
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    Queued,
+    Rendering,
+    Done,
+    Failed(String),
+}
+
+struct Job {
+    state: State,
+    /// Samples per pixel traced so far.
+    samples: u32,
+    total: u32,
+    size: (u32, u32),
+    /// Scale of the written colors, see `Camera::exposure`.
+    exposure: f32,
+    /// Shared so it can be encoded without holding the jobs.
+    image: Option<Arc<Accumulation>>,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done | State::Failed(_))
+    }
+}
+
+/// Jobs by id, in the order they were posted.
+#[derive(Default)]
+struct Jobs {
+    jobs: BTreeMap<usize, Job>,
+    next_id: usize,
+}
+
+impl Jobs {
+    fn push(&mut self, job: Job) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.insert(id, job);
+        id
+    }
+
+    /// Drops the oldest finished jobs beyond `MAX_FINISHED`, with their images.
+    fn evict_finished(&mut self) {
+        let finished: Vec<usize> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.is_finished())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &finished[..finished.len().saturating_sub(MAX_FINISHED)] {
+            self.jobs.remove(id);
+        }
+    }
+}
+
+struct Service {
+    jobs: Mutex<Jobs>,
+    /// Ids of queued jobs with their scenes, for `render_jobs`.
+    queue: mpsc::Sender<(usize, SceneFile)>,
+    /// Confined to the asset directories, posted scenes are untrusted.
+    resolver: AssetResolver,
+    /// Size of renders not asking for one.
+    size: (u32, u32),
+    /// Largest width or height the GPU can render.
+    max_size: u32,
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, json: String) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: json.into_bytes(),
+        }
+    }
+
+    fn text(status: u16, text: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{text}\n").into_bytes(),
+        }
+    }
+
+    async fn write(&self, writer: &mut (impl AsyncWriteExt + Unpin)) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let head = format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+        writer.write_all(head.as_bytes()).await?;
+        writer.write_all(&self.body).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+}
+
+impl Service {
+    fn respond(&self, request: &Request) -> Response {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["renders"]) => self
+                .post(request)
+                .unwrap_or_else(|err| Response::text(400, &format!("{err:#}"))),
+            ("GET", ["renders", id]) => self
+                .with_job(id, |id, job| Response::json(200, status_json(id, job)))
+                .unwrap_or_else(|not_found| not_found),
+            ("GET", ["renders", id, file]) => self.image(id, file),
+            (_, ["renders", ..]) => Response::text(405, "Method not allowed"),
+            _ => Response::text(404, "Not found"),
+        }
+    }
+
+    fn post(&self, request: &Request) -> Result<Response> {
+        let mut size = self.size;
+        let mut samples = None;
+        for pair in request.query.split('&').filter(|pair| !pair.is_empty()) {
+            match pair.split_once('=') {
+                Some(("size", value)) => size = parse_size(value)?,
+                Some(("samples", value)) => samples = Some(value.parse()?),
+                _ => return Err(anyhow!("Unknown query parameter `{pair}`")),
+            }
+        }
+        if size.0 == 0 || size.1 == 0 || samples == Some(0) {
+            return Err(anyhow!("Renders need a size and samples above 0"));
+        }
+        if size.0 > self.max_size || size.1 > self.max_size {
+            return Err(anyhow!(
+                "Renders can be at most {0}x{0} on this GPU",
+                self.max_size
+            ));
+        }
+
+        let source = std::str::from_utf8(&request.body)?;
+        let mut scene_file = SceneFile::parse(source, &self.resolver)?;
+        if let Some(samples) = samples {
+            scene_file.camera.num_samples = samples;
+        }
+        if scene_file.camera.num_samples > MAX_SAMPLES || scene_file.max_bounces > MAX_BOUNCES {
+            return Err(anyhow!(
+                "Renders can take at most {MAX_SAMPLES} samples and {MAX_BOUNCES} bounces"
+            ));
+        }
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let total = scene_file.camera.num_samples;
+        let id = jobs.next_id;
+        match self.queue.try_send((id, scene_file)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                return Ok(Response::text(
+                    503,
+                    &format!("{MAX_QUEUED} renders are queued, try again later"),
+                ))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(anyhow!("The renderer has stopped"))
+            }
+        }
+        jobs.push(Job {
+            state: State::Queued,
+            samples: 0,
+            total,
+            size,
+            exposure: 1.0,
+            image: None,
+        });
+        Ok(Response::json(201, format!("{{\"id\":{id}}}")))
+    }
+
+    /// Reads the job `id` while holding the jobs, or answers `404 Not Found`.
+    fn with_job<T>(&self, id: &str, read: impl FnOnce(usize, &Job) -> T) -> Result<T, Response> {
+        let jobs = self.jobs.lock().unwrap();
+        match id
+            .parse()
+            .ok()
+            .and_then(|id: usize| Some((id, jobs.jobs.get(&id)?)))
+        {
+            Some((id, job)) => Ok(read(id, job)),
+            None => Err(Response::text(404, &format!("No render `{id}`"))),
+        }
+    }
+
+    /// The finished image of job `id` in the format of the extension of `file`, encoded
+    /// without holding the jobs.
+    fn image(&self, id: &str, file: &str) -> Response {
+        let Some(extension) = file.strip_prefix("image.") else {
+            return Response::text(404, "Not found");
+        };
+        let content_type = match extension {
+            "png" => "image/png",
+            "ppm" => "image/x-portable-pixmap",
+            "exr" => "image/x-exr",
+            "pfm" => "application/octet-stream",
+            _ => return Response::text(404, &format!("Unsupported image format `{extension}`")),
+        };
+        let finished = match self.with_job(id, |_, job| {
+            job.image.clone().map(|image| (image, job.exposure))
+        }) {
+            Ok(finished) => finished,
+            Err(not_found) => return not_found,
+        };
+        let Some((image, exposure)) = finished else {
+            return Response::text(409, "The render hasn't finished");
+        };
+        match image.encode(extension, exposure) {
+            Some(body) => Response {
+                status: 200,
+                content_type,
+                body,
+            },
+            None => Response::text(500, &format!("Failed to encode the image as `{extension}`")),
+        }
+    }
+
+    fn update(&self, id: usize, update: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.jobs.get_mut(&id) {
+            update(job);
+            if job.is_finished() {
+                jobs.evict_finished();
+            }
+        }
+    }
+}
+
+fn status_json(id: usize, job: &Job) -> String {
+    let state = match &job.state {
+        State::Queued => "queued",
+        State::Rendering => "rendering",
+        State::Done => "done",
+        State::Failed(_) => "failed",
+    };
+    let error = match &job.state {
+        State::Failed(error) => format!(",\"error\":{}", json_string(error)),
+        _ => String::new(),
+    };
+    format!(
+        "{{\"id\":{id},\"state\":\"{state}\",\"samples\":{},\"total\":{},\"width\":{},\"height\":{}{error}}}",
+        job.samples, job.total, job.size.0, job.size.1
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Reads a line into `line`, failing if it's longer than `MAX_LINE`.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), line: &mut String) -> Result<usize> {
+    let read = reader.take(MAX_LINE as u64).read_line(line).await?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(anyhow!("A request line exceeds {MAX_LINE} bytes"));
+    }
+    Ok(read)
+}
+
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Request> {
+    let mut line = String::new();
+    read_line(reader, &mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Malformed request line `{}`", line.trim_end()));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (method, path, query) = (method.to_owned(), path.to_owned(), query.to_owned());
+
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if read_line(reader, &mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(anyhow!("The body exceeds {MAX_BODY} bytes"));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        query,
+        body,
+    })
+}
+
+/// Renders the queued jobs one after another until the service stops.
+fn render_jobs(
+    gpu: &Gpu,
+    options: &Options,
+    service: &Service,
+    mut renders: mpsc::Receiver<(usize, SceneFile)>,
+) {
+    while let Some((id, scene_file)) = renders.blocking_recv() {
+        service.update(id, |job| job.state = State::Rendering);
+        let Some(size) = service
+            .jobs
+            .lock()
+            .unwrap()
+            .jobs
+            .get(&id)
+            .map(|job| job.size)
+        else {
+            continue;
+        };
+        let result = render(gpu, options, scene_file, size, |samples| {
+            service.update(id, |job| job.samples = samples)
+        });
+        service.update(id, |job| match result {
+            Ok((image, exposure)) => {
+                job.image = Some(Arc::new(image));
+                job.exposure = exposure;
+                job.state = State::Done;
+            }
+            Err(err) => job.state = State::Failed(format!("{err:#}")),
+        });
+    }
+}
+
+/// Traces all samples of `scene_file`, returning the image and its exposure.
+fn render(
+    gpu: &Gpu,
+    options: &Options,
+    scene_file: SceneFile,
+    size: (u32, u32),
+    mut progress: impl FnMut(u32),
+) -> Result<(Accumulation, f32)> {
+    let seed = options.seed.unwrap_or_else(random_seed);
+    let (gpu_camera, renderer, raytracer) =
+        headless::prepare(gpu, options, scene_file, size, seed)?;
+    let samples = 0..gpu_camera.camera().num_samples;
+    raytracer.perform(gpu, &gpu_camera, samples, None, |samples| {
+        progress(samples);
+        ControlFlow::Continue(())
+    })?;
+    Ok((
+        renderer.read_accumulation(gpu)?,
+        gpu_camera.camera().exposure(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> (Service, mpsc::Receiver<(usize, SceneFile)>) {
+        let (queue, renders) = mpsc::channel(MAX_QUEUED);
+        let service = Service {
+            jobs: Mutex::new(Jobs::default()),
+            queue,
+            resolver: AssetResolver::confined(vec![]),
+            size: (64, 32),
+            max_size: 2048,
+        };
+        (service, renders)
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: query.to_owned(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn parses_requests() {
+        let raw = b"POST /renders?size=8x4 HTTP/1.1\r\nHost: x\r\ncontent-length: 5\r\n\r\nScene";
+        let request = read_request(&mut &raw[..]).await.unwrap();
+        assert_eq!(
+            (
+                request.method.as_str(),
+                request.path.as_str(),
+                request.query.as_str()
+            ),
+            ("POST", "/renders", "size=8x4")
+        );
+        assert_eq!(request.body, b"Scene");
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert!(read_request(&mut long.as_bytes()).await.is_err());
+    }
+
+    #[test]
+    fn rejects_unsafe_posts() {
+        let (service, _renders) = service();
+        for (path, body) in [
+            ("/renders?size=4096x16", "Scene()"),
+            ("/renders", r#"Scene(include: ["/etc/hostname"])"#),
+            ("/renders", r#"Scene(include: ["../Cargo.toml"])"#),
+            ("/renders?samples=65537", "Scene()"),
+            ("/renders", "Scene(camera: (samples: 65537))"),
+            ("/renders", "Scene(max_bounces: 1025)"),
+        ] {
+            assert_eq!(service.respond(&request("POST", path, body)).status, 400);
+        }
+    }
+
+    #[test]
+    fn evicts_old_finished_renders() {
+        let (service, _renders) = service();
+        for _ in 0..MAX_FINISHED + 2 {
+            service.respond(&request("POST", "/renders", "Scene()"));
+        }
+        for id in 0..MAX_FINISHED + 1 {
+            service.update(id, |job| job.state = State::Done);
+        }
+
+        let status = |id: usize| {
+            service
+                .respond(&request("GET", &format!("/renders/{id}"), ""))
+                .status
+        };
+        assert_eq!((status(0), status(1)), (404, 200));
+        // Unfinished renders are never evicted.
+        assert_eq!(status(MAX_FINISHED + 1), 200);
+    }
+
+    #[test]
+    fn refuses_posts_once_the_queue_is_full() {
+        let (service, mut renders) = service();
+        for _ in 0..MAX_QUEUED {
+            let response = service.respond(&request("POST", "/renders", "Scene()"));
+            assert_eq!(response.status, 201);
+        }
+        let full = service.respond(&request("POST", "/renders", "Scene()"));
+        assert_eq!(full.status, 503);
+        assert_eq!(service.jobs.lock().unwrap().jobs.len(), MAX_QUEUED);
+
+        renders.try_recv().unwrap();
+        let response = service.respond(&request("POST", "/renders", "Scene()"));
+        assert_eq!(
+            (response.status, response.body),
+            (201, format!("{{\"id\":{MAX_QUEUED}}}").into_bytes())
+        );
+    }
+
+    #[test]
+    fn queues_posted_scenes() {
+        let (service, mut renders) = service();
+        let response = service.respond(&request("POST", "/renders?samples=8", "Scene()"));
+        assert_eq!(
+            (response.status, response.body),
+            (201, b"{\"id\":0}".to_vec())
+        );
+        let (id, scene_file) = renders.try_recv().unwrap();
+        assert_eq!((id, scene_file.camera.num_samples), (0, 8));
+
+        let status = service.respond(&request("GET", "/renders/0", ""));
+        assert_eq!(
+            String::from_utf8(status.body).unwrap(),
+            r#"{"id":0,"state":"queued","samples":0,"total":8,"width":64,"height":32}"#
+        );
+        let image = service.respond(&request("GET", "/renders/0/image.png", ""));
+        assert_eq!(image.status, 409);
+
+        service.update(0, |job| {
+            job.state = State::Failed("GPU \"lost\"".to_owned())
+        });
+        let status = service.respond(&request("GET", "/renders/0", ""));
+        assert!(String::from_utf8(status.body)
+            .unwrap()
+            .ends_with(r#""error":"GPU \"lost\""}"#));
+
+        for (method, path, status) in [
+            ("POST", "/renders", 400),
+            ("GET", "/renders/1", 404),
+            ("DELETE", "/renders/0", 405),
+            ("GET", "/", 404),
+        ] {
+            assert_eq!(service.respond(&request(method, path, "")).status, status);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Shaders split into modules. A `#import <module>` line is replaced by the module of that
+//! name from `src/shaders`, which may import others in turn. Every module is pulled in once,
+//! where it's first imported, so each can import what it uses regardless of the others.
+use crate::material_plugin::{self, MaterialPlugin};
+use anyhow::{anyhow, Result};
+
+const MODULES: &[(&str, &str)] = &[
+    ("rng", include_str!("shaders/rng.wgsl")),
+    ("intersections", include_str!("shaders/intersections.wgsl")),
+    ("materials", include_str!("shaders/materials.wgsl")),
+    ("camera", include_str!("shaders/camera.wgsl")),
+    ("lights", include_str!("shaders/lights.wgsl")),
+];
+
+/// The raytracing shader, `compute.wgsl` with its imports resolved and the `custom_materials`
+/// module generated for `materials`.
+pub fn compute(materials: &[MaterialPlugin]) -> Result<String> {
+    let custom_materials = material_plugin::wgsl(materials);
+    compose(
+        include_str!("compute.wgsl"),
+        &[("custom_materials", &custom_materials)],
+    )
+}
+
+/// Resolves the imports of `source` from the built-in modules and `generated`.
+pub fn compose(source: &str, generated: &[(&str, &str)]) -> Result<String> {
+    let mut modules = MODULES.to_vec();
+    modules.extend(generated);
+    let mut composed = String::with_capacity(source.len());
+    append(source, &modules, &mut composed, &mut vec![])?;
+    Ok(composed)
+}
+
+fn append<'a>(
+    source: &str,
+    modules: &[(&'a str, &'a str)],
+    composed: &mut String,
+    imported: &mut Vec<&'a str>,
+) -> Result<()> {
+    for line in source.lines() {
+        let Some(name) = line.strip_prefix("#import ").map(str::trim) else {
+            composed.push_str(line);
+            composed.push('\n');
+            continue;
+        };
+        if imported.contains(&name) {
+            continue;
+        }
+
+        let &(name, module) = modules
+            .iter()
+            .find(|(module, _)| *module == name)
+            .ok_or_else(|| anyhow!("Unknown shader module `{name}`"))?;
+        imported.push(name);
+        append(module, modules, composed, imported)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modules_are_imported_once() {
+        let source = compute(&[]).unwrap();
+        assert!(!source.contains("#import"));
+        for (name, module) in MODULES {
+            let first_line = module
+                .lines()
+                .find(|line| !line.is_empty() && !line.starts_with("#import"))
+                .unwrap();
+            assert_eq!(
+                source.matches(first_line).count(),
+                1,
+                "`{name}` imported again"
+            );
+        }
+
+        assert!(compose("#import nothing", &[]).is_err());
+    }
+}
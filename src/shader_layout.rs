@@ -0,0 +1,64 @@
+//! Test helpers comparing `encase` layouts of Rust structs with the WGSL structs they mirror.
+use encase::ShaderType;
+
+/// Byte size of the struct `name` in the WGSL `source`, as laid out by naga.
+pub fn wgsl_size(source: &str, name: &str) -> u64 {
+    let module = naga::front::wgsl::parse_str(source)
+        .unwrap_or_else(|err| panic!("{}", err.emit_to_string(source)));
+    let mut layouter = naga::proc::Layouter::default();
+    layouter.update(module.to_ctx()).unwrap();
+
+    let (handle, _) = module
+        .types
+        .iter()
+        .find(|(_, ty)| ty.name.as_deref() == Some(name))
+        .unwrap_or_else(|| panic!("No struct `{name}` in shader"));
+    layouter[handle].size as u64
+}
+
+/// Asserts `T` occupies as many bytes as the WGSL struct `name`. Structs embedding
+/// other structs also catch alignment drift, as it moves the embedded fields.
+pub fn assert_layout<T: ShaderType>(source: &str, name: &str) {
+    assert_eq!(
+        T::min_size().get(),
+        wgsl_size(source, name),
+        "`{}` and WGSL `{name}` differ in size",
+        std::any::type_name::<T>()
+    );
+}
+
+/// Byte offset of `member` in the WGSL struct `name`.
+pub fn wgsl_offset(source: &str, name: &str, member: &str) -> u64 {
+    let module = naga::front::wgsl::parse_str(source)
+        .unwrap_or_else(|err| panic!("{}", err.emit_to_string(source)));
+    let members = module
+        .types
+        .iter()
+        .find_map(|(_, ty)| match &ty.inner {
+            naga::TypeInner::Struct { members, .. } if ty.name.as_deref() == Some(name) => {
+                Some(members)
+            }
+            _ => None,
+        })
+        .unwrap_or_else(|| panic!("No struct `{name}` in shader"));
+    members
+        .iter()
+        .find(|m| m.name.as_deref() == Some(member))
+        .unwrap_or_else(|| panic!("No member `{member}` in `{name}`"))
+        .offset as u64
+}
+
+/// Value of the `u32` constant `name` in the WGSL `source`.
+pub fn wgsl_u32(source: &str, name: &str) -> u32 {
+    let module = naga::front::wgsl::parse_str(source)
+        .unwrap_or_else(|err| panic!("{}", err.emit_to_string(source)));
+    let (_, constant) = module
+        .constants
+        .iter()
+        .find(|(_, constant)| constant.name.as_deref() == Some(name))
+        .unwrap_or_else(|| panic!("No constant `{name}` in shader"));
+    match module.const_expressions[constant.init] {
+        naga::Expression::Literal(naga::Literal::U32(value)) => value,
+        ref init => panic!("Constant `{name}` is not a u32 literal: {init:?}"),
+    }
+}
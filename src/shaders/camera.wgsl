@@ -0,0 +1,219 @@
+#import rng
+
+// Camera rays: lens sampling, distortion and projections, see `initRay`.
+
+// Pixel reconstruction filters, see `PixelFilter`.
+const FILTER_BOX: u32 = 0u;
+const FILTER_TENT: u32 = 1u;
+const FILTER_GAUSSIAN: u32 = 2u;
+const FILTER_MITCHELL: u32 = 3u;
+
+const BOKEH_DISK: u32 = 0u;
+const BOKEH_POLYGON: u32 = 1u;
+const BOKEH_MASK: u32 = 2u;
+const BOKEH_MASK_SIZE: u32 = 16u;
+const BOKEH_MASK_TRIES: u32 = 16u;
+
+fn bokehMaskSet(p: vec2<f32>) -> bool {
+    var cell = min(vec2<u32>((p * 0.5 + 0.5) * f32(BOKEH_MASK_SIZE)), vec2<u32>(BOKEH_MASK_SIZE - 1u));
+    // Mask rows go from the top, the lens v axis points up.
+    var bit = (BOKEH_MASK_SIZE - 1u - cell.y) * BOKEH_MASK_SIZE + cell.x;
+    var word = bit / 32u;
+    return ((cam.bokeh_mask[word / 4u][word % 4u] >> (bit % 32u)) & 1u) != 0u;
+}
+
+// Point on the aperture, in units of the lens radius.
+fn sampleAperture() -> vec2<f32> {
+    if cam.bokeh == BOKEH_POLYGON {
+        // Uniform point in a random triangle between the center and one blade edge.
+        var sector = 2.0 * pi / f32(cam.blades);
+        var a0 = cam.blade_rotation + sector * floor(rand() * f32(cam.blades));
+        var v0 = vec2<f32>(cos(a0), sin(a0));
+        var v1 = vec2<f32>(cos(a0 + sector), sin(a0 + sector));
+        var r1 = rand();
+        var r2 = rand();
+        if r1 + r2 > 1.0 {
+            r1 = 1.0 - r1;
+            r2 = 1.0 - r2;
+        }
+        return r1 * v0 + r2 * v1;
+    }
+
+    if cam.bokeh == BOKEH_MASK {
+        for (var i = 0u; i < BOKEH_MASK_TRIES; i++) {
+            var p = vec2<f32>(2.0 * rand() - 1.0, 2.0 * rand() - 1.0);
+            if bokehMaskSet(p) {
+                return p;
+            }
+        }
+        return vec2<f32>(0.0);
+    }
+
+    var r = sqrt(rand());
+    var phi = 2.0 * pi * rand();
+    return r * vec2<f32>(cos(phi), sin(phi));
+}
+
+const DISTORTION_ITERATIONS: u32 = 8u;
+
+// Inverts the radial distortion: finds the undistorted image position that the lens
+// maps onto pixel position `p`, by fixed-point iteration.
+fn undistort(p: vec2<f32>) -> vec2<f32> {
+    var center = 0.5 * vec2<f32>(f32(cam.width) - 1.0, f32(cam.height) - 1.0);
+    // The vertical field of view is 90°, so the focal length is half the height in pixels.
+    var focal = 0.5 * f32(cam.height);
+    var distorted = (p - center) / focal;
+
+    var undistorted = distorted;
+    for (var i = 0u; i < DISTORTION_ITERATIONS; i++) {
+        var r2 = dot(undistorted, undistorted);
+        undistorted = distorted / (1.0 + r2 * (cam.distortion_k1 + r2 * cam.distortion_k2));
+    }
+
+    return center + undistorted * focal;
+}
+
+const PROJECTION_PERSPECTIVE: u32 = 0u;
+const PROJECTION_EQUIDISTANT: u32 = 1u;
+const PROJECTION_EQUISOLID: u32 = 2u;
+const PROJECTION_EQUIRECTANGULAR: u32 = 3u;
+const PROJECTION_ORTHOGRAPHIC: u32 = 4u;
+
+// Unit direction through pixel position `p`, or zero outside the image circle.
+fn fisheyeDirection(p: vec2<f32>) -> vec3<f32> {
+    var center = 0.5 * vec2<f32>(f32(cam.width) - 1.0, f32(cam.height) - 1.0);
+    // 1 at the edge of the image circle.
+    var offset = (p - center) / (0.5 * f32(cam.height));
+    var r = length(offset);
+    if r > 1.0 {
+        return vec3<f32>(0.0);
+    }
+
+    var theta = r * 0.5 * cam.fov;
+    if cam.projection == PROJECTION_EQUISOLID {
+        theta = 2.0 * asin(min(r * sin(0.25 * cam.fov), 1.0));
+    }
+
+    var forward = normalize(cam.lookat - cam.lookfrom);
+    var right = normalize(cam.delta_u);
+    var down = normalize(cam.delta_v);
+    var radial = vec2<f32>(0.0);
+    if r > 0.0 {
+        radial = offset / r;
+    }
+    return cos(theta) * forward + sin(theta) * (radial.x * right + radial.y * down);
+}
+
+// Unit direction through pixel position `p` of a 360° panorama around `vup`.
+fn equirectangularDirection(p: vec2<f32>) -> vec3<f32> {
+    var longitude = ((p.x + 0.5) / f32(cam.width) - 0.5) * 2.0 * pi;
+    var latitude = (0.5 - (p.y + 0.5) / f32(cam.height)) * pi;
+
+    var up = normalize(cam.vup);
+    var right = normalize(cam.delta_u);
+    var forward = cross(up, right);
+    return cos(latitude) * (sin(longitude) * right + cos(longitude) * forward) + sin(latitude) * up;
+}
+
+// Ray cone through a pixel for texture filtering (Akenine-Möller et al., "Texture Level of
+// Detail Strategies for Real-Time Ray Tracing"): its width at the camera in x and the growth
+// of the width per unit of distance in y.
+fn pixelCone() -> vec2<f32> {
+    var scale = f32(max(cam.preview_scale, 1u));
+    if cam.projection == PROJECTION_PERSPECTIVE {
+        return vec2<f32>(0.0, scale * length(cam.delta_u) / length(cam.lookat - cam.lookfrom));
+    } else if cam.projection == PROJECTION_ORTHOGRAPHIC {
+        return vec2<f32>(scale * cam.view_height / f32(cam.height), 0.0);
+    } else if cam.projection == PROJECTION_EQUIRECTANGULAR {
+        return vec2<f32>(0.0, scale * 2.0 * pi / f32(cam.width));
+    }
+    return vec2<f32>(0.0, scale * cam.fov / f32(cam.height));
+}
+
+// Tent of radius `radius` sampled from `u` in [0, 1).
+fn sampleTent(u: f32, radius: f32) -> f32 {
+    if u < 0.5 {
+        return radius * (sqrt(2.0 * u) - 1.0);
+    }
+    return radius * (1.0 - sqrt(2.0 - 2.0 * u));
+}
+
+// Mitchell-Netravali filter with B = C = 1/3, radius 2 and unit integral.
+fn mitchell(x: f32) -> f32 {
+    let x1 = abs(x);
+    if x1 < 1.0 {
+        return (7.0 * x1 * x1 * x1 - 12.0 * x1 * x1 + 16.0 / 3.0) / 6.0;
+    } else if x1 < 2.0 {
+        return (-7.0 / 3.0 * x1 * x1 * x1 + 12.0 * x1 * x1 - 20.0 * x1 + 32.0 / 3.0) / 6.0;
+    }
+    return 0.0;
+}
+
+// Offset from the pixel center in xy and weight in z of a sample placed by `u` in the unit
+// square. Samples are distributed like the reconstruction filter, which weights them all the
+// same (filter importance sampling) so a sample never has to be added to neighbouring pixels.
+// Mitchell's negative lobes go through a tent of the same radius, weighted by the ratio.
+fn sampleFilter(u: vec2<f32>) -> vec3<f32> {
+    let kind = limits_uniform.pixel_filter;
+    if kind == FILTER_TENT {
+        return vec3<f32>(sampleTent(u.x, 1.0), sampleTent(u.y, 1.0), 1.0);
+    } else if kind == FILTER_GAUSSIAN {
+        // Box-Muller with a standard deviation of half a pixel.
+        let r = 0.5 * sqrt(-2.0 * log(1.0 - u.x));
+        let phi = 2.0 * pi * u.y;
+        return vec3<f32>(r * cos(phi), r * sin(phi), 1.0);
+    } else if kind == FILTER_MITCHELL {
+        let offset = vec2<f32>(sampleTent(u.x, 2.0), sampleTent(u.y, 2.0));
+        let pdf = (2.0 - abs(offset)) / 4.0;
+        return vec3<f32>(offset, mitchell(offset.x) * mitchell(offset.y) / (pdf.x * pdf.y));
+    }
+    return vec3<f32>(u - 0.5, 1.0);
+}
+
+// Camera ray through image position (`x`, `y`) in pixels, pixel centers at whole numbers.
+fn initRay(x: f32, y: f32) -> Ray {
+    var origin = cam.lookfrom;
+    var position = vec2<f32>(x, y);
+    if cam.eye_separation > 0.0 {
+        // Each half of the image is a full view for one eye, centered like a mono view.
+        var eye = select(1.0, -1.0, x < 0.5 * f32(cam.width));
+        position.x -= eye * 0.25 * f32(cam.width);
+        origin += eye * 0.5 * cam.eye_separation * normalize(cam.delta_u);
+    }
+    if cam.distortion_k1 != 0.0 || cam.distortion_k2 != 0.0 {
+        position = undistort(position);
+    }
+
+    var ray: Ray;
+    var direction: vec3<f32>;
+    if cam.projection == PROJECTION_PERSPECTIVE {
+        var pixel = cam.top_left_pixel + position.x * cam.delta_u + position.y * cam.delta_v;
+        direction = pixel - origin;
+    } else if cam.projection == PROJECTION_ORTHOGRAPHIC {
+        var center = 0.5 * vec2<f32>(f32(cam.width) - 1.0, f32(cam.height) - 1.0);
+        var offset = (position - center) * (cam.view_height / f32(cam.height));
+        origin += offset.x * normalize(cam.delta_u) + offset.y * normalize(cam.delta_v);
+        direction = cam.lookat - cam.lookfrom;
+    } else if cam.projection == PROJECTION_EQUIRECTANGULAR {
+        // Scaled like a perspective ray, so the focus plane below becomes a sphere.
+        direction = equirectangularDirection(position) * length(cam.lookat - cam.lookfrom);
+    } else {
+        direction = fisheyeDirection(position) * length(cam.lookat - cam.lookfrom);
+        ray.finished = u32(all(direction == vec3<f32>(0.0)));
+    }
+
+    if cam.aperture > 0.0 && ray.finished == 0u {
+        // The pixel grid lies at the `lookat` distance, move it onto the focus plane.
+        var focus_point = origin + direction * (cam.focus_distance / length(cam.lookat - cam.lookfrom));
+        var lens = cam.aperture * sampleAperture();
+        origin += lens.x * normalize(cam.delta_u) - lens.y * normalize(cam.delta_v);
+        direction = focus_point - origin;
+    }
+
+    ray.origin = origin;
+    ray.direction = direction;
+    ray.medium = NO_MEDIUM;
+    ray.wavelength = 0.0;
+
+    return ray;
+}
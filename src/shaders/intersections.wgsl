@@ -0,0 +1,803 @@
+// Ray intersections with the scene objects, from single primitives up to `hitScene`.
+
+const CSG_UNION: u32 = u32(0);
+const CSG_INTERSECTION: u32 = u32(1);
+const CSG_DIFFERENCE: u32 = u32(2);
+
+const CSG_SPHERE: u32 = u32(0);
+const CSG_CONE: u32 = u32(1);
+
+const SDF_NONE: u32 = u32(0);
+const SDF_SPHERE: u32 = u32(1);
+const SDF_ROUNDED_BOX: u32 = u32(2);
+const SDF_MANDELBOX: u32 = u32(3);
+
+const SDF_MAX_STEPS: i32 = 256;
+
+struct HitRecord {
+    hit: bool,
+    t: f32,
+    point: vec3<f32>,
+    normal: vec3<f32>,
+    front_face: bool,
+    // Barycentric coordinates of the hit, weights of the second and third vertex.
+    barycentric: vec2<f32>,
+    // Surface coordinates in [0, 1], set for spheres.
+    uv: vec2<f32>,
+    // Surface coordinate units per world unit at the hit, for texture filtering.
+    uv_density: f32,
+};
+
+fn rayAt(ray: Ray, t: f32) -> vec3<f32> {
+    return ray.origin + ray.direction * t;
+}
+
+fn inside(x: f32, x_min: f32, x_max: f32) -> bool {
+    return x > x_min && x < x_max;
+}
+
+// Maps a point on the unit sphere to (u, v): u is the angle around the Y axis
+// starting at -X, v the angle from -Y to +Y.
+fn sphereUv(p: vec3<f32>) -> vec2<f32> {
+    var theta = acos(clamp(-p.y, -1.0, 1.0));
+    var phi = atan2(-p.z, p.x) + pi;
+    return vec2<f32>(phi / (2.0 * pi), theta / pi);
+}
+
+fn hitSphere(ray: Ray, sphere: Sphere, t_min: f32, t_max: f32) -> HitRecord {
+    var oc = ray.origin - sphere.center;
+    var a = dot(ray.direction, ray.direction);
+    var b = 2.0 * dot(oc, ray.direction);
+    var c = dot(oc, oc) - sphere.radius * sphere.radius;
+
+    var discriminant = b * b - 4.0 * a * c;
+
+    var record: HitRecord;
+    record.hit = false;
+
+    if discriminant == 0.0 {
+        var t = -b / (2.0 * a);
+
+        if inside(t, t_min, t_max) {
+            record.hit = true;
+            record.t = t;
+            record.point = rayAt(ray, t);
+            record.normal = (record.point - sphere.center) / sphere.radius;
+
+            if dot(ray.direction, record.normal) < 0.0 {
+                record.front_face = true;
+            } else {
+                record.normal = -record.normal;
+                record.front_face = false;
+            }
+        }
+    } else if discriminant >= 0.0 {
+        var t1 = (-b - sqrt(discriminant)) / (2.0 * a);
+        var t2 = (-b + sqrt(discriminant)) / (2.0 * a);
+
+        var t: f32 = t1;
+        if inside(t1, t_min, t_max) {
+            t = t1;
+            record.hit = true;
+        } else if inside(t2, t_min, t_max) {
+            t = t2;
+            record.hit = true;
+        }
+
+        if record.hit {
+            record.t = t;
+            record.point = rayAt(ray, t);
+            record.normal = (record.point - sphere.center) / sphere.radius;
+
+            if dot(ray.direction, record.normal) < 0.0 {
+                record.front_face = true;
+            } else {
+                record.normal = -record.normal;
+                record.front_face = false;
+            }
+        }
+    }
+
+    if discriminant >= 0.0 {
+        var t1 = (-b - sqrt(discriminant)) / (2.0 * a);
+        var t2 = (-b + sqrt(discriminant)) / (2.0 * a);
+
+        var t: f32 = t1;
+        if t1 < t_max && t1 > t_min {
+            t = t1;
+            record.hit = true;
+        } else if t2 < t_max && t2 > t_min {
+            t = t2;
+            record.hit = true;
+        }
+
+        if record.hit {
+            record.t = t;
+            record.point = rayAt(ray, t);
+            record.normal = (record.point - sphere.center) / sphere.radius;
+
+            if dot(ray.direction, record.normal) < 0.0 {
+                record.front_face = true;
+            } else {
+                record.normal = -record.normal;
+                record.front_face = false;
+            }
+        }
+    }
+
+    if record.hit {
+        record.uv = sphereUv((record.point - sphere.center) / abs(sphere.radius));
+        // Along v, lower along u away from the poles.
+        record.uv_density = 1.0 / (pi * abs(sphere.radius));
+    }
+
+    return record;
+}
+
+fn setFaceNormal(ray: Ray, outward_normal: vec3<f32>, record: ptr<function, HitRecord>) {
+    if dot(ray.direction, outward_normal) < 0.0 {
+        (*record).normal = outward_normal;
+        (*record).front_face = true;
+    } else {
+        (*record).normal = -outward_normal;
+        (*record).front_face = false;
+    }
+}
+
+fn quatRotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+// Moves the ray into the object's unrotated frame. Rotations keep `t` unchanged.
+fn rayToLocal(ray: Ray, rotation: Rotation) -> Ray {
+    var inverse = vec4<f32>(-rotation.quat.xyz, rotation.quat.w);
+    var local = ray;
+    local.origin = quatRotate(inverse, ray.origin - rotation.pivot) + rotation.pivot;
+    local.direction = quatRotate(inverse, ray.direction);
+    return local;
+}
+
+fn recordToWorld(record: HitRecord, rotation: Rotation) -> HitRecord {
+    var world = record;
+    world.point = quatRotate(rotation.quat, record.point - rotation.pivot) + rotation.pivot;
+    world.normal = quatRotate(rotation.quat, record.normal);
+    return world;
+}
+
+fn hitCone(ray: Ray, cone: Cone, t_min: f32, t_max: f32) -> HitRecord {
+    var record: HitRecord;
+    record.hit = false;
+
+    var cos2 = cos(cone.angle) * cos(cone.angle);
+    var co = ray.origin - cone.apex;
+    var dv = dot(ray.direction, cone.axis);
+    var cov = dot(co, cone.axis);
+
+    var a = dv * dv - cos2 * dot(ray.direction, ray.direction);
+    var b = 2.0 * (dv * cov - cos2 * dot(ray.direction, co));
+    var c = cov * cov - cos2 * dot(co, co);
+    var discriminant = b * b - 4.0 * a * c;
+
+    var closest = t_max;
+
+    if discriminant >= 0.0 && abs(a) > 1e-8 {
+        var sq = sqrt(discriminant);
+        var roots = array<f32, 2>((-b - sq) / (2.0 * a), (-b + sq) / (2.0 * a));
+
+        for (var i = 0; i < 2; i += 1) {
+            var t = roots[i];
+            if inside(t, t_min, closest) {
+                var cp = rayAt(ray, t) - cone.apex;
+                var h = dot(cp, cone.axis);
+
+                // Reject the mirrored nappe and anything past the base cap.
+                if h >= 0.0 && h <= cone.height {
+                    closest = t;
+                    record.hit = true;
+                    record.t = t;
+                    record.point = rayAt(ray, t);
+                    setFaceNormal(ray, normalize(cp * h / dot(cp, cp) - cone.axis), &record);
+                }
+            }
+        }
+    }
+
+    if abs(dv) > 1e-8 {
+        var base_center = cone.apex + cone.axis * cone.height;
+        var t = dot(base_center - ray.origin, cone.axis) / dv;
+
+        if inside(t, t_min, closest) {
+            var radius = cone.height * tan(cone.angle);
+            var offset = rayAt(ray, t) - base_center;
+
+            if dot(offset, offset) <= radius * radius {
+                record.hit = true;
+                record.t = t;
+                record.point = rayAt(ray, t);
+                setFaceNormal(ray, cone.axis, &record);
+            }
+        }
+    }
+
+    return record;
+}
+
+fn hitDisk(ray: Ray, disk: Disk, t_min: f32, t_max: f32) -> HitRecord {
+    var record: HitRecord;
+    record.hit = false;
+
+    var denom = dot(ray.direction, disk.normal);
+    if abs(denom) < 1e-8 {
+        return record;
+    }
+
+    var t = dot(disk.center - ray.origin, disk.normal) / denom;
+    if !inside(t, t_min, t_max) {
+        return record;
+    }
+
+    var offset = rayAt(ray, t) - disk.center;
+    if dot(offset, offset) > disk.radius * disk.radius {
+        return record;
+    }
+
+    record.hit = true;
+    record.t = t;
+    record.point = rayAt(ray, t);
+    setFaceNormal(ray, disk.normal, &record);
+
+    return record;
+}
+
+// Span of a ray inside a convex shape, with outward normals at both ends.
+struct Interval {
+    hit: bool,
+    t_in: f32,
+    t_out: f32,
+    n_in: vec3<f32>,
+    n_out: vec3<f32>,
+};
+
+fn sphereInterval(ray: Ray, sphere: Sphere) -> Interval {
+    var interval: Interval;
+    interval.hit = false;
+
+    var oc = ray.origin - sphere.center;
+    var a = dot(ray.direction, ray.direction);
+    var b = 2.0 * dot(oc, ray.direction);
+    var c = dot(oc, oc) - sphere.radius * sphere.radius;
+    var discriminant = b * b - 4.0 * a * c;
+
+    if discriminant < 0.0 {
+        return interval;
+    }
+
+    var radius = abs(sphere.radius);
+    interval.hit = true;
+    interval.t_in = (-b - sqrt(discriminant)) / (2.0 * a);
+    interval.t_out = (-b + sqrt(discriminant)) / (2.0 * a);
+    interval.n_in = (rayAt(ray, interval.t_in) - sphere.center) / radius;
+    interval.n_out = (rayAt(ray, interval.t_out) - sphere.center) / radius;
+
+    return interval;
+}
+
+fn coneInterval(ray: Ray, cone: Cone) -> Interval {
+    var interval: Interval;
+    interval.hit = false;
+    interval.t_in = 100000000000.0;
+    interval.t_out = -100000000000.0;
+
+    var cos2 = cos(cone.angle) * cos(cone.angle);
+    var co = ray.origin - cone.apex;
+    var dv = dot(ray.direction, cone.axis);
+    var cov = dot(co, cone.axis);
+
+    var a = dv * dv - cos2 * dot(ray.direction, ray.direction);
+    var b = 2.0 * (dv * cov - cos2 * dot(ray.direction, co));
+    var c = cov * cov - cos2 * dot(co, co);
+    var discriminant = b * b - 4.0 * a * c;
+
+    var ts = array<f32, 3>(0.0, 0.0, 0.0);
+    var ns = array<vec3<f32>, 3>(vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0));
+    var count = 0;
+
+    if discriminant >= 0.0 && abs(a) > 1e-8 {
+        var sq = sqrt(discriminant);
+        var roots = array<f32, 2>((-b - sq) / (2.0 * a), (-b + sq) / (2.0 * a));
+
+        for (var i = 0; i < 2; i += 1) {
+            var cp = rayAt(ray, roots[i]) - cone.apex;
+            var h = dot(cp, cone.axis);
+
+            if h >= 0.0 && h <= cone.height {
+                ts[count] = roots[i];
+                ns[count] = normalize(cp * h / dot(cp, cp) - cone.axis);
+                count += 1;
+            }
+        }
+    }
+
+    if abs(dv) > 1e-8 {
+        var base_center = cone.apex + cone.axis * cone.height;
+        var t = dot(base_center - ray.origin, cone.axis) / dv;
+        var radius = cone.height * tan(cone.angle);
+        var offset = rayAt(ray, t) - base_center;
+
+        if dot(offset, offset) <= radius * radius {
+            ts[count] = t;
+            ns[count] = cone.axis;
+            count += 1;
+        }
+    }
+
+    for (var i = 0; i < count; i += 1) {
+        if ts[i] < interval.t_in {
+            interval.t_in = ts[i];
+            interval.n_in = ns[i];
+        }
+
+        if ts[i] > interval.t_out {
+            interval.t_out = ts[i];
+            interval.n_out = ns[i];
+        }
+    }
+
+    interval.hit = count >= 2;
+    return interval;
+}
+
+fn leafInterval(ray: Ray, leaf: CsgLeaf) -> Interval {
+    if leaf.kind == CSG_SPHERE {
+        return sphereInterval(ray, leaf.sphere);
+    }
+
+    return coneInterval(ray, leaf.cone);
+}
+
+fn inInterval(t: f32, interval: Interval) -> bool {
+    return interval.hit && t > interval.t_in && t < interval.t_out;
+}
+
+// The result's surface is made of operand boundaries, so only the (at most four)
+// interval ends are candidates. Each is kept depending on whether it lies inside the
+// other operand.
+fn hitCsg(ray: Ray, node: CsgNode, t_min: f32, t_max: f32) -> HitRecord {
+    var record: HitRecord;
+    record.hit = false;
+
+    var left = leafInterval(ray, node.left);
+    var right = leafInterval(ray, node.right);
+
+    var ts = array<f32, 4>(left.t_in, left.t_out, right.t_in, right.t_out);
+    var ns = array<vec3<f32>, 4>(left.n_in, left.n_out, right.n_in, right.n_out);
+    var valid = array<bool, 4>(left.hit, left.hit, right.hit, right.hit);
+
+    var closest = t_max;
+    var outward_normal = vec3<f32>(0.0);
+
+    for (var i = 0; i < 4; i += 1) {
+        var t = ts[i];
+        if !valid[i] || !inside(t, t_min, closest) {
+            continue;
+        }
+
+        var from_left = i < 2;
+        var in_other: bool;
+        if from_left {
+            in_other = inInterval(t, right);
+        } else {
+            in_other = inInterval(t, left);
+        }
+
+        var keep = false;
+        var flip = false;
+        if node.op == CSG_UNION {
+            keep = !in_other;
+        } else if node.op == CSG_INTERSECTION {
+            keep = in_other;
+        } else if node.op == CSG_DIFFERENCE {
+            keep = (from_left && !in_other) || (!from_left && in_other);
+            flip = !from_left;
+        }
+
+        if keep {
+            closest = t;
+            record.hit = true;
+            outward_normal = ns[i];
+            if flip {
+                outward_normal = -outward_normal;
+            }
+        }
+    }
+
+    if record.hit {
+        record.t = closest;
+        record.point = rayAt(ray, closest);
+        setFaceNormal(ray, outward_normal, &record);
+    }
+
+    return record;
+}
+
+fn sdfRoundedBox(p: vec3<f32>, half_extents: vec3<f32>, radius: f32) -> f32 {
+    var q = abs(p) - half_extents;
+    return length(max(q, vec3<f32>(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0) - radius;
+}
+
+// Distance estimate of a mandelbox with fold limit 1, min radius 0.5 and fixed radius 1.
+fn sdfMandelbox(p: vec3<f32>, scale: f32, iterations: u32) -> f32 {
+    var z = p;
+    var dr = 1.0;
+
+    for (var i = u32(0); i < iterations; i += u32(1)) {
+        z = clamp(z, vec3<f32>(-1.0), vec3<f32>(1.0)) * 2.0 - z;
+
+        var r2 = dot(z, z);
+        if r2 < 0.25 {
+            z = z * 4.0;
+            dr = dr * 4.0;
+        } else if r2 < 1.0 {
+            z = z / r2;
+            dr = dr / r2;
+        }
+
+        z = scale * z + p;
+        dr = dr * abs(scale) + 1.0;
+    }
+
+    return length(z) / abs(dr);
+}
+
+fn sdfLeaf(p: vec3<f32>, leaf: SdfLeaf) -> f32 {
+    var local = p - leaf.center;
+
+    if leaf.kind == SDF_SPHERE {
+        return length(local) - leaf.radius;
+    } else if leaf.kind == SDF_ROUNDED_BOX {
+        return sdfRoundedBox(local, leaf.size, leaf.radius);
+    } else if leaf.kind == SDF_MANDELBOX {
+        // Fit the fractal's natural extent into the leaf radius.
+        var s = abs(leaf.scale);
+        var extent = 6.0;
+        if s > 1.0 {
+            extent = 2.0 * (s + 1.0) / (s - 1.0);
+        }
+        var factor = leaf.radius / extent;
+        return sdfMandelbox(local / factor, leaf.scale, leaf.iterations) * factor;
+    }
+
+    return 100000000000.0;
+}
+
+fn smoothMin(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return min(a, b);
+    }
+
+    var h = clamp(0.5 + 0.5 * (b - a) / k, 0.0, 1.0);
+    return mix(b, a, h) - k * h * (1.0 - h);
+}
+
+fn sdfNode(p: vec3<f32>, node: SdfNode) -> f32 {
+    var d = sdfLeaf(p, node.a);
+    if node.b.kind != SDF_NONE {
+        d = smoothMin(d, sdfLeaf(p, node.b), node.blend);
+    }
+    return d;
+}
+
+fn sdfNormal(p: vec3<f32>, node: SdfNode, eps: f32) -> vec3<f32> {
+    var e = vec2<f32>(eps, 0.0);
+    return normalize(vec3<f32>(
+        sdfNode(p + e.xyy, node) - sdfNode(p - e.xyy, node),
+        sdfNode(p + e.yxy, node) - sdfNode(p - e.yxy, node),
+        sdfNode(p + e.yyx, node) - sdfNode(p - e.yyx, node),
+    ));
+}
+
+fn hitSdf(ray: Ray, node: SdfNode, t_min: f32, t_max: f32) -> HitRecord {
+    var record: HitRecord;
+    record.hit = false;
+
+    // Only march the part of the ray inside the bounding sphere.
+    var bound: Sphere;
+    bound.center = node.bound_center;
+    bound.radius = node.bound_radius;
+    var span = sphereInterval(ray, bound);
+    if !span.hit {
+        return record;
+    }
+
+    var ray_len = length(ray.direction);
+    var t = max(span.t_in, t_min);
+    var t_end = min(span.t_out, t_max);
+
+    // Rays starting inside (refraction) march on the absolute distance.
+    var sign = 1.0;
+    if sdfNode(rayAt(ray, t), node) < 0.0 {
+        sign = -1.0;
+    }
+
+    for (var i = 0; i < SDF_MAX_STEPS && t < t_end; i += 1) {
+        var p = rayAt(ray, t);
+        var d = sign * sdfNode(p, node);
+        var eps = max(1e-4 * t * ray_len, 1e-5);
+
+        if d < eps {
+            record.hit = true;
+            record.t = t;
+            record.point = p;
+            setFaceNormal(ray, sdfNormal(p, node, eps), &record);
+            return record;
+        }
+
+        t += d / ray_len;
+    }
+
+    return record;
+}
+
+fn meshVertex(i: u32) -> Vertex {
+    if i < limits_uniform.vertex_chunk {
+        return verticesArr.vertices[i];
+    }
+    return verticesArr2.vertices[i - limits_uniform.vertex_chunk];
+}
+
+fn meshIndex(i: u32) -> u32 {
+    if i < limits_uniform.index_chunk {
+        return indicesArr.indices[i];
+    }
+    return indicesArr2.indices[i - limits_uniform.index_chunk];
+}
+
+// Möller–Trumbore ray/triangle intersection.
+fn hitTriangle(ray: Ray, mesh: MeshInstance, triangle: u32, t_min: f32, t_max: f32) -> HitRecord {
+    var record: HitRecord;
+    record.hit = false;
+
+    var first = mesh.first_index + triangle * u32(3);
+    var v0 = meshVertex(mesh.base_vertex + meshIndex(first));
+    var v1 = meshVertex(mesh.base_vertex + meshIndex(first + u32(1)));
+    var v2 = meshVertex(mesh.base_vertex + meshIndex(first + u32(2)));
+
+    var edge1 = v1.position - v0.position;
+    var edge2 = v2.position - v0.position;
+    var pvec = cross(ray.direction, edge2);
+    var det = dot(edge1, pvec);
+
+    // Negative determinant means the triangle faces away from the ray.
+    if mesh.cull_backfaces != u32(0) {
+        if det < mesh.epsilon {
+            return record;
+        }
+    } else if abs(det) < mesh.epsilon {
+        return record;
+    }
+
+    var inv_det = 1.0 / det;
+    var tvec = ray.origin - v0.position;
+    var u = dot(tvec, pvec) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return record;
+    }
+
+    var qvec = cross(tvec, edge1);
+    var v = dot(ray.direction, qvec) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return record;
+    }
+
+    var t = dot(edge2, qvec) * inv_det;
+    if t < max(t_min, mesh.epsilon) || t > t_max {
+        return record;
+    }
+
+    record.hit = true;
+    record.t = t;
+    record.point = rayAt(ray, t);
+    record.barycentric = vec2<f32>(u, v);
+    setFaceNormal(ray, normalize(cross(edge1, edge2)), &record);
+
+    var shading = (1.0 - u - v) * v0.normal + u * v1.normal + v * v2.normal;
+    if dot(shading, shading) > 0.0 {
+        shading = normalize(shading);
+        if record.front_face {
+            record.normal = shading;
+        } else {
+            record.normal = -shading;
+        }
+    }
+
+    return record;
+}
+
+struct SceneHit {
+    record: HitRecord,
+    mat_id: u32,
+};
+
+// Deep enough for the trees the builder makes of any practical scene, deeper subtrees are
+// skipped rather than overflowing.
+const BVH_STACK_SIZE: u32 = 32u;
+
+// Whether the ray enters the node's box between `t_min` and `t_max`.
+fn hitBvhNode(node: BvhNode, origin: vec3<f32>, inv_direction: vec3<f32>, t_min: f32, t_max: f32) -> bool {
+    var t0 = (node.min - origin) * inv_direction;
+    var t1 = (node.max - origin) * inv_direction;
+    var near = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), max(min(t0.z, t1.z), t_min));
+    var far = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), min(max(t0.z, t1.z), t_max));
+    return near <= far;
+}
+
+// Closest hit on the triangles of `instance`, through the bottom level BVH of its mesh.
+fn hitInstance(ray: Ray, instance: MeshInstance, t_min: f32, t_max_in: f32) -> HitRecord {
+    var t_max = t_max_in;
+    var record: HitRecord;
+    record.hit = false;
+
+    // Not normalized, so distances along the local ray are the same as along the world ray.
+    var local = ray;
+    local.origin = instance.to_local * vec4<f32>(ray.origin, 1.0);
+    local.direction = instance.to_local * vec4<f32>(ray.direction, 0.0);
+    var inv_direction = 1.0 / local.direction;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var top = 0u;
+    var node_index = instance.root;
+    loop {
+        var node = bvhArr.nodes[node_index];
+        if hitBvhNode(node, local.origin, inv_direction, t_min, t_max) {
+            if node.count == 0u {
+                if top < BVH_STACK_SIZE {
+                    stack[top] = instance.root + node.index;
+                    top += 1u;
+                }
+                node_index += 1u;
+                continue;
+            }
+
+            for (var tri = node.index; tri < node.index + node.count; tri += 1u) {
+                var hit = hitTriangle(local, instance, tri, t_min, t_max);
+                if hit.hit {
+                    t_max = hit.t;
+                    record = hit;
+                }
+            }
+        }
+
+        if top == 0u {
+            break;
+        }
+        top -= 1u;
+        node_index = stack[top];
+    }
+
+    if record.hit {
+        // Normals go back with the inverse transpose of the mesh to world transform.
+        var linear = mat3x3<f32>(instance.to_local[0], instance.to_local[1], instance.to_local[2]);
+        record.point = rayAt(ray, record.t);
+        record.normal = normalize(transpose(linear) * record.normal);
+    }
+    return record;
+}
+
+// Closest hit on the mesh instances, through the top level BVH over them.
+fn hitMeshes(ray: Ray, t_min: f32, t_max_in: f32) -> SceneHit {
+    var t_max = t_max_in;
+    var closest: SceneHit;
+    closest.record.hit = false;
+    var inv_direction = 1.0 / ray.direction;
+
+    var stack: array<u32, BVH_STACK_SIZE>;
+    var top = 0u;
+    var node_index = 0u;
+    loop {
+        var node = bvhArr.nodes[node_index];
+        if hitBvhNode(node, ray.origin, inv_direction, t_min, t_max) {
+            if node.count == 0u {
+                if top < BVH_STACK_SIZE {
+                    stack[top] = node.index;
+                    top += 1u;
+                }
+                node_index += 1u;
+                continue;
+            }
+
+            for (var i = node.index; i < node.index + node.count; i += 1u) {
+                var instance = instancesArr.instances[i];
+                var record = hitInstance(ray, instance, t_min, t_max);
+                if record.hit {
+                    t_max = record.t;
+                    closest.record = record;
+                    closest.mat_id = instance.mat_id;
+                }
+            }
+        }
+
+        if top == 0u {
+            break;
+        }
+        top -= 1u;
+        node_index = stack[top];
+    }
+
+    return closest;
+}
+
+// Closest hit among all objects between `t_min` and `t_max_in`.
+fn hitScene(ray: Ray, t_min: f32, t_max_in: f32) -> SceneHit {
+    var t_max = t_max_in;
+    var matId = u32(100000);
+    var hitRecord: HitRecord;
+    hitRecord.hit = false;
+
+    for (var i = u32(0); i < spheresArr.length; i += u32(1)) {
+        var record = hitSphere(ray, spheresArr.spheres[i].sphere, t_min, t_max);
+
+        if record.hit {
+            t_max = record.t;
+            matId = spheresArr.spheres[i].mat_id;
+            hitRecord = record;
+        }
+    }
+
+    for (var i = u32(0); i < conesArr.length; i += u32(1)) {
+        var rotation = conesArr.cones[i].rotation;
+        var record = hitCone(rayToLocal(ray, rotation), conesArr.cones[i].cone, t_min, t_max);
+
+        if record.hit {
+            t_max = record.t;
+            matId = conesArr.cones[i].mat_id;
+            hitRecord = recordToWorld(record, rotation);
+        }
+    }
+
+    for (var i = u32(0); i < disksArr.length; i += u32(1)) {
+        var rotation = disksArr.disks[i].rotation;
+        var record = hitDisk(rayToLocal(ray, rotation), disksArr.disks[i].disk, t_min, t_max);
+
+        if record.hit {
+            t_max = record.t;
+            matId = disksArr.disks[i].mat_id;
+            hitRecord = recordToWorld(record, rotation);
+        }
+    }
+
+    for (var i = u32(0); i < csgArr.length; i += u32(1)) {
+        var rotation = csgArr.nodes[i].rotation;
+        var record = hitCsg(rayToLocal(ray, rotation), csgArr.nodes[i], t_min, t_max);
+
+        if record.hit {
+            t_max = record.t;
+            matId = csgArr.nodes[i].mat_id;
+            hitRecord = recordToWorld(record, rotation);
+        }
+    }
+
+    for (var i = u32(0); i < sdfsArr.length; i += u32(1)) {
+        var rotation = sdfsArr.nodes[i].rotation;
+        var record = hitSdf(rayToLocal(ray, rotation), sdfsArr.nodes[i], t_min, t_max);
+
+        if record.hit {
+            t_max = record.t;
+            matId = sdfsArr.nodes[i].mat_id;
+            hitRecord = recordToWorld(record, rotation);
+        }
+    }
+
+    if instancesArr.length > 0u {
+        var meshes = hitMeshes(ray, t_min, t_max);
+        if meshes.record.hit {
+            t_max = meshes.record.t;
+            matId = meshes.mat_id;
+            hitRecord = meshes.record;
+        }
+    }
+
+    var hit: SceneHit;
+    hit.record = hitRecord;
+    hit.mat_id = matId;
+    return hit;
+}
@@ -0,0 +1,293 @@
+#import rng
+#import intersections
+
+// Sky, fog and direct light sampling through the light tree.
+
+const LIGHT_POINT: u32 = u32(0);
+const LIGHT_SPOT: u32 = u32(1);
+const LIGHT_QUAD: u32 = u32(2);
+const LIGHT_DIRECTIONAL: u32 = u32(3);
+const NO_LIGHT: u32 = 0xffffffffu;
+
+// Preetham sky: the Perez distribution scales the zenith luminance and
+// chromaticity by the angles from the zenith and from the sun.
+fn skyColor(direction: vec3<f32>) -> vec3<f32> {
+    var sky = environment.sky;
+    var d = normalize(direction);
+    // Below the horizon looks like the horizon.
+    var cos_theta = max(d.y, 0.01);
+    var cos_gamma = clamp(dot(d, sky.sun_direction), -1.0, 1.0);
+    var gamma = acos(cos_gamma);
+
+    var perez = (1.0 + sky.a * exp(sky.b / cos_theta))
+        * (1.0 + sky.c * exp(sky.d * gamma) + sky.e * cos_gamma * cos_gamma);
+    var yxy = sky.zenith * perez;
+
+    var lum = yxy.x;
+    var xyz = vec3<f32>(yxy.y / yxy.z * lum, lum, (1.0 - yxy.y - yxy.z) / yxy.z * lum);
+    var rgb = vec3<f32>(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    );
+    return max(rgb, vec3<f32>(0.0));
+}
+
+// Fraction of light crossing `distance` of fog.
+fn fogTransmittance(distance: f32) -> f32 {
+    return exp(-environment.fog.density * distance);
+}
+
+// Whether nothing blocks `direction` (unit length) from `point` within `distance`.
+fn unoccluded(point: vec3<f32>, direction: vec3<f32>, distance: f32) -> bool {
+    var shadow: Ray;
+    shadow.origin = point;
+    shadow.direction = direction;
+    return !hitScene(shadow, 0.001, distance).record.hit;
+}
+
+// Radiance of directional lights seen along `direction`, for paths not sampling them explicitly.
+fn directionalDisks(direction: vec3<f32>) -> vec3<f32> {
+    var result = vec3<f32>(0.0);
+    var d = normalize(direction);
+
+    for (var i = u32(0); i < lightsArr.length; i += u32(1)) {
+        var light = lightsArr.lights[i];
+        if light.kind == LIGHT_DIRECTIONAL && dot(d, light.direction) >= light.cos_angle {
+            result += light.intensity / (2.0 * pi * max(1.0 - light.cos_angle, 1e-7));
+        }
+    }
+
+    return result;
+}
+
+// Light from a disk in the sky, tracing a shadow ray towards a random point on it.
+fn sampleDirectionalLight(light: Light, point: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var direction = sampleCone(light.direction, light.cos_angle);
+    var cos_theta = dot(normal, direction);
+    if cos_theta <= 0.0 || !unoccluded(point, direction, 100000000000.0) {
+        return vec3<f32>(0.0);
+    }
+
+    return fogTransmittance(environment.fog.distance) * light.intensity * cos_theta / pi;
+}
+
+// Light from a point or spot light. Balls are sampled within the cone they
+// subtend, the radiance of their surface matching the intensity of a point.
+fn samplePointLight(light: Light, point: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var to_light = light.position - point;
+    var distance = length(to_light);
+    var axis = to_light / distance;
+
+    var intensity = light.intensity;
+    if light.kind == LIGHT_SPOT {
+        var cos_axis = dot(-axis, light.direction);
+        if cos_axis < light.cos_angle {
+            return vec3<f32>(0.0);
+        }
+        if light.falloff > 0.0 {
+            intensity = intensity * pow(max(cos_axis, 0.0), light.falloff);
+        }
+    }
+
+    var direction = axis;
+    var irradiance = intensity / (distance * distance);
+    if light.radius > 0.0 && distance > light.radius {
+        var sin_angle = light.radius / distance;
+        var cos_angle = sqrt(1.0 - sin_angle * sin_angle);
+        direction = sampleCone(axis, cos_angle);
+        var solid_angle = 2.0 * pi * (1.0 - cos_angle);
+        irradiance = intensity / (pi * light.radius * light.radius) * solid_angle;
+    }
+
+    var cos_theta = dot(normal, direction);
+    if cos_theta <= 0.0 || !unoccluded(point, direction, distance - light.radius) {
+        return vec3<f32>(0.0);
+    }
+
+    return fogTransmittance(distance) * irradiance * cos_theta / pi;
+}
+
+// Light from a quad reaching `point`, sampled at a random point on the quad and
+// converted from the area to the solid angle measure.
+fn sampleQuadLight(light: Light, point: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var on_light = light.position + rand() * light.edge_u + rand() * light.edge_v;
+    var to_light = on_light - point;
+    var distance = length(to_light);
+    var direction = to_light / distance;
+
+    var light_normal = cross(light.edge_u, light.edge_v);
+    var area = length(light_normal);
+    var cos_light = dot(-direction, light_normal / area);
+    var cos_theta = dot(normal, direction);
+    if cos_light <= 0.0 || cos_theta <= 0.0 || !unoccluded(point, direction, distance - 0.001) {
+        return vec3<f32>(0.0);
+    }
+
+    return fogTransmittance(distance) * light.intensity * cos_theta * cos_light * area / (distance * distance * pi);
+}
+
+struct EmitterHit {
+    hit: bool,
+    t: f32,
+    radiance: vec3<f32>,
+};
+
+// Closest quad light along `ray` before `t_max`. Their back side is black.
+fn hitEmitters(ray: Ray, t_max_in: f32) -> EmitterHit {
+    var result: EmitterHit;
+    result.hit = false;
+    result.t = t_max_in;
+
+    for (var i = u32(0); i < lightsArr.length; i += u32(1)) {
+        var light = lightsArr.lights[i];
+        if light.kind != LIGHT_QUAD {
+            continue;
+        }
+
+        var n = cross(light.edge_u, light.edge_v);
+        var denom = dot(n, ray.direction);
+        if abs(denom) < 1e-8 {
+            continue;
+        }
+
+        var t = dot(n, light.position - ray.origin) / denom;
+        if t < 0.001 || t >= result.t {
+            continue;
+        }
+
+        var planar = rayAt(ray, t) - light.position;
+        var w = n / dot(n, n);
+        var alpha = dot(w, cross(planar, light.edge_v));
+        var beta = dot(w, cross(light.edge_u, planar));
+        if alpha < 0.0 || alpha > 1.0 || beta < 0.0 || beta > 1.0 {
+            continue;
+        }
+
+        result.hit = true;
+        result.t = t;
+        result.radiance = vec3<f32>(0.0);
+        if denom < 0.0 {
+            result.radiance = light.intensity;
+        }
+    }
+
+    return result;
+}
+
+// Upper bound of the light from a light tree node reaching `point`: its power
+// over the squared distance, times the cosine to the closest direction into its
+// bounding sphere.
+fn lightNodeImportance(node: LightNode, point: vec3<f32>, normal: vec3<f32>) -> f32 {
+    var center = 0.5 * (node.min + node.max);
+    var radius = 0.5 * length(node.max - node.min);
+    var to_center = center - point;
+    var distance = length(to_center);
+    if distance <= radius {
+        return node.power / max(radius * radius, 1e-6);
+    }
+
+    var theta = acos(clamp(dot(normal, to_center / distance), -1.0, 1.0));
+    var theta_bound = asin(radius / distance);
+    var cos_bound = cos(max(theta - theta_bound, 0.0));
+    if cos_bound <= 0.0 {
+        return 0.0;
+    }
+
+    return node.power * cos_bound / max(distance * distance, radius * radius);
+}
+
+struct PickedLight {
+    index: u32,
+    probability: f32,
+};
+
+// Walks the light tree down to a leaf, picking children by importance. `u` is
+// reused at each level after rescaling.
+fn pickFromTree(point: vec3<f32>, normal: vec3<f32>, u_in: f32) -> PickedLight {
+    var picked: PickedLight;
+    picked.probability = 1.0;
+    var u = u_in;
+    var node = lightTree.nodes[0];
+
+    while node.light == NO_LIGHT {
+        var left = lightTree.nodes[node.left];
+        var right = lightTree.nodes[node.right];
+        var left_importance = lightNodeImportance(left, point, normal);
+        var right_importance = lightNodeImportance(right, point, normal);
+        var total = left_importance + right_importance;
+        if total <= 0.0 {
+            picked.probability = 0.0;
+            return picked;
+        }
+
+        var p_left = left_importance / total;
+        if u < p_left {
+            u = u / p_left;
+            picked.probability *= p_left;
+            node = left;
+        } else {
+            u = (u - p_left) / (1.0 - p_left);
+            picked.probability *= 1.0 - p_left;
+            node = right;
+        }
+    }
+
+    picked.index = node.light;
+    return picked;
+}
+
+// Picks a light by the precomputed cumulative probabilities, handing local
+// lights over to the light tree if there is one.
+fn pickLight(point: vec3<f32>, normal: vec3<f32>) -> PickedLight {
+    // First light whose cumulative probability exceeds u.
+    var u = rand();
+    var low = u32(0);
+    var high = lightsArr.length - u32(1);
+    while low < high {
+        var mid = (low + high) / u32(2);
+        if lightsArr.lights[mid].cumulative <= u {
+            low = mid + u32(1);
+        } else {
+            high = mid;
+        }
+    }
+
+    var light = lightsArr.lights[low];
+    var picked: PickedLight;
+    picked.index = low;
+    picked.probability = light.probability;
+
+    if lightTree.length > u32(0) && light.kind != LIGHT_DIRECTIONAL {
+        // Local lights come last, each with the probability of the whole group.
+        var local_start = 1.0 - light.probability;
+        picked = pickFromTree(point, normal, clamp((u - local_start) / light.probability, 0.0, 0.9999999));
+        picked.probability *= light.probability;
+    }
+
+    return picked;
+}
+
+// Light reflected by a Lambertian surface with unit albedo, from one light
+// picked at random and weighted by the inverse of its probability.
+fn sampleLights(point: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    if lightsArr.length == u32(0) {
+        return vec3<f32>(0.0);
+    }
+
+    var picked = pickLight(point, normal);
+    if picked.probability <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+
+    var light = lightsArr.lights[picked.index];
+    var result: vec3<f32>;
+    if light.kind == LIGHT_QUAD {
+        result = sampleQuadLight(light, point, normal);
+    } else if light.kind == LIGHT_DIRECTIONAL {
+        result = sampleDirectionalLight(light, point, normal);
+    } else {
+        result = samplePointLight(light, point, normal);
+    }
+    return result / picked.probability;
+}
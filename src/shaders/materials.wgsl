@@ -0,0 +1,229 @@
+#import rng
+
+// Scattering off surfaces: microfacet and layered models, Fresnel and dispersion.
+
+const MAT_LAMBERTIAN: u32 = u32(0);
+const MAT_METAL: u32 = u32(1);
+const MAT_DIELECTRIC: u32 = u32(2);
+const MAT_NORMAL_MAP: u32 = u32(3);
+const MAT_UV_MAP: u32 = u32(4);
+const MAT_PBR: u32 = u32(5);
+const MAT_SUBSURFACE: u32 = u32(6);
+
+const NO_MEDIUM: u32 = 0xffffffffu;
+const NO_TEXTURE: u32 = 0xffffffffu;
+
+// Albedo at surface coordinates `uv`, scaled by the material's texture if it has one. The
+// mip level matches `uv_footprint`, the extent of the ray cone in surface coordinates. Images
+// are stored top row first, while v grows upwards.
+fn materialAlbedo(material: Material, uv: vec2<f32>, uv_footprint: f32) -> vec3<f32> {
+    if material.texture == NO_TEXTURE {
+        return material.albedo;
+    }
+    var size = vec2<f32>(textureDimensions(textures));
+    var lod = log2(max(uv_footprint * max(size.x, size.y), 1.0));
+    var texel = textureSampleLevel(textures, texture_sampler, vec2<f32>(uv.x, 1.0 - uv.y), i32(material.texture), lod);
+    return material.albedo * texel.rgb;
+}
+
+fn reflect(direction: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    return direction - 2.0 * dot(direction, normal) * normal;
+}
+
+fn nearZero(v: vec3<f32>) -> bool {
+    var va = abs(v);
+    var s = 1e-8;
+    return va.x < s && va.y < s && va.z < s;
+}
+
+// Orthonormal basis (tangent, bitangent) around a unit normal.
+fn tangentFrame(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(n.y) > 0.999 {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    var tangent = normalize(cross(up, n));
+    var bitangent = cross(n, tangent);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+fn fresnelSchlick(cosine: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - cosine, 5.0);
+}
+
+// Tangent frame at a hit: `hint` projected onto the surface, or the default
+// frame (tangents circling the Y axis) if it's zero or parallel to `n`.
+fn shadingFrame(n: vec3<f32>, hint: vec3<f32>) -> mat3x3<f32> {
+    var projected = hint - n * dot(n, hint);
+    if dot(projected, projected) < 1e-8 {
+        return tangentFrame(n);
+    }
+    var tangent = normalize(projected);
+    return mat3x3<f32>(tangent, cross(n, tangent), n);
+}
+
+// Smith masking term for anisotropic GGX, `x` in the tangent frame.
+fn smithG1(x: vec3<f32>, alpha: vec2<f32>) -> f32 {
+    var projected = x.xy * alpha;
+    return 2.0 * x.z / (x.z + sqrt(x.z * x.z + dot(projected, projected)));
+}
+
+struct BsdfSample {
+    direction: vec3<f32>,
+    // BSDF * cosine / pdf.
+    weight: vec3<f32>,
+    valid: bool,
+};
+
+// Samples a reflection off GGX microfacets. The weight excludes Fresnel.
+fn sampleGgx(v: vec3<f32>, frame: mat3x3<f32>, alpha: vec2<f32>) -> BsdfSample {
+    var sample: BsdfSample;
+    sample.valid = false;
+
+    var n = frame[2];
+    var to_local = transpose(frame);
+
+    // Sample the GGX distribution of half vectors by stretching the slope.
+    var u = min(rand(), 0.9999);
+    var phi = 2.0 * pi * rand();
+    var slope = sqrt(u / (1.0 - u)) * vec2<f32>(cos(phi), sin(phi)) * alpha;
+    var h = frame * normalize(vec3<f32>(slope, 1.0));
+
+    var l = reflect(-v, h);
+    if dot(n, l) <= 0.0 {
+        return sample;
+    }
+
+    var n_dot_v = max(dot(n, v), 1e-4);
+    var v_dot_h = max(dot(v, h), 1e-4);
+    var n_dot_h = max(dot(n, h), 1e-4);
+    var g = smithG1(to_local * v, alpha) * smithG1(to_local * l, alpha);
+
+    sample.direction = l;
+    sample.weight = vec3<f32>(g * v_dot_h / (n_dot_v * n_dot_h));
+    sample.valid = true;
+    return sample;
+}
+
+// Metallic-roughness model: GGX specular lobe over a Lambertian base, one lobe
+// picked at random per bounce. An optional clearcoat reflects off the top first.
+fn samplePbr(ray_direction: vec3<f32>, n: vec3<f32>, material: Material) -> BsdfSample {
+    var v = -normalize(ray_direction);
+    var n_dot_v = max(dot(n, v), 1e-4);
+
+    if material.clearcoat > 0.0 {
+        var coat_f0 = (material.clearcoat_ior - 1.0) / (material.clearcoat_ior + 1.0);
+        var coat_fresnel = material.clearcoat * fresnelSchlick(n_dot_v, vec3<f32>(coat_f0 * coat_f0)).x;
+
+        // Reflecting off the coat with its Fresnel probability leaves the rest to the base.
+        if rand() < coat_fresnel {
+            var coat_alpha = max(material.clearcoat_roughness, 0.032) * max(material.clearcoat_roughness, 0.032);
+            return sampleGgx(v, tangentFrame(n), vec2<f32>(coat_alpha));
+        }
+    }
+
+    var roughness = max(vec2<f32>(material.roughness, material.roughness_bitangent), vec2<f32>(0.032));
+    var alpha = roughness * roughness;
+    var f0 = mix(vec3<f32>(0.04), material.albedo, material.metallic);
+    var specular_chance = mix(0.5, 1.0, material.metallic);
+
+    if rand() < specular_chance {
+        var sample = sampleGgx(v, shadingFrame(n, material.tangent), alpha);
+        var h = normalize(v + sample.direction);
+        var v_dot_h = max(dot(v, h), 1e-4);
+        var fresnel = fresnelSchlick(v_dot_h, f0);
+        if material.film_thickness > 0.0 {
+            fresnel = thinFilmOver(v_dot_h, f0, material);
+        }
+        sample.weight = sample.weight * fresnel / specular_chance;
+        return sample;
+    }
+
+    var direction = n + rand_unit_sphere();
+    if nearZero(direction) {
+        direction = n;
+    }
+
+    var sample: BsdfSample;
+    var f = fresnelSchlick(n_dot_v, f0);
+    sample.direction = direction;
+    sample.weight = (1.0 - f) * (1.0 - material.metallic) * material.albedo / (1.0 - specular_chance);
+    sample.valid = true;
+    return sample;
+}
+
+fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
+    var r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
+    r0 = r0 * r0;
+    return r0 + (1.0 - r0) * pow((1.0 - cosine), 5.0);
+}
+
+// Index of refraction at `wavelength` by Cauchy's equation n = A + B / λ², fitted
+// to `refract_idx` at the d line (587.6nm) and the Abbe number
+// (n_d - 1) / (n_F - n_C) with the F and C lines at 486.1 and 656.3nm.
+fn cauchyIor(refract_idx: f32, abbe_number: f32, wavelength: f32) -> f32 {
+    var b = (refract_idx - 1.0) / (abbe_number * (1.0 / (486.1 * 486.1) - 1.0 / (656.3 * 656.3)));
+    var a = refract_idx - b / (587.6 * 587.6);
+    return a + b / (wavelength * wavelength);
+}
+
+const MIN_WAVELENGTH: f32 = 380.0;
+const MAX_WAVELENGTH: f32 = 730.0;
+
+fn spectralBump(wavelength: f32, center: f32, half_width: f32) -> f32 {
+    var x = clamp((wavelength - center) / half_width, -1.0, 1.0);
+    var c = cos(0.5 * pi * x);
+    // Scaled so the mean over the sampled range is 1.
+    return c * c * (MAX_WAVELENGTH - MIN_WAVELENGTH) / half_width;
+}
+
+// RGB carried by a single wavelength sampled uniformly from the visible range,
+// averaging to white over all of them.
+fn wavelengthWeight(wavelength: f32) -> vec3<f32> {
+    return vec3<f32>(
+        spectralBump(wavelength, 610.0, 120.0),
+        spectralBump(wavelength, 540.0, 100.0),
+        spectralBump(wavelength, 450.0, 70.0),
+    );
+}
+
+// Reflectance of a film between a medium of index `outer_ior` and a base of
+// index `base_ior`, for red, green and blue light (650, 510 and 475nm). Sums the
+// reflections off both film boundaries (Airy), Schlick for each of them.
+fn thinFilm(cos_i: f32, outer_ior: f32, thickness: f32, film_ior: f32, base_ior: f32, base_f0: vec3<f32>) -> vec3<f32> {
+    var eta = outer_ior / film_ior;
+    var sin_t2 = eta * eta * (1.0 - cos_i * cos_i);
+    if sin_t2 >= 1.0 {
+        return vec3<f32>(1.0);
+    }
+    var cos_t = sqrt(1.0 - sin_t2);
+
+    var r12 = reflectance(cos_i, film_ior / outer_ior);
+    var r23 = fresnelSchlick(cos_t, base_f0);
+
+    // Reflecting off a denser medium flips the phase. A flip at only one of the
+    // boundaries shifts the interference by half a wavelength.
+    var shift = 0.0;
+    if (film_ior > outer_ior) != (base_ior > film_ior) {
+        shift = pi;
+    }
+    var wavelengths = vec3<f32>(650.0, 510.0, 475.0);
+    var phase = 4.0 * pi * film_ior * thickness * cos_t / wavelengths + shift;
+
+    var interference = 2.0 * sqrt(r12 * r23) * cos(phase);
+    return clamp((r12 + r23 + interference) / (1.0 + r12 * r23 + interference), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Thin film over an opaque base with Fresnel reflectance `f0`.
+fn thinFilmOver(cosine: f32, f0: vec3<f32>, material: Material) -> vec3<f32> {
+    var r = sqrt(min(dot(f0, vec3<f32>(1.0 / 3.0)), 0.99));
+    var base_ior = material.outer_refract_idx * (1.0 + r) / (1.0 - r);
+    return thinFilm(cosine, material.outer_refract_idx, material.film_thickness, material.film_ior, base_ior, f0);
+}
+
+fn refract(uv: vec3<f32>, n: vec3<f32>, etai_over_etat: f32) -> vec3<f32> {
+    var cos_theta = dot(-uv, n);
+    var r_out_parallel = etai_over_etat * (uv + cos_theta * n);
+    var r_out_perp = -sqrt(1.0 - dot(r_out_parallel, r_out_parallel)) * n;
+    return r_out_parallel + r_out_perp;
+}
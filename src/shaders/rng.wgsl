@@ -0,0 +1,69 @@
+// Random numbers and directions. Each invocation seeds its own generator with `init_rand`.
+
+var<private> rnd : vec3u;
+
+// Initializes the random number generator.
+fn init_rand(invocation_id: vec3<u32>) {
+    var A = vec3<u32>(u32(1741651 * 1009),
+        u32(140893 * 1609 * 13),
+        u32(6521 * 983 * 7 * 2));
+    rnd = (invocation_id * A) ^ seed_uniform.seed;
+}
+
+// Returns a random number between 0 and 1.
+fn rand() -> f32 {
+    var C = vec3<u32>(u32(60493 * 9377),
+        u32(11279 * 2539 * 23),
+        u32(7919 * 631 * 5 * 3));
+
+    rnd = (rnd * C) ^ (rnd.yzx >> vec3(4u));
+    return f32(rnd.x ^ rnd.y) / 4294967295.0; // 4294967295.0 is f32(0xffffffff). See #337
+}
+
+// PCG hash (Jarzynski and Olano, "Hash Functions for GPU Rendering").
+fn pcgHash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Position of sample `index` within `pixel`, in the unit square. Consecutive samples follow
+// the R2 sequence (Roberts, "The Unreasonable Effectiveness of Quasirandom Sequences") to
+// cover the pixel evenly, shifted by a hash of the pixel so neighbours don't share a pattern.
+// Fixed point keeps late samples as precise as the first.
+fn pixelSample(index: u32, pixel: vec2<u32>) -> vec2<f32> {
+    let shift = pcgHash(pixel.x ^ pcgHash(pixel.y));
+    let point = vec2<u32>(index * 3242174889u + shift, index * 2447445414u + pcgHash(shift));
+    return vec2<f32>(point >> vec2<u32>(8u)) / 16777216.0;
+}
+
+fn rand_unit_sphere() -> vec3<f32> {
+    var u = rand();
+    var v = rand();
+    var theta = u * 2.0 * pi;
+    var phi = acos(2.0 * v - 1.0);
+    var r = pow(rand(), 1.0 / 3.0);
+    var sin_theta = sin(theta);
+    var cos_theta = cos(theta);
+    var sin_phi = sin(phi);
+    var cos_phi = cos(phi);
+    var x = r * sin_phi * sin_theta;
+    var y = r * sin_phi * cos_theta;
+    var z = r * cos_phi;
+    return vec3<f32>(x, y, z);
+}
+
+fn rand_unit_vector() -> vec3<f32> {
+    var z = 2.0 * rand() - 1.0;
+    var phi = 2.0 * pi * rand();
+    var r = sqrt(1.0 - z * z);
+    return vec3<f32>(r * cos(phi), r * sin(phi), z);
+}
+
+// Uniformly distributed direction within `cos_angle` of `axis`.
+fn sampleCone(axis: vec3<f32>, cos_angle: f32) -> vec3<f32> {
+    var cos_theta = 1.0 - rand() * (1.0 - cos_angle);
+    var sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    var phi = 2.0 * pi * rand();
+    return tangentFrame(axis) * vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
@@ -0,0 +1,195 @@
+//! Short text codes describing a scene and camera, for pasting into bug reports and chats.
+//!
+//! A code is `rt1.` followed by the URL-safe base64 of the LZSS-compressed scene file.
+use crate::assets::AssetResolver;
+use crate::scene_file::SceneFile;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+
+const PREFIX: &str = "rt1.";
+
+pub fn encode(scene_file: &SceneFile) -> String {
+    let source = scene_file.to_ron().to_string();
+    format!("{PREFIX}{}", base64_encode(&compress(source.as_bytes())))
+}
+
+pub fn decode(code: &str, resolver: &AssetResolver) -> Result<SceneFile> {
+    let payload = code
+        .trim()
+        .strip_prefix(PREFIX)
+        .ok_or_else(|| anyhow!("Scene codes start with `{PREFIX}`"))?;
+
+    let source = decompress(&base64_decode(payload)?)?;
+    let source = String::from_utf8(source).context("Scene code is not valid UTF-8")?;
+    SceneFile::parse(&source, resolver)
+}
+
+const WINDOW: usize = 4096;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 15;
+const MAX_CANDIDATES: usize = 64;
+
+/// LZSS with a 4 KiB window: the uncompressed length, then groups of eight
+/// items preceded by a flag byte. A set bit marks a back-reference stored as a
+/// 12-bit distance and 4-bit length, a clear bit a literal byte.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = (data.len() as u32).to_le_bytes().to_vec();
+    let mut recent: HashMap<&[u8], Vec<usize>> = HashMap::new();
+
+    let mut pos = 0;
+    let mut flag_at = 0;
+    let mut item = 0;
+
+    while pos < data.len() {
+        if item % 8 == 0 {
+            flag_at = out.len();
+            out.push(0);
+        }
+
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            if let Some(candidates) = recent.get(&data[pos..pos + MIN_MATCH]) {
+                for &start in candidates.iter().rev().take(MAX_CANDIDATES) {
+                    if pos - start > WINDOW {
+                        break;
+                    }
+                    let len = data[start..]
+                        .iter()
+                        .zip(&data[pos..])
+                        .take(MAX_MATCH)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    if len > best.1 {
+                        best = (pos - start, len);
+                    }
+                }
+            }
+        }
+
+        let advance = if best.1 >= MIN_MATCH {
+            let (distance, len) = (best.0 - 1, best.1 - MIN_MATCH);
+            out[flag_at] |= 1 << (item % 8);
+            out.push((distance >> 4) as u8);
+            out.push(((distance & 0xf) << 4) as u8 | len as u8);
+            best.1
+        } else {
+            out.push(data[pos]);
+            1
+        };
+
+        for start in pos..pos + advance {
+            if start + MIN_MATCH <= data.len() {
+                recent
+                    .entry(&data[start..start + MIN_MATCH])
+                    .or_default()
+                    .push(start);
+            }
+        }
+
+        pos += advance;
+        item += 1;
+    }
+
+    out
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || anyhow!("Scene code is corrupted");
+
+    let len = u32::from_le_bytes(data.get(..4).ok_or_else(corrupt)?.try_into()?) as usize;
+    // Every two bytes of a match expand to at most `MAX_MATCH` bytes, so a longer length
+    // can't be genuine. Checked before allocating, the length is untrusted.
+    if len > (data.len() - 4) / 2 * MAX_MATCH {
+        return Err(corrupt());
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut bytes = data[4..].iter().copied();
+
+    while out.len() < len {
+        let flags = bytes.next().ok_or_else(corrupt)?;
+
+        for bit in 0..8 {
+            if out.len() >= len {
+                break;
+            }
+
+            if flags & (1 << bit) == 0 {
+                out.push(bytes.next().ok_or_else(corrupt)?);
+                continue;
+            }
+
+            let (hi, lo) = (
+                bytes.next().ok_or_else(corrupt)? as usize,
+                bytes.next().ok_or_else(corrupt)? as usize,
+            );
+            let distance = ((hi << 4) | (lo >> 4)) + 1;
+            let match_len = (lo & 0xf) + MIN_MATCH;
+            if distance > out.len() {
+                return Err(corrupt());
+            }
+
+            let start = out.len() - distance;
+            for idx in start..start + match_len {
+                out.push(out[idx]);
+            }
+        }
+    }
+
+    out.truncate(len);
+    Ok(out)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// URL-safe base64 without padding.
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (idx, &byte)| {
+            bits | (byte as u32) << (16 - 8 * idx)
+        });
+
+        for idx in 0..=chunk.len() {
+            out.push(BASE64[(bits >> (18 - 6 * idx)) as usize & 0x3f] as char);
+        }
+    }
+
+    out
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut bits, mut num_bits) = (0u32, 0);
+
+    for c in text.bytes() {
+        let value = BASE64
+            .iter()
+            .position(|&b| b == c)
+            .ok_or_else(|| anyhow!("Invalid character `{}` in scene code", c as char))?;
+
+        bits = ((bits << 6) | value as u32) & 0xffff;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            out.push((bits >> num_bits) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_rejects_oversized_lengths() {
+        let data = b"Scene(spheres: [Sphere(), Sphere(), Sphere(), Sphere()])".repeat(8);
+        assert_eq!(decompress(&compress(&data)).unwrap(), data);
+
+        let mut code = u32::MAX.to_le_bytes().to_vec();
+        code.extend_from_slice(&[0xff, 0, 0]);
+        assert!(decompress(&code).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Statistics of a single sample pass.
+#[derive(Debug)]
+pub struct FrameStats {
+    pub spp: u32,
+    pub rays: u64,
+    pub frame_time: Duration,
+    pub gpu_time: Duration,
+}
+
+enum Sink {
+    Stdout,
+    File(File),
+}
+
+/// Writes one JSON object per line so external tooling can tail the output.
+pub struct StatsWriter {
+    sink: Sink,
+}
+
+impl StatsWriter {
+    pub fn open(path: &Path) -> Result<Self> {
+        let sink = if path.as_os_str() == "-" {
+            Sink::Stdout
+        } else {
+            Sink::File(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+
+        Ok(Self { sink })
+    }
+
+    pub fn write(&mut self, stats: &FrameStats) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let rays_per_sec = stats.rays as f64 / stats.frame_time.as_secs_f64().max(f64::EPSILON);
+        let rss_bytes = resident_memory()
+            .map(|bytes| bytes.to_string())
+            .unwrap_or_else(|| "null".to_owned());
+
+        let line = format!(
+            "{{\"timestamp\":{:.3},\"spp\":{},\"rays_per_sec\":{:.0},\"frame_ms\":{:.3},\"gpu_ms\":{:.3},\"rss_bytes\":{}}}",
+            timestamp,
+            stats.spp,
+            rays_per_sec,
+            stats.frame_time.as_secs_f64() * 1000.0,
+            stats.gpu_time.as_secs_f64() * 1000.0,
+            rss_bytes,
+        );
+
+        match &mut self.sink {
+            Sink::Stdout => println!("{line}"),
+            Sink::File(file) => writeln!(file, "{line}")?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Resident set size of the process, where the platform exposes it cheaply.
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
@@ -0,0 +1,122 @@
+//! Progress of the render in flight, shown in the window title.
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sample passes of one render, counted as the GPU finishes them rather than as they are
+/// submitted, since the tracer can queue passes well ahead of the GPU.
+#[derive(Clone)]
+pub struct RenderStatus {
+    started: Instant,
+    /// Passes of a complete render, `None` for progressive rendering that never completes.
+    samples: Option<u32>,
+    rays_per_pass: u64,
+    done: Arc<Done>,
+}
+
+#[derive(Default)]
+struct Done {
+    passes: AtomicU32,
+    last: Mutex<Option<Instant>>,
+}
+
+impl RenderStatus {
+    pub fn new(samples: Option<u32>, (width, height): (u32, u32)) -> Self {
+        Self {
+            started: Instant::now(),
+            samples,
+            rays_per_pass: width as u64 * height as u64,
+            done: Arc::default(),
+        }
+    }
+
+    /// The same render continued with a different number of passes.
+    pub fn with_samples(&self, samples: Option<u32>) -> Self {
+        Self {
+            samples,
+            ..self.clone()
+        }
+    }
+
+    /// Callback for `wgpu::Queue::on_submitted_work_done` after submitting a pass. Passes of a
+    /// replaced render finishing late only count towards that render.
+    pub fn on_pass_done(&self) -> impl FnOnce() + Send + 'static {
+        let done = self.done.clone();
+        move || {
+            done.passes.fetch_add(1, Ordering::SeqCst);
+            *done.last.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    /// Status as of `now`, the clock stops once the last pass is done.
+    pub fn at(&self, now: Instant) -> Status {
+        let passes = self.done.passes.load(Ordering::SeqCst);
+        let finished = self.samples.is_some_and(|samples| passes >= samples);
+        let end = match *self.done.last.lock().unwrap() {
+            Some(last) if finished => last,
+            _ => now,
+        };
+
+        Status {
+            passes,
+            samples: self.samples,
+            finished,
+            elapsed: end.saturating_duration_since(self.started),
+            rays: passes as u64 * self.rays_per_pass,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub passes: u32,
+    pub samples: Option<u32>,
+    pub finished: bool,
+    pub elapsed: Duration,
+    /// Primary rays traced so far, like `FrameStats::rays`.
+    pub rays: u64,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        match self.samples {
+            _ if self.finished => write!(f, "{} spp in {seconds:.1} s", self.passes)?,
+            Some(samples) => write!(f, "{}/{samples} spp, {seconds:.1} s", self.passes)?,
+            None => write!(f, "{} spp, {seconds:.1} s", self.passes)?,
+        }
+        if self.passes > 0 && seconds > 0.0 {
+            write!(f, ", {:.1} Mrays/s", self.rays as f64 / seconds / 1e6)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_finished_passes() {
+        let status = RenderStatus::new(Some(2), (1000, 500));
+        let stale = RenderStatus::new(Some(2), (1000, 500)).on_pass_done();
+        stale();
+        status.on_pass_done()();
+
+        let now = status.started + Duration::from_secs(2);
+        let at = status.at(now);
+        assert_eq!((at.passes, at.finished, at.rays), (1, false, 500_000));
+        assert_eq!(at.to_string(), "1/2 spp, 2.0 s, 0.2 Mrays/s");
+
+        status.on_pass_done()();
+        let finished = status.at(now + Duration::from_secs(60));
+        assert!(finished.finished);
+        assert!(finished.elapsed < Duration::from_secs(60));
+        assert!(finished.to_string().starts_with("2 spp in 0.0 s"));
+
+        let progressive = status.with_samples(None).at(now);
+        assert!(!progressive.finished);
+        assert_eq!(progressive.to_string(), "2 spp, 2.0 s, 0.5 Mrays/s");
+    }
+}
@@ -0,0 +1,176 @@
+//! Image textures of the scene in a single `texture_2d_array`, materials referring to their
+//! layer. A `binding_array` of textures would keep every image at its own size, but needs
+//! native-only features, while an array binds any number of images in one binding everywhere.
+use crate::gpu::Gpu;
+use crate::image::{decode_srgb, encode_srgb, RgbaImage};
+use anyhow::{anyhow, Result};
+
+pub struct TexturePool {
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+}
+
+impl TexturePool {
+    /// Uploads `images` as the layers of the pool, resampled to the size of the largest one
+    /// since layers share it, with mip chains down to a single texel for `materialAlbedo` to
+    /// filter distant and oblique textures. Without images, a white pixel keeps the binding
+    /// valid.
+    pub fn new(gpu: &Gpu, images: &[RgbaImage]) -> Result<Self> {
+        use wgpu::util::DeviceExt;
+
+        let Gpu { device, queue, .. } = gpu;
+        let limits = device.limits();
+        if images.len() as u32 > limits.max_texture_array_layers {
+            return Err(anyhow!(
+                "Scene has {} textures, the device supports at most {}",
+                images.len(),
+                limits.max_texture_array_layers
+            ));
+        }
+
+        let max_size = limits.max_texture_dimension_2d;
+        let width = images.iter().map(|image| image.width).max().unwrap_or(1);
+        let height = images.iter().map(|image| image.height).max().unwrap_or(1);
+        let (width, height) = (width.min(max_size), height.min(max_size));
+
+        let white = RgbaImage {
+            width: 1,
+            height: 1,
+            pixels: vec![255; 4],
+        };
+        let layers = if images.is_empty() {
+            std::slice::from_ref(&white)
+        } else {
+            images
+        };
+        let mip_level_count = 32 - width.max(height).leading_zeros();
+        let mut pixels = vec![];
+        for image in layers {
+            let mut mip = resample(image, width, height);
+            let (mut mip_width, mut mip_height) = (width, height);
+            pixels.extend_from_slice(&mip);
+            for _ in 1..mip_level_count {
+                mip = downsample(&mip, mip_width, mip_height);
+                (mip_width, mip_height) = ((mip_width / 2).max(1), (mip_height / 2).max(1));
+                pixels.extend_from_slice(&mip);
+            }
+        }
+
+        gpu.push_error_scope();
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("texture pool"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: layers.len() as u32,
+                },
+                mip_level_count,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            &pixels,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("texture pool"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        gpu.pop_error_scope("the texture pool")?;
+
+        Ok(TexturePool { view, sampler })
+    }
+}
+
+/// Pixels of `image` scaled to `width` x `height` with nearest neighbour sampling, the
+/// shader filters between them.
+fn resample(image: &RgbaImage, width: u32, height: u32) -> Vec<u8> {
+    if (image.width, image.height) == (width, height) {
+        return image.pixels.clone();
+    }
+
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        let src_y = (y as u64 * image.height as u64 / height as u64) as usize;
+        for x in 0..width {
+            let src_x = (x as u64 * image.width as u64 / width as u64) as usize;
+            let i = (src_y * image.width as usize + src_x) * 4;
+            pixels.extend_from_slice(&image.pixels[i..i + 4]);
+        }
+    }
+    pixels
+}
+
+/// The next mip level of `width` x `height` pixels, averaging blocks of 2x2 in linear space.
+/// Odd sizes round down, the last row or column joining the block before it.
+fn downsample(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (mip_width, mip_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut mip = Vec::with_capacity((mip_width * mip_height * 4) as usize);
+    for y in 0..mip_height {
+        let rows = 2 * y..(2 * y + 2 + (y == mip_height - 1) as u32 * (height % 2)).min(height);
+        for x in 0..mip_width {
+            let columns = 2 * x..(2 * x + 2 + (x == mip_width - 1) as u32 * (width % 2)).min(width);
+            let mut sum = [0.0; 4];
+            for src_y in rows.clone() {
+                for src_x in columns.clone() {
+                    let i = ((src_y * width + src_x) * 4) as usize;
+                    for (c, sum) in sum.iter_mut().enumerate() {
+                        *sum += match c {
+                            3 => pixels[i + c] as f32 / 255.0,
+                            _ => decode_srgb(pixels[i + c]),
+                        };
+                    }
+                }
+            }
+
+            let count = (rows.len() * columns.len()) as f32;
+            mip.extend_from_slice(&[
+                encode_srgb(sum[0] / count),
+                encode_srgb(sum[1] / count),
+                encode_srgb(sum[2] / count),
+                (sum[3] / count * 255.0).round() as u8,
+            ]);
+        }
+    }
+    mip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downsamples_in_linear_space() {
+        // A third of the light, far brighter than 85 in sRGB. The odd column joins the block.
+        let pixels = [[0, 0, 0, 255], [255, 255, 255, 255], [0, 0, 0, 255]].concat();
+        assert_eq!(downsample(&pixels, 3, 1), [156, 156, 156, 255]);
+        assert_eq!(downsample(&[7; 4], 1, 1), [7; 4]);
+    }
+
+    #[test]
+    fn resamples_to_layer_size() {
+        let image = RgbaImage {
+            width: 2,
+            height: 1,
+            pixels: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        assert_eq!(
+            resample(&image, 4, 2),
+            [[1, 2, 3, 4], [1, 2, 3, 4], [5, 6, 7, 8], [5, 6, 7, 8]]
+                .repeat(2)
+                .concat()
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Variance-driven adaptive sampling: once the image has a few samples, passes only trace the
+//! tiles whose noise estimate (see `variance`) is above a threshold. The tiles are picked on the
+//! GPU and traced with an indirect dispatch, so nothing is read back.
+use crate::gpu::Gpu;
+use crate::render::Renderer;
+use anyhow::Result;
+use encase::ShaderType;
+use std::ops::Range;
+
+/// Edge length of a tile in pixels. Same as in `compute.wgsl` and `tiles.wgsl`.
+pub const TILE_SIZE: u32 = 16;
+
+#[derive(ShaderType)]
+struct AdaptiveUniform {
+    threshold: f32,
+}
+
+pub struct TileScheduler {
+    pipeline: wgpu::ComputePipeline,
+    bgl: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    /// Indirect dispatch arguments followed by the picked tiles, see `TileQueue` in `tiles.wgsl`.
+    queue_buf: wgpu::Buffer,
+    adaptive_buf: wgpu::Buffer,
+    tiles: (u32, u32),
+    threshold: Option<f32>,
+}
+
+impl TileScheduler {
+    /// Full passes traced before the variance estimate is trusted to skip tiles.
+    pub const WARMUP_PASSES: u32 = 8;
+    const WORKGROUP_SIZE: u32 = 8;
+
+    pub fn new(gpu: &Gpu, renderer: &Renderer) -> Result<Self> {
+        let Gpu { device, .. } = gpu;
+
+        gpu.push_error_scope();
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("tiles.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("tiles.wgsl").into()),
+        });
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bgl = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: Some(
+                &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: None,
+                    bind_group_layouts: &[&bgl],
+                    push_constant_ranges: &[],
+                }),
+            ),
+            module: &shader,
+            entry_point: "select_tiles",
+        });
+        gpu.pop_error_scope("the tile selection pipeline")?;
+
+        let adaptive_buf = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: AdaptiveUniform::min_size().get(),
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let tiles = tile_count(renderer);
+        let queue_buf = create_queue_buffer(device, tiles);
+        let bind_group = create_tiles_bg(device, &bgl, renderer, &queue_buf, &adaptive_buf);
+
+        Ok(Self {
+            pipeline,
+            bgl,
+            bind_group,
+            queue_buf,
+            adaptive_buf,
+            tiles,
+            threshold: None,
+        })
+    }
+
+    pub fn on_resize(&mut self, gpu: &Gpu, renderer: &Renderer) {
+        let Gpu { device, .. } = gpu;
+
+        self.tiles = tile_count(renderer);
+        self.queue_buf = create_queue_buffer(device, self.tiles);
+        self.bind_group = create_tiles_bg(
+            device,
+            &self.bgl,
+            renderer,
+            &self.queue_buf,
+            &self.adaptive_buf,
+        );
+    }
+
+    /// The queue bound to the tracer, recreated on resize.
+    pub fn queue_buffer(&self) -> &wgpu::Buffer {
+        &self.queue_buf
+    }
+
+    pub fn threshold(&self) -> Option<f32> {
+        self.threshold
+    }
+
+    /// Enables adaptive sampling down to a mean relative error of `threshold` per tile, or
+    /// disables it with `None`.
+    pub fn set_threshold(&mut self, gpu: &Gpu, threshold: Option<f32>) -> Result<()> {
+        if let Some(threshold) = threshold {
+            gpu.write_uniform(&self.adaptive_buf, &AdaptiveUniform { threshold })?;
+        }
+        self.threshold = threshold;
+        Ok(())
+    }
+
+    /// Queues the tiles covering the `width` x `height` region at `x`, `y`, for tracing only
+    /// part of the image like `select` does.
+    pub fn queue_region(&self, gpu: &Gpu, region: [u32; 4]) {
+        let (columns, rows) = tiles_covering(region, self.tiles);
+        let tiles: Vec<u32> = rows
+            .flat_map(|tile_y| columns.clone().map(move |tile_x| tile_y << 16 | tile_x))
+            .collect();
+
+        let header = [TILE_SIZE, TILE_SIZE, tiles.len() as u32];
+        let bytes: Vec<u8> = header
+            .iter()
+            .chain(&tiles)
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        gpu.write_buffer(&self.queue_buf, 0, &bytes);
+    }
+
+    /// Tiles a pass queues, those covering `region` or all of them.
+    pub fn count(&self, region: Option<[u32; 4]>) -> u32 {
+        match region {
+            Some(region) => {
+                let (columns, rows) = tiles_covering(region, self.tiles);
+                columns.len() as u32 * rows.len() as u32
+            }
+            None => self.tiles.0 * self.tiles.1,
+        }
+    }
+
+    /// Records picking the noisy tiles of the accumulated image into the queue, to be traced
+    /// with `dispatch_workgroups_indirect` on `queue_buffer`.
+    pub fn select(&self, gpu: &Gpu, encoder: &mut wgpu::CommandEncoder) {
+        // Every tile is traced by a TILE_SIZE x TILE_SIZE grid of single invocations.
+        let header = [TILE_SIZE, TILE_SIZE, 0];
+        let bytes: Vec<u8> = header.iter().flat_map(|word| word.to_le_bytes()).collect();
+        gpu.write_buffer(&self.queue_buf, 0, &bytes);
+
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        cpass.set_pipeline(&self.pipeline);
+        cpass.set_bind_group(0, &self.bind_group, &[]);
+        cpass.dispatch_workgroups(
+            self.tiles.0.div_ceil(Self::WORKGROUP_SIZE),
+            self.tiles.1.div_ceil(Self::WORKGROUP_SIZE),
+            1,
+        );
+    }
+}
+
+fn tile_count(renderer: &Renderer) -> (u32, u32) {
+    let size = renderer.scene_texture().size();
+    (
+        size.width.div_ceil(TILE_SIZE),
+        size.height.div_ceil(TILE_SIZE),
+    )
+}
+
+/// Columns and rows of the tiles covering the `width` x `height` region at `x`, `y`, among
+/// `tiles_x` x `tiles_y`.
+fn tiles_covering(
+    [x, y, width, height]: [u32; 4],
+    (tiles_x, tiles_y): (u32, u32),
+) -> (Range<u32>, Range<u32>) {
+    (
+        x / TILE_SIZE..(x + width).div_ceil(TILE_SIZE).min(tiles_x),
+        y / TILE_SIZE..(y + height).div_ceil(TILE_SIZE).min(tiles_y),
+    )
+}
+
+fn create_queue_buffer(device: &wgpu::Device, (tiles_x, tiles_y): (u32, u32)) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("tile queue"),
+        size: 4 * (3 + tiles_x as u64 * tiles_y as u64),
+        mapped_at_creation: false,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::INDIRECT
+            | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+fn create_tiles_bg(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    renderer: &Renderer,
+    queue_buf: &wgpu::Buffer,
+    adaptive_buf: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &renderer
+                        .scene_texture()
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &renderer
+                        .scene_moments_texture()
+                        .create_view(&wgpu::TextureViewDescriptor::default()),
+                ),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: queue_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: adaptive_buf.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader_layout::assert_layout;
+
+    #[test]
+    fn uniform_matches_wgsl_layout() {
+        assert_layout::<AdaptiveUniform>(include_str!("tiles.wgsl"), "AdaptiveUniform");
+    }
+
+    #[test]
+    fn regions_cover_the_tiles_they_touch() {
+        assert_eq!(tiles_covering([16, 0, 40, 16], (4, 2)), (1..4, 0..1));
+        assert_eq!(tiles_covering([60, 20, 100, 100], (4, 2)), (3..4, 1..2));
+    }
+}
@@ -0,0 +1,69 @@
+// Picks the tiles of the accumulated image that are still noisy, see `tiles.rs`.
+
+struct AdaptiveUniform {
+    // Mean relative error per pixel below which a tile needs no more samples.
+    threshold: f32,
+};
+
+// Arguments of the indirect dispatch tracing the picked tiles, followed by the tiles packed as
+// y << 16 | x. The tracer reads it in `raytrace_tiles`.
+struct TileQueue {
+    workgroups_x: u32,
+    workgroups_y: u32,
+    count: atomic<u32>,
+    tiles: array<u32>,
+};
+
+@group(0) @binding(0) var image: texture_2d<f32>;
+@group(0) @binding(1) var moments: texture_2d<f32>;
+@group(0) @binding(2) var<storage, read_write> queue: TileQueue;
+@group(0) @binding(3) var<uniform> adaptive: AdaptiveUniform;
+
+// Same as in `compute.wgsl`.
+const TILE_SIZE: u32 = 16u;
+// Same as in `variance.rs`.
+const MIN_SAMPLES: f32 = 4.0;
+const LUMINANCE_EPSILON: f32 = 0.01;
+
+// Relative standard error of the pixel's mean luminance, see `variance::relative_error`.
+// Negative below `MIN_SAMPLES`.
+fn relativeError(color: vec4<f32>, moments: vec4<f32>) -> f32 {
+    let samples = color.a;
+    if samples < MIN_SAMPLES {
+        return -1.0;
+    }
+    let mean = dot(color.rgb, vec3<f32>(0.2126, 0.7152, 0.0722)) / samples;
+    let variance = max(moments.x / samples - mean * mean, 0.0) * samples / (samples - 1.0);
+    return sqrt(variance / samples) / (mean + LUMINANCE_EPSILON);
+}
+
+@compute
+@workgroup_size(8, 8)
+fn select_tiles(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(image);
+    let tiles = (size + TILE_SIZE - 1u) / TILE_SIZE;
+    if id.x >= tiles.x || id.y >= tiles.y {
+        return;
+    }
+
+    let origin = id.xy * TILE_SIZE;
+    let end = min(origin + TILE_SIZE, size);
+    var sum = 0.0;
+    var pixels = 0.0;
+    var unknown = false;
+    for (var y = origin.y; y < end.y; y++) {
+        for (var x = origin.x; x < end.x; x++) {
+            let error = relativeError(textureLoad(image, vec2<u32>(x, y), 0), textureLoad(moments, vec2<u32>(x, y), 0));
+            if error < 0.0 {
+                unknown = true;
+            }
+            sum += max(error, 0.0);
+            pixels += 1.0;
+        }
+    }
+
+    if unknown || sum / pixels > adaptive.threshold {
+        let index = atomicAdd(&queue.count, 1u);
+        queue.tiles[index] = (id.y << 16u) | id.x;
+    }
+}
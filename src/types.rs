@@ -2,3 +2,6 @@ use nalgebra as na;
 
 pub type Vec3 = na::Vector3<f32>;
 pub type Vec3U = na::Vector3<u32>;
+pub type Vec4 = na::Vector4<f32>;
+pub type Vec4U = na::Vector4<u32>;
+pub type Quat = na::UnitQuaternion<f32>;
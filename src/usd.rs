@@ -0,0 +1,1306 @@
+//! Import of USD stages from text `.usda` layers and `.usdz` packages holding one, so
+//! assets from DCC pipelines can be traced.
+//!
+//! Supported are `Xform` transform ops, `Sphere`, `Cube` and polygon `Mesh` prims,
+//! `UsdPreviewSurface` materials bound with `material:binding` (`displayColor` otherwise),
+//! the first `Camera`, and sphere, rect, disk, distant and dome lights. References,
+//! payloads, variants and textures are skipped with a warning, animated attributes use
+//! their first time sample. Binary `.usdc` layers can't be read, `usdcat -o scene.usda
+//! scene.usdc` converts them.
+//!
+//! The image size comes from the window or `--size` and the perspective field of view is
+//! fixed at 90°. Z-up stages are rotated to be Y-up.
+use crate::camera::{Lens, Projection};
+use crate::scene::{Light, Material, Rotation, Scene, Sky, Sphere, TriangleMesh};
+use crate::scene_file::{CameraSettings, SceneFile};
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use nalgebra as na;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+type Mat4 = na::Matrix4<f32>;
+
+pub fn load(path: &Path) -> Result<SceneFile> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Failed to read scene `{}`", path.display()))?;
+    let is_package = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("usdz"));
+
+    let (scene_file, warnings) = (|| {
+        let layer = if is_package {
+            package_layer(&bytes)?
+        } else {
+            &bytes[..]
+        };
+        import(layer)
+    })()
+    .with_context(|| format!("Invalid USD scene `{}`", path.display()))?;
+
+    for warning in &warnings {
+        eprintln!("Warning: {}: {warning}", path.display());
+    }
+    Ok(scene_file)
+}
+
+fn import(layer: &[u8]) -> Result<(SceneFile, BTreeSet<String>)> {
+    if layer.starts_with(b"PXR-USDC") {
+        return Err(anyhow!(
+            "Binary USD layers are not supported, convert them with `usdcat -o scene.usda scene.usdc`"
+        ));
+    }
+    let source = std::str::from_utf8(layer).context("Expected a text layer")?;
+    if !source.starts_with("#usda") {
+        return Err(anyhow!("Expected a `#usda` header"));
+    }
+
+    let layer = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    }
+    .layer()?;
+    let mut importer = Importer::new(&layer);
+    importer.import(&layer)?;
+    let warnings = std::mem::take(&mut importer.warnings);
+    Ok((importer.finish(), warnings))
+}
+
+/// The root layer of a `.usdz` package, a zip archive whose files are stored uncompressed.
+fn package_layer(bytes: &[u8]) -> Result<&[u8]> {
+    let u16_at = |at: usize| -> Result<usize> {
+        let b = bytes
+            .get(at..at + 2)
+            .ok_or_else(|| anyhow!("Truncated package"))?;
+        Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+    };
+    let u32_at = |at: usize| -> Result<usize> {
+        let b = bytes
+            .get(at..at + 4)
+            .ok_or_else(|| anyhow!("Truncated package"))?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .find(|&at| bytes[at..].starts_with(b"PK\x05\x06"))
+        .ok_or_else(|| anyhow!("Not a zip archive"))?;
+
+    let mut entry = u32_at(end + 16)?;
+    for _ in 0..u16_at(end + 10)? {
+        if u32_at(entry)? != 0x0201_4b50 {
+            return Err(anyhow!("Invalid central directory"));
+        }
+        let name_len = u16_at(entry + 28)?;
+        let name = bytes
+            .get(entry + 46..entry + 46 + name_len)
+            .ok_or_else(|| anyhow!("Truncated package"))?;
+        let name = String::from_utf8_lossy(name);
+
+        // The first layer in the archive is the root one.
+        if [".usda", ".usdc", ".usd"]
+            .iter()
+            .any(|extension| name.to_ascii_lowercase().ends_with(extension))
+        {
+            if u16_at(entry + 10)? != 0 {
+                return Err(anyhow!(
+                    "`{name}` is compressed, packages store files uncompressed"
+                ));
+            }
+            let size = u32_at(entry + 20)?;
+            let local = u32_at(entry + 42)?;
+            if u32_at(local)? != 0x0403_4b50 {
+                return Err(anyhow!("Invalid local header of `{name}`"));
+            }
+            let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+            return bytes
+                .get(start..start + size)
+                .ok_or_else(|| anyhow!("Truncated package"));
+        }
+
+        entry += 46 + name_len + u16_at(entry + 30)? + u16_at(entry + 32)?;
+    }
+
+    Err(anyhow!("The package holds no USD layer"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    /// Strings, tokens and `@asset@` paths.
+    Str(String),
+    /// A `<path>` to a prim or property.
+    Path(String),
+    Num(f64),
+    Punct(char),
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = vec![];
+    let mut rest = source;
+    let mut line = 1;
+
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '\n' => {
+                line += 1;
+                1
+            }
+            c if c.is_whitespace() => c.len_utf8(),
+            '#' => rest.find('\n').unwrap_or(rest.len()),
+            '(' | ')' | '[' | ']' | '{' | '}' | '=' | ',' | ':' | ';' => {
+                tokens.push((Token::Punct(c), line));
+                1
+            }
+            '"' | '\'' => {
+                // Triple quotes allow strings over several lines.
+                let quote = if rest[1..].starts_with(c) && rest[2..].starts_with(c) {
+                    &rest[..3]
+                } else {
+                    &rest[..1]
+                };
+                let body = &rest[quote.len()..];
+                let mut escaped = false;
+                let end = body
+                    .char_indices()
+                    .find(|&(i, c)| {
+                        let end = !escaped && body[i..].starts_with(quote);
+                        escaped = !escaped && c == '\\';
+                        end
+                    })
+                    .map(|(i, _)| i)
+                    .ok_or_else(|| anyhow!("Unterminated string on line {line}"))?;
+
+                let mut text = String::new();
+                let mut chars = body[..end].chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => text.extend(chars.next().map(|c| if c == 'n' { '\n' } else { c })),
+                        c => text.push(c),
+                    }
+                }
+                tokens.push((Token::Str(text), line));
+                line += body[..end].matches('\n').count();
+                end + 2 * quote.len()
+            }
+            '@' | '<' => {
+                let close = if c == '@' { '@' } else { '>' };
+                let end = rest[1..]
+                    .find(close)
+                    .ok_or_else(|| anyhow!("Unterminated `{c}` on line {line}"))?;
+                let text = rest[1..end + 1].to_owned();
+                tokens.push((
+                    if c == '@' {
+                        Token::Str(text)
+                    } else {
+                        Token::Path(text)
+                    },
+                    line,
+                ));
+                end + 2
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && !matches!(c, '_' | ':' | '.'))
+                    .unwrap_or(rest.len());
+                tokens.push((Token::Ident(rest[..len].to_owned()), line));
+                len
+            }
+            _ => {
+                let len = rest
+                    .find(|c: char| {
+                        !c.is_ascii_digit() && !matches!(c, '.' | '-' | '+' | 'e' | 'E')
+                    })
+                    .unwrap_or(rest.len());
+                let word = &rest[..len.max(c.len_utf8())];
+                let number = word
+                    .parse()
+                    .map_err(|_| anyhow!("Unexpected `{word}` on line {line}"))?;
+                tokens.push((Token::Num(number), line));
+                len
+            }
+        };
+        rest = &rest[len..];
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Num(f64),
+    Str(String),
+    Path(String),
+    /// `true`, `false` and `None`.
+    Ident(String),
+    /// Tuples, arrays and time samples.
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            Value::Num(n) => Some(*n as f32),
+            Value::Ident(ident) if ident == "true" || ident == "false" => {
+                Some(if ident == "true" { 1.0 } else { 0.0 })
+            }
+            _ => None,
+        }
+    }
+
+    /// All numbers in the value, flattening nested tuples.
+    fn numbers(&self) -> Result<Vec<f32>> {
+        match self {
+            Value::List(items) => Ok(items
+                .iter()
+                .map(Value::numbers)
+                .collect::<Result<Vec<_>>>()?
+                .concat()),
+            value => Ok(vec![value
+                .as_f32()
+                .ok_or_else(|| anyhow!("Expected a number, got {value:?}"))?]),
+        }
+    }
+
+    fn as_vec3(&self) -> Result<Vec3> {
+        match *self.numbers()? {
+            [x, y, z] => Ok(Vec3::new(x, y, z)),
+            _ => Err(anyhow!("Expected 3 numbers, got {self:?}")),
+        }
+    }
+
+    fn as_vec3s(&self) -> Result<Vec<Vec3>> {
+        let numbers = self.numbers()?;
+        if numbers.len() % 3 != 0 {
+            return Err(anyhow!("Expected triples of numbers"));
+        }
+        Ok(numbers
+            .chunks_exact(3)
+            .map(|p| Vec3::new(p[0], p[1], p[2]))
+            .collect())
+    }
+
+    /// The target of a relationship or connection, also when given as a list.
+    fn as_path(&self) -> Option<&str> {
+        match self {
+            Value::Path(path) => Some(path),
+            Value::List(items) => items.first()?.as_path(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Property {
+    /// Including suffixes like `.connect` or `.timeSamples`.
+    name: String,
+    value: Option<Value>,
+    /// How primvars are spread over the geometry.
+    interpolation: Option<String>,
+}
+
+#[derive(Debug)]
+struct Prim {
+    /// `def`, `over` or `class`.
+    specifier: String,
+    /// The schema, empty for typeless prims.
+    kind: String,
+    name: String,
+    line: usize,
+    metadata: Vec<(String, Value)>,
+    properties: Vec<Property>,
+    children: Vec<Prim>,
+    /// Whether the prim has variant sets, whose opinions aren't applied.
+    variants: bool,
+}
+
+impl Prim {
+    fn property(&self, name: &str) -> Option<&Property> {
+        self.properties
+            .iter()
+            .find(|property| property.name == name)
+    }
+
+    /// The value of an attribute, its first time sample when it's animated.
+    fn value(&self, name: &str) -> Option<&Value> {
+        match self
+            .property(name)
+            .and_then(|property| property.value.as_ref())
+        {
+            Some(value) => Some(value),
+            None => match self
+                .property(&format!("{name}.timeSamples"))?
+                .value
+                .as_ref()?
+            {
+                Value::List(samples) => samples.first(),
+                _ => None,
+            },
+        }
+    }
+
+    fn float(&self, name: &str, default: f32) -> f32 {
+        self.value(name).and_then(Value::as_f32).unwrap_or(default)
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.value(name)? {
+            Value::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// A light input, which older stages author without the `inputs:` namespace.
+    fn input(&self, name: &str) -> Option<&Value> {
+        self.value(&format!("inputs:{name}"))
+            .or_else(|| self.value(name))
+    }
+
+    fn input_float(&self, name: &str, default: f32) -> f32 {
+        self.input(name).and_then(Value::as_f32).unwrap_or(default)
+    }
+}
+
+struct Layer {
+    metadata: Vec<(String, Value)>,
+    prims: Vec<Prim>,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos.min(self.tokens.len().saturating_sub(1)))
+            .map_or(1, |&(_, line)| line)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let (token, _) = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of the layer"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        let found = self.peek() == Some(&Token::Punct(punct));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, punct: char) -> Result<()> {
+        let line = self.line();
+        match self.next()? {
+            Token::Punct(c) if c == punct => Ok(()),
+            other => Err(anyhow!("Expected `{punct}` on line {line}, got {other:?}")),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        let line = self.line();
+        match self.next()? {
+            Token::Ident(ident) => Ok(ident),
+            other => Err(anyhow!("Expected a name on line {line}, got {other:?}")),
+        }
+    }
+
+    fn layer(&mut self) -> Result<Layer> {
+        let metadata = self.metadata()?;
+        let mut prims = vec![];
+        while self.peek().is_some() {
+            let specifier = self.ident()?;
+            prims.push(self.prim(specifier)?);
+        }
+        Ok(Layer { metadata, prims })
+    }
+
+    fn prim(&mut self, specifier: String) -> Result<Prim> {
+        let line = self.line();
+        if !matches!(specifier.as_str(), "def" | "over" | "class") {
+            return Err(anyhow!("Expected a prim on line {line}, got `{specifier}`"));
+        }
+        let kind = match self.peek() {
+            Some(Token::Ident(_)) => self.ident()?,
+            _ => String::new(),
+        };
+        let name = match self.next()? {
+            Token::Str(name) => name,
+            other => {
+                return Err(anyhow!(
+                    "Expected the prim's name on line {line}, got {other:?}"
+                ))
+            }
+        };
+
+        let mut prim = Prim {
+            specifier,
+            kind,
+            name,
+            line,
+            metadata: self.metadata()?,
+            properties: vec![],
+            children: vec![],
+            variants: false,
+        };
+
+        self.expect('{')?;
+        while !self.eat('}') {
+            if self.eat(';') {
+                continue;
+            }
+            let mut word = self.ident()?;
+            match word.as_str() {
+                "def" | "over" | "class" => prim.children.push(self.prim(word)?),
+                "variantSet" => {
+                    self.next()?;
+                    self.expect('=')?;
+                    self.value()?;
+                    prim.variants = true;
+                }
+                "reorder" => {
+                    self.ident()?;
+                    self.expect('=')?;
+                    self.value()?;
+                }
+                _ => {
+                    while matches!(
+                        word.as_str(),
+                        "custom"
+                            | "uniform"
+                            | "varying"
+                            | "config"
+                            | "prepend"
+                            | "append"
+                            | "add"
+                            | "delete"
+                    ) {
+                        word = self.ident()?;
+                    }
+                    // Attributes have a type, relationships `rel`.
+                    if word != "rel" && self.eat('[') {
+                        self.expect(']')?;
+                    }
+                    let name = self.ident()?;
+                    let value = if self.eat('=') {
+                        Some(self.value()?)
+                    } else {
+                        None
+                    };
+                    let interpolation = self
+                        .metadata()?
+                        .into_iter()
+                        .find(|(key, _)| key == "interpolation")
+                        .and_then(|(_, value)| match value {
+                            Value::Str(interpolation) => Some(interpolation),
+                            _ => None,
+                        });
+                    prim.properties.push(Property {
+                        name,
+                        value,
+                        interpolation,
+                    });
+                }
+            }
+        }
+
+        Ok(prim)
+    }
+
+    /// `( key = value ... )` after a layer header, prim or property, if there is one.
+    fn metadata(&mut self) -> Result<Vec<(String, Value)>> {
+        let mut entries = vec![];
+        if !self.eat('(') {
+            return Ok(entries);
+        }
+
+        while !self.eat(')') {
+            let line = self.line();
+            match self.next()? {
+                // A leading string is documentation.
+                Token::Str(_) | Token::Punct(';') => {}
+                Token::Ident(mut key) => {
+                    if matches!(
+                        key.as_str(),
+                        "prepend" | "append" | "add" | "delete" | "reorder"
+                    ) {
+                        key = self.ident()?;
+                    }
+                    let value = if self.eat('=') {
+                        self.value()?
+                    } else {
+                        Value::Ident("None".to_owned())
+                    };
+                    entries.push((key, value));
+                }
+                other => return Err(anyhow!("Unexpected {other:?} in metadata on line {line}")),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let line = self.line();
+        Ok(match self.next()? {
+            Token::Num(n) => Value::Num(n),
+            Token::Str(s) => Value::Str(s),
+            Token::Path(path) => Value::Path(path),
+            Token::Ident(ident) => Value::Ident(ident),
+            Token::Punct(open @ ('(' | '[')) => {
+                let close = if open == '(' { ')' } else { ']' };
+                let mut items = vec![];
+                while !self.eat(close) {
+                    items.push(self.value()?);
+                    if !self.eat(',') {
+                        self.expect(close)?;
+                        break;
+                    }
+                }
+                Value::List(items)
+            }
+            // Time samples keep their values in order, other dictionaries are skipped.
+            Token::Punct('{') if matches!(self.peek(), Some(Token::Num(_))) => {
+                let mut samples = vec![];
+                while !self.eat('}') {
+                    self.next()?;
+                    self.expect(':')?;
+                    samples.push(self.value()?);
+                    self.eat(',');
+                }
+                Value::List(samples)
+            }
+            Token::Punct('{') => {
+                let mut depth = 1;
+                while depth > 0 {
+                    match self.next()? {
+                        Token::Punct('{') => depth += 1,
+                        Token::Punct('}') => depth -= 1,
+                        _ => {}
+                    }
+                }
+                Value::List(vec![])
+            }
+            other => return Err(anyhow!("Expected a value on line {line}, got {other:?}")),
+        })
+    }
+}
+
+struct Importer<'a> {
+    scene: Scene,
+    camera: CameraSettings,
+    has_camera: bool,
+    sky: Sky,
+    /// Prims by path, for material bindings and shader connections.
+    prims: HashMap<String, &'a Prim>,
+    /// Bound materials by path, `None` when they aren't supported.
+    materials: HashMap<String, Option<Material>>,
+    warnings: BTreeSet<String>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(layer: &'a Layer) -> Self {
+        fn index<'a>(prims: &'a [Prim], parent: &str, by_path: &mut HashMap<String, &'a Prim>) {
+            for prim in prims {
+                let path = format!("{parent}/{}", prim.name);
+                index(&prim.children, &path, by_path);
+                by_path.insert(path, prim);
+            }
+        }
+        let mut prims = HashMap::new();
+        index(&layer.prims, "", &mut prims);
+
+        Importer {
+            scene: Scene::default(),
+            camera: CameraSettings::default(),
+            has_camera: false,
+            // Without a dome light, rays leaving the scene see black.
+            sky: Sky::new(Sky::default().sun_direction, Sky::MIN_TURBIDITY, 0.0),
+            prims,
+            materials: HashMap::new(),
+            warnings: BTreeSet::new(),
+        }
+    }
+
+    fn finish(self) -> SceneFile {
+        let mut scene = self.scene;
+        scene.set_sky(self.sky);
+
+        SceneFile {
+            scene,
+            camera: self.camera,
+            max_bounces: SceneFile::DEFAULT_MAX_BOUNCES,
+        }
+    }
+
+    fn warn(&mut self, warning: impl Into<String>) {
+        self.warnings.insert(warning.into());
+    }
+
+    fn import(&mut self, layer: &'a Layer) -> Result<()> {
+        let mut root = Mat4::identity();
+        for (key, value) in &layer.metadata {
+            match (key.as_str(), value) {
+                ("upAxis", Value::Str(axis)) if axis == "Z" => {
+                    root = na::Rotation3::from_axis_angle(
+                        &Vec3::x_axis(),
+                        -std::f32::consts::FRAC_PI_2,
+                    )
+                    .to_homogeneous();
+                }
+                ("subLayers", _) => self.warn("Sublayers are not composed"),
+                _ => {}
+            }
+        }
+
+        for prim in &layer.prims {
+            self.prim(prim, "", &root, None)?;
+        }
+        Ok(())
+    }
+
+    fn prim(
+        &mut self,
+        prim: &'a Prim,
+        parent_path: &str,
+        parent: &Mat4,
+        binding: Option<&'a str>,
+    ) -> Result<()> {
+        let path = format!("{parent_path}/{}", prim.name);
+        // Classes only hold opinions for prims inheriting them.
+        if prim.specifier == "class" || prim.string("visibility") == Some("invisible") {
+            return Ok(());
+        }
+
+        if prim.metadata.iter().any(|(key, _)| {
+            matches!(
+                key.as_str(),
+                "references" | "payload" | "inherits" | "specializes"
+            )
+        }) {
+            self.warn("References, payloads, inherits and specializes are not composed");
+        }
+        if prim.variants {
+            self.warn("Variant sets are not applied");
+        }
+        if prim
+            .properties
+            .iter()
+            .any(|property| property.name.ends_with(".timeSamples"))
+        {
+            self.warn("Animated attributes use their first time sample");
+        }
+
+        let binding = prim
+            .value("material:binding")
+            .and_then(Value::as_path)
+            .or(binding);
+        let matrix = self
+            .transform(prim, parent)
+            .and_then(|matrix| {
+                self.shape(prim, &matrix, binding)?;
+                Ok(matrix)
+            })
+            .with_context(|| format!("Invalid prim `{path}` on line {}", prim.line))?;
+
+        // Shading networks have no geometry below them.
+        if !matches!(prim.kind.as_str(), "Material" | "Shader" | "NodeGraph") {
+            for child in &prim.children {
+                self.prim(child, &path, &matrix, binding)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The prim's local transform from its `xformOpOrder` applied to `parent`.
+    fn transform(&self, prim: &Prim, parent: &Mat4) -> Result<Mat4> {
+        let Some(Value::List(order)) = prim.value("xformOpOrder") else {
+            return Ok(*parent);
+        };
+
+        let mut matrix = *parent;
+        for op in order {
+            let Value::Str(op) = op else {
+                return Err(anyhow!("Expected transform op names in `xformOpOrder`"));
+            };
+            if op == "!resetXformStack!" {
+                matrix = Mat4::identity();
+                continue;
+            }
+
+            let (invert, name) = match op.strip_prefix("!invert!") {
+                Some(name) => (true, name),
+                None => (false, op.as_str()),
+            };
+            let value = prim
+                .value(name)
+                .ok_or_else(|| anyhow!("Missing transform op `{name}`"))?;
+            // Ops may have a suffix, like `xformOp:translate:pivot`.
+            let kind = name
+                .strip_prefix("xformOp:")
+                .and_then(|kind| kind.split(':').next())
+                .ok_or_else(|| anyhow!("Invalid transform op `{name}`"))?;
+
+            let op_matrix = match kind {
+                "translate" => Mat4::new_translation(&value.as_vec3()?),
+                "scale" => Mat4::new_nonuniform_scaling(&value.as_vec3()?),
+                "rotateX" | "rotateY" | "rotateZ" => {
+                    let angle = value
+                        .as_f32()
+                        .ok_or_else(|| anyhow!("Expected an angle in `{name}`"))?;
+                    axis_rotation(kind.chars().last().unwrap(), angle)
+                }
+                // The first axis rotates first.
+                _ if kind.len() == 9 && kind.starts_with("rotate") => {
+                    let angles = value.as_vec3()?;
+                    kind[6..].chars().fold(Mat4::identity(), |rotation, axis| {
+                        let idx = (axis as u8 - b'X') as usize;
+                        axis_rotation(axis, angles[idx]) * rotation
+                    })
+                }
+                "orient" => match *value.numbers()? {
+                    [w, x, y, z] => {
+                        na::UnitQuaternion::from_quaternion(na::Quaternion::new(w, x, y, z))
+                            .to_homogeneous()
+                    }
+                    _ => return Err(anyhow!("Expected a quaternion in `{name}`")),
+                },
+                // USD multiplies row vectors, so its matrices are transposed.
+                "transform" => match value.numbers()? {
+                    values if values.len() == 16 => Mat4::from_column_slice(&values),
+                    _ => return Err(anyhow!("Expected a 4x4 matrix in `{name}`")),
+                },
+                other => return Err(anyhow!("Unknown transform op `{other}`")),
+            };
+            let op_matrix = if invert {
+                op_matrix
+                    .try_inverse()
+                    .ok_or_else(|| anyhow!("`{name}` can't be inverted"))?
+            } else {
+                op_matrix
+            };
+            matrix *= op_matrix;
+        }
+
+        Ok(matrix)
+    }
+
+    fn shape(&mut self, prim: &Prim, matrix: &Mat4, binding: Option<&str>) -> Result<()> {
+        let scale = matrix.fixed_view::<3, 3>(0, 0).determinant().abs().cbrt();
+
+        match prim.kind.as_str() {
+            "" | "Xform" | "Scope" | "SkelRoot" | "Material" | "Shader" | "NodeGraph" => {}
+            "Sphere" => {
+                let material = self.material(prim, binding);
+                let center = transform_point(matrix, Vec3::zeros());
+                let radius = prim.float("radius", 1.0) * scale;
+                self.scene.new_sphere(Sphere::new(center, radius), material);
+            }
+            "Cube" => {
+                let material = self.material(prim, binding);
+                let size = prim.float("size", 2.0);
+                let cube = TriangleMesh::cuboid(Vec3::zeros(), Vec3::new(size, size, size));
+                self.mesh(cube.positions, None, cube.indices, matrix, material)?;
+            }
+            "Mesh" => {
+                let material = self.material(prim, binding);
+                self.polygon_mesh(prim, matrix, material)?;
+            }
+            "Camera" => {
+                if self.has_camera {
+                    self.warn("Only the first camera is used");
+                } else {
+                    self.has_camera = true;
+                    self.camera(prim, matrix);
+                }
+            }
+            "SphereLight" | "RectLight" | "DiskLight" | "DistantLight" | "DomeLight" => {
+                self.light(prim, matrix)
+            }
+            "GeomSubset" => self.warn("Per-face materials of geometry subsets are not supported"),
+            other => self.warn(format!("Prims of type `{other}` are not supported")),
+        }
+        Ok(())
+    }
+
+    /// The bound `UsdPreviewSurface` material, or a diffuse one of the `displayColor`.
+    fn material(&mut self, prim: &Prim, binding: Option<&str>) -> Material {
+        if let Some(material) = binding.and_then(|path| self.bound_material(path)) {
+            return material;
+        }
+
+        let color = match prim.value("primvars:displayColor") {
+            Some(Value::List(colors)) if colors.len() == 1 => colors[0].as_vec3().ok(),
+            Some(colors) => colors.as_vec3().ok(),
+            None => None,
+        };
+        Material::new_lambertian(color.unwrap_or(Vec3::new(0.18, 0.18, 0.18)))
+    }
+
+    fn bound_material(&mut self, path: &str) -> Option<Material> {
+        if let Some(material) = self.materials.get(path) {
+            return *material;
+        }
+        let material = self.preview_surface(path);
+        self.materials.insert(path.to_owned(), material);
+        material
+    }
+
+    fn preview_surface(&mut self, path: &str) -> Option<Material> {
+        let Some(&material) = self.prims.get(path) else {
+            self.warn(format!("Unknown material `{path}`"));
+            return None;
+        };
+        let shader = match material
+            .value("outputs:surface.connect")
+            .and_then(Value::as_path)
+        {
+            // The target is the shader's output, `/Looks/Red/Shader.outputs:surface`.
+            Some(target) => self
+                .prims
+                .get(target.split('.').next().unwrap_or(target))
+                .copied(),
+            None => material
+                .children
+                .iter()
+                .find(|child| child.kind == "Shader"),
+        };
+        let Some(shader) = shader else {
+            self.warn(format!("Material `{path}` has no surface shader"));
+            return None;
+        };
+        match shader.string("info:id") {
+            Some("UsdPreviewSurface") => {}
+            id => {
+                let id = id.unwrap_or("unknown");
+                self.warn(format!(
+                    "Shaders `{id}` are not supported, using displayColor"
+                ));
+                return None;
+            }
+        }
+
+        if shader.properties.iter().any(|property| {
+            property.name.starts_with("inputs:") && property.name.ends_with(".connect")
+        }) {
+            self.warn("Textures are not supported, materials keep their constant inputs");
+        }
+        if shader
+            .value("inputs:emissiveColor")
+            .and_then(|color| color.as_vec3().ok())
+            .is_some_and(|color| color.max() > 0.0)
+        {
+            self.warn("Emissive materials don't emit light, use UsdLux lights");
+        }
+        if shader.float("inputs:useSpecularWorkflow", 0.0) != 0.0 {
+            self.warn("The specular workflow is approximated by the metallic one");
+        }
+
+        let ior = shader.float("inputs:ior", 1.5);
+        if shader.float("inputs:opacity", 1.0) < 0.5 {
+            return Some(Material::new_dielectric(ior, None));
+        }
+        let albedo = shader
+            .value("inputs:diffuseColor")
+            .and_then(|color| color.as_vec3().ok())
+            .unwrap_or(Vec3::new(0.18, 0.18, 0.18));
+        let material = Material::new_pbr(
+            albedo,
+            shader.float("inputs:metallic", 0.0),
+            shader.float("inputs:roughness", 0.5),
+        );
+        let clearcoat = shader.float("inputs:clearcoat", 0.0);
+        Some(if clearcoat > 0.0 {
+            material.clearcoat(
+                clearcoat,
+                shader.float("inputs:clearcoatRoughness", 0.01),
+                ior,
+            )
+        } else {
+            material
+        })
+    }
+
+    fn polygon_mesh(&mut self, prim: &Prim, matrix: &Mat4, material: Material) -> Result<()> {
+        let indices = |name| -> Result<Vec<usize>> {
+            let values = prim
+                .value(name)
+                .ok_or_else(|| anyhow!("Missing `{name}`"))?
+                .numbers()?;
+            Ok(values.into_iter().map(|i| i as usize).collect())
+        };
+        let positions = prim
+            .value("points")
+            .ok_or_else(|| anyhow!("Missing `points`"))?
+            .as_vec3s()?;
+        let counts = indices("faceVertexCounts")?;
+        let corners = indices("faceVertexIndices")?;
+        if counts.iter().sum::<usize>() != corners.len() {
+            return Err(anyhow!(
+                "`faceVertexCounts` don't add up to the face vertices"
+            ));
+        }
+        if let Some(corner) = corners.iter().find(|&&corner| corner >= positions.len()) {
+            return Err(anyhow!("Face vertex {corner} out of range"));
+        }
+        if prim.string("subdivisionScheme").unwrap_or("catmullClark") != "none" {
+            self.warn("Subdivision surfaces are rendered as their control meshes");
+        }
+
+        let left_handed = prim.string("orientation") == Some("leftHanded");
+        let mut triangles = vec![];
+        let mut start = 0;
+        for count in counts {
+            let face = &corners[start..start + count];
+            for i in 1..count.saturating_sub(1) {
+                let (b, c) = if left_handed { (i + 1, i) } else { (i, i + 1) };
+                triangles.extend([face[0] as u32, face[b] as u32, face[c] as u32]);
+            }
+            start += count;
+        }
+
+        // Only per-vertex normals fit the shared vertices, meshes are flat shaded otherwise.
+        let normals = ["normals", "primvars:normals"].iter().find_map(|&name| {
+            let property = prim.property(name)?;
+            let per_vertex = matches!(
+                property.interpolation.as_deref(),
+                None | Some("vertex" | "varying")
+            );
+            let normals = property.value.as_ref()?.as_vec3s().ok()?;
+            (per_vertex && normals.len() == positions.len()).then_some(normals)
+        });
+
+        self.mesh(positions, normals, triangles, matrix, material)
+    }
+
+    fn mesh(
+        &mut self,
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        indices: Vec<u32>,
+        matrix: &Mat4,
+        material: Material,
+    ) -> Result<()> {
+        let positions = positions
+            .into_iter()
+            .map(|p| transform_point(matrix, p))
+            .collect();
+        let mut mesh = TriangleMesh::new(positions, indices);
+        mesh.normals = normals.map(|normals| {
+            normals
+                .into_iter()
+                .map(|n| transform_normal(matrix, n))
+                .collect()
+        });
+        self.scene.new_mesh(mesh, Rotation::identity(), material)
+    }
+
+    /// Cameras look down their -z axis. Focal length and apertures are in tenths of the
+    /// scene unit.
+    fn camera(&mut self, prim: &Prim, matrix: &Mat4) {
+        let eye = transform_point(matrix, Vec3::zeros());
+        self.camera.lookfrom = eye;
+        self.camera.lookat = eye - matrix.transform_vector(&Vec3::z()).normalize();
+        self.camera.vup = matrix.transform_vector(&Vec3::y()).normalize();
+
+        let focal_length = prim.float("focalLength", 50.0);
+        let vertical_aperture = prim.float("verticalAperture", 15.2908);
+        if prim.string("projection") == Some("orthographic") {
+            let height = vertical_aperture / 10.0 * matrix.transform_vector(&Vec3::y()).norm();
+            self.camera.projection = Projection::Orthographic { height };
+        } else {
+            let fov = 2.0
+                * (vertical_aperture / (2.0 * focal_length))
+                    .atan()
+                    .to_degrees();
+            if (fov - 90.0).abs() > 0.5 {
+                self.warn(format!(
+                    "The field of view is fixed at 90°, the camera's {fov:.1}° are ignored"
+                ));
+            }
+        }
+
+        let f_stop = prim.float("fStop", 0.0);
+        if f_stop > 0.0 {
+            let focus_distance = prim.float("focusDistance", 0.0);
+            self.camera.lens = Lens {
+                aperture: focal_length / 10.0 / f_stop / 2.0,
+                focus_distance: (focus_distance > 0.0).then_some(focus_distance),
+                ..Lens::default()
+            };
+        }
+    }
+
+    /// UsdLux lights, which emit toward their -z axis.
+    fn light(&mut self, prim: &Prim, matrix: &Mat4) {
+        let color = prim
+            .input("color")
+            .and_then(|color| color.as_vec3().ok())
+            .unwrap_or(Vec3::new(1.0, 1.0, 1.0));
+        let intensity =
+            prim.input_float("intensity", 1.0) * prim.input_float("exposure", 0.0).exp2();
+        let position = transform_point(matrix, Vec3::zeros());
+        let scale = matrix.fixed_view::<3, 3>(0, 0).determinant().abs().cbrt();
+
+        let light = match prim.kind.as_str() {
+            "SphereLight" => {
+                let radius = if prim.input_float("treatAsPoint", 0.0) != 0.0 {
+                    0.0
+                } else {
+                    prim.input_float("radius", 0.5) * scale
+                };
+                Light::point(position, radius, color, intensity)
+            }
+            kind @ ("RectLight" | "DiskLight") => {
+                let (width, height) = if kind == "RectLight" {
+                    (
+                        prim.input_float("width", 1.0),
+                        prim.input_float("height", 1.0),
+                    )
+                } else {
+                    self.warn("Disk lights are approximated by square lights of the same area");
+                    let side = prim.input_float("radius", 0.5) * std::f32::consts::PI.sqrt();
+                    (side, side)
+                };
+                if prim.input("texture:file").is_some() {
+                    self.warn("Light textures are not supported");
+                }
+                let corner = transform_point(matrix, Vec3::new(-width / 2.0, -height / 2.0, 0.0));
+                let mut edge_u = matrix.transform_vector(&Vec3::new(width, 0.0, 0.0));
+                let mut edge_v = matrix.transform_vector(&Vec3::new(0.0, height, 0.0));
+                if edge_u
+                    .cross(&edge_v)
+                    .dot(&transform_normal(matrix, -Vec3::z()))
+                    < 0.0
+                {
+                    std::mem::swap(&mut edge_u, &mut edge_v);
+                }
+                Light::quad(corner, edge_u, edge_v, color, intensity)
+            }
+            "DistantLight" => Light::directional(
+                matrix.transform_vector(&Vec3::z()),
+                (prim.input_float("angle", 0.53) / 2.0).to_radians(),
+                color,
+                intensity,
+            ),
+            _ => {
+                self.warn("Dome lights are approximated by the daylight sky");
+                self.sky = Sky::new(
+                    Sky::default().sun_direction,
+                    Sky::MIN_TURBIDITY,
+                    (color * intensity).max(),
+                );
+                return;
+            }
+        };
+        self.scene.new_light(light);
+    }
+}
+
+fn axis_rotation(axis: char, degrees: f32) -> Mat4 {
+    let axis = match axis {
+        'X' => Vec3::x_axis(),
+        'Y' => Vec3::y_axis(),
+        _ => Vec3::z_axis(),
+    };
+    na::Rotation3::from_axis_angle(&axis, degrees.to_radians()).to_homogeneous()
+}
+
+fn transform_point(matrix: &Mat4, p: Vec3) -> Vec3 {
+    matrix.transform_point(&na::Point3::from(p)).coords
+}
+
+fn transform_normal(matrix: &Mat4, n: Vec3) -> Vec3 {
+    let linear: na::Matrix3<f32> = matrix.fixed_view::<3, 3>(0, 0).into();
+    let inverse_transpose = linear
+        .try_inverse()
+        .map_or(linear, |inverse| inverse.transpose());
+    (inverse_transpose * n).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAGE: &str = r#"#usda 1.0
+(
+    doc = """A stage for
+    the importer tests."""
+    upAxis = "Y"
+)
+
+def Xform "World"
+{
+    def Camera "Camera"
+    {
+        float focalLength = 10
+        float verticalAperture = 20
+        double3 xformOp:translate = (0, 1, 5)
+        uniform token[] xformOpOrder = ["xformOp:translate"]
+    }
+
+    def Xform "Props" (
+        prepend apiSchemas = ["MaterialBindingAPI"]
+    )
+    {
+        rel material:binding = </World/Looks/Gold>
+        double3 xformOp:translate = (1, 0, 0)
+        float3 xformOp:rotateXYZ = (0, 90, 0)
+        uniform token[] xformOpOrder = ["xformOp:translate", "xformOp:rotateXYZ"]
+
+        def Sphere "Ball"
+        {
+            double radius = 0.5
+            double3 xformOp:translate.timeSamples = {
+                0: (2, 0, 0),
+                24: (0, 0, 0),
+            }
+            uniform token[] xformOpOrder = ["xformOp:translate"]
+        }
+    }
+
+    def Mesh "Floor"
+    {
+        int[] faceVertexCounts = [4]
+        int[] faceVertexIndices = [0, 1, 2, 3]
+        point3f[] points = [(-5, 0, -5), (5, 0, -5), (5, 0, 5), (-5, 0, 5)]
+        uniform token orientation = "leftHanded"
+        uniform token subdivisionScheme = "none"
+        color3f[] primvars:displayColor = [(0.5, 0.25, 1)]
+    }
+
+    def RectLight "Light"
+    {
+        float inputs:intensity = 5
+        float inputs:exposure = 1
+        float xformOp:rotateX = -90
+        double3 xformOp:translate = (0, 3, 0)
+        uniform token[] xformOpOrder = ["xformOp:translate", "xformOp:rotateX"]
+    }
+
+    def Scope "Looks"
+    {
+        def Material "Gold"
+        {
+            token outputs:surface.connect = </World/Looks/Gold/Surface.outputs:surface>
+
+            def Shader "Surface"
+            {
+                uniform token info:id = "UsdPreviewSurface"
+                color3f inputs:diffuseColor = (1, 0.71, 0.29)
+                float inputs:metallic = 1
+                float inputs:roughness = 0.2
+                token outputs:surface
+            }
+        }
+    }
+}
+"#;
+
+    #[test]
+    fn imports_prims_materials_and_lights() {
+        let (scene_file, warnings) = import(STAGE.as_bytes()).unwrap();
+        assert!(warnings.contains("Animated attributes use their first time sample"));
+        assert_eq!(scene_file.camera.lookfrom, Vec3::new(0.0, 1.0, 5.0));
+        assert_eq!(scene_file.camera.lookat, Vec3::new(0.0, 1.0, 4.0));
+        assert!(!warnings
+            .iter()
+            .any(|warning| warning.contains("field of view")));
+
+        let ron = scene_file.to_ron();
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        assert_eq!(objects.len(), 2);
+        // The sample at (2, 0, 0) turned by 90° around y, then moved along x.
+        let center = objects[0].field("center").unwrap().as_vec3().unwrap();
+        assert!(
+            (center - Vec3::new(1.0, 0.0, -2.0)).norm() < 1e-5,
+            "{center:?}"
+        );
+        // Left-handed faces are wound the other way round.
+        let indices = objects[1].field("indices").unwrap().as_list().unwrap();
+        let indices: Vec<f32> = indices.iter().map(|i| i.as_f32().unwrap()).collect();
+        assert_eq!(indices, [0.0, 2.0, 1.0, 0.0, 3.0, 2.0]);
+
+        let materials = ron.field("materials").unwrap().as_map().unwrap();
+        assert!(materials
+            .iter()
+            .any(|(_, material)| material.name() == Some("Pbr")));
+        assert!(materials
+            .iter()
+            .any(|(_, material)| material.name() == Some("Lambertian")));
+
+        // Rotated by -90° around x, the light's -z faces down.
+        let lights = ron.field("lights").unwrap().as_list().unwrap();
+        let edge_u = lights[0].field("edge_u").unwrap().as_vec3().unwrap();
+        let edge_v = lights[0].field("edge_v").unwrap().as_vec3().unwrap();
+        assert!(edge_u.cross(&edge_v).y < 0.0);
+        assert_eq!(
+            lights[0].field("intensity").unwrap().as_f32().unwrap(),
+            10.0
+        );
+    }
+
+    #[test]
+    fn rotates_z_up_stages() {
+        let stage = "#usda 1.0\n(\n    upAxis = \"Z\"\n)\n\ndef Sphere \"Ball\" {\n    double3 xformOp:translate = (0, 0, 2)\n    uniform token[] xformOpOrder = [\"xformOp:translate\"]\n}\n";
+        let (scene_file, _) = import(stage.as_bytes()).unwrap();
+        let ron = scene_file.to_ron();
+        let objects = ron.field("objects").unwrap().as_list().unwrap();
+        let center = objects[0].field("center").unwrap().as_vec3().unwrap();
+        assert!(
+            (center - Vec3::new(0.0, 2.0, 0.0)).norm() < 1e-6,
+            "{center:?}"
+        );
+    }
+
+    #[test]
+    fn reads_layers_from_packages() {
+        fn zip(name: &str, data: &[u8]) -> Vec<u8> {
+            let mut local = b"PK\x03\x04".to_vec();
+            local.extend([0; 14]);
+            local.extend((data.len() as u32).to_le_bytes());
+            local.extend((data.len() as u32).to_le_bytes());
+            local.extend((name.len() as u16).to_le_bytes());
+            local.extend([0; 2]);
+            local.extend(name.as_bytes());
+            local.extend(data);
+
+            let mut central = b"PK\x01\x02".to_vec();
+            central.extend([0; 16]);
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend((name.len() as u16).to_le_bytes());
+            central.extend([0; 12]);
+            central.extend(0u32.to_le_bytes());
+            central.extend(name.as_bytes());
+
+            let mut archive = local.clone();
+            archive.extend(&central);
+            archive.extend(b"PK\x05\x06");
+            archive.extend([0; 4]);
+            archive.extend(1u16.to_le_bytes());
+            archive.extend(1u16.to_le_bytes());
+            archive.extend((central.len() as u32).to_le_bytes());
+            archive.extend((local.len() as u32).to_le_bytes());
+            archive.extend([0; 2]);
+            archive
+        }
+
+        let archive = zip("scene.usda", STAGE.as_bytes());
+        assert_eq!(package_layer(&archive).unwrap(), STAGE.as_bytes());
+
+        let archive = zip("scene.usdc", b"PXR-USDC\0\0");
+        let err = import(package_layer(&archive).unwrap()).err().unwrap();
+        assert!(err.to_string().contains("usdcat"), "{err}");
+    }
+}
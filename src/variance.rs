@@ -0,0 +1,79 @@
+//! Per-pixel noise estimates from the accumulated image (sums of the samples, see
+//! `Accumulation`) and its moments (sums of the squared sample luminance).
+
+/// Samples a pixel needs before its variance estimate means anything.
+pub const MIN_SAMPLES: f32 = 4.0;
+/// Keeps the relative error of black pixels finite. Same as in `render.wgsl`.
+const LUMINANCE_EPSILON: f32 = 0.01;
+
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Relative standard error of a pixel's mean luminance, the expected deviation of the displayed
+/// value from the converged one as a fraction of it. `None` below `MIN_SAMPLES`.
+pub fn relative_error([r, g, b, samples]: [f32; 4], moments: [f32; 4]) -> Option<f32> {
+    if samples < MIN_SAMPLES {
+        return None;
+    }
+
+    let mean = luminance([r, g, b]) / samples;
+    let variance = (moments[0] / samples - mean * mean).max(0.0) * samples / (samples - 1.0);
+    Some((variance / samples).sqrt() / (mean + LUMINANCE_EPSILON))
+}
+
+/// Mean relative error over the pixels with enough samples, `None` when there are none.
+pub fn mean_relative_error(texels: &[[f32; 4]], moments: &[[f32; 4]]) -> Option<f32> {
+    let (sum, pixels) = texels
+        .iter()
+        .zip(moments)
+        .filter_map(|(&texel, &moments)| relative_error(texel, moments))
+        .fold((0.0, 0), |(sum, pixels), error| {
+            (sum + error as f64, pixels + 1)
+        });
+
+    (pixels > 0).then(|| (sum / pixels as f64) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Texel and moments of a gray pixel whose samples alternate between `a` and `b`.
+    fn pixel(a: f32, b: f32, samples: u32) -> ([f32; 4], [f32; 4]) {
+        let mut sum = 0.0;
+        let mut squares = 0.0;
+        for i in 0..samples {
+            let value = if i % 2 == 0 { a } else { b };
+            sum += value;
+            squares += value * value;
+        }
+        ([sum, sum, sum, samples as f32], [squares, 0.0, 0.0, 0.0])
+    }
+
+    #[test]
+    fn error_shrinks_with_samples() {
+        let (texel, moments) = pixel(0.5, 0.5, 16);
+        assert_eq!(relative_error(texel, moments), Some(0.0));
+
+        let (texel, moments) = pixel(0.0, 1.0, 2);
+        assert_eq!(relative_error(texel, moments), None);
+
+        let noisy = |samples| {
+            let (texel, moments) = pixel(0.0, 1.0, samples);
+            relative_error(texel, moments).unwrap()
+        };
+        assert!(noisy(16) > 0.2);
+        assert!((noisy(64) / noisy(256) - 2.0).abs() < 0.05);
+
+        let (texels, moments): (Vec<_>, Vec<_>) =
+            [pixel(0.5, 0.5, 16), pixel(0.0, 1.0, 16), pixel(1.0, 1.0, 1)]
+                .into_iter()
+                .unzip();
+        assert_eq!(
+            mean_relative_error(&texels, &moments),
+            Some(noisy(16) / 2.0)
+        );
+        assert_eq!(mean_relative_error(&texels[2..], &moments[2..]), None);
+    }
+}
@@ -0,0 +1,232 @@
+//! Minimal parser for the subset of XML used by Mitsuba scene files: elements and
+//! attributes, text content is skipped.
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn required(&self, name: &str) -> Result<&str> {
+        self.attribute(name)
+            .ok_or_else(|| anyhow!("Missing attribute `{name}` on `<{}>`", self.name))
+    }
+}
+
+pub fn parse(source: &str) -> Result<Element> {
+    let mut parser = Parser { source, pos: 0 };
+    parser.skip_misc()?;
+    let root = parser.element().map_err(|err| parser.located(err))?;
+    parser.skip_misc()?;
+    if parser.pos < source.len() {
+        return Err(parser.located(anyhow!("Unexpected content after the root element")));
+    }
+    Ok(root)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.source[self.pos..]
+    }
+
+    fn located(&self, err: anyhow::Error) -> anyhow::Error {
+        let line = self.source[..self.pos].matches('\n').count() + 1;
+        err.context(format!("On line {line}"))
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.source.len() - trimmed.len();
+    }
+
+    fn skip_past(&mut self, end: &str) -> Result<()> {
+        let offset = self
+            .rest()
+            .find(end)
+            .ok_or_else(|| anyhow!("Missing `{end}`"))?;
+        self.pos += offset + end.len();
+        Ok(())
+    }
+
+    /// Skips whitespace, comments, declarations and processing instructions.
+    fn skip_misc(&mut self) -> Result<()> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(anyhow!("Expected a name"));
+        }
+        let name = self.rest()[..len].to_owned();
+        self.pos += len;
+        Ok(name)
+    }
+
+    fn element(&mut self) -> Result<Element> {
+        if !self.rest().starts_with('<') {
+            return Err(anyhow!("Expected an element"));
+        }
+        self.pos += 1;
+        let name = self.name()?;
+
+        let mut attributes = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children: vec![],
+                });
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(anyhow!("Expected `=` after attribute `{attribute}`"));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = self
+                .rest()
+                .chars()
+                .next()
+                .filter(|&c| c == '"' || c == '\'')
+                .ok_or_else(|| anyhow!("Expected a quoted value of `{attribute}`"))?;
+            self.pos += 1;
+            let len = self
+                .rest()
+                .find(quote)
+                .ok_or_else(|| anyhow!("Unterminated value of `{attribute}`"))?;
+            let value = unescape(&self.rest()[..len])?;
+            self.pos += len + 1;
+            attributes.push((attribute, value));
+        }
+
+        let mut children = vec![];
+        loop {
+            // Text between elements is not used by scene files.
+            let text = self
+                .rest()
+                .find('<')
+                .ok_or_else(|| anyhow!("Missing `</{name}>`"))?;
+            self.pos += text;
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let closing = self.name()?;
+                if closing != name {
+                    return Err(anyhow!("Expected `</{name}>`, got `</{closing}>`"));
+                }
+                self.skip_whitespace();
+                self.skip_past(">")?;
+                return Ok(Element {
+                    name,
+                    attributes,
+                    children,
+                });
+            }
+            if self.rest().starts_with("<!--") || self.rest().starts_with("<?") {
+                self.skip_misc()?;
+                continue;
+            }
+            children.push(self.element()?);
+        }
+    }
+}
+
+fn unescape(text: &str) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let semicolon = rest[amp..]
+            .find(';')
+            .ok_or_else(|| anyhow!("Unterminated entity in `{text}`"))?;
+        let entity = &rest[amp + 1..amp + semicolon];
+        out.push(match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| anyhow!("Unknown entity `&{entity};`"))?
+            }
+        });
+        rest = &rest[amp + semicolon + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_elements() {
+        let root = parse(
+            r#"<?xml version="1.0"?>
+            <!-- comment -->
+            <scene version="3.0.0">
+                <float name="radius" value='0.5'/>
+                <string name="label" value="a &lt; b &#x26; c"/>
+                <shape type="sphere">text is skipped<ref id="white"/></shape>
+            </scene>"#,
+        )
+        .unwrap();
+
+        assert_eq!(root.name, "scene");
+        assert_eq!(root.attribute("version"), Some("3.0.0"));
+        assert_eq!(root.children.len(), 3);
+        assert_eq!(root.children[0].attribute("value"), Some("0.5"));
+        assert_eq!(root.children[1].attribute("value"), Some("a < b & c"));
+        assert_eq!(root.children[2].children[0].attribute("id"), Some("white"));
+    }
+
+    #[test]
+    fn reports_mismatched_tags() {
+        let err = parse("<scene>\n<shape>\n</scene>").unwrap_err();
+        assert!(format!("{err:#}").contains("line 3"), "{err:#}");
+    }
+}